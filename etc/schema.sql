@@ -5,6 +5,14 @@ CREATE TABLE handler (
     hash TEXT,
     code TEXT NOT NULL,
     status INTEGER NOT NULL,
+    -- Maximum execution time per Event.
+    timeout_ms INTEGER NOT NULL DEFAULT 10,
+    -- Counters maintained when results are saved. Can be recalculated with --recount.
+    success_count BIGINT NOT NULL DEFAULT 0,
+    error_count BIGINT NOT NULL DEFAULT 0,
+    -- Errors that were timeouts, also counted in error_count.
+    timeout_count BIGINT NOT NULL DEFAULT 0,
+    last_result_at TIMESTAMPTZ NULL,
     created TIMESTAMPTZ NOT NULL DEFAULT NOW(),
     UNIQUE(hash));
 
@@ -28,12 +36,45 @@ CREATE TABLE event (
     assertion_id BIGINT NOT NULL,
     subject_entity_id BIGINT NULL REFERENCES entity(entity_id),
     object_entity_id BIGINT NULL REFERENCES entity(entity_id),
-    created TIMESTAMPTZ NOT NULL DEFAULT NOW());
+    -- Hash of source, analyzer, subject, object and normalized JSON.
+    hash TEXT NOT NULL,
+    created TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    -- Logical time of the Event. The insertion time unless one was supplied, e.g. for backfills.
+    occurred_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    -- Reject duplicate events, e.g. from re-running extraction.
+    UNIQUE(hash));
+
+-- Used for looking up Events by entity.
+CREATE INDEX event_subject_idx ON event(subject_entity_id);
+CREATE INDEX event_object_idx ON event(object_entity_id);
 
 -- Queue of Event pointers to be passed to Handler functions.
 CREATE TABLE event_queue (
     event_queue_id BIGSERIAL PRIMARY KEY NOT NULL,
     event_id BIGINT,
+    -- If set, only run this handler for the Event, e.g. when re-run. Otherwise run all enabled handlers.
+    handler_id BIGINT NULL,
+    -- Number of previous attempts where every handler errored.
+    attempts INTEGER NOT NULL DEFAULT 0,
+    -- From the EventQueueState enum. Only 'new' entries are polled.
+    -- Processed entries are deleted, unless EVENT_QUEUE_RETAIN is set.
+    status INTEGER NOT NULL DEFAULT 1,
+    -- When the entry was processed, if retained.
+    processed TIMESTAMPTZ NULL,
+    created TIMESTAMPTZ NOT NULL DEFAULT NOW());
+
+-- Used for polling new entries when processed ones are retained.
+CREATE INDEX event_queue_new_idx
+    ON event_queue(event_queue_id)
+    WHERE status = 1;
+
+-- Events taken off the queue because every handler errored, after retries.
+CREATE TABLE dead_letter_event (
+    dead_letter_id BIGSERIAL PRIMARY KEY NOT NULL,
+    event_id BIGINT NOT NULL,
+    attempts INTEGER NOT NULL,
+    -- JSON array of error messages accumulated for the Event.
+    errors TEXT NOT NULL,
     created TIMESTAMPTZ NOT NULL DEFAULT NOW());
 
 -- Populate Event Queue for new Events.
@@ -44,6 +85,8 @@ $$
 BEGIN
     INSERT INTO event_queue (event_id)
     VALUES (NEW.event_id);
+    -- Wake any waiting executors. Identical notifications in a transaction are collapsed into one.
+    PERFORM pg_notify('event_queue', '');
 RETURN NULL;
 END;
 $$;
@@ -58,8 +101,12 @@ CREATE TABLE execution_result (
     result_id BIGSERIAL PRIMARY KEY NOT NULL,
     handler_id BIGINT NOT NULL,
     event_id BIGINT NOT NULL,
+    -- Metadata assertion that generated the event, or -1.
+    assertion_id BIGINT NOT NULL DEFAULT -1,
     result TEXT NULL,
     error TEXT NULL,
+    -- Kind of error, from the ErrorKind enum.
+    error_kind INTEGER NULL,
     created TIMESTAMPTZ NOT NULL DEFAULT NOW());
 
 -- Used for querying successful results after cursor.
@@ -71,6 +118,10 @@ CREATE INDEX success_execution_idx
 CREATE INDEX all_execution_idx
     ON execution_result(handler_id, result);
 
+-- Used for purging old results.
+CREATE INDEX execution_result_created_idx
+    ON execution_result(created);
+
 -- Metadata assertion of a source.
 -- There may be multiple metadata assertions about a subject entity, even by a source.
 -- Older duplicate assertions may be removed.
@@ -89,14 +140,44 @@ CREATE TABLE metadata_assertion (
     -- 2 is secondary activity (i.e. gathering background metadata)
     reason SMALLINT,
 
+    -- Version of the subject's metadata from this source, starting at 1.
+    -- When the metadata changes, the new assertion gets the next version.
+    -- The latest is the current state, and `created` records when it was asserted.
+    version INTEGER NOT NULL DEFAULT 1,
+
     -- Reject duplicate assertions from the same source based on their hash.
-    UNIQUE(subject_entity_id, hash, source_id)
+    UNIQUE(subject_entity_id, hash, source_id),
+
+    -- Each version of a subject's metadata from a source is only asserted once.
+    -- Also used for finding the latest version for a subject from a source.
+    UNIQUE(subject_entity_id, source_id, version)
 );
 
 -- Named checkpoint date, used by agents.
+-- With an optional tiebreak between items at the same date, e.g. the DOI of the last work harvested.
 CREATE TABLE CHECKPOINT (
     id TEXT PRIMARY KEY NOT NULL,
-    date TIMESTAMPTZ NOT NULL
+    date TIMESTAMPTZ NOT NULL,
+    tiebreak TEXT
+);
+
+-- Named checkpoint position, e.g. an ID, used by agents.
+CREATE TABLE position_checkpoint (
+    id TEXT PRIMARY KEY NOT NULL,
+    position BIGINT NOT NULL
+);
+
+-- Named cursor to resume an interrupted harvest from, with the query it belongs to.
+CREATE TABLE cursor_checkpoint (
+    id TEXT PRIMARY KEY NOT NULL,
+    value TEXT NOT NULL,
+    query TEXT NOT NULL
+);
+
+-- Named on/off control flag, set by operators, e.g. to pause harvesting.
+CREATE TABLE control_flag (
+    id TEXT PRIMARY KEY NOT NULL,
+    value BOOLEAN NOT NULL
 );
 
 
@@ -106,6 +187,13 @@ CREATE TABLE metadata_assertion_queue (
     assertion_id BIGINT,
     created TIMESTAMPTZ NOT NULL DEFAULT NOW());
 
+-- Queue of entities to fetch metadata for, when extraction defers fetching.
+-- Each entity is only queued once at a time.
+CREATE TABLE metadata_fetch_queue (
+    queue_id BIGSERIAL PRIMARY KEY NOT NULL,
+    entity_id BIGINT NOT NULL UNIQUE,
+    created TIMESTAMPTZ NOT NULL DEFAULT NOW());
+
 -- Populate Metadata Assertions Queue for new primary Metadata Assertions.
 CREATE FUNCTION new_metadata_trigger_f()
     RETURNS TRIGGER
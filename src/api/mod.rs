@@ -1,47 +1,278 @@
+use std::{convert::Infallible, sync::OnceLock};
+
 use axum::{
-    extract::{Multipart, Path, Query, State},
-    http::HeaderValue,
-    response::{IntoResponse, Redirect, Response},
-    routing::get,
-    Router,
+    body::Body,
+    extract::{
+        multipart::MultipartError, rejection::JsonRejection, DefaultBodyLimit, FromRef, Multipart,
+        Path, Query, Request, State,
+    },
+    http::{
+        header::{ACCEPT, AUTHORIZATION, CONTENT_LENGTH, ETAG, IF_NONE_MATCH},
+        HeaderMap, HeaderValue, Method,
+    },
+    middleware::{self, Next},
+    response::{
+        sse::{self, KeepAlive, Sse},
+        IntoResponse, Redirect, Response,
+    },
+    routing::{get, post},
+    Json, Router,
 };
 use axum_extra::response::ErasedJson;
+use futures::StreamExt;
 use reqwest::{header::CONTENT_TYPE, StatusCode};
+use scholarly_identifiers::identifiers::Identifier;
 use serde_json::Value;
 use sqlx::{Pool, Postgres};
+use tokio::sync::watch;
 
-use crate::{db, execution::model::HandlerSpec, service, util::VERSION};
+use crate::{
+    db::{
+        self,
+        event::EntityRole,
+        source::{EventAnalyzerId, MetadataSourceId},
+    },
+    execution::model::HandlerSpec,
+    service,
+    util::{env_or_default, hash_data, GIT_COMMIT, VERSION},
+};
 
 mod model;
 
 const RESULT_PAGE_SIZE: i32 = 1000;
 
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// Default maximum size of a request body.
+const DEFAULT_MAX_BODY_BYTES: usize = 4 * 1024 * 1024;
+
+static MAX_BODY_BYTES: OnceLock<usize> = OnceLock::new();
+
+/// Maximum size of a request body, e.g. uploaded Function code or an array of Events.
+/// Configured from the MAX_BODY_BYTES environment variable, read once.
+fn max_body_bytes() -> usize {
+    *MAX_BODY_BYTES.get_or_init(|| env_or_default("MAX_BODY_BYTES", DEFAULT_MAX_BODY_BYTES))
+}
+
+/// Header giving the owner of an uploaded Function.
+const OWNER_HEADER: &str = "x-owner-id";
+
+/// State shared between API handlers.
+#[derive(Clone)]
+pub(crate) struct AppState {
+    pool: Pool<Postgres>,
+
+    /// Timeout for uploaded handler functions that don't specify one.
+    default_handler_timeout_ms: i32,
+
+    /// Key required for mutating requests. If None, the write API is open.
+    api_key: Option<String>,
+
+    /// Set to true when the server starts shutting down, so that long-lived streams can end.
+    shutdown: watch::Receiver<bool>,
+}
+
+impl FromRef<AppState> for Pool<Postgres> {
+    fn from_ref(state: &AppState) -> Pool<Postgres> {
+        state.pool.clone()
+    }
+}
+
+/// Require a matching `Authorization: Bearer` header for mutating requests, if an API key is configured.
+/// Read-only requests are always allowed.
+async fn require_api_key(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let mutating = matches!(
+        *request.method(),
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    );
+
+    if let (true, Some(api_key)) = (mutating, &state.api_key) {
+        let supplied = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.strip_prefix("Bearer "));
+
+        if supplied != Some(api_key.as_str()) {
+            return (
+                StatusCode::UNAUTHORIZED,
+                ErasedJson::pretty(model::ErrorPage::new(
+                    "unauthorized",
+                    "A valid API key is required. Supply it as 'Authorization: Bearer <key>'.",
+                )),
+            )
+                .into_response();
+        }
+    }
+
+    next.run(request).await
+}
+
+/// Whether the request declares a body larger than the limit.
+/// Bodies without a Content-Length are limited as they're read instead.
+fn exceeds_body_limit(headers: &HeaderMap, limit: usize) -> bool {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.trim().parse::<usize>().ok())
+        .is_some_and(|length| length > limit)
+}
+
+fn body_too_large_response() -> Response {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        ErasedJson::pretty(model::ErrorPage::new(
+            "too-large",
+            &format!(
+                "Request body is larger than the limit of {} bytes.",
+                max_body_bytes()
+            ),
+        )),
+    )
+        .into_response()
+}
+
+/// Response for a multipart body that couldn't be read.
+/// A body without a Content-Length is only found to be over the limit part way through reading it.
+fn multipart_error_response(err: MultipartError) -> Response {
+    if err.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        return body_too_large_response();
+    }
+
+    (
+        StatusCode::BAD_REQUEST,
+        ErasedJson::pretty(model::ErrorPage::new("bad-request", &err.body_text())),
+    )
+        .into_response()
+}
+
+/// Reject requests that declare a body over the size limit before reading any of it.
+async fn limit_body(request: Request, next: Next) -> Response {
+    if exceeds_body_limit(request.headers(), max_body_bytes()) {
+        return body_too_large_response();
+    }
+
+    next.run(request).await
+}
+
+/// HTTP status for a service error.
+fn service_error_status(err: &service::ServiceError) -> StatusCode {
+    match err {
+        service::ServiceError::NotFound => StatusCode::NOT_FOUND,
+        service::ServiceError::Validation(_) => StatusCode::BAD_REQUEST,
+        service::ServiceError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Response for a service error, with the message to use if the item wasn't found.
+/// Database errors are logged, and not shown to the client.
+fn service_error_response(err: service::ServiceError, not_found_message: &str) -> Response {
+    let status = service_error_status(&err);
+
+    let page = match err {
+        service::ServiceError::NotFound => model::ErrorPage::new("not-found", not_found_message),
+        service::ServiceError::Validation(message) => {
+            model::ErrorPage::new("bad-request", &message)
+        }
+        service::ServiceError::Database(e) => {
+            log::error!("Database error: {:?}", e);
+            model::ErrorPage::new("internal-error", "Database error.")
+        }
+    };
+
+    (status, ErasedJson::pretty(page)).into_response()
+}
+
+/// Response when a Function that was just saved can't be read back.
+fn retrieve_function_error(err: service::ServiceError) -> Response {
+    log::error!("Error retrieving function: {}", err);
+
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        ErasedJson::pretty(model::ErrorPage::new(
+            "internal-error",
+            "Error retrieving function.",
+        )),
+    )
+        .into_response()
+}
+
+/// Liveness probe. Succeeds as long as the process is serving requests, without touching the database or other state.
+/// For readiness, use the heartbeat.
+async fn livez() -> Response {
+    (StatusCode::OK, [(CONTENT_TYPE, "text/plain")], "ok").into_response()
+}
+
+/// Build metadata, for checking which version is deployed. Doesn't touch the database.
+async fn version() -> Response {
+    ErasedJson::pretty(version_info(GIT_COMMIT)).into_response()
+}
+
+fn version_info(commit: Option<&str>) -> serde_json::Value {
+    let mut result = serde_json::json!({"platform": "Pardalotus Metabeak", "version": VERSION});
+
+    if let Some(commit) = commit {
+        result["commit"] = serde_json::Value::String(commit.to_string());
+    }
+
+    result
+}
+
+/// Known analyzer and source names, as used in Events. Doesn't touch the database.
+async fn vocabulary() -> Response {
+    ErasedJson::pretty(vocabulary_info()).into_response()
+}
+
+fn vocabulary_info() -> serde_json::Value {
+    let analyzers: Vec<String> = EventAnalyzerId::all()
+        .into_iter()
+        .map(|x| x.to_str_value())
+        .collect();
+    let sources: Vec<String> = MetadataSourceId::all()
+        .into_iter()
+        .map(|x| x.to_str_value())
+        .collect();
+
+    serde_json::json!({"analyzers": analyzers, "sources": sources})
+}
+
+/// Readiness probe. Succeeds only if the database can be queried.
 async fn heartbeat(State(shared_state): State<Pool<Postgres>>) -> Response {
+    let pool_stats = db::pool::stats(&shared_state);
+
     match db::pool::heartbeat(&shared_state).await {
         Ok(result) if result => (
             StatusCode::OK,
              ErasedJson::pretty(
-                serde_json::json!({"heartbeat": result, "platform": "Pardalotus API", "version": VERSION}),
+                serde_json::json!({"heartbeat": result, "platform": "Pardalotus API", "version": VERSION, "pool": pool_stats}),
             ),
         ),
         Err(e) => {
             log::error!("Heartbeat failure: {:?}", e);
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                ErasedJson::pretty(serde_json::json!({"heartbeat": false, "platform": "Pardalotus API", "version": VERSION})),
+                ErasedJson::pretty(serde_json::json!({"heartbeat": false, "platform": "Pardalotus API", "version": VERSION, "pool": pool_stats})),
             )
         }
         _ => {
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                ErasedJson::new(serde_json::json!({"heartbeat": false, "platform": "Pardalotus API", "version": VERSION})),
+                ErasedJson::new(serde_json::json!({"heartbeat": false, "platform": "Pardalotus API", "version": VERSION, "pool": pool_stats})),
             )
         }
     }.into_response()
 }
 
-async fn list_functions(State(shared_state): State<Pool<Postgres>>) -> Response {
-    match service::list_handlers(&shared_state).await {
+async fn list_functions(
+    Query(query): Query<model::FunctionsQuery>,
+    State(shared_state): State<Pool<Postgres>>,
+) -> Response {
+    let handlers = match query.owner {
+        Some(owner_id) => service::list_handlers_for_owner(&shared_state, owner_id).await,
+        None => service::list_handlers(&shared_state).await,
+    };
+
+    match handlers {
         Ok(result) => (
             StatusCode::OK,
             ErasedJson::pretty(model::FunctionsPage::from(result)),
@@ -58,73 +289,179 @@ async fn list_functions(State(shared_state): State<Pool<Postgres>>) -> Response
     }
 }
 
-async fn post_function(State(pool): State<Pool<Postgres>>, mut multipart: Multipart) -> Response {
-    while let Ok(Some(field)) = multipart.next_field().await {
+/// Owner of an uploaded Function, from the `X-Owner-Id` header. 0 if there isn't one.
+fn owner_from_headers(headers: &HeaderMap) -> Option<i32> {
+    match headers.get(OWNER_HEADER) {
+        None => Some(0),
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|x| x.trim().parse::<i32>().ok())
+            .filter(|x| *x >= 0),
+    }
+}
+
+/// Code hashes listed in an If-None-Match header, without quotes or weak prefixes.
+fn if_none_match_hashes(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| {
+            let tag = tag.trim();
+            tag.strip_prefix("W/")
+                .unwrap_or(tag)
+                .trim_matches('"')
+                .to_lowercase()
+        })
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+/// Response for an uploaded Function, with its code hash as the ETag.
+/// 201 if this upload created it, otherwise 200.
+fn uploaded_function_response(loaded: HandlerSpec, created: bool) -> Response {
+    let status = if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+
+    let etag = loaded
+        .hash
+        .as_ref()
+        .and_then(|hash| HeaderValue::from_str(&format!("\"{}\"", hash)).ok());
+
+    let mut response = (
+        status,
+        ErasedJson::pretty(model::UploadedFunctionPage::from((loaded, created))),
+    )
+        .into_response();
+
+    if let Some(etag) = etag {
+        response.headers_mut().insert(ETAG, etag);
+    }
+
+    response
+}
+
+async fn post_function(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    let Some(owner_id) = owner_from_headers(&headers) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            ErasedJson::pretty(model::ErrorPage::new(
+                "bad-request",
+                "X-Owner-Id must be a non-negative integer.",
+            )),
+        )
+            .into_response();
+    };
+
+    let pool = state.pool;
+    let mut code: Option<String> = None;
+    let mut timeout_ms = state.default_handler_timeout_ms;
+
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return multipart_error_response(e),
+        };
+
         let name = field.name().unwrap_or("").to_string();
         if name == "data" {
-            if let Ok(data) = field.text().await {
-                let task = HandlerSpec {
-                    handler_id: -1,
-                    code: data,
-                    status: db::handler::HandlerState::Enabled as i32,
-                };
-
-                return match service::load_handler(&pool, &task).await {
-                    service::TaskLoadResult::Exists { task_id } => {
-                        if let Some(loaded) = service::get_handler_by_id(&pool, task_id).await {
-                            (
-                                StatusCode::OK,
-                                ErasedJson::pretty(model::FunctionPage::from((
-                                    loaded,
-                                    String::from("already-exists"),
-                                ))),
-                            )
-                                .into_response()
-                        } else {
-                            (
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                ErasedJson::pretty(model::ErrorPage::new(
-                                    "internal-error",
-                                    "Error retrieving function.",
-                                )),
-                            )
-                                .into_response()
-                        }
-                    }
-
-                    service::TaskLoadResult::New { task_id } => {
-                        (if let Some(loaded) = service::get_handler_by_id(&pool, task_id).await {
-                            (
-                                StatusCode::CREATED,
-                                ErasedJson::pretty(model::FunctionPage::from((
-                                    loaded,
-                                    String::from("created"),
-                                ))),
-                            )
-                                .into_response()
-                        } else {
-                            (
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                ErasedJson::pretty(model::ErrorPage::new(
-                                    "internal-error",
-                                    "Error retrieving function.",
-                                )),
-                            )
-                                .into_response()
-                        })
-                        .into_response()
-                    }
-                    service::TaskLoadResult::FailedSave() => (
+            match field.text().await {
+                Ok(data) => code = Some(data),
+                Err(e) => return multipart_error_response(e),
+            }
+        } else if name == "timeout_ms" {
+            let text = match field.text().await {
+                Ok(text) => text,
+                Err(e) => return multipart_error_response(e),
+            };
+            match text.trim().parse::<i32>() {
+                Ok(value) if value > 0 => timeout_ms = value,
+                _ => {
+                    return (
                         StatusCode::BAD_REQUEST,
                         ErasedJson::pretty(model::ErrorPage::new(
                             "bad-request",
-                            "Error saving function.",
+                            "timeout_ms must be a positive integer.",
                         )),
                     )
-                        .into_response(),
-                };
+                        .into_response()
+                }
+            }
+        }
+    }
+
+    if let Some(data) = code {
+        // Clients that already know the hash can skip validation when it's already uploaded.
+        // As with any matched If-None-Match on a POST, that's a failed precondition.
+        let hash = hash_data(&data);
+        if if_none_match_hashes(&headers).contains(&hash) {
+            match service::get_handler_by_hash(&pool, &hash).await {
+                Ok(loaded) if loaded.code == data => {
+                    let mut response = uploaded_function_response(loaded, false);
+                    *response.status_mut() = StatusCode::PRECONDITION_FAILED;
+                    return response;
+                }
+                Ok(_) | Err(service::ServiceError::NotFound) => {}
+                Err(e) => return retrieve_function_error(e),
             }
         }
+
+        let task = HandlerSpec {
+            handler_id: -1,
+            code: data.clone(),
+            status: db::handler::HandlerState::Enabled as i32,
+            timeout_ms,
+            hash: None,
+            owner_id,
+        };
+
+        return match service::load_handler(&pool, &task).await {
+            service::TaskLoadResult::Exists { task_id } => {
+                match service::get_handler_by_id(&pool, task_id).await {
+                    // Hashes match, so confirm the code is byte-identical too.
+                    Ok(loaded) if loaded.code == data => uploaded_function_response(loaded, false),
+                    Ok(_) => (
+                        StatusCode::CONFLICT,
+                        ErasedJson::pretty(model::ErrorPage::new(
+                            "conflict",
+                            &format!("Function {} has the same hash but different code.", task_id),
+                        )),
+                    )
+                        .into_response(),
+                    Err(e) => retrieve_function_error(e),
+                }
+            }
+
+            service::TaskLoadResult::New { task_id } => {
+                match service::get_handler_by_id(&pool, task_id).await {
+                    Ok(loaded) => uploaded_function_response(loaded, true),
+                    Err(e) => retrieve_function_error(e),
+                }
+            }
+            service::TaskLoadResult::Invalid { message } => (
+                StatusCode::BAD_REQUEST,
+                ErasedJson::pretty(model::ErrorPage::new("invalid-function", &message)),
+            )
+                .into_response(),
+            service::TaskLoadResult::FailedSave() => (
+                StatusCode::BAD_REQUEST,
+                ErasedJson::pretty(model::ErrorPage::new(
+                    "bad-request",
+                    "Error saving function.",
+                )),
+            )
+                .into_response(),
+        };
     }
 
     (
@@ -139,24 +476,141 @@ async fn post_function(State(pool): State<Pool<Postgres>>, mut multipart: Multip
         .into_response()
 }
 
+/// Replace the code of a Function, keeping its ID, so its results continue to accumulate.
+/// The `X-Owner-Id` header must match the Function's owner. The header is supplied by the client,
+/// so this only guards against changing the wrong Function by mistake. Access is controlled by the API key.
+async fn put_function(
+    Path(handler_id): Path<i64>,
+    State(pool): State<Pool<Postgres>>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    let Some(owner_id) = owner_from_headers(&headers) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            ErasedJson::pretty(model::ErrorPage::new(
+                "bad-request",
+                "X-Owner-Id must be a non-negative integer.",
+            )),
+        )
+            .into_response();
+    };
+
+    let existing = match service::get_handler_by_id(&pool, handler_id).await {
+        Ok(existing) => existing,
+        Err(e) => return service_error_response(e, "Couldn't find that Function"),
+    };
+
+    if existing.owner_id != owner_id {
+        return (
+            StatusCode::FORBIDDEN,
+            ErasedJson::pretty(model::ErrorPage::new(
+                "forbidden",
+                "X-Owner-Id doesn't match this Function's owner. Check the Function ID.",
+            )),
+        )
+            .into_response();
+    }
+
+    let mut code: Option<String> = None;
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return multipart_error_response(e),
+        };
+
+        if field.name() == Some("data") {
+            match field.text().await {
+                Ok(data) => code = Some(data),
+                Err(e) => return multipart_error_response(e),
+            }
+        }
+    }
+
+    let Some(code) = code else {
+        return (
+            StatusCode::BAD_REQUEST,
+            ErasedJson::pretty(model::ErrorPage::new(
+                "invalid-function",
+                "No Function supplied. Please check the documentation.",
+            )),
+        )
+            .into_response();
+    };
+
+    match service::update_handler_code(&pool, handler_id, &code).await {
+        service::CodeUpdateResult::Updated => {
+            match service::get_handler_by_id(&pool, handler_id).await {
+                Ok(loaded) => (
+                    StatusCode::OK,
+                    ErasedJson::pretty(model::FunctionPage::from((
+                        loaded,
+                        String::from("updated"),
+                    ))),
+                )
+                    .into_response(),
+                Err(e) => retrieve_function_error(e),
+            }
+        }
+        service::CodeUpdateResult::NotFound => (
+            StatusCode::NOT_FOUND,
+            ErasedJson::pretty(model::ErrorPage::new(
+                "not-found",
+                "Couldn't find that Function",
+            )),
+        )
+            .into_response(),
+        service::CodeUpdateResult::Invalid { message } => (
+            StatusCode::BAD_REQUEST,
+            ErasedJson::pretty(model::ErrorPage::new("invalid-function", &message)),
+        )
+            .into_response(),
+        service::CodeUpdateResult::Conflict { task_id } => (
+            StatusCode::CONFLICT,
+            ErasedJson::pretty(model::ErrorPage::new(
+                "conflict",
+                &format!("Function {} already has this code.", task_id),
+            )),
+        )
+            .into_response(),
+        service::CodeUpdateResult::FailedSave() => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErasedJson::pretty(model::ErrorPage::new(
+                "internal-error",
+                "Error saving function.",
+            )),
+        )
+            .into_response(),
+    }
+}
+
 async fn get_function_info(
     Path(handler_id): Path<i64>,
     State(pool): State<Pool<Postgres>>,
 ) -> Response {
     match service::get_handler_by_id(&pool, handler_id).await {
-        Some(handler) => (
+        Ok(handler) => (
             StatusCode::OK,
             ErasedJson::pretty(model::FunctionPage::from(handler)),
         )
             .into_response(),
-        None => (
-            StatusCode::NOT_FOUND,
-            ErasedJson::pretty(model::ErrorPage {
-                status: String::from("not-found"),
-                message: String::from("Couldn't find that Function"),
-            }),
+        Err(e) => service_error_response(e, "Couldn't find that Function"),
+    }
+}
+
+/// Look up a Function by the SHA-1 hash of its code, to check whether it exists before uploading.
+async fn get_function_by_hash(
+    Path(hash): Path<String>,
+    State(pool): State<Pool<Postgres>>,
+) -> Response {
+    match service::get_handler_by_hash(&pool, &hash.to_lowercase()).await {
+        Ok(handler) => (
+            StatusCode::OK,
+            ErasedJson::pretty(model::FunctionPage::from(handler)),
         )
             .into_response(),
+        Err(e) => service_error_response(e, "Couldn't find a Function with that hash"),
     }
 }
 
@@ -164,79 +618,1326 @@ async fn get_function_code(
     Path(handler_id): Path<i64>,
     State(pool): State<Pool<Postgres>>,
 ) -> Response<String> {
-    match service::get_handler_by_id(&pool, handler_id).await {
-        Some(handler) => Response::builder()
-            .status(StatusCode::OK)
-            .header(CONTENT_TYPE, HeaderValue::from_static("text/javascript"))
-            .body(handler.code)
-            .unwrap(),
-        None => Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .header(CONTENT_TYPE, HeaderValue::from_static("text/javascript"))
-            .body(String::from(""))
-            .unwrap(),
-    }
+    let (status, code) = match service::get_handler_by_id(&pool, handler_id).await {
+        Ok(handler) => (StatusCode::OK, handler.code),
+        Err(e) => (service_error_status(&e), String::from("")),
+    };
+
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, HeaderValue::from_static("text/javascript"))
+        .body(code)
+        .unwrap()
 }
 
 async fn get_function_results(
     Path(handler_id): Path<i64>,
     Query(query): Query<model::ResultQuery>,
+    headers: HeaderMap,
     State(pool): State<Pool<Postgres>>,
 ) -> Response {
-    let (results, next_cursor) = service::get_results(
+    if wants_ndjson(query.format.as_deref(), &headers) {
+        return get_function_results_ndjson(handler_id, query.cursor.unwrap_or(-1), pool).await;
+    }
+
+    let (results, next_cursor) = match service::get_results(
         &pool,
         handler_id,
         query.cursor.unwrap_or(-1),
         RESULT_PAGE_SIZE,
-        true,
+        service::ResultFilter::Success,
+        query.descending(),
     )
-    .await;
+    .await
+    {
+        Ok(page) => page,
+        Err(e) => return service_error_response(e, "Couldn't find that Function"),
+    };
 
     // Convert result JSON strings into result JSON Values for constructing a page.
     // If these don't parse, then ignore them.
     let results: Vec<Value> = results
         .into_iter()
         .filter_map(|x| x.result)
-        .filter_map(|r| match serde_json::from_str(&r) {
-            Ok(x) => Some(x),
-            _ => None,
-        })
+        .filter_map(|r| serde_json::from_str(&r).ok())
         .collect();
     let page = model::ResultsPage::from((results, next_cursor));
 
     (StatusCode::OK, ErasedJson::pretty(page)).into_response()
 }
 
-async fn get_function_debug(
+/// NDJSON is requested with `?format=ndjson` or an `Accept: application/x-ndjson` header.
+fn wants_ndjson(format: Option<&str>, headers: &HeaderMap) -> bool {
+    format == Some("ndjson")
+        || headers
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|x| x.to_str().ok())
+            .any(|x| x.contains(NDJSON_CONTENT_TYPE))
+}
+
+/// Stream all successful results after the cursor as newline-delimited JSON, one result per line.
+/// Results that don't parse as JSON are ignored, as for the results page.
+/// If results can't be fetched part way through, the body is aborted so the client sees an incomplete response.
+async fn get_function_results_ndjson(
+    handler_id: i64,
+    cursor: i64,
+    pool: Pool<Postgres>,
+) -> Response {
+    if let Err(e) = service::get_handler_by_id(&pool, handler_id).await {
+        return service_error_response(e, "Couldn't find that Function");
+    }
+
+    let lines = service::all_results(pool, handler_id, cursor).filter_map(move |x| async move {
+        match x {
+            Ok(x) => {
+                let value = serde_json::from_str::<Value>(&x.result?).ok()?;
+                Some(Ok(format!("{}\n", value)))
+            }
+            Err(e) => {
+                log::error!("Error exporting results for handler {}: {}", handler_id, e);
+                Some(Err(e))
+            }
+        }
+    });
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, HeaderValue::from_static(NDJSON_CONTENT_TYPE))
+        .body(Body::from_stream(lines))
+        .unwrap()
+}
+
+/// Tail successful results as Server-Sent Events.
+/// Each event has the result id as its id, so it can be used as a `cursor` to resume.
+/// The stream ends when the server shuts down, so that it doesn't hold up a graceful shutdown.
+async fn get_function_results_stream(
     Path(handler_id): Path<i64>,
     Query(query): Query<model::ResultQuery>,
+    State(state): State<AppState>,
+) -> Response {
+    if let Err(e) = service::get_handler_by_id(&state.pool, handler_id).await {
+        return service_error_response(e, "Couldn't find that Function");
+    }
+
+    let results = service::tail_results(state.pool, handler_id, query.cursor)
+        .await
+        .take_until(shutdown_started(state.shutdown));
+
+    // Results that don't parse as JSON are ignored, as for the results page.
+    let events = results.filter_map(|x| async move {
+        let result_id = x.result_id;
+        let value = serde_json::from_str::<Value>(&x.result?).ok()?;
+        sse::Event::default()
+            .id(result_id.to_string())
+            .json_data(value)
+            .ok()
+            .map(Ok::<_, Infallible>)
+    });
+
+    // Keep-alive comments stop proxies from closing an idle connection.
+    Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Resolve when the server starts shutting down, or has stopped.
+async fn shutdown_started(mut shutdown: watch::Receiver<bool>) {
+    // An error means the sender was dropped, so the server has stopped anyway.
+    let _ = shutdown.wait_for(|x| *x).await;
+}
+
+/// Most common errors for a Function, grouped by kind and message, with counts.
+async fn get_function_errors(
+    Path(handler_id): Path<i64>,
+    Query(query): Query<model::ErrorSummaryQuery>,
     State(pool): State<Pool<Postgres>>,
 ) -> Response {
-    let (results, next_cursor) = service::get_results(
+    if let Err(e) = service::get_handler_by_id(&pool, handler_id).await {
+        return service_error_response(e, "Couldn't find that Function");
+    }
+
+    match service::get_error_summary(
         &pool,
         handler_id,
-        query.cursor.unwrap_or(-1),
-        RESULT_PAGE_SIZE,
-        false,
+        query.limit.unwrap_or(service::DEFAULT_ERROR_SUMMARY_LIMIT),
     )
-    .await;
+    .await
+    {
+        Ok(summary) => (
+            StatusCode::OK,
+            ErasedJson::pretty(model::ErrorSummaryPage::from(summary)),
+        )
+            .into_response(),
+        Err(e) => service_error_response(e, "Couldn't find that Function"),
+    }
+}
 
-    let page = model::ResultsDebugPage::from((results, next_cursor));
+/// Total, success, error and timeout counts of a Function's results.
+async fn get_function_stats(
+    Path(handler_id): Path<i64>,
+    State(pool): State<Pool<Postgres>>,
+) -> Response {
+    if let Err(e) = service::get_handler_by_id(&pool, handler_id).await {
+        return service_error_response(e, "Couldn't find that Function");
+    }
 
-    (StatusCode::OK, ErasedJson::pretty(page)).into_response()
+    match service::get_result_stats(&pool, handler_id).await {
+        Ok(stats) => (
+            StatusCode::OK,
+            ErasedJson::pretty(model::ResultStatsPage::from(stats)),
+        )
+            .into_response(),
+        Err(e) => service_error_response(e, "Couldn't find that Function"),
+    }
 }
 
-pub(crate) async fn run(pool: &Pool<Postgres>) {
-    let app = Router::new()
-        .route("/", get(Redirect::permanent("https://pardalotus.tech/api")))
-        .route("/functions", get(list_functions).post(post_function))
-        .route("/functions/:handler_id", get(get_function_info))
-        .route("/functions/:handler_id/code.js", get(get_function_code))
-        .route("/functions/:handler_id/results", get(get_function_results))
-        .route("/functions/:handler_id/debug", get(get_function_debug))
-        .route("/heartbeat", get(heartbeat))
-        .with_state(pool.clone());
+/// Count Events by analyzer, to see what a corpus is made of.
+async fn get_event_stats(State(pool): State<Pool<Postgres>>) -> Response {
+    match service::get_event_counts(&pool).await {
+        Ok(counts) => (
+            StatusCode::OK,
+            ErasedJson::pretty(model::EventCountsPage::from(counts)),
+        )
+            .into_response(),
+        Err(e) => service_error_response(e, "Couldn't count Events"),
+    }
+}
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:6464").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+/// Put a range of existing Events back on the queue so they're executed again, e.g. after fixing a Function.
+/// Requires an `after` Event ID, so the whole table isn't re-run by accident.
+async fn post_function_rerun(
+    Path(handler_id): Path<i64>,
+    Query(query): Query<model::RerunQuery>,
+    State(pool): State<Pool<Postgres>>,
+) -> Response {
+    let Some(after) = query.after else {
+        return (
+            StatusCode::BAD_REQUEST,
+            ErasedJson::pretty(model::ErrorPage::new(
+                "range-required",
+                "Supply an Event ID range with 'after', and optionally 'limit'.",
+            )),
+        )
+            .into_response();
+    };
+
+    if let Err(e) = service::get_handler_by_id(&pool, handler_id).await {
+        return service_error_response(e, "Couldn't find that Function");
+    }
+
+    let restrict_to = if query.only_this_function.unwrap_or(false) {
+        Some(handler_id)
+    } else {
+        None
+    };
+
+    match service::rerun_events(
+        &pool,
+        after,
+        query.limit.unwrap_or(service::MAX_RERUN_LIMIT),
+        restrict_to,
+    )
+    .await
+    {
+        Ok((reenqueued, last_event_id)) => (
+            StatusCode::ACCEPTED,
+            ErasedJson::pretty(model::RerunPage {
+                status: String::from("ok"),
+                reenqueued,
+                cursor: last_event_id.unwrap_or(after),
+            }),
+        )
+            .into_response(),
+        Err(e) => service_error_response(e, "Couldn't find Events to re-run"),
+    }
+}
+
+/// Submit an array of Events, in the same format as files loaded with `--load-events`.
+/// Valid Events are inserted even if others are rejected.
+async fn post_events(
+    State(pool): State<Pool<Postgres>>,
+    body: Result<Json<Vec<Value>>, JsonRejection>,
+) -> Response {
+    let items = match body {
+        Ok(Json(items)) => items,
+        Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+            return body_too_large_response()
+        }
+        Err(rejection) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                ErasedJson::pretty(model::ErrorPage::new(
+                    "bad-request",
+                    &format!("Expected a JSON array of Events. {}", rejection.body_text()),
+                )),
+            )
+                .into_response()
+        }
+    };
+
+    match service::load_events(&pool, &items).await {
+        Ok(results) => (
+            StatusCode::OK,
+            ErasedJson::pretty(model::EventsLoadedPage::from(results)),
+        )
+            .into_response(),
+        Err(e) => {
+            log::error!("Failed to load Events: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErasedJson::pretty(model::ErrorPage::new(
+                    "internal-error",
+                    "Can't save Events.",
+                )),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Ask running executors to cancel their current batch.
+async fn post_execution_cancel(State(pool): State<Pool<Postgres>>) -> Response {
+    match service::cancel_execution(&pool).await {
+        Ok(()) => (
+            StatusCode::ACCEPTED,
+            ErasedJson::pretty(serde_json::json!({"status": "cancel-requested"})),
+        )
+            .into_response(),
+        Err(e) => {
+            log::error!("Failed to request cancellation: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErasedJson::pretty(model::ErrorPage::new(
+                    "internal-error",
+                    "Can't request cancellation.",
+                )),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Pause harvesting. Running harvests stop before their next page, without moving the checkpoint.
+async fn post_harvest_pause(State(pool): State<Pool<Postgres>>) -> Response {
+    set_harvest_running(pool, false).await
+}
+
+/// Resume harvesting after a pause.
+async fn post_harvest_resume(State(pool): State<Pool<Postgres>>) -> Response {
+    set_harvest_running(pool, true).await
+}
+
+async fn set_harvest_running(pool: Pool<Postgres>, running: bool) -> Response {
+    match service::set_harvest_running(&pool, running).await {
+        Ok(()) => (
+            StatusCode::OK,
+            ErasedJson::pretty(serde_json::json!({
+                "status": if running { "harvest-running" } else { "harvest-paused" }
+            })),
+        )
+            .into_response(),
+        Err(e) => {
+            log::error!("Failed to set harvest control flag: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErasedJson::pretty(model::ErrorPage::new(
+                    "internal-error",
+                    "Can't pause or resume harvesting.",
+                )),
+            )
+                .into_response()
+        }
+    }
+}
+
+async fn get_function_debug(
+    Path(handler_id): Path<i64>,
+    Query(query): Query<model::ResultQuery>,
+    State(pool): State<Pool<Postgres>>,
+) -> Response {
+    match service::get_results(
+        &pool,
+        handler_id,
+        query.cursor.unwrap_or(-1),
+        RESULT_PAGE_SIZE,
+        query.debug_filter(),
+        query.descending(),
+    )
+    .await
+    {
+        Ok(page) => (
+            StatusCode::OK,
+            ErasedJson::pretty(model::ResultsDebugPage::from(page)),
+        )
+            .into_response(),
+        Err(e) => service_error_response(e, "Couldn't find that Function"),
+    }
+}
+
+async fn get_result(Path(result_id): Path<i64>, State(pool): State<Pool<Postgres>>) -> Response {
+    match service::get_result_by_id(&pool, result_id).await {
+        Ok(result) => (
+            StatusCode::OK,
+            ErasedJson::pretty(model::ResultPage::from(result)),
+        )
+            .into_response(),
+        Err(e) => service_error_response(e, "Couldn't find a Result with that id"),
+    }
+}
+
+/// What's known about an identifier: its latest metadata assertion and how many Events it's in.
+/// The value is the rest of the path, as identifiers like DOIs contain slashes.
+async fn get_identifier(
+    Path((identifier_type, value)): Path<(u32, String)>,
+    Query(query): Query<model::IdentifierQuery>,
+    State(pool): State<Pool<Postgres>>,
+) -> Response {
+    let Some(identifier) = Identifier::from_id_string_pair(&value, identifier_type) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            ErasedJson::pretty(model::ErrorPage::new(
+                "bad-identifier",
+                "Couldn't parse that identifier type and value.",
+            )),
+        )
+            .into_response();
+    };
+
+    let source = match query
+        .source
+        .as_deref()
+        .map(MetadataSourceId::from_str_value)
+    {
+        Some(MetadataSourceId::Unknown) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                ErasedJson::pretty(model::ErrorPage::new(
+                    "bad-source",
+                    "Unknown metadata source.",
+                )),
+            )
+                .into_response();
+        }
+        source => source,
+    };
+
+    match service::get_identifier_info(&pool, &identifier, source).await {
+        Ok(info) => (
+            StatusCode::OK,
+            ErasedJson::pretty(model::IdentifierPage::from((
+                identifier.to_id_string_pair(),
+                info,
+            ))),
+        )
+            .into_response(),
+        Err(e) => service_error_response(e, "Couldn't find that identifier"),
+    }
+}
+
+/// Events where the identifier is the subject or object, for exploring the graph of Events.
+/// A separate route from the identifier itself, as the value is the rest of the path.
+async fn get_identifier_events(
+    Path((identifier_type, value)): Path<(u32, String)>,
+    Query(query): Query<model::IdentifierQuery>,
+    State(pool): State<Pool<Postgres>>,
+) -> Response {
+    let Some(identifier) = Identifier::from_id_string_pair(&value, identifier_type) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            ErasedJson::pretty(model::ErrorPage::new(
+                "bad-identifier",
+                "Couldn't parse that identifier type and value.",
+            )),
+        )
+            .into_response();
+    };
+
+    let Some(role) = query.role.as_deref().and_then(EntityRole::from_str_value) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            ErasedJson::pretty(model::ErrorPage::new(
+                "bad-role",
+                "Supply role=subject or role=object.",
+            )),
+        )
+            .into_response();
+    };
+
+    match service::get_events_for_identifier(
+        &pool,
+        &identifier,
+        role,
+        query.cursor.unwrap_or(-1),
+        RESULT_PAGE_SIZE,
+    )
+    .await
+    {
+        Ok(events) => (
+            StatusCode::OK,
+            ErasedJson::pretty(model::EventsPage::from(events)),
+        )
+            .into_response(),
+        Err(e) => service_error_response(e, "Couldn't find that identifier"),
+    }
+}
+
+/// Events taken off the queue because every handler errored, after retries.
+async fn get_dead_letters(
+    Query(query): Query<model::ResultQuery>,
+    State(pool): State<Pool<Postgres>>,
+) -> Response {
+    match service::get_dead_letters(&pool, query.cursor.unwrap_or(-1), RESULT_PAGE_SIZE).await {
+        Ok(page) => (
+            StatusCode::OK,
+            ErasedJson::pretty(model::DeadLettersPage::from(page)),
+        )
+            .into_response(),
+        Err(e) => service_error_response(e, "No dead letters found"),
+    }
+}
+
+pub(crate) async fn run(
+    pool: &Pool<Postgres>,
+    default_handler_timeout_ms: i32,
+    api_key: Option<String>,
+) {
+    if api_key.is_none() {
+        log::warn!("**********************************************************************");
+        log::warn!(
+            "No METABEAK_API_KEY supplied. The write API is open to anyone who can reach it!"
+        );
+        log::warn!("**********************************************************************");
+    }
+
+    let (shutdown_send, shutdown) = watch::channel(false);
+
+    let app = router(AppState {
+        pool: pool.clone(),
+        default_handler_timeout_ms,
+        api_key,
+        shutdown,
+    });
+
+    // Tell open streams to end, otherwise the graceful shutdown waits for them forever.
+    let shutdown_signal = async move {
+        shutdown_signal().await;
+        let _ = shutdown_send.send(true);
+    };
+
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:6464").await.unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal)
+        .await
+        .unwrap();
+
+    log::info!("API server stopped.");
+}
+
+/// All routes, with authentication and body limits.
+fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/", get(Redirect::permanent("https://pardalotus.tech/api")))
+        .route("/functions", get(list_functions).post(post_function))
+        .route("/functions/by-hash/:hash", get(get_function_by_hash))
+        .route(
+            "/functions/:handler_id",
+            get(get_function_info).put(put_function),
+        )
+        .route("/functions/:handler_id/code.js", get(get_function_code))
+        .route("/functions/:handler_id/results", get(get_function_results))
+        .route(
+            "/functions/:handler_id/results/stream",
+            get(get_function_results_stream),
+        )
+        .route("/functions/:handler_id/debug", get(get_function_debug))
+        .route("/functions/:handler_id/errors", get(get_function_errors))
+        .route("/functions/:handler_id/stats", get(get_function_stats))
+        .route("/functions/:handler_id/rerun", post(post_function_rerun))
+        .route("/results/:result_id", get(get_result))
+        .route("/events", post(post_events))
+        .route("/events/stats", get(get_event_stats))
+        .route(
+            "/events/by-identifier/:identifier_type/*value",
+            get(get_identifier_events),
+        )
+        .route("/dead-letters", get(get_dead_letters))
+        .route("/identifiers/:identifier_type/*value", get(get_identifier))
+        .route("/execution/cancel", post(post_execution_cancel))
+        .route("/harvest/pause", post(post_harvest_pause))
+        .route("/harvest/resume", post(post_harvest_resume))
+        .route("/heartbeat", get(heartbeat))
+        .route("/readyz", get(heartbeat))
+        .route("/livez", get(livez))
+        .route("/version", get(version))
+        .route("/vocabulary", get(vocabulary))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_api_key,
+        ))
+        .layer(middleware::from_fn(limit_body))
+        .layer(DefaultBodyLimit::max(max_body_bytes()))
+        .with_state(state)
+}
+
+/// Resolve when the process is asked to stop, via Ctrl-C or SIGTERM.
+/// In-flight requests are allowed to complete before the server returns.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl-C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => log::info!("Got Ctrl-C, shutting down API server..."),
+        _ = terminate => log::info!("Got SIGTERM, shutting down API server..."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use tower::ServiceExt;
+
+    use crate::db::pool::get_pool;
+
+    use super::*;
+
+    /// All routes against the database from DB_URI, with the write API open.
+    /// Send true on the returned sender to start shutting down. Dropping it has the same effect.
+    async fn test_router() -> (Router, Pool<Postgres>, watch::Sender<bool>) {
+        crate::execution::run::init();
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+        let (shutdown_send, shutdown) = watch::channel(false);
+        let app = router(AppState {
+            pool: pool.clone(),
+            default_handler_timeout_ms: 10,
+            api_key: None,
+            shutdown,
+        });
+        (app, pool, shutdown_send)
+    }
+
+    const BOUNDARY: &str = "test-boundary";
+
+    /// Request with a multipart form body, as Functions are uploaded.
+    fn multipart_request(method: Method, uri: &str, fields: &[(&str, &str)]) -> Request {
+        let mut body = String::new();
+        for (name, value) in fields {
+            body.push_str(&format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                BOUNDARY, name, value
+            ));
+        }
+        body.push_str(&format!("--{}--\r\n", BOUNDARY));
+
+        axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    /// Request with a multipart form body of a single `data` field, streamed in chunks without a Content-Length.
+    fn chunked_multipart_request(method: Method, uri: &str, data: &str) -> Request {
+        let body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"data\"\r\n\r\n{}\r\n--{}--\r\n",
+            BOUNDARY, data, BOUNDARY
+        );
+        let chunks: Vec<Result<Vec<u8>, std::convert::Infallible>> = body
+            .into_bytes()
+            .chunks(64 * 1024)
+            .map(|x| Ok(x.to_vec()))
+            .collect();
+
+        axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .body(Body::from_stream(futures::stream::iter(chunks)))
+            .unwrap()
+    }
+
+    /// Send the request to the app, returning the status and the JSON body, or null if it isn't JSON.
+    async fn send(app: &Router, request: Request) -> (StatusCode, Value) {
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    /// Upload a Function, returning the status and its id.
+    async fn upload_function(app: &Router, code: &str) -> (StatusCode, i64) {
+        let (status, page) = send(
+            app,
+            multipart_request(Method::POST, "/functions", &[("data", code)]),
+        )
+        .await;
+        (status, page["data"]["id"].as_i64().unwrap_or(-1))
+    }
+
+    async fn delete_functions(pool: &Pool<Postgres>, handler_ids: &[i64]) {
+        for handler_id in handler_ids {
+            sqlx::query("DELETE FROM handler WHERE handler_id = $1")
+                .bind(handler_id)
+                .execute(pool)
+                .await
+                .unwrap();
+        }
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Functions are committed, as they're uploaded through the API, then deleted.
+    /// Run with `cargo test put_function_same_code_conflict -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn put_function_same_code_conflict() {
+        let (app, pool, _shutdown) = test_router().await;
+
+        let code = "function f(args) { return ['put_function_same_code_conflict']; }";
+        let other_code = "function f(args) { return ['put_function_same_code_conflict 2']; }";
+        let (_, first_id) = upload_function(&app, code).await;
+        let (_, second_id) = upload_function(&app, other_code).await;
+
+        let (status, page) = send(
+            &app,
+            multipart_request(
+                Method::PUT,
+                &format!("/functions/{}", second_id),
+                &[("data", code)],
+            ),
+        )
+        .await;
+
+        delete_functions(&pool, &[first_id, second_id]).await;
+
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(page["status"], "conflict");
+        assert_eq!(
+            page["message"],
+            format!("Function {} already has this code.", first_id)
+        );
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Code that assigns something other than a function to `f` is rejected at upload, so nothing is saved.
+    /// Run with `cargo test post_function_f_not_function -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn post_function_f_not_function() {
+        let (app, _, _shutdown) = test_router().await;
+
+        let (status, page) = send(
+            &app,
+            multipart_request(Method::POST, "/functions", &[("data", "var f = 5;")]),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(page["status"], "invalid-function");
+        assert_eq!(
+            page["message"],
+            "'f' was not a function, it was number. Check you don't have a conflicting variable named `f`."
+        );
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Events are only re-run by the Function when `only_this_function` is set, otherwise by all.
+    /// Everything is committed, as it's read through the API, then deleted.
+    /// Run with `cargo test post_function_rerun_restrict -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn post_function_rerun_restrict() {
+        let (app, pool, _shutdown) = test_router().await;
+
+        let (_, handler_id) = upload_function(
+            &app,
+            "function f(args) { return ['post_function_rerun_restrict']; }",
+        )
+        .await;
+
+        let run = time::OffsetDateTime::now_utc().unix_timestamp_nanos();
+        let mut event_ids = vec![];
+        let mut tx = pool.begin().await.unwrap();
+        for n in 0..3 {
+            let event = crate::execution::model::Event {
+                event_id: -1,
+                analyzer: EventAnalyzerId::Test,
+                source: MetadataSourceId::Test,
+                subject_id: None,
+                object_id: None,
+                assertion_id: -1,
+                json: format!("{{\"rerun-test\":\"{}-{}\"}}", run, n),
+                occurred_at: None,
+            };
+            let event_id = db::event::insert_event(
+                &event,
+                None,
+                None,
+                db::event::EventQueueState::New,
+                &mut tx,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            event_ids.push(event_id as i64);
+        }
+
+        // Only look at the queue entries from re-running.
+        sqlx::query("DELETE FROM event_queue WHERE event_id = ANY($1)")
+            .bind(&event_ids)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let rerun = |query: String| {
+            axum::http::Request::builder()
+                .method(Method::POST)
+                .uri(format!("/functions/{}/rerun?{}", handler_id, query))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let (restricted_status, restricted) = send(
+            &app,
+            rerun(format!(
+                "after={}&limit=2&only_this_function=true",
+                event_ids[0] - 1
+            )),
+        )
+        .await;
+        let (all_status, all) = send(&app, rerun(format!("after={}&limit=1", event_ids[1]))).await;
+
+        let queued: Vec<(i64, Option<i64>)> = sqlx::query_as(
+            "SELECT event_id, handler_id FROM event_queue
+             WHERE event_id = ANY($1)
+             ORDER BY event_queue_id",
+        )
+        .bind(&event_ids)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+
+        sqlx::query("DELETE FROM event_queue WHERE event_id = ANY($1)")
+            .bind(&event_ids)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM event WHERE event_id = ANY($1)")
+            .bind(&event_ids)
+            .execute(&pool)
+            .await
+            .unwrap();
+        delete_functions(&pool, &[handler_id]).await;
+
+        assert_eq!(restricted_status, StatusCode::ACCEPTED);
+        assert_eq!(restricted["reenqueued"], 2);
+        assert_eq!(restricted["cursor"], event_ids[1]);
+
+        assert_eq!(all_status, StatusCode::ACCEPTED);
+        assert_eq!(all["reenqueued"], 1);
+        assert_eq!(all["cursor"], event_ids[2]);
+
+        assert_eq!(
+            queued,
+            vec![
+                (event_ids[0], Some(handler_id)),
+                (event_ids[1], Some(handler_id)),
+                (event_ids[2], None)
+            ],
+            "Restricted to the Function only when asked."
+        );
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Each submitted Event is inserted, counted as a duplicate, or rejected with its reasons.
+    /// Committed, as it's through the API, then deleted.
+    /// Run with `cargo test post_events_outcomes -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn post_events_outcomes() {
+        let (app, pool, _shutdown) = test_router().await;
+
+        let marker = format!(
+            "post-events-test-{}",
+            time::OffsetDateTime::now_utc().unix_timestamp_nanos()
+        );
+        let event = serde_json::json!({
+            "source": "test",
+            "analyzer": "test",
+            "subject_id": format!("https://example.com/{}", marker),
+        });
+        let body = serde_json::json!([event, event, {"source": "test", "analyzer": 1}]);
+
+        let (status, page) = send(
+            &app,
+            axum::http::Request::builder()
+                .method(Method::POST)
+                .uri("/events")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+        .await;
+
+        let (invalid_status, _) = send(
+            &app,
+            axum::http::Request::builder()
+                .method(Method::POST)
+                .uri("/events")
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from("{\"source\": \"test\"}"))
+                .unwrap(),
+        )
+        .await;
+
+        sqlx::query(
+            "WITH deleted AS (
+                DELETE FROM event
+                WHERE subject_entity_id IN (SELECT entity_id FROM entity WHERE identifier LIKE $1)
+                RETURNING event_id)
+             DELETE FROM event_queue WHERE event_id IN (SELECT event_id FROM deleted)",
+        )
+        .bind(format!("%{}%", marker))
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("DELETE FROM entity WHERE identifier LIKE $1")
+            .bind(format!("%{}%", marker))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(page["inserted"], 1);
+        assert_eq!(page["duplicates"], 1, "Same Event twice.");
+        assert_eq!(
+            page["rejected"],
+            serde_json::json!([{"index": 2, "errors": ["Field 'analyzer' must be a string."]}])
+        );
+
+        assert_eq!(
+            invalid_status,
+            StatusCode::BAD_REQUEST,
+            "Not an array of Events."
+        );
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// An identifier ending in "/events" is looked up as itself, and its Events are at a separate route.
+    /// Run with `cargo test identifier_events_route -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn identifier_events_route() {
+        let (app, _, _shutdown) = test_router().await;
+
+        let get = |uri: &str| {
+            axum::http::Request::builder()
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // DOI type, for a DOI that's never been seen.
+        // Previously taken as the Events listing, which needs a role.
+        let (status, page) = send(&app, get("/identifiers/1/10.5555/never-seen/events")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(page["message"], "Couldn't find that identifier");
+
+        let (status, _) = send(
+            &app,
+            get("/events/by-identifier/1/10.5555/never-seen/events?role=subject"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, page) = send(&app, get("/events/by-identifier/1/10.5555/never-seen")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(page["status"], "bad-role");
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// A matching If-None-Match on upload is a failed precondition, and returns the existing Function.
+    /// Committed, as it's through the API, then deleted.
+    /// Run with `cargo test post_function_if_none_match -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn post_function_if_none_match() {
+        let (app, pool, _shutdown) = test_router().await;
+
+        let code = "function f(args) { return ['post_function_if_none_match']; }";
+        let (_, handler_id) = upload_function(&app, code).await;
+
+        let etag = format!("\"{}\"", hash_data(code));
+        let mut request = multipart_request(Method::POST, "/functions", &[("data", code)]);
+        request
+            .headers_mut()
+            .insert(IF_NONE_MATCH, HeaderValue::from_str(&etag).unwrap());
+        let response = app.clone().oneshot(request).await.unwrap();
+
+        let status = response.status();
+        let response_etag = response.headers().get(ETAG).cloned();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let page: Value = serde_json::from_slice(&body).unwrap();
+
+        delete_functions(&pool, &[handler_id]).await;
+
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(response_etag, Some(HeaderValue::from_str(&etag).unwrap()));
+        assert_eq!(page["created"], false);
+        assert_eq!(page["data"]["id"], handler_id);
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Tailing results of an unknown Function is a 404, and a tail ends when the server shuts down.
+    /// Run with `cargo test results_stream_shutdown -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn results_stream_shutdown() {
+        let (app, pool, shutdown) = test_router().await;
+
+        let get = |uri: String| {
+            axum::http::Request::builder()
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let (status, _) = send(&app, get(String::from("/functions/-1/results/stream"))).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (_, handler_id) = upload_function(
+            &app,
+            "function f(args) { return ['results_stream_shutdown']; }",
+        )
+        .await;
+
+        let response = app
+            .clone()
+            .oneshot(get(format!("/functions/{}/results/stream", handler_id)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = tokio::spawn(axum::body::to_bytes(response.into_body(), usize::MAX));
+        shutdown.send(true).unwrap();
+        let ended = tokio::time::timeout(std::time::Duration::from_secs(5), body).await;
+
+        delete_functions(&pool, &[handler_id]).await;
+
+        assert!(ended.is_ok(), "Stream ended on shutdown.");
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Exporting results of an unknown Function is a 404, not an empty export.
+    /// Run with `cargo test results_ndjson_unknown_function -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn results_ndjson_unknown_function() {
+        let (app, pool, _shutdown) = test_router().await;
+
+        let get = |uri: String| {
+            axum::http::Request::builder()
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let (status, _) = send(
+            &app,
+            get(String::from("/functions/-1/results?format=ndjson")),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (_, handler_id) = upload_function(
+            &app,
+            "function f(args) { return ['results_ndjson_unknown_function']; }",
+        )
+        .await;
+
+        let response = app
+            .clone()
+            .oneshot(get(format!(
+                "/functions/{}/results?format=ndjson",
+                handler_id
+            )))
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        delete_functions(&pool, &[handler_id]).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.is_empty(), "No results yet.");
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Function code over the body limit, sent without a Content-Length so it's only found while reading,
+    /// is a 413 rather than a missing field.
+    /// Run with `cargo test chunked_oversized_function -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn chunked_oversized_function() {
+        let (app, pool, _shutdown) = test_router().await;
+
+        let code = format!("// {}", "x".repeat(max_body_bytes()));
+
+        let (status, page) = send(
+            &app,
+            chunked_multipart_request(Method::POST, "/functions", &code),
+        )
+        .await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(page["status"], "too-large");
+
+        let (_, handler_id) = upload_function(
+            &app,
+            "function f(args) { return ['chunked_oversized_function']; }",
+        )
+        .await;
+
+        let (status, page) = send(
+            &app,
+            chunked_multipart_request(Method::PUT, &format!("/functions/{}", handler_id), &code),
+        )
+        .await;
+
+        delete_functions(&pool, &[handler_id]).await;
+
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(page["status"], "too-large");
+    }
+
+    /// Missing items and bad input are the client's error, database failures aren't.
+    #[test]
+    fn service_error_statuses() {
+        assert_eq!(
+            service_error_status(&service::ServiceError::NotFound),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            service_error_status(&service::ServiceError::Validation(String::from(
+                "Bad limit"
+            ))),
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            service_error_status(&service::ServiceError::from(sqlx::Error::RowNotFound)),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            service_error_status(&service::ServiceError::from(sqlx::Error::PoolTimedOut)),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    /// New and existing Functions are told apart by status code and the `created` field, and both carry the hash.
+    #[tokio::test]
+    async fn uploaded_function_created_or_exists() {
+        let handler = || HandlerSpec {
+            handler_id: 44,
+            code: String::from("function f(args) { return [1]; }"),
+            status: db::handler::HandlerState::Enabled as i32,
+            timeout_ms: 10,
+            hash: Some(String::from("4e77ac0b9eca39a4295686bd73d13aba5e007617")),
+            owner_id: 0,
+        };
+
+        for (created, status_code, status) in [
+            (true, StatusCode::CREATED, "created"),
+            (false, StatusCode::OK, "already-exists"),
+        ] {
+            let response = uploaded_function_response(handler(), created);
+
+            assert_eq!(response.status(), status_code);
+            assert_eq!(
+                response.headers().get(ETAG),
+                Some(&HeaderValue::from_static(
+                    "\"4e77ac0b9eca39a4295686bd73d13aba5e007617\""
+                ))
+            );
+
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let page: Value = serde_json::from_slice(&body).unwrap();
+
+            assert_eq!(page["created"], created);
+            assert_eq!(page["status"], status);
+            assert_eq!(page["data"]["id"], 44);
+            assert_eq!(
+                page["data"]["hash"],
+                "4e77ac0b9eca39a4295686bd73d13aba5e007617"
+            );
+        }
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Uploading the same code twice creates the Function, then returns the same one.
+    /// Committed, as it's through the API, then deleted.
+    /// Run with `cargo test post_function_twice -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn post_function_twice() {
+        let (app, pool, _shutdown) = test_router().await;
+
+        let code = "function f(args) { return ['post_function_twice']; }";
+        let upload = || multipart_request(Method::POST, "/functions", &[("data", code)]);
+
+        let (first_status, first) = send(&app, upload()).await;
+        let (second_status, second) = send(&app, upload()).await;
+
+        delete_functions(&pool, &[first["data"]["id"].as_i64().unwrap()]).await;
+
+        assert_eq!(first_status, StatusCode::CREATED);
+        assert_eq!(first["created"], true);
+        assert_eq!(first["status"], "created");
+
+        assert_eq!(second_status, StatusCode::OK);
+        assert_eq!(second["created"], false);
+        assert_eq!(second["status"], "already-exists");
+        assert_eq!(second["data"]["id"], first["data"]["id"]);
+        assert_eq!(second["data"]["hash"], hash_data(code));
+    }
+
+    /// Only a declared length over the limit is rejected up front.
+    #[test]
+    fn body_limit_from_content_length() {
+        let mut headers = HeaderMap::new();
+        assert!(!exceeds_body_limit(&headers, 100), "No length declared.");
+
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("100"));
+        assert!(!exceeds_body_limit(&headers, 100));
+
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("101"));
+        assert!(exceeds_body_limit(&headers, 100));
+
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("lots"));
+        assert!(!exceeds_body_limit(&headers, 100), "Unreadable length.");
+    }
+
+    #[test]
+    fn if_none_match_header() {
+        let mut headers = HeaderMap::new();
+        assert!(if_none_match_hashes(&headers).is_empty());
+
+        headers.insert(
+            IF_NONE_MATCH,
+            HeaderValue::from_static(
+                "\"4E77AC0B9ECA39A4295686BD73D13ABA5E007617\", W/\"abc\", def",
+            ),
+        );
+        assert_eq!(
+            if_none_match_hashes(&headers),
+            vec![
+                String::from("4e77ac0b9eca39a4295686bd73d13aba5e007617"),
+                String::from("abc"),
+                String::from("def")
+            ],
+            "Quotes and weak prefixes are removed, and hashes compared in lower case."
+        );
+    }
+
+    #[test]
+    fn owner_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            owner_from_headers(&headers),
+            Some(0),
+            "No owner by default."
+        );
+
+        headers.insert(OWNER_HEADER, HeaderValue::from_static("42"));
+        assert_eq!(owner_from_headers(&headers), Some(42));
+
+        headers.insert(OWNER_HEADER, HeaderValue::from_static("-1"));
+        assert_eq!(owner_from_headers(&headers), None);
+
+        headers.insert(OWNER_HEADER, HeaderValue::from_static("alice"));
+        assert_eq!(owner_from_headers(&headers), None);
+    }
+
+    /// Commit is only included when known.
+    #[test]
+    fn version_commit() {
+        assert_eq!(
+            version_info(None),
+            serde_json::json!({"platform": "Pardalotus Metabeak", "version": VERSION})
+        );
+        assert_eq!(
+            version_info(Some("58370ef"))["commit"],
+            serde_json::json!("58370ef")
+        );
+    }
+
+    /// Debug results are all results, unless only errors are asked for.
+    #[test]
+    fn debug_errors_filter() {
+        let query = |errors| model::ResultQuery {
+            cursor: None,
+            format: None,
+            order: None,
+            errors,
+        };
+
+        assert_eq!(query(None).debug_filter(), service::ResultFilter::All);
+        assert_eq!(
+            query(Some(false)).debug_filter(),
+            service::ResultFilter::All
+        );
+        assert_eq!(
+            query(Some(true)).debug_filter(),
+            service::ResultFilter::Errors
+        );
+    }
+
+    #[test]
+    fn vocabulary_names() {
+        let vocabulary = vocabulary_info();
+
+        assert_eq!(
+            vocabulary["analyzers"],
+            serde_json::json!([
+                "test",
+                "lifecycle",
+                "reference",
+                "contribution",
+                "identifier",
+                "organizations"
+            ])
+        );
+        assert_eq!(
+            vocabulary["sources"],
+            serde_json::json!(["test", "crossref", "content-negotiation", "datacite"])
+        );
+    }
 }
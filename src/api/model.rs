@@ -1,7 +1,18 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use time::OffsetDateTime;
 
-use crate::{db::handler::HandlerState, execution::model::ExecutionResult};
+use crate::{
+    db::source::MetadataSourceId,
+    db::{
+        event::DeadLetter,
+        handler::{ErrorSummary, HandlerState, ResultStats},
+    },
+    execution::model::{Event, ExecutionResult},
+    service::{EventLoadResult, IdentifierInfo, ResultFilter},
+};
 
 use super::HandlerSpec;
 
@@ -25,6 +36,13 @@ pub(crate) struct Function {
     pub(crate) id: i64,
     pub(crate) code: String,
     pub(crate) status: HandlerState,
+    pub(crate) timeout_ms: i32,
+
+    /// SHA-1 hash of the code.
+    pub(crate) hash: Option<String>,
+
+    /// Owner, as supplied when the Function was uploaded. 0 if none was.
+    pub(crate) owner_id: i32,
 }
 
 impl From<HandlerSpec> for Function {
@@ -37,6 +55,9 @@ impl From<HandlerSpec> for Function {
                 2 => HandlerState::Disabled,
                 _ => HandlerState::Unknown,
             },
+            timeout_ms: value.timeout_ms,
+            hash: value.hash,
+            owner_id: value.owner_id,
         }
     }
 }
@@ -65,6 +86,28 @@ impl From<(HandlerSpec, String)> for FunctionPage {
     }
 }
 
+/// Response to uploading a Function, which may already have existed.
+#[derive(Serialize)]
+pub(crate) struct UploadedFunctionPage {
+    /// "created" or "already-exists".
+    pub(crate) status: String,
+
+    /// Whether this upload created the Function, for clients to branch on rather than the status.
+    pub(crate) created: bool,
+
+    pub(crate) data: Function,
+}
+
+impl From<(HandlerSpec, bool)> for UploadedFunctionPage {
+    fn from((value, created): (HandlerSpec, bool)) -> Self {
+        UploadedFunctionPage {
+            status: String::from(if created { "created" } else { "already-exists" }),
+            created,
+            data: Function::from(value),
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub(crate) struct FunctionsPage {
     pub(crate) status: String,
@@ -80,6 +123,12 @@ impl From<Vec<HandlerSpec>> for FunctionsPage {
     }
 }
 
+#[derive(Deserialize)]
+pub(crate) struct FunctionsQuery {
+    /// Only list Functions belonging to this owner.
+    pub(crate) owner: Option<i32>,
+}
+
 #[derive(Serialize)]
 pub(crate) struct ResultsPage {
     pub(crate) status: String,
@@ -100,6 +149,30 @@ impl From<(Vec<Value>, i64)> for ResultsPage {
 #[derive(Deserialize)]
 pub(crate) struct ResultQuery {
     pub(crate) cursor: Option<i64>,
+
+    /// Set to `ndjson` for newline-delimited JSON of all results, rather than a page.
+    pub(crate) format: Option<String>,
+
+    /// Set to `desc` for the newest results first.
+    pub(crate) order: Option<String>,
+
+    /// For debug results, set to `true` for only errors.
+    pub(crate) errors: Option<bool>,
+}
+
+impl ResultQuery {
+    pub(crate) fn descending(&self) -> bool {
+        self.order.as_deref() == Some("desc")
+    }
+
+    /// Results for the debug page, which are all results unless only errors are asked for.
+    pub(crate) fn debug_filter(&self) -> ResultFilter {
+        if self.errors == Some(true) {
+            ResultFilter::Errors
+        } else {
+            ResultFilter::All
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -119,3 +192,276 @@ impl From<(Vec<ExecutionResult>, i64)> for ResultsDebugPage {
         }
     }
 }
+
+/// A single result, the same as an element of [ResultsDebugPage].
+#[derive(Serialize)]
+pub(crate) struct ResultPage {
+    pub(crate) status: String,
+    pub(crate) data: ExecutionResult,
+}
+
+impl From<ExecutionResult> for ResultPage {
+    fn from(data: ExecutionResult) -> Self {
+        ResultPage {
+            status: String::from("ok"),
+            data,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RerunQuery {
+    /// Re-run Events with IDs after this. Required, so the whole table isn't re-run by accident.
+    pub(crate) after: Option<i64>,
+
+    /// Maximum number of Events to re-run.
+    pub(crate) limit: Option<i64>,
+
+    /// Only run this Function for the Events, not all enabled Functions.
+    pub(crate) only_this_function: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct RerunPage {
+    pub(crate) status: String,
+
+    /// Number of Events put back on the queue.
+    pub(crate) reenqueued: i64,
+
+    /// Pass as `after` to re-run the next range.
+    pub(crate) cursor: i64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct RejectedEvent {
+    /// Position in the submitted array.
+    pub(crate) index: usize,
+    pub(crate) errors: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct EventsLoadedPage {
+    pub(crate) status: String,
+
+    /// Number of new Events inserted.
+    pub(crate) inserted: usize,
+
+    /// Number of Events identical to existing ones, so not inserted.
+    pub(crate) duplicates: usize,
+
+    pub(crate) rejected: Vec<RejectedEvent>,
+}
+
+impl From<Vec<EventLoadResult>> for EventsLoadedPage {
+    fn from(value: Vec<EventLoadResult>) -> Self {
+        let mut page = EventsLoadedPage {
+            status: String::from("ok"),
+            inserted: 0,
+            duplicates: 0,
+            rejected: vec![],
+        };
+
+        for (index, result) in value.into_iter().enumerate() {
+            match result {
+                EventLoadResult::New => page.inserted += 1,
+                EventLoadResult::Duplicate => page.duplicates += 1,
+                EventLoadResult::Invalid(errors) => {
+                    page.rejected.push(RejectedEvent { index, errors })
+                }
+            }
+        }
+
+        page
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct DeadLetterEvent {
+    pub(crate) id: i64,
+    pub(crate) event_id: i64,
+
+    /// Number of attempts where every handler errored.
+    pub(crate) attempts: i32,
+    pub(crate) errors: Vec<String>,
+
+    #[serde(with = "time::serde::iso8601")]
+    pub(crate) created: OffsetDateTime,
+}
+
+impl From<DeadLetter> for DeadLetterEvent {
+    fn from(value: DeadLetter) -> Self {
+        DeadLetterEvent {
+            id: value.dead_letter_id,
+            event_id: value.event_id,
+            attempts: value.attempts,
+            // Stored as a JSON array. If it doesn't parse, keep the raw string.
+            errors: serde_json::from_str(&value.errors).unwrap_or_else(|_| vec![value.errors]),
+            created: value.created,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct DeadLettersPage {
+    pub(crate) status: String,
+    pub(crate) cursor: i64,
+    pub(crate) data: Vec<DeadLetterEvent>,
+}
+
+impl From<(Vec<DeadLetter>, i64)> for DeadLettersPage {
+    fn from((data, cursor): (Vec<DeadLetter>, i64)) -> Self {
+        DeadLettersPage {
+            status: String::from("ok"),
+            data: data.into_iter().map(DeadLetterEvent::from).collect(),
+            cursor,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ErrorSummaryQuery {
+    /// Maximum number of distinct errors.
+    pub(crate) limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ErrorSummaryPage {
+    pub(crate) status: String,
+    pub(crate) data: Vec<ErrorSummary>,
+}
+
+impl From<Vec<ErrorSummary>> for ErrorSummaryPage {
+    fn from(data: Vec<ErrorSummary>) -> Self {
+        ErrorSummaryPage {
+            status: String::from("ok"),
+            data,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct ResultStatsPage {
+    pub(crate) status: String,
+    pub(crate) data: ResultStats,
+}
+
+impl From<ResultStats> for ResultStatsPage {
+    fn from(data: ResultStats) -> Self {
+        ResultStatsPage {
+            status: String::from("ok"),
+            data,
+        }
+    }
+}
+
+/// Number of Events from each analyzer.
+#[derive(Serialize)]
+pub(crate) struct EventCountsPage {
+    pub(crate) status: String,
+    pub(crate) data: BTreeMap<String, i64>,
+}
+
+impl From<BTreeMap<String, i64>> for EventCountsPage {
+    fn from(data: BTreeMap<String, i64>) -> Self {
+        EventCountsPage {
+            status: String::from("ok"),
+            data,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct IdentifierQuery {
+    /// Only consider metadata from this source, e.g. "crossref".
+    pub(crate) source: Option<String>,
+
+    /// For the identifier's Events, whether it's the "subject" or "object".
+    pub(crate) role: Option<String>,
+
+    /// For the identifier's Events, only those with IDs after this.
+    pub(crate) cursor: Option<i64>,
+}
+
+/// Events for an identifier, in the public JSON representation.
+#[derive(Serialize)]
+pub(crate) struct EventsPage {
+    pub(crate) status: String,
+    pub(crate) cursor: i64,
+    pub(crate) data: Vec<Value>,
+}
+
+impl From<(Vec<Event>, i64)> for EventsPage {
+    fn from((events, cursor): (Vec<Event>, i64)) -> Self {
+        EventsPage {
+            status: String::from("ok"),
+            data: events
+                .iter()
+                .filter_map(|event| {
+                    let mut json: Value = serde_json::from_str(&event.to_json_value()?).ok()?;
+                    json["event_id"] = Value::from(event.event_id);
+                    Some(json)
+                })
+                .collect(),
+            cursor,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct Assertion {
+    pub(crate) id: i64,
+    pub(crate) source: String,
+
+    /// Version of the subject's metadata from the source.
+    pub(crate) version: i32,
+    pub(crate) json: Value,
+
+    #[serde(with = "time::serde::iso8601")]
+    pub(crate) created: OffsetDateTime,
+}
+
+#[derive(Serialize)]
+pub(crate) struct IdentifierData {
+    /// Normalized identifier.
+    pub(crate) identifier: String,
+    pub(crate) identifier_type: u32,
+    pub(crate) entity_id: i64,
+
+    /// Most recent metadata assertion, if any.
+    pub(crate) assertion: Option<Assertion>,
+
+    /// Number of Events with this identifier as subject.
+    pub(crate) subject_events: i64,
+
+    /// Number of Events with this identifier as object.
+    pub(crate) object_events: i64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct IdentifierPage {
+    pub(crate) status: String,
+    pub(crate) data: IdentifierData,
+}
+
+impl From<((String, u32), IdentifierInfo)> for IdentifierPage {
+    fn from(((identifier, identifier_type), info): ((String, u32), IdentifierInfo)) -> Self {
+        IdentifierPage {
+            status: String::from("ok"),
+            data: IdentifierData {
+                identifier,
+                identifier_type,
+                entity_id: info.entity_id,
+                // Assertions that aren't valid JSON are represented as null.
+                assertion: info.assertion.map(|x| Assertion {
+                    id: x.assertion_id,
+                    source: MetadataSourceId::from_int_value(x.source_id).to_str_value(),
+                    version: x.version,
+                    json: serde_json::from_str(&x.json).unwrap_or(Value::Null),
+                    created: x.created,
+                }),
+                subject_events: info.subject_events,
+                object_events: info.object_events,
+            },
+        }
+    }
+}
@@ -1,6 +1,6 @@
 //! Functions for operating agents
 
-use sqlx::{Postgres, Transaction};
+use sqlx::{Pool, Postgres, Transaction};
 use time::OffsetDateTime;
 
 /// Get a named checkpoint, or None if it wasn't set.
@@ -17,7 +17,21 @@ pub(crate) async fn get_checkpoint<'a>(
     Ok(date)
 }
 
-/// Get a named checkpoint, or None if it wasn't set.
+/// Get a named checkpoint with its tiebreak, if any, or None if it wasn't set.
+pub(crate) async fn get_checkpoint_position<'a>(
+    id: &str,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<Option<(OffsetDateTime, Option<String>)>, sqlx::Error> {
+    let position: Option<(OffsetDateTime, Option<String>)> =
+        sqlx::query_as("SELECT date, tiebreak FROM checkpoint WHERE id = $1;")
+            .bind(id)
+            .fetch_optional(&mut **tx)
+            .await?;
+
+    Ok(position)
+}
+
+/// Set a named checkpoint. Any tiebreak is removed, as it belonged to the previous date.
 pub(crate) async fn set_checkpoint<'a>(
     id: &str,
     value: OffsetDateTime,
@@ -27,7 +41,7 @@ pub(crate) async fn set_checkpoint<'a>(
         "INSERT INTO checkpoint (id, date)
         VALUES ($1, $2)
         ON CONFLICT (id) DO
-        UPDATE SET date = $2",
+        UPDATE SET date = $2, tiebreak = NULL",
     )
     .bind(id)
     .bind(value)
@@ -36,3 +50,452 @@ pub(crate) async fn set_checkpoint<'a>(
 
     Ok(())
 }
+
+/// Set a named checkpoint with an optional tiebreak, only if it moves it forward.
+/// Positions are compared by date, then tiebreak, with no tiebreak first.
+/// An earlier position, e.g. from an out-of-order or retried harvest, leaves it unchanged.
+pub(crate) async fn set_checkpoint_forward<'a>(
+    id: &str,
+    value: OffsetDateTime,
+    tiebreak: Option<&str>,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO checkpoint (id, date, tiebreak)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (id) DO
+        UPDATE SET date = EXCLUDED.date, tiebreak = EXCLUDED.tiebreak
+        WHERE (EXCLUDED.date, COALESCE(EXCLUDED.tiebreak, ''))
+            > (checkpoint.date, COALESCE(checkpoint.tiebreak, ''))",
+    )
+    .bind(id)
+    .bind(value)
+    .bind(tiebreak)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Remove a named checkpoint, so the next harvest starts afresh.
+/// Return the previous value, or None if it wasn't set.
+pub(crate) async fn clear_checkpoint<'a>(
+    id: &str,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<Option<OffsetDateTime>, sqlx::Error> {
+    let date: Option<OffsetDateTime> =
+        sqlx::query_scalar("DELETE FROM checkpoint WHERE id = $1 RETURNING date;")
+            .bind(id)
+            .fetch_optional(&mut **tx)
+            .await?;
+
+    Ok(date)
+}
+
+/// Get a named position checkpoint, or None if it wasn't set.
+pub(crate) async fn get_position_checkpoint<'a>(
+    id: &str,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<Option<i64>, sqlx::Error> {
+    let position: Option<i64> =
+        sqlx::query_scalar("SELECT position FROM position_checkpoint WHERE id = $1;")
+            .bind(id)
+            .fetch_optional(&mut **tx)
+            .await?;
+
+    Ok(position)
+}
+
+/// Set a named position checkpoint, only if it moves it forward.
+/// A lower position, e.g. from a concurrent extractor that started earlier, leaves it unchanged.
+pub(crate) async fn set_position_checkpoint<'a>(
+    id: &str,
+    value: i64,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO position_checkpoint (id, position)
+        VALUES ($1, $2)
+        ON CONFLICT (id) DO
+        UPDATE SET position = GREATEST(position_checkpoint.position, EXCLUDED.position)",
+    )
+    .bind(id)
+    .bind(value)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Get a named cursor checkpoint, with the query it belongs to, or None if it wasn't set.
+pub(crate) async fn get_cursor_checkpoint<'a>(
+    id: &str,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<Option<(String, String)>, sqlx::Error> {
+    sqlx::query_as("SELECT value, query FROM cursor_checkpoint WHERE id = $1;")
+        .bind(id)
+        .fetch_optional(&mut **tx)
+        .await
+}
+
+/// Set a named cursor checkpoint, for the given query.
+pub(crate) async fn set_cursor_checkpoint<'a>(
+    id: &str,
+    value: &str,
+    query: &str,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO cursor_checkpoint (id, value, query)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (id) DO
+        UPDATE SET value = $2, query = $3",
+    )
+    .bind(id)
+    .bind(value)
+    .bind(query)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Remove a named cursor checkpoint, e.g. once the harvest it belongs to has finished.
+pub(crate) async fn clear_cursor_checkpoint<'a>(
+    id: &str,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM cursor_checkpoint WHERE id = $1;")
+        .bind(id)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Control flag for harvesting. Harvests run unless it's set to false.
+pub(crate) const HARVEST_RUNNING: &str = "harvest-running";
+
+/// Get a named control flag, or None if it wasn't set.
+/// Read from the pool, so that a change made by another process is seen straight away.
+pub(crate) async fn get_control_flag(
+    id: &str,
+    pool: &Pool<Postgres>,
+) -> Result<Option<bool>, sqlx::Error> {
+    sqlx::query_scalar("SELECT value FROM control_flag WHERE id = $1;")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Set a named control flag.
+pub(crate) async fn set_control_flag(
+    id: &str,
+    value: bool,
+    pool: &Pool<Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO control_flag (id, value)
+        VALUES ($1, $2)
+        ON CONFLICT (id) DO
+        UPDATE SET value = $2",
+    )
+    .bind(id)
+    .bind(value)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Advisory lock key for executing batches of Events. The ASCII of "metabeak".
+const EXECUTE_LOCK_KEY: i64 = 0x6d65_7461_6265_616b;
+
+/// Wait for the lock for executing a batch of Events, held until the transaction ends.
+/// Only one process holding it can execute at a time.
+pub(crate) async fn lock_execution<'a>(
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT pg_advisory_xact_lock($1);")
+        .bind(EXECUTE_LOCK_KEY)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use time::Duration;
+
+    use crate::db::pool::get_pool;
+
+    use super::*;
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Run with `cargo test checkpoint_never_goes_backward -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn checkpoint_never_goes_backward() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        // Rolled back when dropped, so nothing is left behind.
+        let mut tx = pool.begin().await.unwrap();
+
+        let later = OffsetDateTime::from_unix_timestamp(1732000000).unwrap();
+        let earlier = later - Duration::DAY;
+
+        set_checkpoint_forward("test-checkpoint", later, None, &mut tx)
+            .await
+            .unwrap();
+        set_checkpoint_forward("test-checkpoint", earlier, None, &mut tx)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            get_checkpoint("test-checkpoint", &mut tx).await.unwrap(),
+            Some(later),
+            "Earlier date should be ignored."
+        );
+
+        let even_later = later + Duration::DAY;
+        set_checkpoint_forward("test-checkpoint", even_later, None, &mut tx)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            get_checkpoint("test-checkpoint", &mut tx).await.unwrap(),
+            Some(even_later)
+        );
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Run with `cargo test position_checkpoint_never_goes_backward -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn position_checkpoint_never_goes_backward() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        // Rolled back when dropped, so nothing is left behind.
+        let mut tx = pool.begin().await.unwrap();
+
+        set_position_checkpoint("test-position-checkpoint", 200, &mut tx)
+            .await
+            .unwrap();
+        set_position_checkpoint("test-position-checkpoint", 100, &mut tx)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            get_position_checkpoint("test-position-checkpoint", &mut tx)
+                .await
+                .unwrap(),
+            Some(200),
+            "Lower position should be ignored."
+        );
+
+        set_position_checkpoint("test-position-checkpoint", 300, &mut tx)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            get_position_checkpoint("test-position-checkpoint", &mut tx)
+                .await
+                .unwrap(),
+            Some(300)
+        );
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// At the same date, the tiebreak decides whether the checkpoint moves forward.
+    /// Setting the date directly removes the tiebreak.
+    /// Run with `cargo test checkpoint_tiebreak_forward -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn checkpoint_tiebreak_forward() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        // Rolled back when dropped, so nothing is left behind.
+        let mut tx = pool.begin().await.unwrap();
+
+        let date = OffsetDateTime::from_unix_timestamp(1732000000).unwrap();
+
+        set_checkpoint_forward("test-checkpoint", date, None, &mut tx)
+            .await
+            .unwrap();
+        set_checkpoint_forward("test-checkpoint", date, Some("10.5555/2"), &mut tx)
+            .await
+            .unwrap();
+        set_checkpoint_forward("test-checkpoint", date, Some("10.5555/1"), &mut tx)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            get_checkpoint_position("test-checkpoint", &mut tx)
+                .await
+                .unwrap(),
+            Some((date, Some(String::from("10.5555/2")))),
+            "Earlier tiebreak at the same date should be ignored."
+        );
+
+        set_checkpoint_forward("test-checkpoint", date, None, &mut tx)
+            .await
+            .unwrap();
+        assert_eq!(
+            get_checkpoint_position("test-checkpoint", &mut tx)
+                .await
+                .unwrap(),
+            Some((date, Some(String::from("10.5555/2")))),
+            "No tiebreak comes first."
+        );
+
+        let later = date + Duration::DAY;
+        set_checkpoint("test-checkpoint", later, &mut tx)
+            .await
+            .unwrap();
+        assert_eq!(
+            get_checkpoint_position("test-checkpoint", &mut tx)
+                .await
+                .unwrap(),
+            Some((later, None))
+        );
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Run with `cargo test clear_checkpoint_returns_previous -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn clear_checkpoint_returns_previous() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        // Rolled back when dropped, so nothing is left behind.
+        let mut tx = pool.begin().await.unwrap();
+
+        let date = OffsetDateTime::from_unix_timestamp(1732000000).unwrap();
+        set_checkpoint("test-checkpoint", date, &mut tx)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            clear_checkpoint("test-checkpoint", &mut tx).await.unwrap(),
+            Some(date)
+        );
+        assert_eq!(
+            get_checkpoint("test-checkpoint", &mut tx).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            clear_checkpoint("test-checkpoint", &mut tx).await.unwrap(),
+            None,
+            "Already cleared."
+        );
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Run with `cargo test cursor_checkpoint_with_query -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn cursor_checkpoint_with_query() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        // Rolled back when dropped, so nothing is left behind.
+        let mut tx = pool.begin().await.unwrap();
+
+        assert_eq!(
+            get_cursor_checkpoint("test-cursor", &mut tx).await.unwrap(),
+            None
+        );
+
+        set_cursor_checkpoint(
+            "test-cursor",
+            "first",
+            "from-index-date:2024-11-20",
+            &mut tx,
+        )
+        .await
+        .unwrap();
+        set_cursor_checkpoint(
+            "test-cursor",
+            "second",
+            "from-index-date:2024-11-21",
+            &mut tx,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            get_cursor_checkpoint("test-cursor", &mut tx).await.unwrap(),
+            Some((
+                String::from("second"),
+                String::from("from-index-date:2024-11-21")
+            )),
+            "Cursor and query are replaced together."
+        );
+
+        clear_cursor_checkpoint("test-cursor", &mut tx)
+            .await
+            .unwrap();
+        assert_eq!(
+            get_cursor_checkpoint("test-cursor", &mut tx).await.unwrap(),
+            None
+        );
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Run with `cargo test control_flag_set_and_unset -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn control_flag_set_and_unset() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        assert_eq!(
+            get_control_flag("test-flag", &pool).await.unwrap(),
+            None,
+            "Unset by default."
+        );
+
+        set_control_flag("test-flag", false, &pool).await.unwrap();
+        let paused = get_control_flag("test-flag", &pool).await.unwrap();
+
+        set_control_flag("test-flag", true, &pool).await.unwrap();
+        let resumed = get_control_flag("test-flag", &pool).await.unwrap();
+
+        sqlx::query("DELETE FROM control_flag WHERE id = 'test-flag'")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(paused, Some(false));
+        assert_eq!(resumed, Some(true));
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// While one transaction holds the execution lock, another can't take it.
+    /// It's released when the holding transaction ends.
+    #[tokio::test]
+    #[ignore]
+    async fn execution_lock_exclusive() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        let try_lock = |pool: sqlx::Pool<Postgres>| async move {
+            let mut tx = pool.begin().await.unwrap();
+            let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_xact_lock($1);")
+                .bind(EXECUTE_LOCK_KEY)
+                .fetch_one(&mut *tx)
+                .await
+                .unwrap();
+            locked
+        };
+
+        let mut first = pool.begin().await.unwrap();
+        lock_execution(&mut first).await.unwrap();
+
+        assert!(!try_lock(pool.clone()).await, "Lock should be held.");
+
+        first.rollback().await.unwrap();
+
+        assert!(try_lock(pool.clone()).await, "Lock should be released.");
+    }
+}
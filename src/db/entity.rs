@@ -1,18 +1,244 @@
 //! Entities and their identifiers.
 
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+use futures::{StreamExt, TryStreamExt};
+use lru::LruCache;
 use scholarly_identifiers::identifiers::Identifier;
 use sqlx::{Pool, Postgres};
 
+/// Default number of identifiers to cache.
+pub(crate) const DEFAULT_IDENTIFIER_CACHE_SIZE: usize = 100_000;
+
+/// Number of identifiers resolved at once by `resolve_identifiers`.
+/// Each uses a pool connection, so keep well below the pool size.
+pub(crate) const RESOLVE_CONCURRENCY: usize = 8;
+
+/// Identifier type and normalized value.
+pub(crate) type IdentifierKey = (i32, String);
+
+/// Key for an identifier, as used by the cache and `resolve_identifiers`.
+pub(crate) fn identifier_key(identifier: &Identifier) -> IdentifierKey {
+    let (identifier_str, identifier_type) = identifier.to_id_string_pair();
+    (identifier_type as i32, identifier_str)
+}
+
+/// Bounded cache of identifier to entity_id.
+/// Entities are never deleted, so entries never become invalid, they just get evicted.
+pub(crate) struct IdentifierCache {
+    cache: Mutex<LruCache<IdentifierKey, i64>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Number of identifier cache lookups found and not found, since the process started.
+/// Each miss is a database round trip.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub(crate) struct IdentifierCacheStats {
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+}
+
+impl IdentifierCacheStats {
+    /// Proportion of lookups that didn't need the database.
+    pub(crate) fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+impl IdentifierCache {
+    /// New cache with given capacity, or None if capacity is zero.
+    pub(crate) fn new(size: usize) -> Option<IdentifierCache> {
+        NonZeroUsize::new(size).map(|size| IdentifierCache {
+            cache: Mutex::new(LruCache::new(size)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    fn get(&self, key: &IdentifierKey) -> Option<i64> {
+        let entity_id = self.cache.lock().unwrap().get(key).copied();
+
+        let counter = if entity_id.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        entity_id
+    }
+
+    fn stats(&self) -> IdentifierCacheStats {
+        IdentifierCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn put(&self, key: IdentifierKey, entity_id: i64) {
+        self.cache.lock().unwrap().put(key, entity_id);
+    }
+}
+
+static IDENTIFIER_CACHE: OnceLock<Option<IdentifierCache>> = OnceLock::new();
+
+/// Set the size of the identifier cache. Zero disables it.
+/// Must be called before the first identifier is resolved, otherwise the default size is used.
+pub(crate) fn init_identifier_cache(size: usize) {
+    if IDENTIFIER_CACHE.set(IdentifierCache::new(size)).is_err() {
+        log::error!("Identifier cache already initialized.");
+    }
+}
+
+fn identifier_cache() -> Option<&'static IdentifierCache> {
+    IDENTIFIER_CACHE
+        .get_or_init(|| IdentifierCache::new(DEFAULT_IDENTIFIER_CACHE_SIZE))
+        .as_ref()
+}
+
+/// Hits and misses of the identifier cache, or None if it's disabled.
+pub(crate) fn identifier_cache_stats() -> Option<IdentifierCacheStats> {
+    identifier_cache().map(IdentifierCache::stats)
+}
+
 /// Retrieve the entity_id for an identifier. Create if necessary.
 /// This function is idempotent.
 /// To be called from outside a transaction so that it can't be rolled back.
 /// May be called from code subject to a READ COMMITTED transaction.
+/// Results are cached, so repeated identifiers don't need a database round trip.
 pub(crate) async fn resolve_identifier(
     identifier: &Identifier,
     pool: &Pool<Postgres>,
 ) -> Result<i64, sqlx::Error> {
-    let (identifier_str, identifier_type) = identifier.to_id_string_pair();
+    resolve_key(identifier_key(identifier), pool).await
+}
+
+/// Retrieve the entity_id for an identifier key, as `resolve_identifier`.
+pub(crate) async fn resolve_key(
+    key: IdentifierKey,
+    pool: &Pool<Postgres>,
+) -> Result<i64, sqlx::Error> {
+    resolve_with_cache(
+        identifier_cache(),
+        key,
+        |(identifier_type, identifier_str)| async move {
+            insert_or_select(identifier_type, &identifier_str, pool).await
+        },
+    )
+    .await
+}
+
+/// Retrieve the entity_ids for many identifiers with the lookup function, keyed by `identifier_key`.
+/// Each distinct identifier is looked up once, with up to `concurrency` at a time.
+/// The lookup is normally `resolve_key`, which, like `resolve_identifier`, is to be called from outside a transaction.
+/// Not an `async fn`, so the keys are collected before the future is returned, and it doesn't hold the iterator.
+/// That keeps the caller's future `Send` when the iterator borrows from it.
+pub(crate) fn resolve_identifiers<'a, F, Fut>(
+    identifiers: impl IntoIterator<Item = &'a Identifier>,
+    concurrency: usize,
+    lookup: F,
+) -> impl Future<Output = Result<HashMap<IdentifierKey, i64>, sqlx::Error>>
+where
+    F: Fn(IdentifierKey) -> Fut,
+    Fut: Future<Output = Result<i64, sqlx::Error>>,
+{
+    let keys: HashSet<IdentifierKey> = identifiers.into_iter().map(identifier_key).collect();
+
+    resolve_keys(keys, concurrency, lookup)
+}
+
+/// Find the entity_id for an identifier, without creating it.
+/// Return None if the identifier has never been seen.
+pub(crate) async fn find_identifier(
+    identifier: &Identifier,
+    pool: &Pool<Postgres>,
+) -> Result<Option<i64>, sqlx::Error> {
+    let key = identifier_key(identifier);
 
+    let cache = identifier_cache();
+    if let Some(entity_id) = cache.and_then(|cache| cache.get(&key)) {
+        return Ok(Some(entity_id));
+    }
+
+    let row: Option<(i64,)> = sqlx::query_as(
+        "SELECT entity_id FROM entity
+         WHERE identifier_type = $1 AND identifier = $2;",
+    )
+    .bind(key.0)
+    .bind(&key.1)
+    .fetch_optional(pool)
+    .await?;
+
+    if let (Some(cache), Some((entity_id,))) = (cache, row) {
+        cache.put(key, entity_id);
+    }
+
+    Ok(row.map(|(entity_id,)| entity_id))
+}
+
+/// Look up the key in the cache, falling back to the lookup function and caching its result.
+async fn resolve_with_cache<F, Fut>(
+    cache: Option<&IdentifierCache>,
+    key: IdentifierKey,
+    lookup: F,
+) -> Result<i64, sqlx::Error>
+where
+    F: FnOnce(IdentifierKey) -> Fut,
+    Fut: Future<Output = Result<i64, sqlx::Error>>,
+{
+    if let Some(entity_id) = cache.and_then(|cache| cache.get(&key)) {
+        return Ok(entity_id);
+    }
+
+    let entity_id = lookup(key.clone()).await?;
+
+    if let Some(cache) = cache {
+        cache.put(key, entity_id);
+    }
+
+    Ok(entity_id)
+}
+
+/// Look up each key with the lookup function, running up to `concurrency` at a time.
+async fn resolve_keys<F, Fut>(
+    keys: HashSet<IdentifierKey>,
+    concurrency: usize,
+    lookup: F,
+) -> Result<HashMap<IdentifierKey, i64>, sqlx::Error>
+where
+    F: Fn(IdentifierKey) -> Fut,
+    Fut: Future<Output = Result<i64, sqlx::Error>>,
+{
+    futures::stream::iter(keys)
+        .map(|key| {
+            let entity_id = lookup(key.clone());
+            async move { entity_id.await.map(|entity_id| (key, entity_id)) }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .try_collect()
+        .await
+}
+
+/// Retrieve or create the entity_id for the identifier in the database.
+async fn insert_or_select(
+    identifier_type: i32,
+    identifier_str: &str,
+    pool: &Pool<Postgres>,
+) -> Result<i64, sqlx::Error> {
     // Assume that most identifiers won't have been seen before. So start with
     // the INSERT ... IGNORE and query later on if it did already exist.
     let row: Option<(i64,)> = sqlx::query_as(
@@ -22,8 +248,8 @@ pub(crate) async fn resolve_identifier(
         ON CONFLICT (identifier_type, identifier) DO NOTHING
         RETURNING entity_id;",
     )
-    .bind(identifier_type as i32)
-    .bind(&identifier_str)
+    .bind(identifier_type)
+    .bind(identifier_str)
     .fetch_optional(pool)
     .await?;
 
@@ -37,10 +263,170 @@ pub(crate) async fn resolve_identifier(
         "SELECT entity_id FROM entity
                  WHERE identifier_type = $1 AND identifier = $2;",
     )
-    .bind(identifier_type as i32)
-    .bind(&identifier_str)
+    .bind(identifier_type)
+    .bind(identifier_str)
     .fetch_one(pool)
     .await?;
 
     Ok(row.0)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    /// A cached identifier is only looked up once.
+    #[tokio::test]
+    async fn cached_identifier_avoids_lookup() {
+        let cache = IdentifierCache::new(10);
+        let lookups = AtomicUsize::new(0);
+
+        for _ in 0..3 {
+            let entity_id = resolve_with_cache(
+                cache.as_ref(),
+                (1, String::from("10.5555/12345678")),
+                |_| async {
+                    lookups.fetch_add(1, Ordering::SeqCst);
+                    Ok(42)
+                },
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(entity_id, 42);
+        }
+
+        assert_eq!(lookups.load(Ordering::SeqCst), 1, "Expected one lookup.");
+    }
+
+    /// With no cache, every identifier is looked up.
+    #[tokio::test]
+    async fn no_cache_always_looks_up() {
+        let cache = IdentifierCache::new(0);
+        assert!(cache.is_none(), "Zero size disables the cache.");
+
+        let lookups = AtomicUsize::new(0);
+
+        for _ in 0..3 {
+            resolve_with_cache(None, (1, String::from("10.5555/12345678")), |_| async {
+                lookups.fetch_add(1, Ordering::SeqCst);
+                Ok(42)
+            })
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+    }
+
+    /// Least recently used entries are evicted when full.
+    #[tokio::test]
+    async fn cache_evicts() {
+        let cache = IdentifierCache::new(1).unwrap();
+        cache.put((1, String::from("a")), 1);
+        cache.put((1, String::from("b")), 2);
+
+        assert_eq!(cache.get(&(1, String::from("a"))), None);
+        assert_eq!(cache.get(&(1, String::from("b"))), Some(2));
+    }
+
+    /// Many Events sharing a few identifiers resolve each distinct one once, and no more than the limit at a time.
+    #[tokio::test]
+    async fn resolve_keys_dedup_and_bound() {
+        let lookups = AtomicUsize::new(0);
+        let in_flight = AtomicUsize::new(0);
+        let max_in_flight = AtomicUsize::new(0);
+
+        // 1000 Events with subjects and objects drawn from 30 DOIs.
+        let keys: HashSet<IdentifierKey> = (0..1000)
+            .flat_map(|event| [event % 10, 10 + event % 20])
+            .map(|doi| (1, format!("10.5555/{}", doi)))
+            .collect();
+
+        let entity_ids = resolve_keys(keys, 4, |(_, identifier)| {
+            let lookups = &lookups;
+            let in_flight = &in_flight;
+            let max_in_flight = &max_in_flight;
+            async move {
+                lookups.fetch_add(1, Ordering::SeqCst);
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(current, Ordering::SeqCst);
+
+                tokio::task::yield_now().await;
+
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok(identifier.trim_start_matches("10.5555/").parse().unwrap())
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(lookups.load(Ordering::SeqCst), 30);
+        assert_eq!(entity_ids.len(), 30);
+        for doi in 0..30 {
+            assert_eq!(
+                entity_ids.get(&(1, format!("10.5555/{}", doi))),
+                Some(&doi),
+                "Each identifier gets its own entity_id."
+            );
+        }
+
+        let max_in_flight = max_in_flight.load(Ordering::SeqCst);
+        assert!(max_in_flight > 1, "Expected lookups to overlap.");
+        assert!(max_in_flight <= 4, "Expected at most 4 at once.");
+    }
+
+    /// A failed lookup fails the whole resolution.
+    #[tokio::test]
+    async fn resolve_keys_error() {
+        let keys: HashSet<IdentifierKey> = (0..5).map(|x| (1, x.to_string())).collect();
+
+        let result = resolve_keys(keys, 2, |(_, identifier)| async move {
+            if identifier == "3" {
+                Err(sqlx::Error::PoolTimedOut)
+            } else {
+                Ok(1)
+            }
+        })
+        .await;
+
+        assert!(result.is_err());
+    }
+
+    /// A batch with many references to the same few identifiers only looks each up once.
+    #[tokio::test]
+    async fn reference_heavy_batch() {
+        let cache = IdentifierCache::new(100);
+        let lookups = AtomicUsize::new(0);
+
+        // 5 citing works, each referencing the same 20 DOIs.
+        for _ in 0..5 {
+            for reference in 0..20 {
+                resolve_with_cache(
+                    cache.as_ref(),
+                    (1, format!("10.5555/{}", reference)),
+                    |_| async {
+                        lookups.fetch_add(1, Ordering::SeqCst);
+                        Ok(reference)
+                    },
+                )
+                .await
+                .unwrap();
+            }
+        }
+
+        assert_eq!(lookups.load(Ordering::SeqCst), 20);
+
+        let stats = cache.unwrap().stats();
+        assert_eq!(
+            stats,
+            IdentifierCacheStats {
+                hits: 80,
+                misses: 20
+            }
+        );
+        assert_eq!(stats.hit_rate(), 0.8);
+    }
+}
@@ -1,32 +1,75 @@
 //! Model and database functions for Events and Event Queue.
 
 use scholarly_identifiers::identifiers::Identifier;
-use sqlx::{prelude::FromRow, Postgres, Transaction};
+use std::{collections::BTreeMap, env, sync::OnceLock};
 
-use crate::execution::model::Event;
+use sqlx::{prelude::FromRow, Pool, Postgres, Transaction};
+use time::OffsetDateTime;
+
+use crate::{
+    execution::model::{Event, EXTRACTOR_FIELD, SUBJECT_TYPE_FIELD},
+    util::{hash_data, parse_flag},
+};
 
 use super::source::{EventAnalyzerId, MetadataSourceId};
 
 /// State of an Event Queue item.
-/// Currently only 'new', as event queue items will be deleted once handled.
+/// Event queue items are deleted once handled, unless they're retained, in which case they're marked as processed.
 #[derive(Debug, Copy, Clone)]
 pub(crate) enum EventQueueState {
     New = 1,
+    Processed = 2,
+}
+
+static RETAIN_PROCESSED: OnceLock<bool> = OnceLock::new();
+
+/// Keep processed Event Queue entries, marked as processed, rather than deleting them.
+/// Configured from the EVENT_QUEUE_RETAIN environment variable, read once.
+fn retain_processed() -> bool {
+    *RETAIN_PROCESSED.get_or_init(|| parse_flag(env::var("EVENT_QUEUE_RETAIN").ok().as_deref()))
+}
+
+/// Hash identifying an Event by its source, analyzer, subject, object and JSON.
+/// JSON is normalized, so differences in whitespace or key order don't count.
+/// The extractor version and subject type don't count either, so re-extracting with a new version,
+/// which may add the subject type, doesn't duplicate Events.
+pub(crate) fn event_hash(event: &Event) -> String {
+    let json = match serde_json::from_str::<serde_json::Value>(&event.json) {
+        Ok(serde_json::Value::Object(mut value)) => {
+            value.remove(EXTRACTOR_FIELD);
+            value.remove(SUBJECT_TYPE_FIELD);
+            serde_json::Value::Object(value).to_string()
+        }
+        Ok(value) => value.to_string(),
+        Err(_) => event.json.clone(),
+    };
+
+    let data = serde_json::json!([
+        event.source as i32,
+        event.analyzer as i32,
+        event.subject_id.as_ref().map(|x| x.to_stable_string()),
+        event.object_id.as_ref().map(|x| x.to_stable_string()),
+        json,
+    ]);
+
+    hash_data(&data.to_string())
 }
 
 /// Insert an Event.
 /// Ignore the pre-existing event_id, create a new one.
+/// If an identical Event already exists, do nothing and return None.
 pub(crate) async fn insert_event<'a>(
     event: &Event,
     subject_entity_id: Option<i64>,
     object_entity_id: Option<i64>,
     status: EventQueueState,
     tx: &mut Transaction<'a, Postgres>,
-) -> Result<u64, sqlx::Error> {
-    let row: (i64,) = sqlx::query_as(
+) -> Result<Option<u64>, sqlx::Error> {
+    let row: Option<(i64,)> = sqlx::query_as(
         "INSERT INTO event
-         (json, status, source_id, analyzer_id, subject_entity_id, object_entity_id, assertion_id)
-        VALUES ($1, $2, $3, $4, $5, $6, $7)
+         (json, status, source_id, analyzer_id, subject_entity_id, object_entity_id, assertion_id, hash, occurred_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, COALESCE($9, NOW()))
+        ON CONFLICT (hash) DO NOTHING
         RETURNING event_id;",
     )
     .bind(&event.json)
@@ -36,10 +79,12 @@ pub(crate) async fn insert_event<'a>(
     .bind(subject_entity_id)
     .bind(object_entity_id)
     .bind(event.assertion_id)
-    .fetch_one(&mut **tx)
+    .bind(event_hash(event))
+    .bind(event.occurred_at)
+    .fetch_optional(&mut **tx)
     .await?;
 
-    Ok(row.0 as u64)
+    Ok(row.map(|(event_id,)| event_id as u64))
 }
 
 /// Result from polling the Event Queue.
@@ -54,10 +99,42 @@ pub(crate) struct EventQueueEntry {
     pub(crate) object_id_type: Option<i32>,
     pub(crate) object_id_value: Option<String>,
     pub(crate) assertion_id: i64,
+
+    /// Handler the queue entry is restricted to, if any.
+    pub(crate) handler_id: Option<i64>,
+
+    /// Number of previous attempts where every handler errored.
+    pub(crate) attempts: i32,
+
+    pub(crate) occurred_at: OffsetDateTime,
+}
+
+/// Event polled from the queue, with the state of its queue entry.
+#[derive(Debug)]
+pub(crate) struct QueuedEvent {
+    pub(crate) event: Event,
+
+    /// Handler the queue entry is restricted to, if any.
+    pub(crate) handler_id: Option<i64>,
+
+    /// Number of previous attempts where every handler errored.
+    pub(crate) attempts: i32,
+}
+
+/// Event taken off the queue because every handler errored, after retries.
+#[derive(FromRow, Debug)]
+pub(crate) struct DeadLetter {
+    pub(crate) dead_letter_id: i64,
+    pub(crate) event_id: i64,
+    pub(crate) attempts: i32,
+
+    /// JSON array of error messages.
+    pub(crate) errors: String,
+    pub(crate) created: OffsetDateTime,
 }
 
 impl EventQueueEntry {
-    fn to_event(self) -> Event {
+    fn into_event(self) -> Event {
         Event {
             event_id: self.event_id,
             analyzer: EventAnalyzerId::from_int_value(self.analyzer_id),
@@ -79,6 +156,7 @@ impl EventQueueEntry {
                 None
             },
             json: self.json,
+            occurred_at: Some(self.occurred_at),
         }
     }
 }
@@ -86,48 +164,309 @@ impl EventQueueEntry {
 /// Poll from execution_events queue in a transaction. Uses SKIP LOCKED to avoid
 /// deadlocking with other executions. Rows are locked until the transaction is
 /// committed or aborted.
+/// Each Event is returned with the handler it's restricted to, if any, and its number of failed attempts.
+/// Polled entries are deleted, or if EVENT_QUEUE_RETAIN is set, marked as processed.
 pub(crate) async fn poll<'a>(
     limit: i32,
     tx: &mut Transaction<'a, Postgres>,
-) -> Result<Vec<Event>, sqlx::Error> {
+) -> Result<Vec<QueuedEvent>, sqlx::Error> {
+    // Lock the entries first, so they're finished even if their Event is missing.
+    let event_queue_ids: Vec<i64> = sqlx::query_scalar(
+        "SELECT event_queue_id
+         FROM event_queue
+         WHERE status = $2
+         ORDER BY event_queue_id ASC
+         FOR UPDATE SKIP LOCKED
+         LIMIT $1",
+    )
+    .bind(limit)
+    .bind(EventQueueState::New as i32)
+    .fetch_all(&mut **tx)
+    .await?;
+
     let rows: Vec<EventQueueEntry> = sqlx::query_as(
+        "SELECT
+            event.event_id as event_id,
+            event.analyzer_id as analyzer_id,
+            event.source_id as source_id,
+            event.assertion_id as assertion_id,
+            subject.identifier_type as subject_id_type,
+            subject.identifier as subject_id_value,
+            object.identifier_type as object_id_type,
+            object.identifier as object_id_value,
+            event.json as json,
+            event_queue.handler_id as handler_id,
+            event_queue.attempts as attempts,
+            event.occurred_at as occurred_at
+        FROM
+            event_queue
+            INNER JOIN event ON event_queue.event_id = event.event_id
+            LEFT JOIN entity AS subject ON subject.entity_id = event.subject_entity_id
+            LEFT JOIN entity AS object ON object.entity_id = event.object_entity_id
+        WHERE event_queue.event_queue_id = ANY($1)
+        ORDER BY event_queue.event_queue_id ASC;",
+    )
+    .bind(&event_queue_ids)
+    .fetch_all(&mut **tx)
+    .await? as Vec<EventQueueEntry>;
+
+    if retain_processed() {
+        mark_processed(&event_queue_ids, tx).await?;
+    } else {
+        delete_processed(&event_queue_ids, tx).await?;
+    }
+
+    Ok(rows
+        .into_iter()
+        .map(|r| {
+            let handler_id = r.handler_id;
+            let attempts = r.attempts;
+            QueuedEvent {
+                event: r.into_event(),
+                handler_id,
+                attempts,
+            }
+        })
+        .collect())
+}
+
+/// Delete processed Event Queue entries.
+async fn delete_processed<'a>(
+    event_queue_ids: &[i64],
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM event_queue WHERE event_queue_id = ANY($1);")
+        .bind(event_queue_ids)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Mark Event Queue entries as processed, rather than deleting them, to keep an audit trail.
+pub(crate) async fn mark_processed<'a>(
+    event_queue_ids: &[i64],
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE event_queue
+         SET status = $2, processed = NOW()
+         WHERE event_queue_id = ANY($1);",
+    )
+    .bind(event_queue_ids)
+    .bind(EventQueueState::Processed as i32)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Put an Event back on the queue after every handler errored, recording the number of attempts so far.
+pub(crate) async fn requeue_failed<'a>(
+    event_id: i64,
+    handler_id: Option<i64>,
+    attempts: i32,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO event_queue (event_id, handler_id, attempts) VALUES ($1, $2, $3);")
+        .bind(event_id)
+        .bind(handler_id)
+        .bind(attempts)
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+/// Record an Event as a dead letter, with the error messages accumulated from all its execution results.
+/// Should be called after the results are saved in the same transaction.
+pub(crate) async fn record_dead_letter<'a>(
+    event_id: i64,
+    attempts: i32,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO dead_letter_event (event_id, attempts, errors)
+         SELECT $1, $2, COALESCE(json_agg(error ORDER BY result_id), '[]')::TEXT
+         FROM execution_result
+         WHERE event_id = $1 AND error IS NOT NULL;",
+    )
+    .bind(event_id)
+    .bind(attempts)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Get a page of dead letters after the given ID.
+pub(crate) async fn get_dead_letters(
+    pool: &Pool<Postgres>,
+    after: i64,
+    limit: i32,
+) -> Result<Vec<DeadLetter>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT dead_letter_id, event_id, attempts, errors, created
+         FROM dead_letter_event
+         WHERE dead_letter_id > $1
+         ORDER BY dead_letter_id ASC
+         LIMIT $2",
+    )
+    .bind(after)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+/// Count the Events where the entity is the subject, and where it's the object.
+pub(crate) async fn count_for_entity(
+    entity_id: i64,
+    pool: &Pool<Postgres>,
+) -> Result<(i64, i64), sqlx::Error> {
+    sqlx::query_as(
+        "SELECT
+            (SELECT COUNT(*) FROM event WHERE subject_entity_id = $1),
+            (SELECT COUNT(*) FROM event WHERE object_entity_id = $1);",
+    )
+    .bind(entity_id)
+    .fetch_one(pool)
+    .await
+}
+
+/// Count all Events by the name of the analyzer that produced them.
+/// Scans the whole table, so suited to occasional capacity planning rather than frequent polling.
+pub(crate) async fn counts_by_analyzer(
+    pool: &Pool<Postgres>,
+) -> Result<BTreeMap<String, i64>, sqlx::Error> {
+    let rows: Vec<(i32, i64)> =
+        sqlx::query_as("SELECT analyzer_id, COUNT(*) FROM event GROUP BY analyzer_id;")
+            .fetch_all(pool)
+            .await?;
+
+    let mut counts = BTreeMap::new();
+    for (analyzer_id, count) in rows {
+        *counts
+            .entry(EventAnalyzerId::from_int_value(analyzer_id).to_str_value())
+            .or_insert(0) += count;
+    }
+
+    Ok(counts)
+}
+
+/// Which end of an Event an entity is at.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum EntityRole {
+    Subject,
+    Object,
+}
+
+impl EntityRole {
+    pub(crate) fn from_str_value(value: &str) -> Option<EntityRole> {
+        match value {
+            "subject" => Some(EntityRole::Subject),
+            "object" => Some(EntityRole::Object),
+            _ => None,
+        }
+    }
+}
+
+/// Events where the entity is the subject, with IDs after the cursor, in order.
+pub(crate) async fn events_for_subject(
+    entity_id: i64,
+    cursor: i64,
+    limit: i32,
+    pool: &Pool<Postgres>,
+) -> Result<Vec<Event>, sqlx::Error> {
+    events_for_entity(EntityRole::Subject, entity_id, cursor, limit, pool).await
+}
+
+/// Events where the entity is the object, e.g. everything that references a DOI, with IDs after the cursor, in order.
+pub(crate) async fn events_for_object(
+    entity_id: i64,
+    cursor: i64,
+    limit: i32,
+    pool: &Pool<Postgres>,
+) -> Result<Vec<Event>, sqlx::Error> {
+    events_for_entity(EntityRole::Object, entity_id, cursor, limit, pool).await
+}
+
+/// Events with the entity in the given role. Hydrated with the same joins as [poll].
+async fn events_for_entity(
+    role: EntityRole,
+    entity_id: i64,
+    cursor: i64,
+    limit: i32,
+    pool: &Pool<Postgres>,
+) -> Result<Vec<Event>, sqlx::Error> {
+    let column = match role {
+        EntityRole::Subject => "subject_entity_id",
+        EntityRole::Object => "object_entity_id",
+    };
+
+    // Not from the queue, so there's no handler restriction or attempts.
+    let rows: Vec<EventQueueEntry> = sqlx::query_as(&format!(
+        "SELECT
+            event.event_id as event_id,
+            event.analyzer_id as analyzer_id,
+            event.source_id as source_id,
+            event.assertion_id as assertion_id,
+            subject.identifier_type as subject_id_type,
+            subject.identifier as subject_id_value,
+            object.identifier_type as object_id_type,
+            object.identifier as object_id_value,
+            event.json as json,
+            NULL::BIGINT as handler_id,
+            0 as attempts,
+            event.occurred_at as occurred_at
+        FROM
+            event
+            LEFT JOIN entity AS subject ON subject.entity_id = event.subject_entity_id
+            LEFT JOIN entity AS object ON object.entity_id = event.object_entity_id
+        WHERE event.{} = $1
+        AND event.event_id > $2
+        ORDER BY event.event_id ASC
+        LIMIT $3;",
+        column
+    ))
+    .bind(entity_id)
+    .bind(cursor)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.into_iter().map(|r| r.into_event()).collect())
+}
+
+/// Put existing Events back on the queue, so they're executed again.
+/// Takes up to `limit` Events with IDs after `after_event_id`, in order.
+/// If `handler_id` is given, only that handler will be run for them.
+/// Return the number of Events re-enqueued, and the ID of the last one.
+pub(crate) async fn reenqueue_events(
+    limit: i64,
+    after_event_id: i64,
+    handler_id: Option<i64>,
+    pool: &Pool<Postgres>,
+) -> Result<(i64, Option<i64>), sqlx::Error> {
+    sqlx::query_as(
         "WITH
-            entries AS (
-                SELECT
-                    event_queue.event_queue_id as event_queue_id,
-                    event_queue.event_id as event_id
-                FROM event_queue
-                ORDER BY event_queue.event_queue_id ASC
-                FOR UPDATE SKIP LOCKED
+            selected AS (
+                SELECT event_id FROM event
+                WHERE event_id > $2
+                ORDER BY event_id ASC
                 LIMIT $1
             ),
-            events AS (
-                SELECT
-                    event.event_id as event_id,
-                    event.analyzer_id as analyzer_id,
-                    event.source_id as source_id,
-                    event.assertion_id as assertion_id,
-                    subject.identifier_type as subject_id_type,
-                    subject.identifier as subject_id_value,
-                    object.identifier_type as object_id_type,
-                    object.identifier as object_id_value,
-                    event.json as json
-                FROM
-                    entries
-                    INNER JOIN event ON entries.event_id = event.event_id
-                    LEFT JOIN entity AS subject ON subject.entity_id = event.subject_entity_id
-                    LEFT JOIN entity AS object ON object.entity_id = event.object_entity_id
-            ),
-            deleted AS (
-                DELETE FROM event_queue
-                WHERE event_queue_id IN (SELECT event_queue_id FROM entries))
-        SELECT * from events;",
+            inserted AS (
+                INSERT INTO event_queue (event_id, handler_id)
+                SELECT event_id, $3 FROM selected
+                RETURNING event_id
+            )
+        SELECT COUNT(*), MAX(event_id) FROM inserted;",
     )
     .bind(limit)
-    .fetch_all(&mut **tx)
-    .await? as Vec<EventQueueEntry>;
-
-    Ok(rows.into_iter().map(|r| r.to_event()).collect())
+    .bind(after_event_id)
+    .bind(handler_id)
+    .fetch_one(pool)
+    .await
 }
 
 #[cfg(test)]
@@ -146,9 +485,12 @@ mod tests {
             object_id_type: Some(1), // Type of DOI from `scholarly_identifiers` crate.
             object_id_value: Some(String::from("10.5555/87654321")),
             assertion_id: -1,
+            handler_id: None,
+            attempts: 0,
+            occurred_at: OffsetDateTime::UNIX_EPOCH,
         };
 
-        let event = result.to_event();
+        let event = result.into_event();
 
         assert_eq!(
             event.analyzer,
@@ -191,9 +533,12 @@ mod tests {
             object_id_type: None,
             object_id_value: None,
             assertion_id: -1,
+            handler_id: None,
+            attempts: 0,
+            occurred_at: OffsetDateTime::UNIX_EPOCH,
         };
 
-        let event = result.to_event();
+        let event = result.into_event();
 
         assert_eq!(
             event.analyzer,
@@ -219,6 +564,80 @@ mod tests {
         assert_eq!(event.event_id, 1, "Same Event ID should be copied");
     }
 
+    fn hash_test_event(
+        event_id: i64,
+        analyzer: EventAnalyzerId,
+        object_id: Option<&str>,
+        json: &str,
+    ) -> Event {
+        Event {
+            event_id,
+            analyzer,
+            source: MetadataSourceId::Crossref,
+            subject_id: Some(Identifier::parse("10.5555/12345678")),
+            object_id: object_id.map(Identifier::parse),
+            json: String::from(json),
+            assertion_id: event_id,
+            occurred_at: None,
+        }
+    }
+
+    /// Events differing only in JSON formatting, assertion or event ID have the same hash.
+    /// Any other difference changes the hash.
+    #[test]
+    fn event_hash_identity() {
+        let event = hash_test_event(
+            1,
+            EventAnalyzerId::Reference,
+            Some("10.5555/87654321"),
+            "{\"a\": 1, \"b\": 2}",
+        );
+
+        let reformatted = hash_test_event(
+            2,
+            EventAnalyzerId::Reference,
+            Some("10.5555/87654321"),
+            "{\"b\":2,\"a\":1}",
+        );
+        assert_eq!(event_hash(&event), event_hash(&reformatted));
+
+        let different_analyzer = hash_test_event(
+            1,
+            EventAnalyzerId::Identifier,
+            Some("10.5555/87654321"),
+            "{\"a\": 1, \"b\": 2}",
+        );
+        assert_ne!(event_hash(&event), event_hash(&different_analyzer));
+
+        let different_object =
+            hash_test_event(1, EventAnalyzerId::Reference, None, "{\"a\": 1, \"b\": 2}");
+        assert_ne!(event_hash(&event), event_hash(&different_object));
+
+        let different_json = hash_test_event(
+            1,
+            EventAnalyzerId::Reference,
+            Some("10.5555/87654321"),
+            "{\"a\": 1, \"b\": 3}",
+        );
+        assert_ne!(event_hash(&event), event_hash(&different_json));
+
+        let with_extractor = hash_test_event(
+            1,
+            EventAnalyzerId::Reference,
+            Some("10.5555/87654321"),
+            "{\"a\": 1, \"b\": 2, \"_extractor\": \"crossref@1\"}",
+        );
+        assert_eq!(event_hash(&event), event_hash(&with_extractor));
+
+        let with_subject_type = hash_test_event(
+            1,
+            EventAnalyzerId::Reference,
+            Some("10.5555/87654321"),
+            "{\"a\": 1, \"b\": 2, \"subject_type\": \"journal-article\"}",
+        );
+        assert_eq!(event_hash(&event), event_hash(&with_subject_type));
+    }
+
     /// For both Subject and Object the type and value must be present together as a pair.
     /// Test that getting them separately doesn't cause issues.
     #[test]
@@ -233,9 +652,12 @@ mod tests {
             object_id_type: None,
             object_id_value: Some(String::from("10.5555/87654321")),
             assertion_id: -1,
+            handler_id: None,
+            attempts: 0,
+            occurred_at: OffsetDateTime::UNIX_EPOCH,
         };
 
-        let event = result.to_event();
+        let event = result.into_event();
 
         assert_eq!(
             event.subject_id, None,
@@ -256,9 +678,12 @@ mod tests {
             object_id_type: Some(1),
             object_id_value: None,
             assertion_id: -1,
+            handler_id: None,
+            attempts: 0,
+            occurred_at: OffsetDateTime::UNIX_EPOCH,
         };
 
-        let event = result.to_event();
+        let event = result.into_event();
 
         assert_eq!(
             event.subject_id, None,
@@ -269,4 +694,28 @@ mod tests {
             "Object should be None unless both type and value are present"
         );
     }
+
+    #[test]
+    fn entity_role() {
+        assert_eq!(
+            EntityRole::from_str_value("subject"),
+            Some(EntityRole::Subject)
+        );
+        assert_eq!(
+            EntityRole::from_str_value("object"),
+            Some(EntityRole::Object)
+        );
+        assert_eq!(EntityRole::from_str_value("Object"), None);
+    }
+
+    #[test]
+    fn retain_flag() {
+        assert!(!parse_flag(None));
+        assert!(!parse_flag(Some("")));
+        assert!(!parse_flag(Some("false")));
+        assert!(!parse_flag(Some("0")));
+        assert!(parse_flag(Some("1")));
+        assert!(parse_flag(Some("true")));
+        assert!(parse_flag(Some(" YES ")));
+    }
 }
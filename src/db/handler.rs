@@ -1,8 +1,11 @@
 //! Model and database functions for Handler Functions and execution results.
 
-use crate::execution::model::{ExecutionResult, HandlerSpec};
+use std::collections::BTreeMap;
+
+use crate::execution::model::{ErrorKind, ExecutionResult, HandlerSpec};
 use serde::{Deserialize, Serialize};
-use sqlx::{Pool, Postgres, Transaction};
+use sqlx::{prelude::FromRow, Connection, Pool, Postgres, Transaction};
+use time::OffsetDateTime;
 
 /// State of a handler function.
 /// Currently they are always enabled.
@@ -13,20 +16,19 @@ pub enum HandlerState {
     Unknown = 3,
 }
 
-/// Insert a handler function.
+/// Insert a handler function for its owner.
 /// Returning the Handler ID, and boolean flag to indicate if it was newly created or already existed.
 pub(crate) async fn insert_handler(
     task: &HandlerSpec,
     hash: &str,
-    owner_id: i32,
     status: HandlerState,
     pool: &Pool<Postgres>,
 ) -> Result<(i64, bool), sqlx::Error> {
     let row: (Option<i64>, Option<i64>) = sqlx::query_as(
         "WITH new_id AS (
                     INSERT INTO handler
-                    (owner_id, hash, code, status)
-                    VALUES ($1, $2, $3, $4)
+                    (owner_id, hash, code, status, timeout_ms)
+                    VALUES ($1, $2, $3, $4, $5)
                     ON CONFLICT (hash) DO NOTHING
                     RETURNING handler_id),
         old_id AS (SELECT handler_id
@@ -34,10 +36,11 @@ pub(crate) async fn insert_handler(
                     WHERE hash = $2 LIMIT 1)
         SELECT (SELECT * from new_id) AS new, (SELECT * FROM old_id) AS old;",
     )
-    .bind(owner_id)
+    .bind(task.owner_id)
     .bind(hash)
     .bind(&task.code)
     .bind(status as i32)
+    .bind(task.timeout_ms)
     .fetch_one(pool)
     .await?;
 
@@ -48,6 +51,29 @@ pub(crate) async fn insert_handler(
     }
 }
 
+/// Replace the code of a handler, keeping its id so that results continue to accumulate across edits.
+/// Return false if there's no handler with the id.
+/// The hash must be unique, so this fails if another handler already has the same code.
+pub(crate) async fn update_code<'a>(
+    handler_id: i64,
+    code: &str,
+    hash: &str,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE handler
+         SET code = $2, hash = $3
+         WHERE handler_id = $1",
+    )
+    .bind(handler_id)
+    .bind(code)
+    .bind(hash)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
 /// Retrieve all Handler functions that are enabled.
 /// Assumes that there is a small enough number that they will fit in heap.
 pub(crate) async fn get_all_enabled_handlers<'a>(
@@ -65,21 +91,110 @@ pub(crate) async fn get_all_enabled_handlers<'a>(
     Ok(rows)
 }
 
-/// Save a set of [RunResult]s.
+/// Retrieve enabled Handler functions belonging to the owner.
+pub(crate) async fn get_handlers_for_owner<'a>(
+    owner_id: i32,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<Vec<HandlerSpec>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT *
+         FROM handler
+         WHERE status = $1
+         AND owner_id = $2
+         ORDER BY handler_id ASC",
+    )
+    .bind(HandlerState::Enabled as i32)
+    .bind(owner_id)
+    .fetch_all(&mut **tx)
+    .await
+}
+
+/// Number of successful and error results for a handler.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ResultCounts {
+    pub(crate) success: i64,
+    pub(crate) error: i64,
+
+    /// Errors that were timeouts, also included in `error`.
+    pub(crate) timeout: i64,
+}
+
+/// Count successful, error and timeout results per handler id.
+pub(crate) fn count_results<'r>(
+    results: impl IntoIterator<Item = &'r ExecutionResult>,
+) -> BTreeMap<i64, ResultCounts> {
+    let mut counts: BTreeMap<i64, ResultCounts> = BTreeMap::new();
+
+    for result in results {
+        let entry = counts.entry(result.handler_id).or_default();
+        if result.result.is_some() {
+            entry.success += 1;
+        }
+        if result.error.is_some() {
+            entry.error += 1;
+        }
+        if result.error_kind == Some(ErrorKind::Timeout) {
+            entry.timeout += 1;
+        }
+    }
+
+    counts
+}
+
+/// Save a set of [ExecutionResult]s.
+/// Also increment the counters on each handler, in the same transaction.
+/// Each result is inserted in its own savepoint. If the database rejects one, it's replaced with
+/// a `SaveFailed` error so that the rest of the batch is still saved.
 pub(crate) async fn save_results<'a>(
     results: &[ExecutionResult],
     tx: &mut Transaction<'a, Postgres>,
 ) -> Result<(), sqlx::Error> {
+    let mut saved = Vec::with_capacity(results.len());
+    let mut replacements = vec![];
+
     for result in results.iter() {
+        match insert_result(result, tx).await {
+            Ok(()) => saved.push(result),
+            Err(sqlx::Error::Database(e)) => {
+                log::error!(
+                    "Couldn't save result for handler {} event {}: {}",
+                    result.handler_id,
+                    result.event_id,
+                    e
+                );
+
+                let replacement = save_failed(result, &e.to_string());
+                match insert_result(&replacement, tx).await {
+                    Ok(()) => replacements.push(replacement),
+                    Err(sqlx::Error::Database(e)) => {
+                        log::error!(
+                            "Couldn't save error for handler {} event {}: {}",
+                            result.handler_id,
+                            result.event_id,
+                            e
+                        );
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    for (handler_id, counts) in count_results(saved.into_iter().chain(replacements.iter())) {
         sqlx::query(
-            "INSERT INTO execution_result
-             (handler_id, event_id, result, error)
-            VALUES ($1, $2, $3, $4);",
+            "UPDATE handler
+             SET
+                success_count = success_count + $2,
+                error_count = error_count + $3,
+                timeout_count = timeout_count + $4,
+                last_result_at = NOW()
+             WHERE handler_id = $1;",
         )
-        .bind(result.handler_id)
-        .bind(result.event_id)
-        .bind(&result.result)
-        .bind(&result.error)
+        .bind(handler_id)
+        .bind(counts.success)
+        .bind(counts.error)
+        .bind(counts.timeout)
         .execute(&mut **tx)
         .await?;
     }
@@ -87,6 +202,135 @@ pub(crate) async fn save_results<'a>(
     Ok(())
 }
 
+/// Insert a single result in a savepoint, so that if the database rejects it the transaction can carry on.
+async fn insert_result<'a>(
+    result: &ExecutionResult,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<(), sqlx::Error> {
+    let mut savepoint = Connection::begin(&mut **tx).await?;
+
+    let inserted = sqlx::query(
+        "INSERT INTO execution_result
+         (handler_id, event_id, assertion_id, result, error, error_kind)
+        VALUES ($1, $2, $3, $4, $5, $6);",
+    )
+    .bind(result.handler_id)
+    .bind(result.event_id)
+    .bind(result.assertion_id)
+    .bind(&result.result)
+    .bind(&result.error)
+    .bind(result.error_kind)
+    .execute(&mut *savepoint)
+    .await;
+
+    match inserted {
+        Ok(_) => savepoint.commit().await,
+        Err(e) => {
+            savepoint.rollback().await?;
+            Err(e)
+        }
+    }
+}
+
+/// Error to save in place of a result that the database rejected.
+fn save_failed(result: &ExecutionResult, message: &str) -> ExecutionResult {
+    ExecutionResult {
+        result_id: -1,
+        handler_id: result.handler_id,
+        event_id: result.event_id,
+        assertion_id: result.assertion_id,
+        result: None,
+        error: Some(format!("Result couldn't be saved: {}", message)),
+        error_kind: Some(ErrorKind::SaveFailed),
+        created: None,
+    }
+}
+
+/// Recalculate the counters on all handlers from the stored results.
+/// Expensive, as it scans all results. Returns the number of handlers updated.
+pub(crate) async fn recount_results(pool: &Pool<Postgres>) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query(
+        "UPDATE handler
+         SET
+            success_count = COALESCE(counts.success_count, 0),
+            error_count = COALESCE(counts.error_count, 0),
+            timeout_count = COALESCE(counts.timeout_count, 0),
+            last_result_at = counts.last_result_at
+         FROM handler AS h
+         LEFT JOIN (
+            SELECT
+                handler_id,
+                COUNT(*) FILTER (WHERE result IS NOT NULL) AS success_count,
+                COUNT(*) FILTER (WHERE error IS NOT NULL) AS error_count,
+                COUNT(*) FILTER (WHERE error_kind = $1) AS timeout_count,
+                MAX(created) AS last_result_at
+            FROM execution_result
+            GROUP BY handler_id) AS counts
+         ON counts.handler_id = h.handler_id
+         WHERE handler.handler_id = h.handler_id;",
+    )
+    .bind(ErrorKind::Timeout)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Count results created before the date, which [purge_results] would delete.
+pub(crate) async fn count_results_before(
+    pool: &Pool<Postgres>,
+    before: OffsetDateTime,
+) -> Result<i64, sqlx::Error> {
+    // Use execution_result_created_idx
+    sqlx::query_scalar("SELECT COUNT(*) FROM execution_result WHERE created < $1")
+        .bind(before)
+        .fetch_one(pool)
+        .await
+}
+
+/// Delete results created before the date. Returns the number of results deleted.
+/// Handler counters are reduced by the deleted results in the same statement, so they agree with [recount_results].
+pub(crate) async fn purge_results(
+    pool: &Pool<Postgres>,
+    before: OffsetDateTime,
+) -> Result<u64, sqlx::Error> {
+    // Use execution_result_created_idx
+    let deleted: i64 = sqlx::query_scalar(
+        "WITH purged AS (
+            DELETE FROM execution_result
+            WHERE created < $1
+            RETURNING
+                handler_id,
+                result IS NOT NULL AS success,
+                error IS NOT NULL AS error,
+                error_kind = $2 AS timeout),
+         counts AS (
+            SELECT
+                handler_id,
+                COUNT(*) AS total,
+                COUNT(*) FILTER (WHERE success) AS success_count,
+                COUNT(*) FILTER (WHERE error) AS error_count,
+                COUNT(*) FILTER (WHERE timeout) AS timeout_count
+            FROM purged
+            GROUP BY handler_id),
+         updated AS (
+            UPDATE handler
+            SET
+                success_count = handler.success_count - counts.success_count,
+                error_count = handler.error_count - counts.error_count,
+                timeout_count = handler.timeout_count - counts.timeout_count
+            FROM counts
+            WHERE handler.handler_id = counts.handler_id)
+         SELECT COALESCE(SUM(total), 0)::BIGINT FROM counts;",
+    )
+    .bind(before)
+    .bind(ErrorKind::Timeout)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(deleted as u64)
+}
+
 pub(crate) async fn get_by_id(
     pool: &Pool<Postgres>,
     handler_id: i64,
@@ -95,7 +339,10 @@ pub(crate) async fn get_by_id(
         "SELECT
             handler_id,
             code,
-            status
+            status,
+            timeout_ms,
+            hash,
+            owner_id
          FROM handler
          WHERE handler_id = $1
          LIMIT 1;",
@@ -105,26 +352,131 @@ pub(crate) async fn get_by_id(
     .await
 }
 
+/// Find the handler with the given code hash, if there is one.
+pub(crate) async fn get_by_hash(
+    pool: &Pool<Postgres>,
+    hash: &str,
+) -> Result<Option<HandlerSpec>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT
+            handler_id,
+            code,
+            status,
+            timeout_ms,
+            hash,
+            owner_id
+         FROM handler
+         WHERE hash = $1
+         LIMIT 1;",
+    )
+    .bind(hash)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Number of errors with the same kind and message for a handler.
+#[derive(Debug, FromRow, Serialize)]
+pub(crate) struct ErrorSummary {
+    pub(crate) error_kind: Option<ErrorKind>,
+    pub(crate) error: String,
+    pub(crate) count: i64,
+
+    /// Fraction of all the handler's errors.
+    pub(crate) proportion: f64,
+}
+
+/// Most common errors for a handler, grouped by kind and message, most frequent first.
+pub(crate) async fn error_summary(
+    pool: &Pool<Postgres>,
+    handler_id: i64,
+    limit: i64,
+) -> Result<Vec<ErrorSummary>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT
+            error_kind,
+            error,
+            COUNT(*) AS count,
+            COUNT(*)::FLOAT8 / (SUM(COUNT(*)) OVER ())::FLOAT8 AS proportion
+         FROM execution_result
+         WHERE handler_id = $1
+         AND error IS NOT NULL
+         GROUP BY error_kind, error
+         ORDER BY count DESC, error ASC
+         LIMIT $2",
+    )
+    .bind(handler_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+}
+
+/// Totals of a handler's results, for gauging its health.
+#[derive(Debug, Default, FromRow, PartialEq, Serialize)]
+pub(crate) struct ResultStats {
+    pub(crate) total: i64,
+    pub(crate) success: i64,
+    pub(crate) error: i64,
+
+    /// Errors that were timeouts, also included in `error`.
+    pub(crate) timeout: i64,
+}
+
+/// All of a handler's results, its successes, errors and timeouts.
+/// Read from the handler's counters, so doesn't scan the results. Zero for an unknown handler.
+pub(crate) async fn result_stats(
+    pool: &Pool<Postgres>,
+    handler_id: i64,
+) -> Result<ResultStats, sqlx::Error> {
+    let stats = sqlx::query_as(
+        "SELECT
+            success_count + error_count AS total,
+            success_count AS success,
+            error_count AS error,
+            timeout_count AS timeout
+         FROM handler
+         WHERE handler_id = $1",
+    )
+    .bind(handler_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(stats.unwrap_or_default())
+}
+
+/// SQL comparison and ordering for paging through results from a cursor.
+fn result_order(descending: bool) -> (&'static str, &'static str) {
+    if descending {
+        ("<", "DESC")
+    } else {
+        (">", "ASC")
+    }
+}
+
 /// Get successful results for handler after cursor.
+/// If descending, get results before the cursor, newest first.
 pub(crate) async fn get_success_results(
     pool: &Pool<Postgres>,
     handler_id: i64,
     after: i64,
     limit: i32,
+    descending: bool,
 ) -> Result<Vec<ExecutionResult>, sqlx::Error> {
+    let (comparison, order) = result_order(descending);
+
     // Use success_execution_idx
-    let rows: Vec<ExecutionResult> = sqlx::query_as(
+    let rows: Vec<ExecutionResult> = sqlx::query_as(&format!(
         "SELECT * FROM execution_result
          WHERE
             handler_id = $1
          AND
-            result_id > $2
+            result_id {} $2
          AND
            result IS NOT NULL
-         ORDER BY result_id ASC
+         ORDER BY result_id {}
          LIMIT $3
          ",
-    )
+        comparison, order
+    ))
     .bind(handler_id)
     .bind(after)
     .bind(limit)
@@ -135,23 +487,61 @@ pub(crate) async fn get_success_results(
 }
 
 /// Get all results for handler after cursor.
+/// If descending, get results before the cursor, newest first.
 pub(crate) async fn get_all_results(
     pool: &Pool<Postgres>,
     handler_id: i64,
     after: i64,
     limit: i32,
+    descending: bool,
 ) -> Result<Vec<ExecutionResult>, sqlx::Error> {
+    let (comparison, order) = result_order(descending);
+
     // Use all_execution_idx
-    let rows: Vec<ExecutionResult> = sqlx::query_as(
+    let rows: Vec<ExecutionResult> = sqlx::query_as(&format!(
         "SELECT * FROM execution_result
          WHERE
             handler_id = $1
          AND
-            result_id > $2
-         ORDER BY result_id ASC
+            result_id {} $2
+         ORDER BY result_id {}
          LIMIT $3
          ",
-    )
+        comparison, order
+    ))
+    .bind(handler_id)
+    .bind(after)
+    .bind(limit)
+    .fetch_all(pool)
+    .await? as Vec<ExecutionResult>;
+
+    Ok(rows)
+}
+
+/// Get error results for handler after cursor.
+/// If descending, get results before the cursor, newest first.
+pub(crate) async fn get_error_results(
+    pool: &Pool<Postgres>,
+    handler_id: i64,
+    after: i64,
+    limit: i32,
+    descending: bool,
+) -> Result<Vec<ExecutionResult>, sqlx::Error> {
+    let (comparison, order) = result_order(descending);
+
+    let rows: Vec<ExecutionResult> = sqlx::query_as(&format!(
+        "SELECT * FROM execution_result
+         WHERE
+            handler_id = $1
+         AND
+            result_id {} $2
+         AND
+           error IS NOT NULL
+         ORDER BY result_id {}
+         LIMIT $3
+         ",
+        comparison, order
+    ))
     .bind(handler_id)
     .bind(after)
     .bind(limit)
@@ -160,3 +550,538 @@ pub(crate) async fn get_all_results(
 
     Ok(rows)
 }
+
+/// Get a single result by id, if it exists.
+pub(crate) async fn get_result_by_id(
+    pool: &Pool<Postgres>,
+    result_id: i64,
+) -> Result<Option<ExecutionResult>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT * FROM execution_result
+         WHERE result_id = $1",
+    )
+    .bind(result_id)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Get the most recent result id for handler, if there are any results.
+pub(crate) async fn get_latest_result_id(
+    pool: &Pool<Postgres>,
+    handler_id: i64,
+) -> Result<Option<i64>, sqlx::Error> {
+    let result_id: Option<i64> = sqlx::query_scalar(
+        "SELECT MAX(result_id) FROM execution_result
+         WHERE handler_id = $1",
+    )
+    .bind(handler_id)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(result_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use crate::db::pool::get_pool;
+    use crate::execution::model::ErrorKind;
+
+    use super::*;
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Run with `cargo test handlers_for_owner -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn handlers_for_owner() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        // Rolled back when dropped, so nothing is left behind.
+        let mut tx = pool.begin().await.unwrap();
+
+        for (owner_id, code, status) in [
+            (
+                1001,
+                "function f(args) { return [1]; }",
+                HandlerState::Enabled,
+            ),
+            (
+                1001,
+                "function f(args) { return [2]; }",
+                HandlerState::Disabled,
+            ),
+            (
+                1002,
+                "function f(args) { return [3]; }",
+                HandlerState::Enabled,
+            ),
+        ] {
+            sqlx::query(
+                "INSERT INTO handler (owner_id, hash, code, status)
+                 VALUES ($1, $2, $3, $4)",
+            )
+            .bind(owner_id)
+            .bind(crate::util::hash_data(code))
+            .bind(code)
+            .bind(status as i32)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        }
+
+        let handlers = get_handlers_for_owner(1001, &mut tx).await.unwrap();
+
+        assert_eq!(handlers.len(), 1, "Only enabled handlers for the owner.");
+        assert_eq!(handlers[0].owner_id, 1001);
+        assert_eq!(handlers[0].code, "function f(args) { return [1]; }");
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Run with `cargo test update_code_keeps_id -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn update_code_keeps_id() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        // Rolled back when dropped, so nothing is left behind.
+        let mut tx = pool.begin().await.unwrap();
+
+        let code = "function f(args) { return [1]; }";
+        let other_code = "function f(args) { return [2]; }";
+
+        let mut ids = vec![];
+        for code in [code, other_code] {
+            let handler_id: i64 = sqlx::query_scalar(
+                "INSERT INTO handler (owner_id, hash, code, status)
+                 VALUES (0, $1, $2, $3)
+                 RETURNING handler_id",
+            )
+            .bind(crate::util::hash_data(code))
+            .bind(code)
+            .bind(HandlerState::Enabled as i32)
+            .fetch_one(&mut *tx)
+            .await
+            .unwrap();
+            ids.push(handler_id);
+        }
+        let handler_id = ids[0];
+
+        let new_code = "function f(args) { return [3]; }";
+        let new_hash = crate::util::hash_data(new_code);
+        assert!(update_code(handler_id, new_code, &new_hash, &mut tx)
+            .await
+            .unwrap());
+
+        let (code, hash): (String, String) =
+            sqlx::query_as("SELECT code, hash FROM handler WHERE handler_id = $1")
+                .bind(handler_id)
+                .fetch_one(&mut *tx)
+                .await
+                .unwrap();
+        assert_eq!(code, new_code, "Same id has the new code.");
+        assert_eq!(hash, new_hash);
+
+        assert!(
+            !update_code(-1, new_code, &new_hash, &mut tx).await.unwrap(),
+            "No such handler."
+        );
+
+        assert!(
+            update_code(
+                handler_id,
+                other_code,
+                &crate::util::hash_data(other_code),
+                &mut tx
+            )
+            .await
+            .is_err(),
+            "Can't take another handler's hash."
+        );
+    }
+
+    fn success(handler_id: i64) -> ExecutionResult {
+        ExecutionResult {
+            result_id: -1,
+            handler_id,
+            event_id: 1,
+            result: Some(String::from("{}")),
+            error: None,
+            error_kind: None,
+            assertion_id: -1,
+            created: None,
+        }
+    }
+
+    fn error(handler_id: i64) -> ExecutionResult {
+        ExecutionResult {
+            result_id: -1,
+            handler_id,
+            event_id: 1,
+            result: None,
+            error: Some(String::from("Failed")),
+            error_kind: Some(ErrorKind::ExecRuntime),
+            assertion_id: -1,
+            created: None,
+        }
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Results are committed, as they're read from the pool, then deleted.
+    /// Run with `cargo test error_results_only -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn error_results_only() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        let handler_id = -1001;
+        let mut tx = pool.begin().await.unwrap();
+        save_results(
+            &[success(handler_id), error(handler_id), success(handler_id)],
+            &mut tx,
+        )
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let errors = get_error_results(&pool, handler_id, -1, 10, false)
+            .await
+            .unwrap();
+        let all = get_all_results(&pool, handler_id, -1, 10, false)
+            .await
+            .unwrap();
+
+        sqlx::query("DELETE FROM execution_result WHERE handler_id = $1")
+            .bind(handler_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(all.len(), 3);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].error, Some(String::from("Failed")));
+        assert_eq!(errors[0].result, None);
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Stats come from the handler's counters, as results are saved.
+    /// Committed, then deleted.
+    /// Run with `cargo test result_stats_counts -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn result_stats_counts() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        let code = "function result_stats_counts(args) { return []; }";
+        let handler_id: i64 = sqlx::query_scalar(
+            "INSERT INTO handler (owner_id, hash, code, status)
+             VALUES (0, $1, $2, $3)
+             RETURNING handler_id",
+        )
+        .bind(crate::util::hash_data(code))
+        .bind(code)
+        .bind(HandlerState::Enabled as i32)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let mut timeout = error(handler_id);
+        timeout.error_kind = Some(ErrorKind::Timeout);
+
+        let mut tx = pool.begin().await.unwrap();
+        save_results(
+            &[
+                success(handler_id),
+                error(handler_id),
+                timeout,
+                success(handler_id),
+            ],
+            &mut tx,
+        )
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let stats = result_stats(&pool, handler_id).await.unwrap();
+
+        sqlx::query("DELETE FROM execution_result WHERE handler_id = $1")
+            .bind(handler_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM handler WHERE handler_id = $1")
+            .bind(handler_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            stats,
+            ResultStats {
+                total: 4,
+                success: 2,
+                error: 2,
+                timeout: 1
+            }
+        );
+
+        let unknown = result_stats(&pool, handler_id).await.unwrap();
+        assert_eq!(unknown, ResultStats::default());
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// A result the database rejects is replaced by an error, and the others are still saved.
+    /// Run with `cargo test save_results_bad_row -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn save_results_bad_row() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        let handler_id = -1003;
+
+        // Postgres text can't contain NUL.
+        let mut bad = success(handler_id);
+        bad.event_id = 2;
+        bad.result = Some(String::from("{\"value\":\"\0\"}"));
+
+        let mut tx = pool.begin().await.unwrap();
+        save_results(&[success(handler_id), bad, success(handler_id)], &mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        let all = get_all_results(&pool, handler_id, -1, 10, false)
+            .await
+            .unwrap();
+
+        sqlx::query("DELETE FROM execution_result WHERE handler_id = $1")
+            .bind(handler_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(all.len(), 3, "All rows saved, including the replacement.");
+        assert_eq!(all.iter().filter(|x| x.result.is_some()).count(), 2);
+
+        let failed: Vec<&ExecutionResult> = all.iter().filter(|x| x.error.is_some()).collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].event_id, 2);
+        assert_eq!(failed[0].error_kind, Some(ErrorKind::SaveFailed));
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Purging old results reduces the counters to match a recount of the results that are left.
+    /// Backdates its results to 1970 and purges before 1971, so use a test database.
+    /// Committed, as [purge_results] and [recount_results] read from the pool, then deleted.
+    /// Run with `cargo test purge_then_recount -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn purge_then_recount() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        let code = "function purge_then_recount(args) { return []; }";
+        let handler_id: i64 = sqlx::query_scalar(
+            "INSERT INTO handler (owner_id, hash, code, status)
+             VALUES (0, $1, $2, $3)
+             RETURNING handler_id",
+        )
+        .bind(crate::util::hash_data(code))
+        .bind(code)
+        .bind(HandlerState::Enabled as i32)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let timeout = || ExecutionResult {
+            error_kind: Some(ErrorKind::Timeout),
+            ..error(handler_id)
+        };
+
+        // Old results, to be purged.
+        let mut tx = pool.begin().await.unwrap();
+        save_results(
+            &[success(handler_id), timeout(), error(handler_id)],
+            &mut tx,
+        )
+        .await
+        .unwrap();
+        sqlx::query("UPDATE execution_result SET created = '1970-01-01' WHERE handler_id = $1")
+            .bind(handler_id)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+
+        // New results, to be kept.
+        let mut tx = pool.begin().await.unwrap();
+        save_results(
+            &[success(handler_id), timeout(), success(handler_id)],
+            &mut tx,
+        )
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let counters = move |pool: Pool<Postgres>| async move {
+            let (success, error, timeout): (i64, i64, i64) = sqlx::query_as(
+                "SELECT success_count, error_count, timeout_count FROM handler WHERE handler_id = $1",
+            )
+            .bind(handler_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+            ResultCounts {
+                success,
+                error,
+                timeout,
+            }
+        };
+
+        let before = OffsetDateTime::UNIX_EPOCH + time::Duration::days(365);
+        let purged = purge_results(&pool, before).await.unwrap();
+        let after_purge = counters(pool.clone()).await;
+        recount_results(&pool).await.unwrap();
+        let recount = counters(pool.clone()).await;
+
+        sqlx::query("DELETE FROM execution_result WHERE handler_id = $1")
+            .bind(handler_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM handler WHERE handler_id = $1")
+            .bind(handler_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(purged, 3);
+        assert_eq!(
+            after_purge,
+            ResultCounts {
+                success: 2,
+                error: 1,
+                timeout: 1
+            }
+        );
+        assert_eq!(after_purge, recount);
+    }
+
+    /// Counts are per handler, and distinguish success from error. Timeouts are also errors.
+    #[test]
+    fn count_mixed_batch() {
+        let mut timeout = error(2);
+        timeout.error_kind = Some(ErrorKind::Timeout);
+        let results = vec![success(1), error(1), success(1), timeout, success(3)];
+
+        let counts = count_results(&results);
+
+        assert_eq!(
+            counts.get(&1),
+            Some(&ResultCounts {
+                success: 2,
+                error: 1,
+                timeout: 0
+            })
+        );
+        assert_eq!(
+            counts.get(&2),
+            Some(&ResultCounts {
+                success: 0,
+                error: 1,
+                timeout: 1
+            })
+        );
+        assert_eq!(
+            counts.get(&3),
+            Some(&ResultCounts {
+                success: 1,
+                error: 0,
+                timeout: 0
+            })
+        );
+        assert_eq!(
+            counts.get(&4),
+            None,
+            "No entry for handlers without results."
+        );
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Incrementing the counters across batches gives the same totals as recounting the stored results.
+    /// Committed, as [recount_results] reads from the pool, then deleted.
+    /// Run with `cargo test count_incremental_matches_recount -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn count_incremental_matches_recount() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        let code = "function count_incremental_matches_recount(args) { return []; }";
+        let handler_id: i64 = sqlx::query_scalar(
+            "INSERT INTO handler (owner_id, hash, code, status)
+             VALUES (0, $1, $2, $3)
+             RETURNING handler_id",
+        )
+        .bind(crate::util::hash_data(code))
+        .bind(code)
+        .bind(HandlerState::Enabled as i32)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let mut timeout = error(handler_id);
+        timeout.error_kind = Some(ErrorKind::Timeout);
+
+        let batches = [
+            vec![success(handler_id), error(handler_id), success(handler_id)],
+            vec![error(handler_id), timeout, error(handler_id)],
+            vec![],
+            vec![success(handler_id)],
+        ];
+
+        for batch in batches.iter() {
+            let mut tx = pool.begin().await.unwrap();
+            save_results(batch, &mut tx).await.unwrap();
+            tx.commit().await.unwrap();
+        }
+
+        let counters = move |pool: Pool<Postgres>| async move {
+            let (success, error, timeout): (i64, i64, i64) = sqlx::query_as(
+                "SELECT success_count, error_count, timeout_count FROM handler WHERE handler_id = $1",
+            )
+            .bind(handler_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+            ResultCounts {
+                success,
+                error,
+                timeout,
+            }
+        };
+
+        let incremental = counters(pool.clone()).await;
+        recount_results(&pool).await.unwrap();
+        let recount = counters(pool.clone()).await;
+
+        sqlx::query("DELETE FROM execution_result WHERE handler_id = $1")
+            .bind(handler_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM handler WHERE handler_id = $1")
+            .bind(handler_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            incremental,
+            ResultCounts {
+                success: 3,
+                error: 4,
+                timeout: 1
+            }
+        );
+        assert_eq!(incremental, recount);
+    }
+}
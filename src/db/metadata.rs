@@ -1,6 +1,7 @@
 use super::source::MetadataSourceId;
 use scholarly_identifiers::identifiers::Identifier;
 use sqlx::{prelude::FromRow, Pool, Postgres, Transaction};
+use time::OffsetDateTime;
 
 /// Reason for making a metadata assertion.
 /// Leaving space for a 'secondary' reason, which is metadata fetched in connection with a primary assertion.
@@ -13,8 +14,11 @@ pub(crate) enum MetadataAssertionReason {
     Secondary = 2,
 }
 
-/// Insert a metadata assertion.
-/// If there's a hash-based duplicate, ignore it.
+/// Insert a metadata assertion, as the next version for the subject from the source.
+/// If there's a hash-based duplicate, don't insert it. If that duplicate isn't the latest version,
+/// i.e. the metadata changed back, give it the next version and date so it becomes the latest again.
+/// The queue trigger only fires for new rows, so a primary duplicate that becomes the latest again
+/// is queued here, to have its Events extracted again.
 pub(crate) async fn insert_metadata_assertion<'a>(
     json: &str,
     source: MetadataSourceId,
@@ -23,21 +27,44 @@ pub(crate) async fn insert_metadata_assertion<'a>(
     reason: MetadataAssertionReason,
     tx: &mut Transaction<'a, Postgres>,
 ) -> Result<(), sqlx::Error> {
-    sqlx::query(
+    // Concurrent inserts for the same subject and source would otherwise compute the same next version,
+    // and one would fail on the unique version. Held until the transaction ends.
+    // The two-key form is a separate key space from the execution lock's single key.
+    sqlx::query("SELECT pg_advisory_xact_lock($1, ($2 % 2147483647)::INT);")
+        .bind(source as i32)
+        .bind(subject_entity_id)
+        .execute(&mut **tx)
+        .await?;
+
+    // A row from the update path has xmax set, a newly inserted one doesn't.
+    let upserted: Option<(i64, bool)> = sqlx::query_as(
         "INSERT INTO metadata_assertion
-         (json, source_id, subject_entity_id, hash, reason)
-        VALUES ($1, $2, $3, $4, $5)
+         (json, source_id, subject_entity_id, hash, reason, version)
+        VALUES ($1, $2, $3, $4, $5,
+            COALESCE(
+                (SELECT MAX(version) FROM metadata_assertion
+                 WHERE subject_entity_id = $3 AND source_id = $2),
+                0) + 1)
         ON CONFLICT (subject_entity_id, hash, source_id)
-        DO NOTHING;",
+        DO UPDATE SET version = EXCLUDED.version, created = NOW()
+        WHERE EXCLUDED.version > metadata_assertion.version + 1
+        RETURNING assertion_id, xmax <> 0 AS updated;",
     )
     .bind(json)
     .bind(source as i32)
     .bind(subject_entity_id)
     .bind(hash)
     .bind(reason as i16)
-    .execute(&mut **tx)
+    .fetch_optional(&mut **tx)
     .await?;
 
+    if let (Some((assertion_id, true)), MetadataAssertionReason::Primary) = (upserted, reason) {
+        sqlx::query("INSERT INTO metadata_assertion_queue (assertion_id) VALUES ($1);")
+            .bind(assertion_id)
+            .execute(&mut **tx)
+            .await?;
+    }
+
     Ok(())
 }
 
@@ -49,6 +76,9 @@ pub(crate) struct MetadataQueueEntry {
     pub(crate) subject_id_type: i32,
     pub(crate) subject_id_value: String,
     pub(crate) assertion_id: i64,
+
+    /// A later version of the subject's metadata from the same source exists.
+    pub(crate) superseded: bool,
 }
 
 impl MetadataQueueEntry {
@@ -62,6 +92,7 @@ impl MetadataQueueEntry {
 /// Poll from metadata_assertion_queue in a transaction. Uses SKIP LOCKED to avoid
 /// deadlocking with other executions. Rows are locked until the transaction is
 /// committed or aborted.
+/// Superseded assertions are returned, and marked, so they can be skipped.
 pub(crate) async fn poll_assertions<'a>(
     limit: i32,
     tx: &mut Transaction<'a, Postgres>,
@@ -75,7 +106,13 @@ pub(crate) async fn poll_assertions<'a>(
                     metadata_assertion.json as json,
                     metadata_assertion.assertion_id as assertion_id,
                     subject.identifier_type as subject_id_type,
-                    subject.identifier as subject_id_value
+                    subject.identifier as subject_id_value,
+                    EXISTS (
+                        SELECT 1 FROM metadata_assertion AS later
+                        WHERE later.subject_entity_id = metadata_assertion.subject_entity_id
+                        AND later.source_id = metadata_assertion.source_id
+                        AND later.version > metadata_assertion.version
+                    ) as superseded
                 FROM metadata_assertion_queue
                 JOIN metadata_assertion
                 ON metadata_assertion_queue.assertion_id = metadata_assertion.assertion_id
@@ -96,18 +133,390 @@ pub(crate) async fn poll_assertions<'a>(
     Ok(rows)
 }
 
+/// Entity waiting in the metadata fetch queue.
+#[derive(FromRow, Debug)]
+pub(crate) struct MetadataFetchEntry {
+    pub(crate) entity_id: i64,
+    pub(crate) identifier_type: i32,
+    pub(crate) identifier: String,
+}
+
+impl MetadataFetchEntry {
+    pub(crate) fn identifier(&self) -> Option<Identifier> {
+        Identifier::from_id_string_pair(&self.identifier, self.identifier_type as u32)
+    }
+}
+
+/// Queue an entity to have its metadata fetched by a worker, rather than during extraction.
+/// An entity that's already queued isn't queued again.
+pub(crate) async fn enqueue_fetch<'a>(
+    entity_id: i64,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO metadata_fetch_queue (entity_id)
+        VALUES ($1)
+        ON CONFLICT (entity_id) DO NOTHING;",
+    )
+    .bind(entity_id)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Poll from metadata_fetch_queue in a transaction, oldest first. Uses SKIP LOCKED so that
+/// workers don't fetch the same entity. Entries are removed when the transaction is committed.
+pub(crate) async fn poll_fetch_queue<'a>(
+    limit: i32,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<Vec<MetadataFetchEntry>, sqlx::Error> {
+    sqlx::query_as(
+        "WITH
+            queued AS (
+                SELECT
+                    metadata_fetch_queue.queue_id AS queue_id,
+                    entity.entity_id AS entity_id,
+                    entity.identifier_type AS identifier_type,
+                    entity.identifier AS identifier
+                FROM metadata_fetch_queue
+                JOIN entity ON entity.entity_id = metadata_fetch_queue.entity_id
+                ORDER BY metadata_fetch_queue.queue_id ASC
+                FOR UPDATE OF metadata_fetch_queue SKIP LOCKED
+                LIMIT $1),
+            deleted AS (
+                DELETE FROM metadata_fetch_queue
+                WHERE queue_id IN (SELECT queue_id FROM queued))
+        SELECT entity_id, identifier_type, identifier FROM queued;",
+    )
+    .bind(limit)
+    .fetch_all(&mut **tx)
+    .await
+}
+
+/// Get a single Metadata Assertion by id, in the same form as entries from the queue.
+/// Doesn't touch the queue.
+pub(crate) async fn get_assertion_by_id(
+    assertion_id: i64,
+    pool: &Pool<Postgres>,
+) -> Result<Option<MetadataQueueEntry>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT
+            metadata_assertion.source_id as source_id,
+            metadata_assertion.json as json,
+            metadata_assertion.assertion_id as assertion_id,
+            subject.identifier_type as subject_id_type,
+            subject.identifier as subject_id_value,
+            EXISTS (
+                SELECT 1 FROM metadata_assertion AS later
+                WHERE later.subject_entity_id = metadata_assertion.subject_entity_id
+                AND later.source_id = metadata_assertion.source_id
+                AND later.version > metadata_assertion.version
+            ) as superseded
+        FROM metadata_assertion
+        JOIN entity AS subject ON subject.entity_id = metadata_assertion.subject_entity_id
+        WHERE metadata_assertion.assertion_id = $1;",
+    )
+    .bind(assertion_id)
+    .fetch_optional(pool)
+    .await
+}
+
 /// Is there a metadata assertion for this entity?
 pub(crate) async fn has_metadata_assertion(entity_id: i64, pool: &Pool<Postgres>) -> bool {
-    match sqlx::query(
-        "SELECT subject_entity_id
+    matches!(
+        sqlx::query(
+            "SELECT subject_entity_id
+            FROM metadata_assertion
+            WHERE subject_entity_id = $1;",
+        )
+        .bind(entity_id)
+        .fetch_optional(pool)
+        .await,
+        Ok(Some(_))
+    )
+}
+
+/// Latest version of the metadata about the entity from the source.
+pub(crate) async fn latest_for_subject(
+    entity_id: i64,
+    source: MetadataSourceId,
+    pool: &Pool<Postgres>,
+) -> Result<Option<MetadataAssertion>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT assertion_id, source_id, json, created, version
+        FROM metadata_assertion
+        WHERE subject_entity_id = $1 AND source_id = $2
+        ORDER BY version DESC
+        LIMIT 1;",
+    )
+    .bind(entity_id)
+    .bind(source as i32)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Stored metadata assertion.
+#[derive(FromRow, Debug)]
+pub(crate) struct MetadataAssertion {
+    pub(crate) assertion_id: i64,
+    pub(crate) source_id: i32,
+    pub(crate) json: String,
+    pub(crate) created: OffsetDateTime,
+    pub(crate) version: i32,
+}
+
+/// Most recent metadata assertion about the entity, from any source.
+pub(crate) async fn latest_assertion(
+    entity_id: i64,
+    pool: &Pool<Postgres>,
+) -> Result<Option<MetadataAssertion>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT assertion_id, source_id, json, created, version
         FROM metadata_assertion
-        WHERE subject_entity_id = $1;",
+        WHERE subject_entity_id = $1
+        ORDER BY created DESC, version DESC
+        LIMIT 1;",
     )
     .bind(entity_id)
     .fetch_optional(pool)
     .await
-    {
-        Ok(Some(_)) => true,
-        _ => false,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use crate::db::pool::get_pool;
+
+    use super::*;
+
+    /// Number of times the assertion is on the metadata assertion queue.
+    async fn times_queued(assertion_id: i64, tx: &mut Transaction<'_, Postgres>) -> i64 {
+        sqlx::query_scalar("SELECT COUNT(*) FROM metadata_assertion_queue WHERE assertion_id = $1;")
+            .bind(assertion_id)
+            .fetch_one(&mut **tx)
+            .await
+            .unwrap()
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Metadata that changes back becomes the latest version again, and is queued again.
+    /// An exact duplicate of the latest isn't.
+    /// Run with `cargo test reverted_duplicate_requeued -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn reverted_duplicate_requeued() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        // Rolled back when dropped, so nothing is left behind.
+        let mut tx = pool.begin().await.unwrap();
+
+        let entity_id: i64 = sqlx::query_scalar(
+            "INSERT INTO entity (identifier_type, identifier)
+            VALUES (1, '10.5555/reverted-duplicate-test')
+            RETURNING entity_id;",
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+
+        for (json, hash) in [("{\"v\":1}", "hash-1"), ("{\"v\":2}", "hash-2")] {
+            insert_metadata_assertion(
+                json,
+                MetadataSourceId::Crossref,
+                entity_id,
+                hash,
+                MetadataAssertionReason::Primary,
+                &mut tx,
+            )
+            .await
+            .unwrap();
+        }
+
+        let (first_id, first_version): (i64, i32) = sqlx::query_as(
+            "SELECT assertion_id, version FROM metadata_assertion
+            WHERE subject_entity_id = $1 AND hash = 'hash-1';",
+        )
+        .bind(entity_id)
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+        assert_eq!(first_version, 1);
+
+        // As if extraction had taken it off the queue.
+        sqlx::query("DELETE FROM metadata_assertion_queue WHERE assertion_id = $1;")
+            .bind(first_id)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+
+        // Changed back.
+        insert_metadata_assertion(
+            "{\"v\":1}",
+            MetadataSourceId::Crossref,
+            entity_id,
+            "hash-1",
+            MetadataAssertionReason::Primary,
+            &mut tx,
+        )
+        .await
+        .unwrap();
+
+        let version: i32 =
+            sqlx::query_scalar("SELECT version FROM metadata_assertion WHERE assertion_id = $1;")
+                .bind(first_id)
+                .fetch_one(&mut *tx)
+                .await
+                .unwrap();
+        assert_eq!(version, 3, "Latest version again.");
+        assert_eq!(times_queued(first_id, &mut tx).await, 1, "Queued again.");
+
+        // Already the latest, so nothing changes.
+        insert_metadata_assertion(
+            "{\"v\":1}",
+            MetadataSourceId::Crossref,
+            entity_id,
+            "hash-1",
+            MetadataAssertionReason::Primary,
+            &mut tx,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            times_queued(first_id, &mut tx).await,
+            1,
+            "Not queued twice."
+        );
+
+        // Versions are unique per subject and source.
+        let duplicate_version = sqlx::query(
+            "INSERT INTO metadata_assertion (json, source_id, subject_entity_id, hash, reason, version)
+            VALUES ('{}', $1, $2, 'hash-3', 1, 3);",
+        )
+        .bind(MetadataSourceId::Crossref as i32)
+        .bind(entity_id)
+        .execute(&mut *tx)
+        .await;
+        assert!(duplicate_version.is_err());
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Different metadata for the same subject inserted in overlapping transactions gets consecutive versions,
+    /// rather than one failing on a duplicate version.
+    /// Committed, as the transactions need to overlap, then deleted.
+    /// Run with `cargo test concurrent_versions_unique -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn concurrent_versions_unique() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        let entity_id: i64 = sqlx::query_scalar(
+            "INSERT INTO entity (identifier_type, identifier)
+            VALUES (1, $1)
+            RETURNING entity_id;",
+        )
+        .bind(format!(
+            "10.5555/concurrent-versions-{}",
+            OffsetDateTime::now_utc().unix_timestamp_nanos()
+        ))
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let insert = |hash: &'static str| {
+            let pool = pool.clone();
+            async move {
+                let mut tx = pool.begin().await.unwrap();
+                let inserted = insert_metadata_assertion(
+                    "{}",
+                    MetadataSourceId::Crossref,
+                    entity_id,
+                    hash,
+                    MetadataAssertionReason::Primary,
+                    &mut tx,
+                )
+                .await;
+
+                // Hold the transaction a while, so the two overlap.
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                tx.commit().await.unwrap();
+                inserted
+            }
+        };
+
+        let (first, second) = tokio::join!(insert("hash-1"), insert("hash-2"));
+
+        let mut versions: Vec<i32> = sqlx::query_scalar(
+            "SELECT version FROM metadata_assertion WHERE subject_entity_id = $1;",
+        )
+        .bind(entity_id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        versions.sort();
+
+        sqlx::query(
+            "DELETE FROM metadata_assertion_queue WHERE assertion_id IN
+            (SELECT assertion_id FROM metadata_assertion WHERE subject_entity_id = $1);",
+        )
+        .bind(entity_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("DELETE FROM metadata_assertion WHERE subject_entity_id = $1;")
+            .bind(entity_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM entity WHERE entity_id = $1;")
+            .bind(entity_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(first.is_ok(), "{:?}", first);
+        assert!(second.is_ok(), "{:?}", second);
+        assert_eq!(versions, vec![1, 2]);
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// An entity is only queued once, and polling takes it off the queue.
+    /// Run with `cargo test fetch_queue_once -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn fetch_queue_once() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        // Rolled back when dropped, so nothing is left behind.
+        let mut tx = pool.begin().await.unwrap();
+
+        let entity_id: i64 = sqlx::query_scalar(
+            "INSERT INTO entity (identifier_type, identifier)
+            VALUES (1, '10.5555/fetch-queue-test')
+            RETURNING entity_id;",
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+
+        enqueue_fetch(entity_id, &mut tx).await.unwrap();
+        enqueue_fetch(entity_id, &mut tx).await.unwrap();
+
+        let polled: Vec<MetadataFetchEntry> = poll_fetch_queue(100, &mut tx)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|x| x.entity_id == entity_id)
+            .collect();
+
+        assert_eq!(polled.len(), 1, "Queued once.");
+        assert_eq!(polled[0].identifier, "10.5555/fetch-queue-test");
+
+        let again = poll_fetch_queue(100, &mut tx).await.unwrap();
+        assert!(
+            !again.iter().any(|x| x.entity_id == entity_id),
+            "Taken off the queue."
+        );
     }
 }
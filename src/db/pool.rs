@@ -1,19 +1,89 @@
 //! Database pool.
 
+use serde::Serialize;
 use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
 use std::time::Duration;
 
+use crate::util::env_or_default;
+
+/// Default maximum number of connections in the pool.
+const DEFAULT_MAX_CONNECTIONS: u32 = 50;
+
+/// Default idle timeout. Allow for long transactions for bulk ingestion.
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 60 * 60;
+
+/// Database connection string, from the file at `uri_file` if given, otherwise `uri`.
+/// Reading it from a file, e.g. a mounted secret, keeps the password out of the process environment.
+/// Surrounding whitespace and newlines in the file are ignored.
+pub(crate) fn db_uri(uri: Option<String>, uri_file: Option<String>) -> Result<String, String> {
+    match (uri_file, uri) {
+        (Some(path), _) => match std::fs::read_to_string(&path) {
+            Ok(content) if !content.trim().is_empty() => Ok(String::from(content.trim())),
+            Ok(_) => Err(format!("DB_URI_FILE {} is empty", path)),
+            Err(e) => Err(format!("Can't read DB_URI_FILE {}: {}", path, e)),
+        },
+        (None, Some(uri)) => Ok(uri),
+        (None, None) => Err(String::from("Neither DB_URI nor DB_URI_FILE supplied")),
+    }
+}
+
+/// Connect a pool. Size and idle timeout are configured from the DB_MAX_CONNECTIONS and DB_IDLE_TIMEOUT_SECS environment variables.
 pub(crate) async fn get_pool(uri: String) -> Result<Pool<Postgres>, sqlx::Error> {
+    let max_connections = env_or_default("DB_MAX_CONNECTIONS", DEFAULT_MAX_CONNECTIONS);
+    let idle_timeout_secs = env_or_default("DB_IDLE_TIMEOUT_SECS", DEFAULT_IDLE_TIMEOUT_SECS);
+
+    log::info!(
+        "Database pool max connections: {}, idle timeout: {}s",
+        max_connections,
+        idle_timeout_secs
+    );
+
     let pool: Pool<Postgres> = PgPoolOptions::new()
-        .max_connections(50)
-        // Allow for long transactions for bulk ingestion
-        .idle_timeout(Duration::from_secs(60 * 60))
+        .max_connections(max_connections)
+        .idle_timeout(Duration::from_secs(idle_timeout_secs))
         .connect(&uri)
         .await?;
 
     Ok(pool)
 }
 
+/// Snapshot of connection pool usage.
+#[derive(Debug, Serialize)]
+pub(crate) struct PoolStats {
+    /// Number of open connections.
+    pub(crate) size: u32,
+
+    /// Number of open connections that are idle.
+    pub(crate) idle: usize,
+
+    /// Number of open connections in use.
+    pub(crate) in_use: u32,
+
+    /// Most connections the pool will open.
+    pub(crate) max_connections: u32,
+
+    /// All connections are open and in use, so new requests will wait.
+    pub(crate) saturated: bool,
+}
+
+/// Current usage of the connection pool.
+pub(crate) fn stats(pool: &Pool<Postgres>) -> PoolStats {
+    let size = pool.size();
+    let idle = pool.num_idle();
+    let max_connections = pool.options().get_max_connections();
+
+    // Connections can be acquired between the two reads, so don't underflow.
+    let in_use = size.saturating_sub(idle as u32);
+
+    PoolStats {
+        size,
+        idle,
+        in_use,
+        max_connections,
+        saturated: in_use >= max_connections,
+    }
+}
+
 pub(crate) async fn close_pool(pool: &Pool<Postgres>) {
     pool.close().await
 }
@@ -23,3 +93,41 @@ pub(crate) async fn heartbeat(pool: &sqlx::Pool<sqlx::Postgres>) -> Result<bool,
     let result: i32 = sqlx::query_scalar("SELECT 1;").fetch_one(pool).await?;
     Ok(result == 1)
 }
+
+/// Send a notification on the given channel.
+pub(crate) async fn notify(channel: &str, pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    sqlx::query("SELECT pg_notify($1, '');")
+        .bind(channel)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The file is preferred over the variable, and its trailing newline is dropped.
+    #[test]
+    fn db_uri_sources() {
+        let path = std::env::temp_dir().join(format!("metabeak-db-uri-{}", std::process::id()));
+        std::fs::write(&path, "postgres://from-file/metabeak\n").unwrap();
+        let path = String::from(path.to_str().unwrap());
+
+        assert_eq!(
+            db_uri(
+                Some(String::from("postgres://from-env/metabeak")),
+                Some(path.clone())
+            ),
+            Ok(String::from("postgres://from-file/metabeak"))
+        );
+        assert_eq!(
+            db_uri(Some(String::from("postgres://from-env/metabeak")), None),
+            Ok(String::from("postgres://from-env/metabeak"))
+        );
+        assert!(db_uri(None, None).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(db_uri(None, Some(path)).is_err());
+    }
+}
@@ -11,6 +11,9 @@ pub(crate) enum MetadataSourceId {
 
     /// Retrieved from the relevant RA by content negotiation. This might be Crossref, DataCite or others.
     ContentNegotiation = 3,
+
+    /// Direct from DataCite
+    Datacite = 4,
 }
 
 impl MetadataSourceId {
@@ -19,6 +22,7 @@ impl MetadataSourceId {
             "crossref" => MetadataSourceId::Crossref,
             "test" => MetadataSourceId::Test,
             "content-negotiation" => MetadataSourceId::ContentNegotiation,
+            "datacite" => MetadataSourceId::Datacite,
             _ => MetadataSourceId::Unknown,
         }
     }
@@ -28,14 +32,25 @@ impl MetadataSourceId {
             2 => MetadataSourceId::Crossref,
             1 => MetadataSourceId::Test,
             3 => MetadataSourceId::ContentNegotiation,
+            4 => MetadataSourceId::Datacite,
             _ => MetadataSourceId::Unknown,
         }
     }
 
+    /// All known sources, in order of their ids. Excludes Unknown.
+    /// Ids are contiguous, so this stays in sync with [MetadataSourceId::from_int_value].
+    pub(crate) fn all() -> Vec<MetadataSourceId> {
+        (1..)
+            .map(MetadataSourceId::from_int_value)
+            .take_while(|x| *x != MetadataSourceId::Unknown)
+            .collect()
+    }
+
     pub(crate) fn to_str_value(self) -> String {
         String::from(match self {
             MetadataSourceId::Crossref => "crossref",
             MetadataSourceId::ContentNegotiation => "content-negotiation",
+            MetadataSourceId::Datacite => "datacite",
             MetadataSourceId::Test => "test",
             _ => "UNKNOWN",
         })
@@ -48,7 +63,7 @@ mod metadata_source_tests {
 
     #[test]
     fn roundtrip_metadatasource() {
-        let inputs = ["crossref", "test", "content-negotiation"];
+        let inputs = ["crossref", "test", "content-negotiation", "datacite"];
         for input in inputs.iter() {
             let from_str = MetadataSourceId::from_str_value(input);
             let as_str = from_str.to_str_value();
@@ -60,6 +75,19 @@ mod metadata_source_tests {
         }
     }
 
+    #[test]
+    fn all_metadatasources() {
+        assert_eq!(
+            MetadataSourceId::all(),
+            vec![
+                MetadataSourceId::Test,
+                MetadataSourceId::Crossref,
+                MetadataSourceId::ContentNegotiation,
+                MetadataSourceId::Datacite
+            ]
+        );
+    }
+
     /// To cope with foreign keys shifting, or weird inputs, represent unknown values rather than fail.
     #[test]
     fn always_returns() {
@@ -115,6 +143,15 @@ impl EventAnalyzerId {
         }
     }
 
+    /// All known analyzers, in order of their ids. Excludes Unknown.
+    /// Ids are contiguous, so this stays in sync with [EventAnalyzerId::from_int_value].
+    pub(crate) fn all() -> Vec<EventAnalyzerId> {
+        (1..)
+            .map(EventAnalyzerId::from_int_value)
+            .take_while(|x| *x != EventAnalyzerId::Unknown)
+            .collect()
+    }
+
     pub(crate) fn to_str_value(self) -> String {
         String::from(match self {
             EventAnalyzerId::Lifecycle => "lifecycle",
@@ -127,6 +164,20 @@ impl EventAnalyzerId {
         })
     }
 
+    /// Parse a comma-separated list of analyzer names, e.g. "reference,lifecycle".
+    /// Unknown names are an error.
+    pub(crate) fn parse_list(value: &str) -> Result<Vec<EventAnalyzerId>, String> {
+        value
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| match EventAnalyzerId::from_str_value(x) {
+                EventAnalyzerId::Unknown => Err(format!("Unknown analyzer '{}'", x)),
+                analyzer => Ok(analyzer),
+            })
+            .collect()
+    }
+
     pub(crate) fn from_int_value(value: i32) -> EventAnalyzerId {
         match value {
             2 => EventAnalyzerId::Lifecycle,
@@ -166,6 +217,19 @@ mod event_analyzer_id_tests {
         }
     }
 
+    /// Every analyzer, each of which round-trips through its string value.
+    #[test]
+    fn all_event_analyzer_ids() {
+        let all = EventAnalyzerId::all();
+        assert_eq!(all.len(), 6);
+        for analyzer in all {
+            assert_eq!(
+                EventAnalyzerId::from_str_value(&analyzer.to_str_value()),
+                analyzer
+            );
+        }
+    }
+
     /// To cope with foreign keys shifting, or weird inputs, represent unknown values rather than fail.
     #[test]
     fn always_returns() {
@@ -183,4 +247,17 @@ mod event_analyzer_id_tests {
             "Unknown string values return an 'unknown' value."
         );
     }
+
+    #[test]
+    fn parse_analyzer_list() {
+        assert_eq!(
+            EventAnalyzerId::parse_list("reference,lifecycle"),
+            Ok(vec![EventAnalyzerId::Reference, EventAnalyzerId::Lifecycle])
+        );
+        assert_eq!(
+            EventAnalyzerId::parse_list(" contribution , "),
+            Ok(vec![EventAnalyzerId::Contribution])
+        );
+        assert!(EventAnalyzerId::parse_list("reference,citations").is_err());
+    }
 }
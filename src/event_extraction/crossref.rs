@@ -2,26 +2,87 @@ use scholarly_identifiers::identifiers::Identifier;
 
 use crate::db::metadata::MetadataQueueEntry;
 use crate::db::source::{EventAnalyzerId, MetadataSourceId};
-use crate::execution::model::Event;
+use crate::execution::model::{arxiv_identifier, Event, SUBJECT_TYPE_FIELD};
+use crate::util::normalize_isbn;
 
+/// Version of the Crossref extractor, recorded in each Event's JSON.
+/// Increment when a change means previously extracted Events should be extracted again.
+pub(crate) const EXTRACTOR_VERSION: &str = "crossref@5";
+
+/// Extract Events from a Crossref Metadata Assertion.
+/// Only call this for assertions from Crossref, see the dispatch in the extraction service.
+/// If `analyzers` is given, only run the extractors for those analyzers.
 pub(crate) fn extract_events(
     assertion: &MetadataQueueEntry,
     maybe_json: Option<serde_json::Value>,
+    analyzers: Option<&[EventAnalyzerId]>,
 ) -> Vec<Event> {
     let mut results = vec![];
 
-    if assertion.source_id == MetadataSourceId::Crossref as i32 {
-        if let Some(json) = maybe_json {
+    let wanted = |analyzer: EventAnalyzerId| analyzers.is_none_or(|x| x.contains(&analyzer));
+
+    // Data quality Events are only extracted when the analyzer is asked for by name.
+    let wanted_explicitly =
+        |analyzer: EventAnalyzerId| analyzers.is_some_and(|x| x.contains(&analyzer));
+
+    if let Some(json) = maybe_json {
+        if wanted(EventAnalyzerId::Lifecycle) {
             lifecycle(&mut results, assertion);
+            has_abstract(&json, &mut results, assertion);
+            license(&json, &mut results, assertion);
+            preprint_links(&json, &mut results, assertion);
+        }
+        if wanted_explicitly(EventAnalyzerId::Lifecycle) {
+            invalid_orcid(&json, &mut results, assertion);
+        }
+        if wanted(EventAnalyzerId::Contribution) {
             orcid(&json, &mut results, assertion);
+        }
+        if wanted(EventAnalyzerId::Organizations) {
             author_ror(&json, &mut results, assertion);
+        }
+        if wanted(EventAnalyzerId::Identifier) {
             isbn(&json, &mut results, assertion);
+            clinical_trial(&json, &mut results, assertion);
+            alternative_id(&json, &mut results, assertion);
+        }
+        if wanted(EventAnalyzerId::Reference) {
             references(&json, &mut results, assertion);
+            part_of(&json, &mut results, assertion);
+            arxiv_relations(&json, &mut results, assertion);
+        }
+
+        if let Some(subject_type) = json.get("type").and_then(|x| x.as_str()) {
+            for event in results.iter_mut() {
+                add_subject_type(event, subject_type);
+            }
         }
     }
     results
 }
 
+/// Add the work type of the subject, e.g. "journal-article", to the Event's JSON, so handlers can filter on it.
+/// It's appended, rather than re-serializing, so the other fields keep their order.
+fn add_subject_type(event: &mut Event, subject_type: &str) {
+    let Some(fields) = event
+        .json
+        .trim_end()
+        .strip_suffix('}')
+        .filter(|x| x.starts_with('{'))
+    else {
+        return;
+    };
+
+    let separator = if fields.trim_end() == "{" { "" } else { "," };
+    event.json = format!(
+        "{}{}{}:{}}}",
+        fields,
+        separator,
+        serde_json::Value::from(SUBJECT_TYPE_FIELD),
+        serde_json::Value::from(subject_type)
+    );
+}
+
 fn lifecycle(results: &mut Vec<Event>, assertion: &MetadataQueueEntry) {
     results.push(Event {
         event_id: -1,
@@ -31,11 +92,86 @@ fn lifecycle(results: &mut Vec<Event>, assertion: &MetadataQueueEntry) {
         source: MetadataSourceId::from_int_value(assertion.source_id),
         assertion_id: assertion.assertion_id,
         json: serde_json::json!({"type": "indexed"}).to_string(),
+        occurred_at: None,
     });
 }
 
+/// Records with an abstract, for tracking metadata completeness.
+fn has_abstract(
+    json: &serde_json::Value,
+    results: &mut Vec<Event>,
+    assertion: &MetadataQueueEntry,
+) {
+    let present = json
+        .get("abstract")
+        .and_then(|x| x.as_str())
+        .is_some_and(|x| !x.trim().is_empty());
+
+    if present {
+        results.push(Event {
+            event_id: -1,
+            analyzer: EventAnalyzerId::Lifecycle,
+            subject_id: Some(assertion.subject_id()),
+            object_id: None,
+            source: MetadataSourceId::from_int_value(assertion.source_id),
+            assertion_id: assertion.assertion_id,
+            json: serde_json::json!({"type": "has-abstract"}).to_string(),
+            occurred_at: None,
+        });
+    }
+}
+
+/// Format a Crossref date, e.g. `{"date-parts": [[2021, 1, 1]]}`, as far as it's given, e.g. "2021-01-01" or "2021-01".
+fn date_parts_string(date: &serde_json::Value) -> Option<String> {
+    let parts: Vec<i64> = date
+        .get("date-parts")
+        .and_then(|x| x.as_array())
+        .and_then(|x| x.first())
+        .and_then(|x| x.as_array())?
+        .iter()
+        .map_while(|x| x.as_i64())
+        .collect();
+
+    match parts.as_slice() {
+        [year] => Some(format!("{:04}", year)),
+        [year, month] => Some(format!("{:04}-{:02}", year, month)),
+        [year, month, day, ..] => Some(format!("{:04}-{:02}-{:02}", year, month, day)),
+        [] => None,
+    }
+}
+
+/// Licenses of the work, one Event per license with its URL, the version of the content it applies to, and its start date.
+/// Licenses without a URL are skipped.
+fn license(json: &serde_json::Value, results: &mut Vec<Event>, assertion: &MetadataQueueEntry) {
+    if let Some(licenses) = json.get("license").and_then(|x| x.as_array()) {
+        for license in licenses {
+            if let Some(url) = license.get("URL").and_then(|x| x.as_str()) {
+                let content_version = license.get("content-version").and_then(|x| x.as_str());
+                let start = license.get("start").and_then(date_parts_string);
+
+                results.push(Event {
+                    event_id: -1,
+                    analyzer: EventAnalyzerId::Lifecycle,
+                    subject_id: Some(assertion.subject_id()),
+                    object_id: None,
+                    source: MetadataSourceId::from_int_value(assertion.source_id),
+                    assertion_id: assertion.assertion_id,
+                    json: serde_json::json!({
+                        "type": "license",
+                        "url": url,
+                        "content-version": content_version,
+                        "start": start
+                    })
+                    .to_string(),
+                    occurred_at: None,
+                });
+            }
+        }
+    }
+}
+
 fn get_orcid_from_author(author_json: &serde_json::Value) -> Option<Identifier> {
-    if let Some(orcid) = author_json.get("ORCID").map(|x| x.as_str()).flatten() {
+    if let Some(orcid) = author_json.get("ORCID").and_then(|x| x.as_str()) {
         return Some(Identifier::parse(orcid));
     }
 
@@ -43,7 +179,7 @@ fn get_orcid_from_author(author_json: &serde_json::Value) -> Option<Identifier>
 }
 
 fn orcid(json: &serde_json::Value, results: &mut Vec<Event>, assertion: &MetadataQueueEntry) {
-    if let Some(authors) = json.get("author").map(|x| x.as_array()).flatten() {
+    if let Some(authors) = json.get("author").and_then(|x| x.as_array()) {
         for author in authors {
             if let Some(orcid) = get_orcid_from_author(author) {
                 results.push(Event {
@@ -54,25 +190,66 @@ fn orcid(json: &serde_json::Value, results: &mut Vec<Event>, assertion: &Metadat
                     source: MetadataSourceId::from_int_value(assertion.source_id),
                     assertion_id: assertion.assertion_id,
                     json: serde_json::json!({"type":"author"}).to_string(),
+                    occurred_at: None,
                 });
             }
         }
     }
 }
 
+/// Is this a URL on orcid.org? Valid ORCIDs are parsed as such, so a URI means it's malformed.
+fn is_orcid_url(value: &str) -> bool {
+    let lower = value.trim().to_lowercase();
+    let without_scheme = lower
+        .strip_prefix("https://")
+        .or(lower.strip_prefix("http://"))
+        .unwrap_or(&lower);
+
+    without_scheme.starts_with("orcid.org/") || without_scheme.starts_with("www.orcid.org/")
+}
+
+/// Author ORCIDs that don't validate, e.g. with a bad checksum, as a data quality signal.
+fn invalid_orcid(
+    json: &serde_json::Value,
+    results: &mut Vec<Event>,
+    assertion: &MetadataQueueEntry,
+) {
+    if let Some(authors) = json.get("author").and_then(|x| x.as_array()) {
+        for author in authors {
+            if let Some(raw) = author.get("ORCID").and_then(|x| x.as_str()) {
+                if let Identifier::Uri(_) = Identifier::parse(raw) {
+                    if is_orcid_url(raw) {
+                        results.push(Event {
+                            event_id: -1,
+                            analyzer: EventAnalyzerId::Lifecycle,
+                            subject_id: Some(assertion.subject_id()),
+                            object_id: None,
+                            source: MetadataSourceId::from_int_value(assertion.source_id),
+                            assertion_id: assertion.assertion_id,
+                            json: serde_json::json!({"type": "invalid-orcid", "raw": raw})
+                                .to_string(),
+                            occurred_at: None,
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
 fn author_ror(json: &serde_json::Value, results: &mut Vec<Event>, assertion: &MetadataQueueEntry) {
-    if let Some(authors) = json.get("author").map(|x| x.as_array()).flatten() {
+    if let Some(authors) = json.get("author").and_then(|x| x.as_array()) {
         for author in authors {
             // ORCID may be null.
             let orcid_uri = get_orcid_from_author(author).map(|x| x.to_uri());
 
-            if let Some(affiliations) = author.get("affiliation").map(|x| x.as_array()).flatten() {
+            if let Some(affiliations) = author.get("affiliation").and_then(|x| x.as_array()) {
                 for affiliation in affiliations {
-                    if let Some(ids) = affiliation.get("id").map(|x| x.as_array()).flatten() {
+                    if let Some(ids) = affiliation.get("id").and_then(|x| x.as_array()) {
                         for id in ids {
                             if let (Some(the_id), Some(id_type)) = (
-                                id.get("id").map(|x| x.as_str()).flatten(),
-                                id.get("id-type").map(|x| x.as_str()).flatten(),
+                                id.get("id").and_then(|x| x.as_str()),
+                                id.get("id-type").and_then(|x| x.as_str()),
                             ) {
                                 if id_type == "ROR" {
                                     let ror_id = Identifier::parse(the_id);
@@ -86,6 +263,7 @@ fn author_ror(json: &serde_json::Value, results: &mut Vec<Event>, assertion: &Me
                                             assertion_id: assertion.assertion_id,
                                             json: serde_json::json!({"type":"author-ror","author":&orcid_uri})
                                                 .to_string(),
+                                            occurred_at: None,
                                         });
                                 }
                             }
@@ -97,39 +275,163 @@ fn author_ror(json: &serde_json::Value, results: &mut Vec<Event>, assertion: &Me
     }
 }
 
+/// Get the ISBN values from an `isbn-type` entry.
+/// The value is usually a string, but accept an array of strings too.
+fn get_isbn_values(isbn_type_entry: &serde_json::Value) -> Option<Vec<&str>> {
+    match isbn_type_entry.get("value") {
+        Some(serde_json::Value::String(value)) => Some(vec![value.as_str()]),
+        Some(serde_json::Value::Array(values)) => {
+            let strings: Vec<&str> = values.iter().filter_map(|x| x.as_str()).collect();
+            if strings.len() == values.len() && !strings.is_empty() {
+                Some(strings)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
 fn isbn(json: &serde_json::Value, results: &mut Vec<Event>, assertion: &MetadataQueueEntry) {
-    if let Some(Some(isbn_types)) = json.get("isbn-type").map(serde_json::Value::as_array) {
-        for isbn_type_entry in isbn_types {
-            if let Some(isbn_type) = isbn_type_entry
-                .get("type")
-                .map(serde_json::Value::as_str)
-                .flatten()
-            {
-                if let Some(isbn) = isbn_type_entry
-                    .get(&"value")
-                    .map(serde_json::Value::as_str)
-                    .flatten()
-                {
-                    let isbn_identifier = Identifier::parse(isbn);
+    // Usually an array of objects, but tolerate a single object.
+    let isbn_types: Vec<&serde_json::Value> = match json.get("isbn-type") {
+        Some(serde_json::Value::Array(entries)) => entries.iter().collect(),
+        Some(entry @ serde_json::Value::Object(_)) => vec![entry],
+        Some(other) => {
+            log::debug!(
+                "Malformed isbn-type in assertion {}: {}",
+                assertion.assertion_id,
+                other
+            );
+            vec![]
+        }
+        None => vec![],
+    };
 
-                    results.push(Event {
-                        event_id: -1,
-                        analyzer: EventAnalyzerId::Identifier,
-                        subject_id: Some(assertion.subject_id()),
-                        object_id: Some(isbn_identifier),
-                        source: MetadataSourceId::from_int_value(assertion.source_id),
-                        assertion_id: assertion.assertion_id,
-                        json: serde_json::json!({"type":"has-isbn", "isbn-type": isbn_type})
-                            .to_string(),
-                    });
+    // Records sometimes repeat the same ISBN and type, possibly in ISBN-10 and ISBN-13 forms. Only report each once.
+    let mut seen: Vec<(&str, String)> = vec![];
+
+    for isbn_type_entry in isbn_types {
+        let isbn_type = isbn_type_entry.get("type").and_then(|x| x.as_str());
+        let isbn_values = get_isbn_values(isbn_type_entry);
+
+        if let (Some(isbn_type), Some(isbn_values)) = (isbn_type, isbn_values) {
+            for isbn in isbn_values {
+                // Canonical ISBN-13 so both forms are the same entity.
+                let normalized = normalize_isbn(isbn);
+                let canonical = normalized.clone().unwrap_or(String::from(isbn));
+
+                if seen.contains(&(isbn_type, canonical.clone())) {
+                    continue;
                 }
+                seen.push((isbn_type, canonical.clone()));
+
+                let isbn_identifier = Identifier::parse(&canonical);
+
+                // Keep the original form if it was changed.
+                let original = if canonical != isbn { Some(isbn) } else { None };
+
+                results.push(Event {
+                    event_id: -1,
+                    analyzer: EventAnalyzerId::Identifier,
+                    subject_id: Some(assertion.subject_id()),
+                    object_id: Some(isbn_identifier),
+                    source: MetadataSourceId::from_int_value(assertion.source_id),
+                    assertion_id: assertion.assertion_id,
+                    json: match original {
+                        Some(original) => serde_json::json!({"type":"has-isbn", "isbn-type": isbn_type, "original-isbn": original}),
+                        None => serde_json::json!({"type":"has-isbn", "isbn-type": isbn_type}),
+                    }
+                    .to_string(),
+                    occurred_at: None,
+                });
+            }
+        } else {
+            log::debug!(
+                "Malformed isbn-type entry in assertion {}: {}",
+                assertion.assertion_id,
+                isbn_type_entry
+            );
+        }
+    }
+}
+
+/// Links to the publisher's own ids for the work, and sometimes other scholarly identifiers.
+/// Values that parse as a known identifier type are linked as that type, otherwise as a string.
+/// Blank values, and those that are the work's own DOI, are skipped.
+fn alternative_id(
+    json: &serde_json::Value,
+    results: &mut Vec<Event>,
+    assertion: &MetadataQueueEntry,
+) {
+    if let Some(values) = json.get("alternative-id").and_then(|x| x.as_array()) {
+        for value in values
+            .iter()
+            .filter_map(|x| x.as_str())
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+        {
+            let alternative_id = Identifier::parse(value);
+            if alternative_id == assertion.subject_id() {
+                continue;
+            }
+
+            results.push(Event {
+                event_id: -1,
+                analyzer: EventAnalyzerId::Identifier,
+                subject_id: Some(assertion.subject_id()),
+                object_id: Some(alternative_id),
+                source: MetadataSourceId::from_int_value(assertion.source_id),
+                assertion_id: assertion.assertion_id,
+                json: serde_json::json!({"type":"alternative-id", "value": value}).to_string(),
+                occurred_at: None,
+            });
+        }
+    }
+}
+
+/// Links to clinical trial registrations. The trial number is registry-specific, so it's represented as a string.
+fn clinical_trial(
+    json: &serde_json::Value,
+    results: &mut Vec<Event>,
+    assertion: &MetadataQueueEntry,
+) {
+    if let Some(trials) = json.get("clinical-trial-number").and_then(|x| x.as_array()) {
+        for trial in trials {
+            // Entries without a number can't be linked, so skip them.
+            let number = trial
+                .get("clinical-trial-number")
+                .and_then(|x| x.as_str())
+                .map(|x| x.trim())
+                .filter(|x| !x.is_empty());
+
+            if let Some(number) = number {
+                let registry = trial.get("registry").and_then(|x| x.as_str());
+
+                results.push(Event {
+                    event_id: -1,
+                    analyzer: EventAnalyzerId::Identifier,
+                    subject_id: Some(assertion.subject_id()),
+                    object_id: Some(Identifier::String(String::from(number))),
+                    source: MetadataSourceId::from_int_value(assertion.source_id),
+                    assertion_id: assertion.assertion_id,
+                    json: serde_json::json!({"type":"clinical-trial", "registry": registry})
+                        .to_string(),
+                    occurred_at: None,
+                });
+            } else {
+                log::debug!(
+                    "Clinical trial entry without number in assertion {}: {}",
+                    assertion.assertion_id,
+                    trial
+                );
             }
         }
     }
 }
 
 fn references(json: &serde_json::Value, results: &mut Vec<Event>, assertion: &MetadataQueueEntry) {
-    if let Some(references) = json.get("reference").map(|x| x.as_array()).flatten() {
+    if let Some(references) = json.get("reference").and_then(|x| x.as_array()) {
         for reference in references {
             // If there's no DOI it's unlinked, and should be skipped.
             if let Some(doi) = reference.get("DOI") {
@@ -143,6 +445,7 @@ fn references(json: &serde_json::Value, results: &mut Vec<Event>, assertion: &Me
                         source: MetadataSourceId::from_int_value(assertion.source_id),
                         assertion_id: assertion.assertion_id,
                         json: serde_json::json!({"type":"references"}).to_string(),
+                        occurred_at: None,
                     });
                 }
             }
@@ -150,11 +453,167 @@ fn references(json: &serde_json::Value, results: &mut Vec<Event>, assertion: &Me
     }
 }
 
+/// Work types that are part of a book, so their ISBN is the book's.
+const BOOK_PART_TYPES: [&str; 4] = [
+    "book-chapter",
+    "book-section",
+    "book-part",
+    "reference-entry",
+];
+
+/// Links from chapter-level records to the containing work, e.g. from a book chapter to its book.
+/// These are records of one of the [`BOOK_PART_TYPES`] with a container title and an ISBN, which is the container's ISBN.
+/// Other types, e.g. articles in a book series, may have an ISBN that isn't their container's.
+/// Link to the parent DOI if the record gives one, otherwise to each ISBN.
+fn part_of(json: &serde_json::Value, results: &mut Vec<Event>, assertion: &MetadataQueueEntry) {
+    let work_type = json.get("type").and_then(|x| x.as_str());
+    if !work_type.is_some_and(|x| BOOK_PART_TYPES.contains(&x)) {
+        return;
+    }
+
+    let container_title = json
+        .get("container-title")
+        .and_then(|x| x.as_array())
+        .and_then(|x| x.first())
+        .and_then(|x| x.as_str());
+
+    let isbns: Vec<&str> = json
+        .get("ISBN")
+        .and_then(|x| x.as_array())
+        .map(|x| x.iter().filter_map(|x| x.as_str()).collect())
+        .unwrap_or_default();
+
+    if let Some(container_title) = container_title {
+        if isbns.is_empty() {
+            return;
+        }
+
+        let parent_dois: Vec<&str> = json
+            .get("relation")
+            .and_then(|x| x.get("is-part-of"))
+            .and_then(|x| x.as_array())
+            .map(|x| {
+                x.iter()
+                    .filter(|x| x.get("id-type").and_then(|x| x.as_str()) == Some("doi"))
+                    .filter_map(|x| x.get("id").and_then(|x| x.as_str()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let parents = if parent_dois.is_empty() {
+            isbns
+        } else {
+            parent_dois
+        };
+
+        for parent in parents {
+            results.push(Event {
+                event_id: -1,
+                analyzer: EventAnalyzerId::Reference,
+                subject_id: Some(assertion.subject_id()),
+                object_id: Some(Identifier::parse(
+                    &normalize_isbn(parent).unwrap_or(String::from(parent)),
+                )),
+                source: MetadataSourceId::from_int_value(assertion.source_id),
+                assertion_id: assertion.assertion_id,
+                json: serde_json::json!({"type":"is-part-of", "container-title": container_title})
+                    .to_string(),
+                occurred_at: None,
+            });
+        }
+    }
+}
+
+/// Relation types that link a preprint and its published version.
+const PREPRINT_RELATIONS: [&str; 2] = ["has-preprint", "is-preprint-of"];
+
+/// Works in the `relation` field, with the relation type, e.g. `has-preprint`, and the id type.
+/// Only DOIs and arXiv ids are recognised. Other id types, and malformed ids, are skipped.
+fn related_works(json: &serde_json::Value) -> Vec<(&str, &str, Identifier)> {
+    let mut works = vec![];
+
+    if let Some(relations) = json.get("relation").and_then(|x| x.as_object()) {
+        for (relation_type, related) in relations {
+            for item in related.as_array().into_iter().flatten() {
+                let id_type = item.get("id-type").and_then(|x| x.as_str());
+                let Some(id) = item.get("id").and_then(|x| x.as_str()) else {
+                    continue;
+                };
+
+                let identifier = match id_type {
+                    Some("arxiv") => arxiv_identifier(id),
+                    Some("doi") => match Identifier::parse(id) {
+                        doi @ Identifier::Doi { .. } => Some(doi),
+                        _ => None,
+                    },
+                    _ => continue,
+                };
+
+                match (id_type, identifier) {
+                    (Some(id_type), Some(identifier)) => {
+                        works.push((relation_type.as_str(), id_type, identifier))
+                    }
+                    _ => log::debug!("Skipping malformed id {} in {}", id, relation_type),
+                }
+            }
+        }
+    }
+
+    works
+}
+
+/// Relations to works identified by arXiv id, e.g. `has-preprint`. The relation type is the Event type.
+/// Malformed arXiv ids are skipped.
+fn arxiv_relations(
+    json: &serde_json::Value,
+    results: &mut Vec<Event>,
+    assertion: &MetadataQueueEntry,
+) {
+    for (relation_type, id_type, object_id) in related_works(json) {
+        if id_type == "arxiv" {
+            results.push(Event {
+                event_id: -1,
+                analyzer: EventAnalyzerId::Reference,
+                subject_id: Some(assertion.subject_id()),
+                object_id: Some(object_id),
+                source: MetadataSourceId::from_int_value(assertion.source_id),
+                assertion_id: assertion.assertion_id,
+                json: serde_json::json!({ "type": relation_type }).to_string(),
+                occurred_at: None,
+            });
+        }
+    }
+}
+
+/// Links between a preprint and its published version, by DOI or arXiv id, in either direction.
+/// The direction is the relation type, `has-preprint` from the published work, `is-preprint-of` from the preprint.
+fn preprint_links(
+    json: &serde_json::Value,
+    results: &mut Vec<Event>,
+    assertion: &MetadataQueueEntry,
+) {
+    for (relation_type, _, object_id) in related_works(json) {
+        if PREPRINT_RELATIONS.contains(&relation_type) {
+            results.push(Event {
+                event_id: -1,
+                analyzer: EventAnalyzerId::Lifecycle,
+                subject_id: Some(assertion.subject_id()),
+                object_id: Some(object_id),
+                source: MetadataSourceId::from_int_value(assertion.source_id),
+                assertion_id: assertion.assertion_id,
+                json: serde_json::json!({"type": "preprint-link", "direction": relation_type})
+                    .to_string(),
+                occurred_at: None,
+            });
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::{fs, path::PathBuf};
 
-    use crate::metadata_assertion::crossref::metadata_agent;
+    use crate::metadata_assertion::service::get_identifier_and_json;
 
     use super::*;
 
@@ -163,9 +622,9 @@ mod tests {
     /// Simulate a MetadataQueueEntry coming off the queue, reading JSON from a local file.
     fn read_entry(path: &str, source_id: MetadataSourceId) -> MetadataQueueEntry {
         // In normal execution this is round-tripping through the database so it's reasonable to convert to string and back.
-        let s = fs::read_to_string(&PathBuf::from(path)).unwrap();
+        let s = fs::read_to_string(PathBuf::from(path)).unwrap();
         let json_val = serde_json::from_str(&s).unwrap();
-        let (identifier, json) = metadata_agent::get_identifier_and_json(json_val).unwrap();
+        let (identifier, json) = get_identifier_and_json(json_val, source_id).unwrap();
         let (subject_id_value, subject_id_type) = identifier.to_id_string_pair();
 
         MetadataQueueEntry {
@@ -174,6 +633,7 @@ mod tests {
             json,
             subject_id_type: subject_id_type as i32,
             subject_id_value,
+            superseded: false,
         }
     }
 
@@ -189,13 +649,59 @@ mod tests {
         }
     }
 
+    /// When an allow-list of analyzers is given, only those extractors run.
+    #[test]
+    fn test_analyzer_filter() {
+        let entry = read_entry(
+            "testing/unit/crossref-article.json",
+            MetadataSourceId::Crossref,
+        );
+
+        let all = extract_events(
+            &entry,
+            Some(serde_json::from_str(&entry.json).unwrap()),
+            None,
+        );
+        assert!(all
+            .iter()
+            .any(|x| x.analyzer == EventAnalyzerId::Contribution));
+
+        let events = extract_events(
+            &entry,
+            Some(serde_json::from_str(&entry.json).unwrap()),
+            Some(&[EventAnalyzerId::Reference, EventAnalyzerId::Lifecycle]),
+        );
+
+        assert!(events
+            .iter()
+            .any(|x| x.analyzer == EventAnalyzerId::Lifecycle));
+        assert!(events
+            .iter()
+            .any(|x| x.analyzer == EventAnalyzerId::Reference));
+        assert!(events
+            .iter()
+            .all(|x| x.analyzer == EventAnalyzerId::Lifecycle
+                || x.analyzer == EventAnalyzerId::Reference));
+
+        let none = extract_events(
+            &entry,
+            Some(serde_json::from_str(&entry.json).unwrap()),
+            Some(&[]),
+        );
+        assert!(none.is_empty());
+    }
+
     #[test]
     fn test_contribution() {
         let entry = read_entry(
             "testing/unit/crossref-article.json",
             MetadataSourceId::Crossref,
         );
-        let events = extract_events(&entry, Some(serde_json::from_str(&entry.json).unwrap()));
+        let events = extract_events(
+            &entry,
+            Some(serde_json::from_str(&entry.json).unwrap()),
+            None,
+        );
 
         // List of events and labels for debugging.
         let expected_events = vec![
@@ -213,7 +719,8 @@ mod tests {
                         String::from("0009-0005-5061-2894"),
                     )),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"author"}"##),
+                    json: String::from(r##"{"type":"author","subject_type":"journal-article"}"##),
+                    occurred_at: None,
                 },
             ),
             (
@@ -230,7 +737,8 @@ mod tests {
                         String::from("0009-0009-8606-9140"),
                     )),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"author"}"##),
+                    json: String::from(r##"{"type":"author","subject_type":"journal-article"}"##),
+                    occurred_at: None,
                 },
             ),
             // This ORCID is invalid, and the checksum digit doesn't validate.
@@ -249,7 +757,8 @@ mod tests {
                         String::from("http://orcid.org/0009-0009-8606-9149"),
                     )),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"author"}"##),
+                    json: String::from(r##"{"type":"author","subject_type":"journal-article"}"##),
+                    occurred_at: None,
                 },
             ),
         ];
@@ -263,8 +772,11 @@ mod tests {
             "testing/unit/crossref-article.json",
             MetadataSourceId::Crossref,
         );
-        let article_events =
-            extract_events(&article, Some(serde_json::from_str(&article.json).unwrap()));
+        let article_events = extract_events(
+            &article,
+            Some(serde_json::from_str(&article.json).unwrap()),
+            None,
+        );
 
         let expected_article_events = vec![(
             "lifecycle",
@@ -278,7 +790,8 @@ mod tests {
                 }),
                 object_id: None,
                 assertion_id: 2,
-                json: String::from(r##"{"type":"indexed"}"##),
+                json: String::from(r##"{"type":"indexed","subject_type":"journal-article"}"##),
+                occurred_at: None,
             },
         )];
 
@@ -288,7 +801,8 @@ mod tests {
             "testing/unit/crossref-book.json",
             MetadataSourceId::Crossref,
         );
-        let book_events = extract_events(&book, Some(serde_json::from_str(&book.json).unwrap()));
+        let book_events =
+            extract_events(&book, Some(serde_json::from_str(&book.json).unwrap()), None);
 
         // List of events and labels for debugging.
         let expected_book_events = vec![(
@@ -303,20 +817,138 @@ mod tests {
                 }),
                 object_id: None,
                 assertion_id: 2,
-                json: String::from(r##"{"type":"indexed"}"##),
+                json: String::from(r##"{"type":"indexed","subject_type":"book"}"##),
+                occurred_at: None,
             },
         )];
 
         assert_contains_events(expected_book_events, book_events);
     }
 
+    /// One Event per license with a URL. Partial start dates are kept as far as they go.
+    #[test]
+    fn test_license() {
+        let entry = read_entry(
+            "testing/unit/crossref/licenses.json",
+            MetadataSourceId::Crossref,
+        );
+        let events = extract_events(
+            &entry,
+            Some(serde_json::from_str(&entry.json).unwrap()),
+            None,
+        );
+
+        let license_events: Vec<&str> = events
+            .iter()
+            .filter(|x| x.json.contains(r#""type":"license""#))
+            .map(|x| x.json.as_str())
+            .collect();
+
+        assert_eq!(
+            license_events,
+            vec![
+                r#"{"content-version":"vor","start":"2021-01-01","type":"license","url":"https://creativecommons.org/licenses/by/4.0","subject_type":"journal-article"}"#,
+                r#"{"content-version":"tdm","start":"2021-02","type":"license","url":"https://www.elsevier.com/tdm/userlicense/1.0","subject_type":"journal-article"}"#
+            ]
+        );
+        assert!(events
+            .iter()
+            .filter(|x| x.json.contains("license"))
+            .all(|x| x.analyzer == EventAnalyzerId::Lifecycle && x.object_id.is_none()));
+    }
+
+    /// Only records with a non-empty abstract have the event.
+    #[test]
+    fn test_has_abstract() {
+        let has_abstract = |path: &str| {
+            let entry = read_entry(path, MetadataSourceId::Crossref);
+            extract_events(
+                &entry,
+                Some(serde_json::from_str(&entry.json).unwrap()),
+                None,
+            )
+            .into_iter()
+            .filter(|x| x.json.contains("has-abstract"))
+            .collect::<Vec<Event>>()
+        };
+
+        assert_eq!(
+            has_abstract("testing/unit/crossref-article.json"),
+            vec![Event {
+                event_id: -1,
+                analyzer: EventAnalyzerId::Lifecycle,
+                source: MetadataSourceId::Crossref,
+                subject_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
+                    prefix: String::from("10.33262"),
+                    suffix: String::from("exploradordigital.v8i4.3221"),
+                }),
+                object_id: None,
+                assertion_id: 2,
+                json: String::from(r##"{"type":"has-abstract","subject_type":"journal-article"}"##),
+                occurred_at: None,
+            }]
+        );
+
+        assert!(has_abstract("testing/unit/crossref-book.json").is_empty());
+        assert!(has_abstract("testing/unit/crossref/empty-abstract.json").is_empty());
+    }
+
+    /// Only reported when the lifecycle analyzer is asked for.
+    #[test]
+    fn test_invalid_orcid() {
+        let entry = read_entry(
+            "testing/unit/crossref-article.json",
+            MetadataSourceId::Crossref,
+        );
+        let invalid_orcid = |analyzers: Option<&[EventAnalyzerId]>| {
+            extract_events(
+                &entry,
+                Some(serde_json::from_str(&entry.json).unwrap()),
+                analyzers,
+            )
+            .into_iter()
+            .filter(|x| x.json.contains("invalid-orcid"))
+            .collect::<Vec<Event>>()
+        };
+
+        assert!(invalid_orcid(None).is_empty());
+
+        assert_eq!(
+            invalid_orcid(Some(&[EventAnalyzerId::Lifecycle])),
+            vec![Event {
+                event_id: -1,
+                analyzer: EventAnalyzerId::Lifecycle,
+                source: MetadataSourceId::Crossref,
+                subject_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
+                    prefix: String::from("10.33262"),
+                    suffix: String::from("exploradordigital.v8i4.3221"),
+                }),
+                object_id: None,
+                assertion_id: 2,
+                json: String::from(
+                    r##"{"raw":"http://orcid.org/0009-0009-8606-9149","type":"invalid-orcid","subject_type":"journal-article"}"##
+                ),
+                occurred_at: None,
+            }]
+        );
+
+        assert!(is_orcid_url("https://orcid.org/0000-0002-1825-0090"));
+        assert!(!is_orcid_url(
+            "https://example.com/orcid.org/0000-0002-1825-0090"
+        ));
+    }
+
     #[test]
     fn test_isbn() {
         let entry = read_entry(
             "testing/unit/crossref-book.json",
             MetadataSourceId::Crossref,
         );
-        let events = extract_events(&entry, Some(serde_json::from_str(&entry.json).unwrap()));
+        let events = extract_events(
+            &entry,
+            Some(serde_json::from_str(&entry.json).unwrap()),
+            None,
+        );
 
         // List of events and labels for debugging.
         let expected_events = vec![
@@ -334,7 +966,10 @@ mod tests {
                         String::from("9780511806223"),
                     )),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"has-isbn","isbn-type":"electronic"}"##),
+                    json: String::from(
+                        r##"{"type":"has-isbn","isbn-type":"electronic","subject_type":"book"}"##,
+                    ),
+                    occurred_at: None,
                 },
             ),
             (
@@ -351,7 +986,10 @@ mod tests {
                         String::from("9780521643863"),
                     )),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"has-isbn","isbn-type":"print"}"##),
+                    json: String::from(
+                        r##"{"type":"has-isbn","isbn-type":"print","subject_type":"book"}"##,
+                    ),
+                    occurred_at: None,
                 },
             ),
             (
@@ -368,7 +1006,10 @@ mod tests {
                         String::from("9780521643658"),
                     )),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"has-isbn","isbn-type":"print"}"##),
+                    json: String::from(
+                        r##"{"type":"has-isbn","isbn-type":"print","subject_type":"book"}"##,
+                    ),
+                    occurred_at: None,
                 },
             ),
             // Invalid checksum should mean identifier isn't recognised as an ISBN.
@@ -386,7 +1027,10 @@ mod tests {
                         String::from("9780521643869"),
                     )),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"has-isbn","isbn-type":"print"}"##),
+                    json: String::from(
+                        r##"{"type":"has-isbn","isbn-type":"print","subject_type":"book"}"##,
+                    ),
+                    occurred_at: None,
                 },
             ),
         ];
@@ -394,57 +1038,624 @@ mod tests {
         assert_contains_events(expected_events, events);
     }
 
-    /// All linked references. No unlinked ones.
+    /// Only well-formed `isbn-type` entries produce events, and duplicates are reported once.
     #[test]
-    fn test_references() {
+    fn test_isbn_malformed() {
         let entry = read_entry(
-            "testing/unit/crossref-article.json",
+            "testing/unit/crossref/isbn-malformed.json",
             MetadataSourceId::Crossref,
         );
-        let events = extract_events(&entry, Some(serde_json::from_str(&entry.json).unwrap()));
+        let events = extract_events(
+            &entry,
+            Some(serde_json::from_str(&entry.json).unwrap()),
+            None,
+        );
 
         let expected_events = vec![
             (
-                "ref-1",
+                "print isbn",
                 Event {
                     event_id: -1,
-                    analyzer: EventAnalyzerId::Reference,
+                    analyzer: EventAnalyzerId::Identifier,
                     source: MetadataSourceId::Crossref,
                     subject_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
-                        prefix: String::from("10.33262"),
-                        suffix: String::from("exploradordigital.v8i4.3221"),
-                    }),
-                    object_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
-                        prefix: String::from("10.35381"),
-                        suffix: String::from("r.k.v5i5.1052"),
+                        prefix: String::from("10.5555"),
+                        suffix: String::from("isbn-malformed"),
                     }),
+                    object_id: Some(scholarly_identifiers::identifiers::Identifier::Isbn(
+                        String::from("9780521643658"),
+                    )),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"references"}"##),
+                    json: String::from(
+                        r##"{"type":"has-isbn","isbn-type":"print","subject_type":"book"}"##,
+                    ),
+                    occurred_at: None,
                 },
             ),
             (
-                "ref-2",
+                "electronic isbn in array",
                 Event {
                     event_id: -1,
-                    analyzer: EventAnalyzerId::Reference,
+                    analyzer: EventAnalyzerId::Identifier,
                     source: MetadataSourceId::Crossref,
                     subject_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
-                        prefix: String::from("10.33262"),
-                        suffix: String::from("exploradordigital.v8i4.3221"),
-                    }),
-                    object_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
-                        prefix: String::from("10.15517"),
-                        suffix: String::from("revedu.v45i1.41009"),
+                        prefix: String::from("10.5555"),
+                        suffix: String::from("isbn-malformed"),
                     }),
+                    object_id: Some(scholarly_identifiers::identifiers::Identifier::Isbn(
+                        String::from("9780511806223"),
+                    )),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"references"}"##),
+                    json: String::from(
+                        r##"{"type":"has-isbn","isbn-type":"electronic","subject_type":"book"}"##,
+                    ),
+                    occurred_at: None,
                 },
             ),
+        ];
+
+        let isbn_events: Vec<&Event> = events
+            .iter()
+            .filter(|e| e.analyzer == EventAnalyzerId::Identifier)
+            .collect();
+        assert_eq!(
+            isbn_events.len(),
+            2,
+            "Expected only well-formed, de-duplicated ISBNs. Got {:?}",
+            isbn_events
+        );
+
+        assert_contains_events(expected_events, events);
+    }
+
+    /// ISBN-10s are converted to ISBN-13, so both forms of the same ISBN collapse into one event.
+    #[test]
+    fn test_isbn_10() {
+        let entry = read_entry(
+            "testing/unit/crossref/isbn-10.json",
+            MetadataSourceId::Crossref,
+        );
+        let events = extract_events(
+            &entry,
+            Some(serde_json::from_str(&entry.json).unwrap()),
+            None,
+        );
+
+        let expected_events = vec![
             (
-                "ref-3",
+                "print isbn, in both forms",
                 Event {
                     event_id: -1,
-                    analyzer: EventAnalyzerId::Reference,
+                    analyzer: EventAnalyzerId::Identifier,
+                    source: MetadataSourceId::Crossref,
+                    subject_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
+                        prefix: String::from("10.5555"),
+                        suffix: String::from("isbn-10"),
+                    }),
+                    object_id: Some(scholarly_identifiers::identifiers::Identifier::Isbn(
+                        String::from("9780521643658"),
+                    )),
+                    assertion_id: 2,
+                    json: String::from(
+                        r##"{"type":"has-isbn","isbn-type":"print","subject_type":"book"}"##,
+                    ),
+                    occurred_at: None,
+                },
+            ),
+            (
+                "electronic isbn-10, with original",
+                Event {
+                    event_id: -1,
+                    analyzer: EventAnalyzerId::Identifier,
+                    source: MetadataSourceId::Crossref,
+                    subject_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
+                        prefix: String::from("10.5555"),
+                        suffix: String::from("isbn-10"),
+                    }),
+                    object_id: Some(scholarly_identifiers::identifiers::Identifier::Isbn(
+                        String::from("9780511806223"),
+                    )),
+                    assertion_id: 2,
+                    json: String::from(
+                        r##"{"type":"has-isbn","isbn-type":"electronic","original-isbn":"0511806221","subject_type":"book"}"##,
+                    ),
+                    occurred_at: None,
+                },
+            ),
+        ];
+
+        let isbn_events = events
+            .iter()
+            .filter(|e| e.analyzer == EventAnalyzerId::Identifier)
+            .count();
+        assert_eq!(isbn_events, 2, "ISBN-10 and ISBN-13 forms should collapse.");
+
+        assert_contains_events(expected_events, events);
+    }
+
+    /// A single `isbn-type` object rather than an array is accepted.
+    #[test]
+    fn test_isbn_object() {
+        let entry = read_entry(
+            "testing/unit/crossref/isbn-object.json",
+            MetadataSourceId::Crossref,
+        );
+        let events = extract_events(
+            &entry,
+            Some(serde_json::from_str(&entry.json).unwrap()),
+            None,
+        );
+
+        let expected_events = vec![(
+            "print isbn",
+            Event {
+                event_id: -1,
+                analyzer: EventAnalyzerId::Identifier,
+                source: MetadataSourceId::Crossref,
+                subject_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
+                    prefix: String::from("10.5555"),
+                    suffix: String::from("isbn-object"),
+                }),
+                object_id: Some(scholarly_identifiers::identifiers::Identifier::Isbn(
+                    String::from("9780521643658"),
+                )),
+                assertion_id: 2,
+                json: String::from(
+                    r##"{"type":"has-isbn","isbn-type":"print","subject_type":"book"}"##,
+                ),
+                occurred_at: None,
+            },
+        )];
+
+        assert_contains_events(expected_events, events);
+    }
+
+    /// Clinical trial numbers with their registry. Entries without a number are skipped.
+    #[test]
+    fn test_clinical_trial() {
+        let entry = read_entry(
+            "testing/unit/crossref/clinical-trial.json",
+            MetadataSourceId::Crossref,
+        );
+        let events = extract_events(
+            &entry,
+            Some(serde_json::from_str(&entry.json).unwrap()),
+            None,
+        );
+
+        let expected_events = vec![
+            (
+                "clinicaltrials.gov",
+                Event {
+                    event_id: -1,
+                    analyzer: EventAnalyzerId::Identifier,
+                    source: MetadataSourceId::Crossref,
+                    subject_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
+                        prefix: String::from("10.5555"),
+                        suffix: String::from("clinical-trial"),
+                    }),
+                    object_id: Some(scholarly_identifiers::identifiers::Identifier::String(
+                        String::from("NCT01234567"),
+                    )),
+                    assertion_id: 2,
+                    json: String::from(
+                        r##"{"type":"clinical-trial","registry":"10.18810/clinical-trials-gov","subject_type":"journal-article"}"##,
+                    ),
+                    occurred_at: None,
+                },
+            ),
+            (
+                "isrctn",
+                Event {
+                    event_id: -1,
+                    analyzer: EventAnalyzerId::Identifier,
+                    source: MetadataSourceId::Crossref,
+                    subject_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
+                        prefix: String::from("10.5555"),
+                        suffix: String::from("clinical-trial"),
+                    }),
+                    object_id: Some(scholarly_identifiers::identifiers::Identifier::String(
+                        String::from("ISRCTN12345678"),
+                    )),
+                    assertion_id: 2,
+                    json: String::from(
+                        r##"{"type":"clinical-trial","registry":"10.18810/isrctn","subject_type":"journal-article"}"##,
+                    ),
+                    occurred_at: None,
+                },
+            ),
+        ];
+
+        let clinical_trial_count = events
+            .iter()
+            .filter(|x| x.json.contains("clinical-trial"))
+            .count();
+        assert_eq!(
+            clinical_trial_count, 2,
+            "Entry without a number is skipped."
+        );
+
+        assert_contains_events(expected_events, events);
+    }
+
+    /// One Event per alternative id. DOIs are linked as DOIs, other ids as strings.
+    #[test]
+    fn test_alternative_id() {
+        let entry = read_entry(
+            "testing/unit/crossref/alternative-id.json",
+            MetadataSourceId::Crossref,
+        );
+        let events = extract_events(
+            &entry,
+            Some(serde_json::from_str(&entry.json).unwrap()),
+            None,
+        );
+
+        let subject_id = || {
+            Some(scholarly_identifiers::identifiers::Identifier::Doi {
+                prefix: String::from("10.5555"),
+                suffix: String::from("alternative-id"),
+            })
+        };
+
+        let expected_events = vec![
+            (
+                "publisher id",
+                Event {
+                    event_id: -1,
+                    analyzer: EventAnalyzerId::Identifier,
+                    source: MetadataSourceId::Crossref,
+                    subject_id: subject_id(),
+                    object_id: Some(scholarly_identifiers::identifiers::Identifier::String(
+                        String::from("S0140673620300012"),
+                    )),
+                    assertion_id: 2,
+                    json: String::from(
+                        r##"{"type":"alternative-id","value":"S0140673620300012","subject_type":"journal-article"}"##,
+                    ),
+                    occurred_at: None,
+                },
+            ),
+            (
+                "doi",
+                Event {
+                    event_id: -1,
+                    analyzer: EventAnalyzerId::Identifier,
+                    source: MetadataSourceId::Crossref,
+                    subject_id: subject_id(),
+                    object_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
+                        prefix: String::from("10.5555"),
+                        suffix: String::from("alternative-other"),
+                    }),
+                    assertion_id: 2,
+                    json: String::from(
+                        r##"{"type":"alternative-id","value":"10.5555/alternative-other","subject_type":"journal-article"}"##,
+                    ),
+                    occurred_at: None,
+                },
+            ),
+        ];
+
+        let alternative_id_count = events
+            .iter()
+            .filter(|x| x.json.contains(r#""type":"alternative-id""#))
+            .count();
+        assert_eq!(
+            alternative_id_count, 2,
+            "Blank values and the work's own DOI are skipped."
+        );
+
+        assert_contains_events(expected_events, events);
+    }
+
+    /// A book chapter links to its book by ISBN when there's no parent DOI.
+    #[test]
+    fn test_part_of_isbn() {
+        let entry = read_entry(
+            "testing/unit/crossref/book-chapter.json",
+            MetadataSourceId::Crossref,
+        );
+        let events = extract_events(
+            &entry,
+            Some(serde_json::from_str(&entry.json).unwrap()),
+            None,
+        );
+
+        let expected_events = vec![
+            (
+                "part of print isbn",
+                Event {
+                    event_id: -1,
+                    analyzer: EventAnalyzerId::Reference,
+                    source: MetadataSourceId::Crossref,
+                    subject_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
+                        prefix: String::from("10.5555"),
+                        suffix: String::from("book-chapter"),
+                    }),
+                    object_id: Some(scholarly_identifiers::identifiers::Identifier::Isbn(
+                        String::from("9780521643658"),
+                    )),
+                    assertion_id: 2,
+                    json: String::from(
+                        r##"{"container-title":"A Book","type":"is-part-of","subject_type":"book-chapter"}"##,
+                    ),
+                    occurred_at: None,
+                },
+            ),
+            (
+                "part of electronic isbn",
+                Event {
+                    event_id: -1,
+                    analyzer: EventAnalyzerId::Reference,
+                    source: MetadataSourceId::Crossref,
+                    subject_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
+                        prefix: String::from("10.5555"),
+                        suffix: String::from("book-chapter"),
+                    }),
+                    object_id: Some(scholarly_identifiers::identifiers::Identifier::Isbn(
+                        String::from("9780511806223"),
+                    )),
+                    assertion_id: 2,
+                    json: String::from(
+                        r##"{"container-title":"A Book","type":"is-part-of","subject_type":"book-chapter"}"##,
+                    ),
+                    occurred_at: None,
+                },
+            ),
+        ];
+
+        assert_contains_events(expected_events, events);
+    }
+
+    /// A parent DOI is preferred over the ISBN. Books themselves aren't part of anything.
+    #[test]
+    fn test_part_of_doi() {
+        let entry = read_entry(
+            "testing/unit/crossref/book-chapter-parent-doi.json",
+            MetadataSourceId::Crossref,
+        );
+        let events = extract_events(
+            &entry,
+            Some(serde_json::from_str(&entry.json).unwrap()),
+            None,
+        );
+
+        let part_of_events: Vec<&Event> = events
+            .iter()
+            .filter(|x| x.json.contains("is-part-of"))
+            .collect();
+
+        assert_eq!(
+            part_of_events,
+            vec![&Event {
+                event_id: -1,
+                analyzer: EventAnalyzerId::Reference,
+                source: MetadataSourceId::Crossref,
+                subject_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
+                    prefix: String::from("10.5555"),
+                    suffix: String::from("book-chapter-parent-doi"),
+                }),
+                object_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
+                    prefix: String::from("10.5555"),
+                    suffix: String::from("book"),
+                }),
+                assertion_id: 2,
+                json: String::from(
+                    r##"{"container-title":"A Book","type":"is-part-of","subject_type":"book-chapter"}"##
+                ),
+                occurred_at: None,
+            }]
+        );
+
+        let book = read_entry(
+            "testing/unit/crossref-book.json",
+            MetadataSourceId::Crossref,
+        );
+        let book_events =
+            extract_events(&book, Some(serde_json::from_str(&book.json).unwrap()), None);
+        assert!(!book_events.iter().any(|x| x.json.contains("is-part-of")));
+    }
+
+    /// Only parts of books are linked to their container, even if other works have a container title and ISBN.
+    #[test]
+    fn test_part_of_other_type() {
+        let entry = read_entry(
+            "testing/unit/crossref/article-with-isbn.json",
+            MetadataSourceId::Crossref,
+        );
+        let events = extract_events(
+            &entry,
+            Some(serde_json::from_str(&entry.json).unwrap()),
+            None,
+        );
+
+        assert!(!events.is_empty());
+        assert!(!events.iter().any(|x| x.json.contains("is-part-of")));
+    }
+
+    /// Relations to arXiv preprints. Malformed ids and relations to other identifier types are skipped.
+    #[test]
+    fn test_arxiv_relation() {
+        let entry = read_entry(
+            "testing/unit/crossref/arxiv-preprint.json",
+            MetadataSourceId::Crossref,
+        );
+        let events = extract_events(
+            &entry,
+            Some(serde_json::from_str(&entry.json).unwrap()),
+            None,
+        );
+
+        let relation_events: Vec<&Event> = events
+            .iter()
+            .filter(|x| x.analyzer == EventAnalyzerId::Reference)
+            .filter(|x| x.json.contains("has-preprint") || x.json.contains("is-supplemented-by"))
+            .collect();
+
+        assert_eq!(
+            relation_events,
+            vec![&Event {
+                event_id: -1,
+                analyzer: EventAnalyzerId::Reference,
+                source: MetadataSourceId::Crossref,
+                subject_id: Some(Identifier::Doi {
+                    prefix: String::from("10.5555"),
+                    suffix: String::from("arxiv-preprint"),
+                }),
+                object_id: Some(Identifier::Uri(String::from(
+                    "https://arxiv.org/abs/2101.00001"
+                ))),
+                assertion_id: 2,
+                json: String::from(r##"{"type":"has-preprint","subject_type":"journal-article"}"##),
+                occurred_at: None,
+            }]
+        );
+    }
+
+    /// Preprint links in both directions, by arXiv id and DOI. Other relations and id types are skipped.
+    #[test]
+    fn test_preprint_link() {
+        let preprint_events = |path: &str| -> Vec<Event> {
+            let entry = read_entry(path, MetadataSourceId::Crossref);
+            extract_events(
+                &entry,
+                Some(serde_json::from_str(&entry.json).unwrap()),
+                None,
+            )
+            .into_iter()
+            .filter(|x| x.json.contains("preprint-link"))
+            .collect()
+        };
+
+        assert_eq!(
+            preprint_events("testing/unit/crossref/arxiv-preprint.json"),
+            vec![Event {
+                event_id: -1,
+                analyzer: EventAnalyzerId::Lifecycle,
+                source: MetadataSourceId::Crossref,
+                subject_id: Some(Identifier::Doi {
+                    prefix: String::from("10.5555"),
+                    suffix: String::from("arxiv-preprint"),
+                }),
+                object_id: Some(Identifier::Uri(String::from(
+                    "https://arxiv.org/abs/2101.00001"
+                ))),
+                assertion_id: 2,
+                json: String::from(
+                    r##"{"direction":"has-preprint","type":"preprint-link","subject_type":"journal-article"}"##
+                ),
+                occurred_at: None,
+            }]
+        );
+
+        assert_eq!(
+            preprint_events("testing/unit/crossref/preprint-link.json"),
+            vec![Event {
+                event_id: -1,
+                analyzer: EventAnalyzerId::Lifecycle,
+                source: MetadataSourceId::Crossref,
+                subject_id: Some(Identifier::Doi {
+                    prefix: String::from("10.5555"),
+                    suffix: String::from("preprint-link"),
+                }),
+                object_id: Some(Identifier::Doi {
+                    prefix: String::from("10.5555"),
+                    suffix: String::from("published-article"),
+                }),
+                assertion_id: 2,
+                json: String::from(
+                    r##"{"direction":"is-preprint-of","type":"preprint-link","subject_type":"posted-content"}"##
+                ),
+                occurred_at: None,
+            }]
+        );
+    }
+
+    /// Every Event carries the work type, and it's omitted when the record has none.
+    #[test]
+    fn test_subject_type() {
+        let entry = read_entry(
+            "testing/unit/crossref-article.json",
+            MetadataSourceId::Crossref,
+        );
+        let mut json: serde_json::Value = serde_json::from_str(&entry.json).unwrap();
+
+        let events = extract_events(&entry, Some(json.clone()), None);
+        assert!(events
+            .iter()
+            .any(|x| x.analyzer == EventAnalyzerId::Reference));
+        for event in events {
+            let event_json: serde_json::Value = serde_json::from_str(&event.json).unwrap();
+            assert_eq!(event_json["subject_type"], "journal-article");
+        }
+
+        json.as_object_mut().unwrap().remove("type");
+        let events = extract_events(&entry, Some(json), None);
+        assert!(!events.is_empty());
+        assert!(events.iter().all(|x| !x.json.contains("subject_type")));
+    }
+
+    /// All linked references. No unlinked ones.
+    #[test]
+    fn test_references() {
+        let entry = read_entry(
+            "testing/unit/crossref-article.json",
+            MetadataSourceId::Crossref,
+        );
+        let events = extract_events(
+            &entry,
+            Some(serde_json::from_str(&entry.json).unwrap()),
+            None,
+        );
+
+        let expected_events = vec![
+            (
+                "ref-1",
+                Event {
+                    event_id: -1,
+                    analyzer: EventAnalyzerId::Reference,
+                    source: MetadataSourceId::Crossref,
+                    subject_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
+                        prefix: String::from("10.33262"),
+                        suffix: String::from("exploradordigital.v8i4.3221"),
+                    }),
+                    object_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
+                        prefix: String::from("10.35381"),
+                        suffix: String::from("r.k.v5i5.1052"),
+                    }),
+                    assertion_id: 2,
+                    json: String::from(
+                        r##"{"type":"references","subject_type":"journal-article"}"##,
+                    ),
+                    occurred_at: None,
+                },
+            ),
+            (
+                "ref-2",
+                Event {
+                    event_id: -1,
+                    analyzer: EventAnalyzerId::Reference,
+                    source: MetadataSourceId::Crossref,
+                    subject_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
+                        prefix: String::from("10.33262"),
+                        suffix: String::from("exploradordigital.v8i4.3221"),
+                    }),
+                    object_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
+                        prefix: String::from("10.15517"),
+                        suffix: String::from("revedu.v45i1.41009"),
+                    }),
+                    assertion_id: 2,
+                    json: String::from(
+                        r##"{"type":"references","subject_type":"journal-article"}"##,
+                    ),
+                    occurred_at: None,
+                },
+            ),
+            (
+                "ref-3",
+                Event {
+                    event_id: -1,
+                    analyzer: EventAnalyzerId::Reference,
                     source: MetadataSourceId::Crossref,
                     subject_id: Some(scholarly_identifiers::identifiers::Identifier::Doi {
                         prefix: String::from("10.33262"),
@@ -455,7 +1666,10 @@ mod tests {
                         suffix: String::from("educsci12030191"),
                     }),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"references"}"##),
+                    json: String::from(
+                        r##"{"type":"references","subject_type":"journal-article"}"##,
+                    ),
+                    occurred_at: None,
                 },
             ),
             (
@@ -473,7 +1687,10 @@ mod tests {
                         suffix: String::from("cl_rcm.v7i4.7011"),
                     }),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"references"}"##),
+                    json: String::from(
+                        r##"{"type":"references","subject_type":"journal-article"}"##,
+                    ),
+                    occurred_at: None,
                 },
             ),
             (
@@ -491,7 +1708,10 @@ mod tests {
                         suffix: String::from("exploradordigital.v8i3.3178"),
                     }),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"references"}"##),
+                    json: String::from(
+                        r##"{"type":"references","subject_type":"journal-article"}"##,
+                    ),
+                    occurred_at: None,
                 },
             ),
             (
@@ -509,7 +1729,10 @@ mod tests {
                         suffix: String::from("espacios-a21v42n08p04"),
                     }),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"references"}"##),
+                    json: String::from(
+                        r##"{"type":"references","subject_type":"journal-article"}"##,
+                    ),
+                    occurred_at: None,
                 },
             ),
             (
@@ -527,7 +1750,10 @@ mod tests {
                         suffix: String::from("j.ctv2wk71sb"),
                     }),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"references"}"##),
+                    json: String::from(
+                        r##"{"type":"references","subject_type":"journal-article"}"##,
+                    ),
+                    occurred_at: None,
                 },
             ),
             (
@@ -545,7 +1771,10 @@ mod tests {
                         suffix: String::from("fepol.3"),
                     }),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"references"}"##),
+                    json: String::from(
+                        r##"{"type":"references","subject_type":"journal-article"}"##,
+                    ),
+                    occurred_at: None,
                 },
             ),
             (
@@ -563,7 +1792,10 @@ mod tests {
                         suffix: String::from("s10639-023-11723-7"),
                     }),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"references"}"##),
+                    json: String::from(
+                        r##"{"type":"references","subject_type":"journal-article"}"##,
+                    ),
+                    occurred_at: None,
                 },
             ),
             (
@@ -581,7 +1813,10 @@ mod tests {
                         suffix: String::from("educsci14040367"),
                     }),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"references"}"##),
+                    json: String::from(
+                        r##"{"type":"references","subject_type":"journal-article"}"##,
+                    ),
+                    occurred_at: None,
                 },
             ),
             (
@@ -599,7 +1834,10 @@ mod tests {
                         suffix: String::from("educsci12030179"),
                     }),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"references"}"##),
+                    json: String::from(
+                        r##"{"type":"references","subject_type":"journal-article"}"##,
+                    ),
+                    occurred_at: None,
                 },
             ),
             (
@@ -617,7 +1855,10 @@ mod tests {
                         suffix: String::from("ap.v6i1.1.463"),
                     }),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"references"}"##),
+                    json: String::from(
+                        r##"{"type":"references","subject_type":"journal-article"}"##,
+                    ),
+                    occurred_at: None,
                 },
             ),
         ];
@@ -633,7 +1874,11 @@ mod tests {
             "testing/unit/crossref/author-ror.json",
             MetadataSourceId::Crossref,
         );
-        let events = extract_events(&entry, Some(serde_json::from_str(&entry.json).unwrap()));
+        let events = extract_events(
+            &entry,
+            Some(serde_json::from_str(&entry.json).unwrap()),
+            None,
+        );
 
         let expected_events = vec![
             (
@@ -651,8 +1896,9 @@ mod tests {
                     )),
                     assertion_id: 2,
                     json: String::from(
-                        r##"{"type":"author-ror","author":"https://orcid.org/0000-0002-6176-8203"}"##,
+                        r##"{"type":"author-ror","author":"https://orcid.org/0000-0002-6176-8203","subject_type":"journal-article"}"##,
                     ),
+                    occurred_at: None,
                 },
             ),
             // No ORCID ID, but ROR present.
@@ -670,7 +1916,10 @@ mod tests {
                         String::from("05arjae42"),
                     )),
                     assertion_id: 2,
-                    json: String::from(r##"{"type":"author-ror","author":null}"##),
+                    json: String::from(
+                        r##"{"type":"author-ror","author":null,"subject_type":"journal-article"}"##,
+                    ),
+                    occurred_at: None,
                 },
             ),
             (
@@ -688,8 +1937,9 @@ mod tests {
                     )),
                     assertion_id: 2,
                     json: String::from(
-                        r##"{"type":"author-ror","author":"https://orcid.org/0000-0002-6420-3232"}"##,
+                        r##"{"type":"author-ror","author":"https://orcid.org/0000-0002-6420-3232","subject_type":"journal-article"}"##,
                     ),
+                    occurred_at: None,
                 },
             ),
             (
@@ -707,8 +1957,9 @@ mod tests {
                     )),
                     assertion_id: 2,
                     json: String::from(
-                        r##"{"type":"author-ror","author":"https://orcid.org/0000-0002-2775-2953"}"##,
+                        r##"{"type":"author-ror","author":"https://orcid.org/0000-0002-2775-2953","subject_type":"journal-article"}"##,
                     ),
+                    occurred_at: None,
                 },
             ),
         ];
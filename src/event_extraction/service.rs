@@ -1,38 +1,61 @@
 //! Service functions for event extraction.
 
+use std::collections::BTreeMap;
+
 use sqlx::{Pool, Postgres};
 
-use crate::db::entity::resolve_identifier;
+use crate::db::agents::{get_position_checkpoint, set_position_checkpoint};
+use crate::db::entity::{identifier_cache_stats, resolve_identifier};
 use crate::db::event::insert_event;
 use crate::db::event::EventQueueState;
-use crate::db::metadata::poll_assertions;
 use crate::db::metadata::MetadataQueueEntry;
+use crate::db::metadata::{get_assertion_by_id, poll_assertions};
+use crate::db::source::{EventAnalyzerId, MetadataSourceId};
 use crate::event_extraction::crossref;
-use crate::execution::model::Event;
+use crate::execution::model::{Event, EXTRACTOR_FIELD};
 use crate::metadata_assertion;
 
 const BATCH_SIZE: i32 = 1;
 
+/// Position checkpoint for the last Metadata Assertion extracted.
+pub(crate) const EXTRACT_LAST_ASSERTION: &str = "extract-last-assertion";
+
+/// Default number of concurrent extraction tasks.
+pub(crate) const DEFAULT_WORKERS: usize = 5;
+
+/// Number of Events produced by each analyzer, by name.
+pub(crate) type AnalyzerCounts = BTreeMap<String, usize>;
+
 /// Poll the metadata queue and extract events. Return number of metadata
-/// assertions read, and number of Events prodced.
+/// assertions read, and number of Events prodced, including duplicates that
+/// weren't inserted, and the same broken down by analyzer.
 ///
 /// Synchronously retrieve metadata for connected works.
 ///
 /// This is transactional with respect to the queue polled and Events inserted.
 /// Writes to entities table do not occur in the same transaction, allowing the
 /// creation (and deduplicatoin) of identifiers to be effectively idempotent.
+///
+/// Records the last assertion_id processed in the `extract-last-assertion`
+/// checkpoint, in the same transaction.
+///
+/// If `analyzers` is given, only Events from those analyzers are extracted.
 pub(crate) async fn pump_n(
     pool: &Pool<Postgres>,
     batch_size: i32,
-) -> anyhow::Result<(usize, usize)> {
+    analyzers: Option<&[EventAnalyzerId]>,
+) -> anyhow::Result<(usize, usize, AnalyzerCounts)> {
     let mut tx = pool.begin().await?;
 
     let assertions = poll_assertions(batch_size, &mut tx).await?;
 
     let count_processed = assertions.len();
+    let last_assertion_id = assertions.last().map(|x| x.assertion_id);
 
-    let events = metadata_assertions_to_events(assertions);
+    let events = metadata_assertions_to_events(assertions, analyzers);
     let count_events = events.len();
+    let analyzer_counts = count_by_analyzer(&events);
+    let mut count_new = 0;
 
     for event in events {
         log::debug!("Extract Event: {:?}", event);
@@ -53,50 +76,153 @@ pub(crate) async fn pump_n(
         log::debug!("Get assertions...");
         // Subject entity should have a metadata assertion by now, as it was used to generate events.
         // Ensure it here for consistency.
+        // If fetches are deferred, these are queued for `--fetch-metadata` instead.
         if let (Some(ref identifier), Some(entity_id)) = (&event.subject_id, subject_entity_id) {
-            metadata_assertion::retrieve::ensure_metadata_assertion(
-                identifier, entity_id, &pool, &mut tx,
+            metadata_assertion::retrieve::request_metadata_assertion(
+                identifier, entity_id, pool, &mut tx,
             )
-            .await;
+            .await?;
         }
 
         // Object entity usually won't have metadata assertion yet.
         if let (Some(ref identifier), Some(entity_id)) = (&event.object_id, object_entity_id) {
-            metadata_assertion::retrieve::ensure_metadata_assertion(
-                identifier, entity_id, &pool, &mut tx,
+            metadata_assertion::retrieve::request_metadata_assertion(
+                identifier, entity_id, pool, &mut tx,
             )
-            .await;
+            .await?;
         }
 
         log::debug!("Insert...");
-        insert_event(
+        if insert_event(
             &event,
             subject_entity_id,
             object_entity_id,
             EventQueueState::New,
             &mut tx,
         )
-        .await?;
+        .await?
+        .is_some()
+        {
+            count_new += 1;
+        }
+    }
+
+    if count_events > 0 {
+        log::info!(
+            "Inserted {} new events, ignored {} duplicates",
+            count_new,
+            count_events - count_new
+        );
+
+        if let Some(stats) = identifier_cache_stats() {
+            log::debug!(
+                "Identifier cache: {} hits, {} misses, hit rate {:.2}",
+                stats.hits,
+                stats.misses,
+                stats.hit_rate()
+            );
+        }
+    }
+
+    if let Some(assertion_id) = last_assertion_id {
+        set_position_checkpoint(EXTRACT_LAST_ASSERTION, assertion_id, &mut tx).await?;
     }
 
     tx.commit().await?;
 
-    Ok((count_processed, count_events))
+    Ok((count_processed, count_events, analyzer_counts))
+}
+
+/// Count the Events from each analyzer.
+fn count_by_analyzer(events: &[Event]) -> AnalyzerCounts {
+    let mut counts = AnalyzerCounts::new();
+    for event in events {
+        *counts.entry(event.analyzer.to_str_value()).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Counts as a list for logging, e.g. "contribution: 5, reference: 120".
+fn format_counts(counts: &AnalyzerCounts) -> String {
+    counts
+        .iter()
+        .map(|(analyzer, count)| format!("{}: {}", analyzer, count))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Extracts Events from a Metadata Assertion, optionally only for the given analyzers.
+type Extractor =
+    fn(&MetadataQueueEntry, Option<serde_json::Value>, Option<&[EventAnalyzerId]>) -> Vec<Event>;
+
+/// Extractor for Metadata Assertions from the source, if there is one, with its version.
+fn extractor_for(source: MetadataSourceId) -> Option<(Extractor, &'static str)> {
+    match source {
+        MetadataSourceId::Crossref => Some((crossref::extract_events, crossref::EXTRACTOR_VERSION)),
+        _ => None,
+    }
+}
+
+/// Run the extractor and record its version in each Event's JSON.
+fn extract_with(
+    (extractor, version): (Extractor, &str),
+    assertion: &MetadataQueueEntry,
+    json: Option<serde_json::Value>,
+    analyzers: Option<&[EventAnalyzerId]>,
+) -> Vec<Event> {
+    let mut events = extractor(assertion, json, analyzers);
+    for event in events.iter_mut() {
+        stamp_extractor_version(event, version);
+    }
+    events
+}
+
+/// Add the extractor version to the Event's JSON.
+/// Left alone if the JSON isn't an object.
+fn stamp_extractor_version(event: &mut Event, version: &str) {
+    if let Ok(serde_json::Value::Object(mut json)) = serde_json::from_str(&event.json) {
+        json.insert(
+            String::from(EXTRACTOR_FIELD),
+            serde_json::Value::String(String::from(version)),
+        );
+        event.json = serde_json::Value::Object(json).to_string();
+    }
 }
 
 /// Extract Events from the given Metadata Assertions.
-fn metadata_assertions_to_events(assertions: Vec<MetadataQueueEntry>) -> Vec<Event> {
+/// Each is passed to the extractor for its source. There are no Events from sources without one.
+fn metadata_assertions_to_events(
+    assertions: Vec<MetadataQueueEntry>,
+    analyzers: Option<&[EventAnalyzerId]>,
+) -> Vec<Event> {
     let mut results = vec![];
 
     for assertion in assertions {
+        // Only extract from the latest version of the metadata. The later version will be extracted in its turn.
+        if assertion.superseded {
+            log::debug!(
+                "Skip superseded assertion id {} for {:?}",
+                assertion.assertion_id,
+                assertion.subject_id()
+            );
+            continue;
+        }
+
         // There's no guarantee that the input will be JSON, depending on where it came from.
         // But parse this outside the handlers, else it forces each one to repeatedly deserialize.
-        let json = match serde_json::from_str(&assertion.json) {
-            Ok(json) => Some(json),
-            Err(_) => None,
+        let json = serde_json::from_str(&assertion.json).ok();
+
+        let Some(extractor) = extractor_for(MetadataSourceId::from_int_value(assertion.source_id))
+        else {
+            log::debug!(
+                "No extractor for source {} of assertion id {}",
+                assertion.source_id,
+                assertion.assertion_id
+            );
+            continue;
         };
 
-        let mut events = crossref::extract_events(&assertion, json);
+        let mut events = extract_with(extractor, &assertion, json, analyzers);
         log::info!(
             "Got {} events from assertion id  {} for {:?}",
             events.len(),
@@ -109,21 +235,204 @@ fn metadata_assertions_to_events(assertions: Vec<MetadataQueueEntry>) -> Vec<Eve
     results
 }
 
+/// Extract Events from a single Metadata Assertion, without inserting anything or touching the queue.
+/// Superseded assertions are extracted too. For developing and debugging extractors.
+/// Return None if there's no such assertion.
+pub(crate) async fn extract_one(
+    pool: &Pool<Postgres>,
+    assertion_id: i64,
+    analyzers: Option<&[EventAnalyzerId]>,
+) -> anyhow::Result<Option<Vec<Event>>> {
+    let Some(assertion) = get_assertion_by_id(assertion_id, pool).await? else {
+        return Ok(None);
+    };
+
+    if assertion.superseded {
+        log::warn!(
+            "Assertion id {} is superseded, so wouldn't be extracted from the queue.",
+            assertion_id
+        );
+    }
+
+    let json = serde_json::from_str(&assertion.json).ok();
+
+    let events = match extractor_for(MetadataSourceId::from_int_value(assertion.source_id)) {
+        Some(extractor) => extract_with(extractor, &assertion, json, analyzers),
+        None => {
+            log::warn!(
+                "No extractor for source {} of assertion id {}",
+                assertion.source_id,
+                assertion_id
+            );
+            vec![]
+        }
+    };
+
+    Ok(Some(events))
+}
+
 /// Poll the metadata queue and extract events.
-pub(crate) async fn drain(pool: &Pool<Postgres>) -> anyhow::Result<()> {
+/// If `analyzers` is given, only Events from those analyzers are extracted.
+/// If `once` is set, stop after one batch.
+pub(crate) async fn drain(
+    pool: &Pool<Postgres>,
+    analyzers: Option<&[EventAnalyzerId]>,
+    once: bool,
+) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    let last_assertion_id = get_position_checkpoint(EXTRACT_LAST_ASSERTION, &mut tx).await?;
+    tx.commit().await?;
+    log::info!(
+        "Start extraction. Last assertion extracted: {:?}",
+        last_assertion_id
+    );
+
     let mut count = BATCH_SIZE;
 
+    // Tallied across batches, including duplicates.
+    let mut total_counts = AnalyzerCounts::new();
+
     // Stop as soon as the page of events is not full, as it's the last page.
     while count >= BATCH_SIZE {
-        let (count_assertions_read, count_events_produced) = pump_n(pool, BATCH_SIZE).await?;
+        let (count_assertions_read, count_events_produced, analyzer_counts) =
+            pump_n(pool, BATCH_SIZE, analyzers).await?;
         count = count_assertions_read as i32;
 
+        for (analyzer, count) in analyzer_counts {
+            *total_counts.entry(analyzer).or_insert(0) += count;
+        }
+
         log::debug!(
             "Polled {} metadata assertions to make {} events",
             count_assertions_read,
             count_events_produced,
         );
+
+        if once {
+            break;
+        }
     }
 
+    log::info!(
+        "Stop extraction. Events by analyzer, including duplicates: {}",
+        format_counts(&total_counts)
+    );
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use scholarly_identifiers::identifiers::Identifier;
+
+    use super::*;
+
+    /// Assertion with the JSON of a Crossref article, from the given source.
+    fn entry(source: MetadataSourceId) -> MetadataQueueEntry {
+        let (subject_id_value, subject_id_type) =
+            Identifier::parse("10.33262/exploradordigital.v8i4.3221").to_id_string_pair();
+
+        MetadataQueueEntry {
+            source_id: source as i32,
+            json: fs::read_to_string("testing/unit/crossref-article.json").unwrap(),
+            subject_id_type: subject_id_type as i32,
+            subject_id_value,
+            assertion_id: 2,
+            superseded: false,
+        }
+    }
+
+    /// Assertions are only passed to the extractor for their source.
+    #[test]
+    fn dispatch_by_source() {
+        assert!(
+            !metadata_assertions_to_events(vec![entry(MetadataSourceId::Crossref)], None)
+                .is_empty()
+        );
+
+        for source in [
+            MetadataSourceId::Datacite,
+            MetadataSourceId::ContentNegotiation,
+            MetadataSourceId::Test,
+            MetadataSourceId::Unknown,
+        ] {
+            assert!(
+                metadata_assertions_to_events(vec![entry(source)], None).is_empty(),
+                "Expected no events from {:?}",
+                source
+            );
+        }
+    }
+
+    /// Every extracted Event records the extractor version.
+    #[test]
+    fn extractor_version() {
+        let events = metadata_assertions_to_events(vec![entry(MetadataSourceId::Crossref)], None);
+        assert!(!events.is_empty());
+
+        for event in events {
+            let json: serde_json::Value = serde_json::from_str(&event.json).unwrap();
+            assert_eq!(json[EXTRACTOR_FIELD], crossref::EXTRACTOR_VERSION);
+        }
+    }
+
+    /// An article with references, authors and other links has Events from several analyzers.
+    /// Each analyzer's count is what extracting only that analyzer produces.
+    #[test]
+    fn counts_for_mixed_assertion() {
+        let events = metadata_assertions_to_events(vec![entry(MetadataSourceId::Crossref)], None);
+        let counts = count_by_analyzer(&events);
+
+        assert!(counts.len() > 1, "Expected several analyzers: {:?}", counts);
+        assert_eq!(counts.values().sum::<usize>(), events.len());
+
+        for analyzer in EventAnalyzerId::all() {
+            let only = metadata_assertions_to_events(
+                vec![entry(MetadataSourceId::Crossref)],
+                Some(&[analyzer]),
+            );
+
+            assert_eq!(
+                counts
+                    .get(&analyzer.to_str_value())
+                    .copied()
+                    .unwrap_or_default(),
+                only.len(),
+                "Count for {:?}",
+                analyzer
+            );
+        }
+
+        let mut counts = AnalyzerCounts::new();
+        counts.insert(String::from("reference"), 120);
+        counts.insert(String::from("contribution"), 5);
+        assert_eq!(format_counts(&counts), "contribution: 5, reference: 120");
+    }
+
+    /// Existing fields are kept, and JSON that isn't an object is left alone.
+    #[test]
+    fn stamp_version() {
+        let mut event = Event {
+            event_id: -1,
+            analyzer: EventAnalyzerId::Reference,
+            source: MetadataSourceId::Crossref,
+            subject_id: None,
+            object_id: None,
+            assertion_id: 2,
+            json: String::from(r#"{"type":"references"}"#),
+            occurred_at: None,
+        };
+
+        stamp_extractor_version(&mut event, "crossref@1");
+        assert_eq!(
+            event.json,
+            r#"{"_extractor":"crossref@1","type":"references"}"#
+        );
+
+        event.json = String::from("[]");
+        stamp_extractor_version(&mut event, "crossref@1");
+        assert_eq!(event.json, "[]");
+    }
+}
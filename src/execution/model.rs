@@ -1,20 +1,45 @@
 //! Model for representing Handlers, and data going into and out of them.
 
+use std::sync::OnceLock;
+
 use scholarly_identifiers::identifiers::Identifier;
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
-use time::OffsetDateTime;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
 use crate::{
     db::source::{EventAnalyzerId, MetadataSourceId},
     util::VERSION,
 };
 
+static CUSTOM_ENVIRONMENT: OnceLock<serde_json::Map<String, serde_json::Value>> = OnceLock::new();
+
+/// Parse the custom values for the environment. Must be a JSON object.
+pub(crate) fn parse_custom_environment(
+    value: &str,
+) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+    match serde_json::from_str(value) {
+        Ok(serde_json::Value::Object(custom)) => Ok(custom),
+        Ok(_) => Err(String::from("Expected a JSON object.")),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Set the custom values available to all functions as `environment.custom`.
+/// Must be called before the V8 environment is initialized, as the environment is built into the startup snapshot.
+/// Only the first call has an effect.
+pub(crate) fn init_custom_environment(custom: serde_json::Map<String, serde_json::Value>) {
+    let _ = CUSTOM_ENVIRONMENT.set(custom);
+}
+
 /// Environment passed into each function execution.
 #[derive(Serialize, Deserialize)]
 pub(crate) struct Global {
     environment: String,
     version: String,
+
+    /// Deployment-specific values, from the HANDLER_ENV_JSON environment variable.
+    custom: serde_json::Map<String, serde_json::Value>,
 }
 
 impl Global {
@@ -22,6 +47,7 @@ impl Global {
         Global {
             environment: String::from("Pardalotus Metabeak"),
             version: String::from(VERSION),
+            custom: CUSTOM_ENVIRONMENT.get().cloned().unwrap_or_default(),
         }
     }
 
@@ -42,6 +68,16 @@ pub(crate) struct HandlerSpec {
 
     /// Weak reference to HandlerStatus for ease of database interaction.
     pub(crate) status: i32,
+
+    /// Maximum time the function may take to run for each Event, in milliseconds.
+    pub(crate) timeout_ms: i32,
+
+    /// SHA-1 hash of the code, used to find existing handlers with the same code.
+    /// None prior to saving.
+    pub(crate) hash: Option<String>,
+
+    /// Owner of the handler. 0 for handlers without an owner, e.g. loaded from disk.
+    pub(crate) owner_id: i32,
 }
 
 /// Input data for a handler function run.
@@ -66,6 +102,9 @@ pub(crate) struct Event {
     // Remainder of the JSON structure once the hydrated fields have been removed.
     // See DR-0012.
     pub(crate) json: String,
+
+    // Logical time of the Event, if known. When not supplied, it's the time the Event is inserted.
+    pub(crate) occurred_at: Option<OffsetDateTime>,
 }
 
 /// Equality based on the JSON value.
@@ -77,6 +116,7 @@ impl PartialEq for Event {
             && self.subject_id == other.subject_id
             && self.object_id == other.object_id
             && self.assertion_id == other.assertion_id
+            && self.occurred_at == other.occurred_at
             && if let (Ok(self_json), Ok(other_json)) = (
                 serde_json::from_str::<serde_json::Value>(&self.json),
                 serde_json::from_str::<serde_json::Value>(&other.json),
@@ -88,6 +128,102 @@ impl PartialEq for Event {
     }
 }
 
+/// Host of the Handle System proxy.
+const HANDLE_PROXY_HOST: &str = "hdl.handle.net";
+
+/// Is this a Handle, expressed as a URI on the Handle proxy?
+/// There's no Handle type in `scholarly_identifiers`, so Handles are represented as URIs and recognised here.
+pub(crate) fn is_handle(identifier: &Identifier) -> bool {
+    if let Identifier::Uri(uri) = identifier {
+        let lower = uri.to_lowercase();
+        let without_scheme = lower
+            .strip_prefix("https://")
+            .or(lower.strip_prefix("http://"));
+
+        without_scheme
+            .and_then(|x| x.strip_prefix(HANDLE_PROXY_HOST))
+            .map(|x| x.starts_with('/') && x.len() > 1)
+            .unwrap_or(false)
+    } else {
+        false
+    }
+}
+
+/// Prefix of the URI for an arXiv abstract page, used to represent arXiv ids.
+const ARXIV_ABS_PREFIX: &str = "https://arxiv.org/abs/";
+
+/// Is this a plausible arXiv id, with an optional version? Either new style, e.g. "2101.00001v2",
+/// or old style, e.g. "hep-th/9901001" or "math.AG/0101001".
+fn is_arxiv_id(id: &str) -> bool {
+    let all_digits = |x: &str| !x.is_empty() && x.chars().all(|c| c.is_ascii_digit());
+
+    let without_version = match id.rsplit_once('v') {
+        Some((base, version)) if all_digits(version) => base,
+        _ => id,
+    };
+
+    if let Some((archive, number)) = without_version.split_once('/') {
+        !archive.is_empty()
+            && archive
+                .chars()
+                .all(|c| c.is_ascii_alphabetic() || c == '-' || c == '.')
+            && number.len() == 7
+            && all_digits(number)
+    } else if let Some((year_month, number)) = without_version.split_once('.') {
+        year_month.len() == 4
+            && all_digits(year_month)
+            && (number.len() == 4 || number.len() == 5)
+            && all_digits(number)
+    } else {
+        false
+    }
+}
+
+/// Normalize an arXiv id, e.g. "arXiv:2101.00001", "2101.00001" or an arxiv.org URL, to an Identifier.
+/// There's no arXiv type in `scholarly_identifiers`, so they're represented as the URI of the abstract page, like Handles.
+/// None if it isn't an arXiv id.
+pub(crate) fn arxiv_identifier(value: &str) -> Option<Identifier> {
+    let value = value.trim();
+    let lower = value.to_lowercase();
+
+    let without_scheme = lower
+        .strip_prefix("https://")
+        .or(lower.strip_prefix("http://"))
+        .map(|x| x.strip_prefix("www.").unwrap_or(x));
+
+    // Take the id from the original value, as old style subject classes are upper case.
+    let id = if let Some(path) = without_scheme {
+        let path = path
+            .strip_prefix("arxiv.org/abs/")
+            .or(path.strip_prefix("arxiv.org/pdf/"))?;
+        let id = &value[value.len() - path.len()..];
+        id.strip_suffix(".pdf").unwrap_or(id)
+    } else if lower.starts_with("arxiv:") {
+        &value["arxiv:".len()..]
+    } else {
+        value
+    };
+
+    is_arxiv_id(id).then(|| Identifier::Uri(format!("{}{}", ARXIV_ABS_PREFIX, id)))
+}
+
+/// Is this an arXiv id, as represented by [arxiv_identifier]?
+pub(crate) fn is_arxiv(identifier: &Identifier) -> bool {
+    matches!(identifier, Identifier::Uri(uri) if uri.starts_with(ARXIV_ABS_PREFIX))
+}
+
+/// Parse an identifier from the public representation.
+/// As well as the types recognised by `scholarly_identifiers`, accepts arXiv ids with an "arXiv:" prefix.
+pub(crate) fn parse_identifier(value: &str) -> Identifier {
+    if value.trim().to_lowercase().starts_with("arxiv:") {
+        if let Some(identifier) = arxiv_identifier(value) {
+            return identifier;
+        }
+    }
+
+    Identifier::parse(value)
+}
+
 /// Map an Identifier Type to the value passed to the Handler.
 fn identifier_type_string(identifier: &Identifier) -> serde_json::Value {
     serde_json::Value::String(String::from(match identifier {
@@ -97,6 +233,8 @@ fn identifier_type_string(identifier: &Identifier) -> serde_json::Value {
         } => "doi",
         Identifier::Orcid(_) => "orcid",
         Identifier::Ror(_) => "ror",
+        Identifier::Uri(_) if is_handle(identifier) => "handle",
+        Identifier::Uri(_) if is_arxiv(identifier) => "arxiv",
         Identifier::Uri(_) => "uri",
         Identifier::String(_) => "string",
         Identifier::Isbn(_) => "isbn",
@@ -111,11 +249,28 @@ fn is_hydrated_field(field: &str) -> bool {
         || field.eq("subject_id_type")
         || field.eq("object_id")
         || field.eq("object_id_type")
+        || field.eq("occurred_at")
 }
 
+/// Field for the stored, unhydrated, JSON, when it's passed to handlers.
+const RAW_FIELD: &str = "_raw";
+
+/// Field in the stored JSON recording the extractor version that produced the Event, e.g. "crossref@1".
+/// Events extracted before it was introduced, or loaded from elsewhere, don't have it.
+pub(crate) const EXTRACTOR_FIELD: &str = "_extractor";
+
+/// Field in the stored JSON with the work type of the subject, e.g. "journal-article", if it's known.
+pub(crate) const SUBJECT_TYPE_FIELD: &str = "subject_type";
+
 impl Event {
     /// Serialize to a public JSON representation, hydrating some fields from database values.
     pub(crate) fn to_json_value(&self) -> Option<String> {
+        self.to_handler_json_value(false)
+    }
+
+    /// Serialize to the public JSON representation.
+    /// If `include_raw` is set, the stored JSON string is included as `_raw`, for debugging.
+    pub(crate) fn to_handler_json_value(&self, include_raw: bool) -> Option<String> {
         let analyzer_value = serde_json::Value::String(self.analyzer.to_str_value());
         let source_value = serde_json::Value::String(self.source.to_str_value());
 
@@ -161,6 +316,22 @@ impl Event {
                         }
                     }
 
+                    if let Some(occurred_at) =
+                        self.occurred_at.and_then(|x| x.format(&Rfc3339).ok())
+                    {
+                        data_obj.insert(
+                            String::from("occurred_at"),
+                            serde_json::Value::String(occurred_at),
+                        );
+                    }
+
+                    if include_raw {
+                        data_obj.insert(
+                            String::from(RAW_FIELD),
+                            serde_json::Value::String(self.json.clone()),
+                        );
+                    }
+
                     if let Ok(json) = serde_json::to_string(&serde_json::Value::Object(data_obj)) {
                         Some(json)
                     } else {
@@ -185,6 +356,58 @@ impl Event {
         }
     }
 
+    /// Check that a JSON value in the public representation can be loaded as an Event.
+    /// Return all of the problems found, not just the first.
+    pub(crate) fn validate_json(value: &serde_json::Value) -> Result<(), Vec<String>> {
+        let mut errors = vec![];
+
+        let data_obj = match value.as_object() {
+            Some(data_obj) => data_obj,
+            None => return Err(vec![String::from("Event must be a JSON object.")]),
+        };
+
+        for field in ["analyzer", "source"] {
+            match data_obj.get(field) {
+                Some(serde_json::Value::String(_)) => {}
+                Some(_) => errors.push(format!("Field '{}' must be a string.", field)),
+                None => errors.push(format!("Missing required field '{}'.", field)),
+            }
+        }
+
+        if let Some(value) = data_obj.get("event_id") {
+            if !value.is_i64() {
+                errors.push(String::from("Field 'event_id' must be an integer."));
+            }
+        }
+
+        for field in ["subject_id", "object_id"] {
+            match data_obj.get(field) {
+                None => {}
+                Some(serde_json::Value::String(value)) if value.trim().is_empty() => {
+                    errors.push(format!("Field '{}' must not be empty.", field))
+                }
+                // Values that aren't a recognised type of identifier are kept as plain strings.
+                Some(serde_json::Value::String(_)) => {}
+                Some(_) => errors.push(format!("Field '{}' must be a string.", field)),
+            }
+        }
+
+        match data_obj.get("occurred_at") {
+            None => {}
+            Some(serde_json::Value::String(value))
+                if OffsetDateTime::parse(value, &Rfc3339).is_ok() => {}
+            Some(_) => errors.push(String::from(
+                "Field 'occurred_at' must be an ISO 8601 date-time, e.g. '2024-01-01T00:00:00Z'.",
+            )),
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
     /// Load a JSON event from the public JSON representation.
     /// None if there was a problem parsing it.
     /// This clones subfields of the JSON Value, and is on a hot path. Candidate for optimisation if needed.
@@ -209,19 +432,30 @@ impl Event {
                     };
 
                     let subject_id = if let Some(val) = data_obj.get("subject_id") {
-                        val.as_str().map(Identifier::parse)
+                        val.as_str().map(parse_identifier)
                     } else {
                         None
                     };
 
                     let object_id = if let Some(val) = data_obj.get("object_id") {
-                        val.as_str().map(Identifier::parse)
+                        val.as_str().map(parse_identifier)
                     } else {
                         None
                     };
 
+                    // When absent, the insertion time is used.
+                    let occurred_at = data_obj
+                        .get("occurred_at")
+                        .and_then(|x| x.as_str())
+                        .and_then(|x| OffsetDateTime::parse(x, &Rfc3339).ok());
+
                     let mut normalized_event = serde_json::Map::new();
                     for field in data_obj.keys() {
+                        // Only ever passed to handlers, never stored.
+                        if field == RAW_FIELD {
+                            continue;
+                        }
+
                         if is_hydrated_field(field) {
                             if let Some(obj) = data_obj.get(field) {
                                 normalized_event.insert(field.clone(), obj.clone());
@@ -239,6 +473,7 @@ impl Event {
                             object_id,
                             assertion_id,
                             json,
+                            occurred_at,
                         })
                     } else {
                         // Highly unlikely.
@@ -259,6 +494,46 @@ impl Event {
     }
 }
 
+/// Kind of error from a handler function run, so that errors can be distinguished without parsing the message.
+/// Stored as an integer.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, sqlx::Type)]
+#[repr(i32)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ErrorKind {
+    /// The code couldn't be compiled.
+    LoadCompile = 1,
+
+    /// The code threw an exception when it was loaded.
+    LoadRuntime = 2,
+
+    /// Load or execution took too long and was terminated.
+    Timeout = 3,
+
+    /// The code didn't define the function, or it wasn't a function.
+    NoFunction = 4,
+
+    /// The function didn't return an array of JSON-serializable results.
+    BadReturn = 5,
+
+    /// The function threw an exception when it was run.
+    ExecRuntime = 6,
+
+    /// The isolate exceeded its memory limit and was terminated.
+    MemoryLimit = 7,
+
+    /// A result was larger than the maximum size, so it wasn't stored.
+    ResultTooLarge = 8,
+
+    /// The function returned more results than the maximum, so the remainder weren't stored.
+    TooManyResults = 9,
+
+    /// The handler used up its time budget for the batch, so wasn't run for the Event.
+    TimeBudget = 10,
+
+    /// The result couldn't be saved, e.g. because the database rejected its content.
+    SaveFailed = 11,
+}
+
 /// Result from a handler function run.
 /// A handler function returns an array of results. There will be one of these objects per entry.
 #[derive(Debug, PartialEq, FromRow, Serialize)]
@@ -273,12 +548,234 @@ pub(crate) struct ExecutionResult {
     /// ID of the event it was triggered from.
     pub(crate) event_id: i64,
 
+    /// ID of the metadata assertion that generated the event, for provenance.
+    /// -1 if the event was imported, or the result isn't for a single event.
+    pub(crate) assertion_id: i64,
+
     /// Single JSON object.
     pub(crate) result: Option<String>,
 
     /// Error string, if execution failed.
     pub(crate) error: Option<String>,
 
+    /// Kind of error, if execution failed.
+    pub(crate) error_kind: Option<ErrorKind>,
+
     #[serde(with = "time::serde::iso8601::option")]
     pub(crate) created: Option<OffsetDateTime>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_json_ok() {
+        let value = serde_json::json!({
+            "source": "test",
+            "analyzer": "test",
+            "subject_id": "https://doi.org/10.5555/12345678",
+            "object_id": "https://orcid.org/0000-0002-1825-0097",
+        });
+
+        assert_eq!(Event::validate_json(&value), Ok(()));
+    }
+
+    /// Custom environment values must be a JSON object.
+    #[test]
+    fn parse_custom_environment_object() {
+        let custom = parse_custom_environment(r#"{"corpus": "books", "beta": true}"#).unwrap();
+        assert_eq!(custom.get("corpus"), Some(&serde_json::json!("books")));
+        assert_eq!(custom.get("beta"), Some(&serde_json::json!(true)));
+
+        assert!(parse_custom_environment("[1, 2]").is_err());
+        assert!(parse_custom_environment("{corpus: books}").is_err());
+    }
+
+    /// All errors are reported together.
+    #[test]
+    fn validate_json_errors() {
+        let value = serde_json::json!({
+            "source": 1,
+            "event_id": "one",
+            "subject_id": "",
+            "object_id": 5,
+        });
+
+        assert_eq!(
+            Event::validate_json(&value),
+            Err(vec![
+                String::from("Missing required field 'analyzer'."),
+                String::from("Field 'source' must be a string."),
+                String::from("Field 'event_id' must be an integer."),
+                String::from("Field 'subject_id' must not be empty."),
+                String::from("Field 'object_id' must be a string."),
+            ])
+        );
+    }
+
+    /// Identifiers that aren't a recognised type are accepted, and loaded as plain strings.
+    #[test]
+    fn validate_json_plain_string() {
+        let value = serde_json::json!({
+            "source": "test",
+            "analyzer": "test",
+            "subject_id": "not an identifier",
+            "object_id": "local-id-1234",
+        });
+
+        assert_eq!(Event::validate_json(&value), Ok(()));
+
+        let event = Event::from_json_value(&value.to_string()).unwrap();
+        assert!(matches!(event.subject_id, Some(Identifier::String(_))));
+    }
+
+    /// Handle URIs are tagged as Handles when hydrated, and round-trip through the public representation.
+    #[test]
+    fn handle_subject_type() {
+        let event = Event {
+            event_id: 1,
+            analyzer: EventAnalyzerId::Test,
+            source: MetadataSourceId::Test,
+            subject_id: Some(Identifier::parse("https://hdl.handle.net/10013/epic.12345")),
+            object_id: Some(Identifier::parse("https://example.com/10013/epic.12345")),
+            json: String::from("{}"),
+            assertion_id: -1,
+            occurred_at: None,
+        };
+
+        let json: serde_json::Value =
+            serde_json::from_str(&event.to_json_value().unwrap()).unwrap();
+
+        assert_eq!(json["subject_id_type"], "handle");
+        assert_eq!(json["object_id_type"], "uri", "Other URIs are unchanged.");
+
+        let reloaded = Event::from_json_value(&json.to_string()).unwrap();
+        assert!(reloaded.subject_id.as_ref().is_some_and(is_handle));
+    }
+
+    /// A supplied timestamp round-trips through the public representation.
+    #[test]
+    fn occurred_at_round_trip() {
+        let event = Event::from_json_value(
+            r#"{"source": "test", "analyzer": "test", "occurred_at": "2019-03-04T05:06:07Z"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            event.occurred_at,
+            Some(OffsetDateTime::parse("2019-03-04T05:06:07Z", &Rfc3339).unwrap())
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&event.to_json_value().unwrap()).unwrap();
+        assert_eq!(json["occurred_at"], "2019-03-04T05:06:07Z");
+
+        let reloaded = Event::from_json_value(&json.to_string()).unwrap();
+        assert_eq!(reloaded.occurred_at, event.occurred_at);
+    }
+
+    /// The stored JSON can be passed to handlers as `_raw`, but isn't stored again.
+    #[test]
+    fn raw_json() {
+        let event = Event {
+            event_id: 1,
+            analyzer: EventAnalyzerId::Test,
+            source: MetadataSourceId::Test,
+            subject_id: Some(Identifier::parse("10.5555/12345678")),
+            object_id: None,
+            json: String::from(r#"{"type":"test"}"#),
+            assertion_id: -1,
+            occurred_at: None,
+        };
+
+        let json: serde_json::Value =
+            serde_json::from_str(&event.to_json_value().unwrap()).unwrap();
+        assert!(json.get("_raw").is_none(), "Not included by default.");
+
+        let json: serde_json::Value =
+            serde_json::from_str(&event.to_handler_json_value(true).unwrap()).unwrap();
+        assert_eq!(json["_raw"], r#"{"type":"test"}"#);
+        assert_eq!(json["subject_id_type"], "doi");
+
+        let reloaded = Event::from_json_value(&json.to_string()).unwrap();
+        assert!(!reloaded.json.contains("_raw"));
+    }
+
+    /// Without a timestamp, none is supplied, so the insertion time is used.
+    #[test]
+    fn occurred_at_absent() {
+        let event = Event::from_json_value(r#"{"source": "test", "analyzer": "test"}"#).unwrap();
+        assert_eq!(event.occurred_at, None);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&event.to_json_value().unwrap()).unwrap();
+        assert!(json.get("occurred_at").is_none());
+
+        assert_eq!(
+            Event::validate_json(&serde_json::json!({
+                "source": "test",
+                "analyzer": "test",
+                "occurred_at": "last tuesday",
+            })),
+            Err(vec![String::from(
+                "Field 'occurred_at' must be an ISO 8601 date-time, e.g. '2024-01-01T00:00:00Z'."
+            )])
+        );
+    }
+
+    /// arXiv ids in their various forms are normalized to the abstract page, and tagged as arXiv ids when hydrated.
+    #[test]
+    fn arxiv_object_type() {
+        let expected = Some(Identifier::Uri(String::from(
+            "https://arxiv.org/abs/2101.00001",
+        )));
+        assert_eq!(arxiv_identifier("arXiv:2101.00001"), expected);
+        assert_eq!(arxiv_identifier("2101.00001"), expected);
+        assert_eq!(
+            arxiv_identifier("https://arxiv.org/pdf/2101.00001.pdf"),
+            expected
+        );
+        assert_eq!(
+            arxiv_identifier("http://www.arxiv.org/abs/math.AG/0101001v2"),
+            Some(Identifier::Uri(String::from(
+                "https://arxiv.org/abs/math.AG/0101001v2"
+            )))
+        );
+        assert_eq!(arxiv_identifier("10.5555/12345678"), None);
+        assert_eq!(arxiv_identifier("arXiv:12345"), None);
+
+        let event = Event::from_json_value(
+            r#"{"source": "test", "analyzer": "test", "object_id": "arXiv:2101.00001"}"#,
+        )
+        .unwrap();
+        assert_eq!(event.object_id, expected);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&event.to_json_value().unwrap()).unwrap();
+        assert_eq!(json["object_id_type"], "arxiv");
+        assert_eq!(json["object_id_uri"], "https://arxiv.org/abs/2101.00001");
+    }
+
+    #[test]
+    fn is_handle_uris() {
+        assert!(is_handle(&Identifier::Uri(String::from(
+            "http://hdl.handle.net/1721.1/12345"
+        ))));
+        assert!(!is_handle(&Identifier::Uri(String::from(
+            "https://hdl.handle.net/"
+        ))));
+        assert!(!is_handle(&Identifier::Uri(String::from(
+            "https://hdl.handle.net.example.com/1721.1/12345"
+        ))));
+        assert!(!is_handle(&Identifier::parse("10.5555/12345678")));
+    }
+
+    #[test]
+    fn validate_json_not_object() {
+        assert_eq!(
+            Event::validate_json(&serde_json::json!(["source", "analyzer"])),
+            Err(vec![String::from("Event must be a JSON object.")])
+        );
+    }
+}
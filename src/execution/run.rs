@@ -2,35 +2,355 @@
 //! For each function, spin up a V8 environment and execute the function.
 
 use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    env,
+    ffi::c_void,
     sync::{
+        atomic::{AtomicBool, Ordering},
         mpsc::{self, RecvTimeoutError},
-        Once,
+        Arc, Mutex, Once, OnceLock,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use v8::{Context, Function, HandleScope, IsolateHandle, Local, Object, V8};
+use v8::{Context, Function, HandleScope, IsolateHandle, Local, Object, OwnedIsolate, V8};
 
 use crate::execution::model::Global;
+use crate::util::{env_or_default, parse_flag};
 
-use super::model::{Event, ExecutionResult, HandlerSpec};
+use super::model::{ErrorKind, Event, ExecutionResult, HandlerSpec};
 
 static V8_INITIALIZED: Once = Once::new();
 
-// Maximum time a JS execution can take.
-static EXECUTION_TIMEOUT: Duration = Duration::from_millis(10);
+// Maximum time a JS execution can take, unless the handler specifies otherwise.
+pub(crate) const DEFAULT_EXECUTION_TIMEOUT_MS: i32 = 10;
 
 // Maximum time a JS load can take. This takes a while as the environment is set up.
 static LOAD_TIMEOUT: Duration = Duration::from_millis(10);
 
-/// Initialize the V8 environment.
+// How often the watchdog wakes to check for cancellation.
+static WATCHDOG_TICK: Duration = Duration::from_millis(10);
+
+/// Execution of a batch was cancelled before it completed.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Cancelled;
+
+const MEMORY_LIMIT_MESSAGE: &str = "Handler exceeded memory limit and was terminated.";
+
+// Default maximum heap size for each isolate.
+const DEFAULT_HEAP_LIMIT_MB: usize = 64;
+
+static HEAP_LIMIT_BYTES: OnceLock<usize> = OnceLock::new();
+
+/// Maximum heap size for each isolate, in bytes.
+/// Configured from the METABEAK_HEAP_LIMIT_MB environment variable, read once.
+fn heap_limit_bytes() -> usize {
+    *HEAP_LIMIT_BYTES.get_or_init(|| {
+        let mb = match env::var("METABEAK_HEAP_LIMIT_MB").map(|x| x.parse::<usize>()) {
+            Ok(Ok(mb)) if mb > 0 => mb,
+            Ok(_) => {
+                log::error!(
+                    "Invalid METABEAK_HEAP_LIMIT_MB, using default {}MB",
+                    DEFAULT_HEAP_LIMIT_MB
+                );
+                DEFAULT_HEAP_LIMIT_MB
+            }
+            Err(_) => DEFAULT_HEAP_LIMIT_MB,
+        };
+        mb * 1024 * 1024
+    })
+}
+
+// Default maximum number of results from each function call.
+const DEFAULT_MAX_RESULTS_PER_EVENT: usize = 1000;
+
+static MAX_RESULTS_PER_EVENT: OnceLock<usize> = OnceLock::new();
+
+/// Maximum number of results stored from each function call.
+/// Configured from the MAX_RESULTS_PER_EVENT environment variable, read once.
+fn max_results_per_event() -> usize {
+    *MAX_RESULTS_PER_EVENT
+        .get_or_init(|| env_or_default("MAX_RESULTS_PER_EVENT", DEFAULT_MAX_RESULTS_PER_EVENT))
+}
+
+// Default maximum size of each serialized result.
+const DEFAULT_MAX_RESULT_KB: usize = 256;
+
+static MAX_RESULT_BYTES: OnceLock<usize> = OnceLock::new();
+
+/// Maximum size of each serialized result, in bytes.
+/// Configured from the METABEAK_MAX_RESULT_KB environment variable, read once.
+fn max_result_bytes() -> usize {
+    *MAX_RESULT_BYTES
+        .get_or_init(|| env_or_default("METABEAK_MAX_RESULT_KB", DEFAULT_MAX_RESULT_KB) * 1024)
+}
+
+static INCLUDE_RAW_EVENT: OnceLock<bool> = OnceLock::new();
+
+/// Pass the stored Event JSON to handlers as `_raw`, as well as the hydrated fields.
+/// Configured from the HANDLER_INCLUDE_RAW_EVENT environment variable, read once.
+fn include_raw_event() -> bool {
+    *INCLUDE_RAW_EVENT
+        .get_or_init(|| parse_flag(env::var("HANDLER_INCLUDE_RAW_EVENT").ok().as_deref()))
+}
+
+static HANDLER_TIME_BUDGET: OnceLock<Option<Duration>> = OnceLock::new();
+
+/// Most time each handler can spend running its function over the Events of a batch, or None for no limit.
+/// Configured from the METABEAK_HANDLER_BUDGET_MS environment variable, read once. Zero, the default, is no limit.
+fn handler_time_budget() -> Option<Duration> {
+    *HANDLER_TIME_BUDGET.get_or_init(|| {
+        let ms: u64 = env_or_default("METABEAK_HANDLER_BUDGET_MS", 0);
+        (ms > 0).then(|| Duration::from_millis(ms))
+    })
+}
+
+static TIMEOUT_GRACE: OnceLock<Duration> = OnceLock::new();
+
+/// Extra time allowed past every timeout before the watchdog terminates a handler.
+/// Under load a fast handler can be preempted long enough to overrun a short timeout, so this trades
+/// prompt termination of slow handlers for fewer spurious timeouts.
+/// Configured from the METABEAK_TIMEOUT_GRACE_MS environment variable, read once. Default is none.
+fn timeout_grace() -> Duration {
+    *TIMEOUT_GRACE
+        .get_or_init(|| Duration::from_millis(env_or_default("METABEAK_TIMEOUT_GRACE_MS", 0)))
+}
+
+static ISOLATE_POOLING: OnceLock<bool> = OnceLock::new();
+
+/// Keep each handler's isolate, with its code loaded, between batches. See DR-0020.
+/// Configured from the METABEAK_ISOLATE_POOL environment variable, read once.
+fn isolate_pooling() -> bool {
+    *ISOLATE_POOLING.get_or_init(|| parse_flag(env::var("METABEAK_ISOLATE_POOL").ok().as_deref()))
+}
+
+/// An isolate for a handler, with its context and function once the code is loaded.
+///
+/// rusty_v8 enters an isolate when it's created, and requires isolates to be dropped in reverse order. Pooled isolates
+/// outlive the batch that created them, so they're exited straight after creation, only entered while in use, and
+/// entered again to be dropped.
+struct HandlerIsolate {
+    handler_id: i64,
+    code: String,
+
+    /// Declared before the isolate so that the handles are dropped before it.
+    loaded: Option<(v8::Global<Context>, v8::Global<Function>, FunctionKind)>,
+
+    isolate: OwnedIsolate,
+
+    /// Declared after the isolate so that it's dropped after it.
+    /// Boxed so it doesn't move, as the near-heap-limit callback has a pointer to it.
+    heap_state: Box<HeapLimitState>,
+}
+
+impl HandlerIsolate {
+    fn new(handler_spec: &HandlerSpec) -> HandlerIsolate {
+        let heap_state = Box::new(HeapLimitState::new());
+        let mut isolate = create_isolate(&heap_state);
+
+        // Safety: it was entered on creation, so it's the current isolate.
+        unsafe { isolate.exit() };
+
+        HandlerIsolate {
+            handler_id: handler_spec.handler_id,
+            code: handler_spec.code.clone(),
+            loaded: None,
+            isolate,
+            heap_state,
+        }
+    }
+}
+
+impl Drop for HandlerIsolate {
+    fn drop(&mut self) {
+        // Safety: it's exited when the isolate is dropped.
+        unsafe { self.isolate.enter() };
+        self.loaded = None;
+    }
+}
+
+/// Handlers whose isolates can stay pooled, as (handler id, code).
+type RetainedHandlers = Arc<Vec<(i64, String)>>;
+
+/// The handlers set by [retain_pooled_isolates], if any.
+/// The generation increases each time it's set, so each thread can tell if it has applied the latest.
+static POOL_RETAIN: Mutex<(u64, Option<RetainedHandlers>)> = Mutex::new((0, None));
+
+thread_local! {
+    /// Isolates are tied to the thread that created them, so each thread has its own pool.
+    static ISOLATE_POOL: RefCell<Vec<HandlerIsolate>> = const { RefCell::new(Vec::new()) };
+
+    /// Generation of [POOL_RETAIN] last applied to this thread's pool.
+    static POOL_GENERATION: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Use this thread's pool, first dropping any isolates that [retain_pooled_isolates] has excluded since it was last used.
+fn with_pool<T>(f: impl FnOnce(&mut Vec<HandlerIsolate>) -> T) -> T {
+    let (generation, handlers) = POOL_RETAIN.lock().unwrap().clone();
+
+    ISOLATE_POOL.with_borrow_mut(|pool| {
+        if generation != POOL_GENERATION.get() {
+            let handlers = handlers.unwrap_or_default();
+            pool.retain(|x| {
+                handlers
+                    .iter()
+                    .any(|(handler_id, code)| *handler_id == x.handler_id && *code == x.code)
+            });
+            POOL_GENERATION.set(generation);
+        }
+
+        f(pool)
+    })
+}
+
+/// Take the isolate for the handler from this thread's pool, if there is one with the same code.
+fn take_pooled_isolate(handler_spec: &HandlerSpec) -> Option<HandlerIsolate> {
+    with_pool(|pool| {
+        let index = pool
+            .iter()
+            .position(|x| x.handler_id == handler_spec.handler_id)?;
+        let handler_isolate = pool.swap_remove(index);
+
+        // If the code has changed, the isolate is dropped here.
+        (handler_isolate.code == handler_spec.code).then_some(handler_isolate)
+    })
+}
+
+/// Keep the isolate in this thread's pool for the next batch.
+fn pool_isolate(handler_isolate: HandlerIsolate) {
+    with_pool(|pool| pool.push(handler_isolate));
+}
+
+/// Drop pooled isolates for handlers that aren't in the list, e.g. because they were disabled.
+/// The current thread's pool is updated now. Isolates can only be dropped on the thread that created them,
+/// so other threads' pools are updated the next time they're used.
+pub(crate) fn retain_pooled_isolates(handlers: &[HandlerSpec]) {
+    {
+        let mut retain = POOL_RETAIN.lock().unwrap();
+        retain.0 += 1;
+        retain.1 = Some(Arc::new(
+            handlers
+                .iter()
+                .map(|x| (x.handler_id, x.code.clone()))
+                .collect(),
+        ));
+    }
+
+    with_pool(|_| ());
+}
+
+/// State shared with the near-heap-limit callback for an isolate.
+/// Must outlive the isolate it's registered with.
+struct HeapLimitState {
+    handle: OnceLock<IsolateHandle>,
+    exceeded: AtomicBool,
+}
+
+impl HeapLimitState {
+    fn new() -> HeapLimitState {
+        HeapLimitState {
+            handle: OnceLock::new(),
+            exceeded: AtomicBool::new(false),
+        }
+    }
+
+    fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::SeqCst)
+    }
+}
+
+/// Called by V8 when the isolate approaches its heap limit.
+/// Terminate execution and raise the limit, giving V8 room to unwind rather than abort the process.
+extern "C" fn near_heap_limit_callback(
+    data: *mut c_void,
+    current_heap_limit: usize,
+    _initial_heap_limit: usize,
+) -> usize {
+    // Safety: data is the HeapLimitState registered in `create_isolate`, which outlives the isolate.
+    let state = unsafe { &*(data as *const HeapLimitState) };
+    state.exceeded.store(true, Ordering::SeqCst);
+    if let Some(handle) = state.handle.get() {
+        handle.terminate_execution();
+    }
+
+    current_heap_limit * 2
+}
+
+/// Create an isolate with a heap limit, reporting to the given state when it's exceeded.
+/// Isolates are created from the startup snapshot if it's available, so the context already has the global 'environment' variable.
+fn create_isolate(heap_state: &HeapLimitState) -> OwnedIsolate {
+    let params = v8::CreateParams::default().heap_limits(0, heap_limit_bytes());
+    let params = match snapshot() {
+        Some(blob) => params.snapshot_blob(blob),
+        None => params,
+    };
+
+    let mut isolate = v8::Isolate::new(params);
+
+    // Microtasks, i.e. Promise callbacks, are only run by `settle_promise`, while the watchdog is timing the call.
+    isolate.set_microtasks_policy(v8::MicrotasksPolicy::Explicit);
+
+    // Only set once per state.
+    let _ = heap_state.handle.set(isolate.thread_safe_handle());
+
+    isolate.add_near_heap_limit_callback(
+        near_heap_limit_callback,
+        heap_state as *const HeapLimitState as *mut c_void,
+    );
+
+    isolate
+}
+
+/// Startup snapshot with the global environment already set up. See DR-0019.
+static SNAPSHOT: OnceLock<Option<Vec<u8>>> = OnceLock::new();
+
+/// The startup snapshot, if it was created.
+fn snapshot() -> Option<&'static [u8]> {
+    SNAPSHOT.get().and_then(|x| x.as_deref())
+}
+
+/// Build a startup snapshot whose default context has the global 'environment' variable.
+fn create_snapshot() -> Option<Vec<u8>> {
+    let mut snapshot_creator = v8::Isolate::snapshot_creator(None, None);
+    {
+        let scope = &mut v8::HandleScope::new(&mut snapshot_creator);
+        let context = v8::Context::new(scope, Default::default());
+        {
+            let context_scope = &mut v8::ContextScope::new(scope, context);
+            let global = context.global(context_scope);
+            set_variable_from_json(
+                context_scope,
+                global,
+                "environment",
+                &Global::build().json(),
+            );
+        }
+        scope.set_default_context(context);
+    }
+
+    snapshot_creator
+        .create_blob(v8::FunctionCodeHandling::Keep)
+        .map(|blob| blob.to_vec())
+}
+
+/// Initialize the V8 environment, and build the startup snapshot.
 /// Guard against re-initialization to make this safe to use, especially calling from tests.
+/// V8 aborts the process if it can't be initialized, so only call this if handlers will be run.
 pub(crate) fn init() {
     V8_INITIALIZED.call_once(|| {
         let platform = v8::new_default_platform(0, false).make_shared();
         V8::initialize_platform(platform);
         V8::initialize();
+
+        let snapshot = create_snapshot();
+        if snapshot.is_none() {
+            log::error!(
+                "Failed to create V8 startup snapshot. Isolates will be set up individually."
+            );
+        }
+        let _ = SNAPSHOT.set(snapshot);
     })
 }
 
@@ -53,20 +373,35 @@ fn report_result_output(
             handler_spec.handler_id,
             event_id,
             results,
+            ErrorKind::BadReturn,
             String::from(
                 "Function didn't return a JSON-serializable value. Check for a `return` statement.",
             ),
         );
     } else if let Ok(result_array) = serde_json::from_str::<Vec<serde_json::Value>>(&result_json) {
         // Expect an array of results. Split this up and save eacn one as a JSON blob.
-        for result in result_array.iter() {
+        for result in result_array.iter().take(max_results_per_event()) {
             match serde_json::to_string(result) {
+                // The limit applies to each result, so one oversized result doesn't lose the others.
+                Ok(result_json) if result_json.len() > max_result_bytes() => report_error(
+                    handler_spec.handler_id,
+                    event_id,
+                    results,
+                    ErrorKind::ResultTooLarge,
+                    format!(
+                        "Result exceeded size limit: {} bytes, limit is {} bytes.",
+                        result_json.len(),
+                        max_result_bytes()
+                    ),
+                ),
                 Ok(result_json) => results.push(ExecutionResult {
                     result_id: -1,
                     event_id,
                     handler_id: handler_spec.handler_id,
                     result: Some(result_json),
                     error: None,
+                    error_kind: None,
+                    assertion_id: -1,
                     created: None,
                 }),
                 Err(e) => {
@@ -79,26 +414,135 @@ fn report_result_output(
                         handler_spec.handler_id,
                         event_id,
                         results,
+                        ErrorKind::BadReturn,
                         String::from("Failed to parse result from function."),
                     );
                 }
             }
         }
+
+        if result_array.len() > max_results_per_event() {
+            report_error(
+                handler_spec.handler_id,
+                event_id,
+                results,
+                ErrorKind::TooManyResults,
+                format!(
+                    "Function returned {} results, limit is {}. Only the first {} were kept.",
+                    result_array.len(),
+                    max_results_per_event(),
+                    max_results_per_event()
+                ),
+            );
+        }
     } else {
         report_error(
             handler_spec.handler_id,
             event_id,
              results,
+            ErrorKind::BadReturn,
             String::from("Failed to parse result from function. Check that you returned an array of results that can be represented in JSON."),
         );
     }
 }
 
+/// If a function call returned a Promise, e.g. from an `async` function, run queued microtasks so it can settle, and take its value.
+/// A handler can queue microtasks forever, so this must be called while the watchdog is timing the call.
+/// If the Promise was rejected, or didn't settle, an exception is thrown in the scope and None is returned.
+fn settle_promise<'s>(
+    try_catch_scope: &mut v8::TryCatch<'_, HandleScope<'s>>,
+    run: Option<Local<'s, v8::Value>>,
+) -> Option<Local<'s, v8::Value>> {
+    let value = run?;
+    if !value.is_promise() {
+        return Some(value);
+    }
+
+    // Guarded by enclosing if, so this is safe.
+    let promise = value.cast::<v8::Promise>();
+    try_catch_scope.perform_microtask_checkpoint();
+
+    match promise.state() {
+        v8::PromiseState::Fulfilled => Some(promise.result(try_catch_scope)),
+        v8::PromiseState::Rejected => {
+            let reason = promise.result(try_catch_scope);
+            try_catch_scope.throw_exception(reason);
+            None
+        }
+        v8::PromiseState::Pending => {
+            // If the watchdog terminated it, that's reported separately.
+            if !try_catch_scope.is_execution_terminating() {
+                let message = v8::String::new(
+                    try_catch_scope,
+                    "Promise returned by the function didn't settle.",
+                )
+                .unwrap();
+                try_catch_scope.throw_exception(message.into());
+            }
+            None
+        }
+    }
+}
+
+/// Report the outcome of a function call, either its results or the error.
+/// Returns false if the isolate was terminated for exceeding its memory limit, so no further calls should be made.
+fn report_run(
+    handler_spec: &HandlerSpec,
+    event_id: i64,
+    run: Option<Local<'_, v8::Value>>,
+    heap_state: &HeapLimitState,
+    results: &mut Vec<ExecutionResult>,
+    try_catch_scope: &mut v8::TryCatch<'_, HandleScope<'_>>,
+) -> bool {
+    match run {
+        None if heap_state.exceeded() => {
+            report_error(
+                handler_spec.handler_id,
+                event_id,
+                results,
+                ErrorKind::MemoryLimit,
+                String::from(MEMORY_LIMIT_MESSAGE),
+            );
+            false
+        }
+        None => {
+            // Run failed. Try to report the exception.
+            if let Some(ex) = try_catch_scope.exception() {
+                let message = ex.to_rust_string_lossy(try_catch_scope);
+                report_error(
+                    handler_spec.handler_id,
+                    event_id,
+                    results,
+                    ErrorKind::ExecRuntime,
+                    format!("Failed to run the function. Exception: {}", message),
+                );
+            } else {
+                report_error(
+                    handler_spec.handler_id,
+                    event_id,
+                    results,
+                    ErrorKind::ExecRuntime,
+                    String::from("Failed to run the function, no exception available."),
+                );
+            }
+            true
+        }
+        Some(result) => {
+            // Run succeeded. Expect an array of results in a
+            // JSON object, which will be translated into
+            // individual Result objects.
+            report_result_output(handler_spec, event_id, results, result, try_catch_scope);
+            true
+        }
+    }
+}
+
 /// Push an error message to the results.
 fn report_error(
     handler_id: i64,
     event_id: i64,
     results: &mut Vec<ExecutionResult>,
+    kind: ErrorKind,
     message: String,
 ) {
     results.push(ExecutionResult {
@@ -107,12 +551,24 @@ fn report_error(
         handler_id,
         result: None,
         error: Some(message),
+        error_kind: Some(kind),
+        assertion_id: -1,
         created: None,
     });
 }
 
-/// From a Context in which a script has already been loaded and executed, leaving a function named 'f'.
-/// Retrieve that function and return it.
+/// How the handler function is called.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionKind {
+    /// Function `f` is called once per Event.
+    PerEvent,
+
+    /// Function `f_batch` is called once with an array of all Events.
+    Batch,
+}
+
+/// From a Context in which a script has already been loaded and executed, leaving a function named 'f' or 'f_batch'.
+/// Retrieve that function and return it, preferring 'f_batch' if it's defined.
 /// Returns the function as a Value and cast to a Function, as required by the V8 function invocation API.
 /// A little strange, but lets us keep the separation of concerns, and handle both "does f exist" and "is f a function".
 fn get_f_function<'s>(
@@ -120,29 +576,55 @@ fn get_f_function<'s>(
     results: &mut Vec<ExecutionResult>,
     task_scope: &mut HandleScope<'s>,
     task_proxy: Local<'s, Object>,
-) -> Option<(Local<'s, Function>, Local<'s, v8::Value>)> {
+) -> Option<(Local<'s, Function>, Local<'s, v8::Value>, FunctionKind)> {
+    // Handlers can opt in to receiving all Events in one call.
+    let batch_function_key = v8::String::new(task_scope, "f_batch").unwrap();
+    if let Some(batch_function) = task_proxy.get(task_scope, batch_function_key.into()) {
+        if batch_function.is_function() {
+            // Guarded by enclosing if, so this is safe.
+            return Some((
+                batch_function.cast::<Function>(),
+                batch_function,
+                FunctionKind::Batch,
+            ));
+        }
+    }
+
     // Now we can look for the function that was registered.
     let function_key = v8::String::new(task_scope, "f").unwrap();
 
     if let Some(query_function) = task_proxy.get(task_scope, function_key.into()) {
         if !query_function.is_function() {
-            report_error(            handler_spec.handler_id,
+            // Name the type, so `var f = 5;` is easy to spot.
+            let type_name = query_function
+                .type_of(task_scope)
+                .to_rust_string_lossy(task_scope);
+
+            report_error(
+                handler_spec.handler_id,
                 -1,
                 results,
-                String::from(
-                    "'f' was not a function. Check you have don't have a conflicting variable named `f`.",
+                ErrorKind::NoFunction,
+                format!(
+                    "'f' was not a function, it was {}. Check you don't have a conflicting variable named `f`.",
+                    type_name
                 ),
             );
             None
         } else {
             // Guarded by enclosing if, so this is safe.
-            Some((query_function.cast::<Function>(), query_function))
+            Some((
+                query_function.cast::<Function>(),
+                query_function,
+                FunctionKind::PerEvent,
+            ))
         }
     } else {
         report_error(
             handler_spec.handler_id,
             -1,
             results,
+            ErrorKind::NoFunction,
             String::from("Didn't find named function."),
         );
         None
@@ -157,9 +639,10 @@ fn load_script(
     task_scope: &mut HandleScope<'_, Context>,
 ) -> bool {
     if let Some(code) = v8::String::new(task_scope, &handler_spec.code) {
-        if let Some(script) = v8::Script::compile(task_scope, code, None) {
-            let mut try_catch_scope = v8::TryCatch::new(task_scope);
+        // Compile within the TryCatch so syntax errors can be reported.
+        let mut try_catch_scope = v8::TryCatch::new(task_scope);
 
+        if let Some(script) = v8::Script::compile(&mut try_catch_scope, code, None) {
             let run = script.run(&mut try_catch_scope);
 
             match run {
@@ -170,6 +653,7 @@ fn load_script(
                             handler_spec.handler_id,
                             -1,
                             results,
+                            ErrorKind::LoadRuntime,
                             format!("Failed to load the function. Exception: {}", message),
                         );
                         false
@@ -178,6 +662,7 @@ fn load_script(
                             handler_spec.handler_id,
                             -1,
                             results,
+                            ErrorKind::LoadRuntime,
                             String::from("Failed to load the function, no exception available."),
                         );
                         false
@@ -189,11 +674,19 @@ fn load_script(
                 }
             }
         } else {
+            let message = match try_catch_scope.exception() {
+                Some(ex) => format!(
+                    "Failed to compile code. Exception: {}",
+                    ex.to_rust_string_lossy(&mut try_catch_scope)
+                ),
+                None => String::from("Failed to compile code."),
+            };
             report_error(
                 handler_spec.handler_id,
                 -1,
                 results,
-                String::from("Failed to compile code."),
+                ErrorKind::LoadCompile,
+                message,
             );
             false
         }
@@ -202,6 +695,7 @@ fn load_script(
             handler_spec.handler_id,
             -1,
             results,
+            ErrorKind::LoadCompile,
             String::from("Failed to load code."),
         );
         false
@@ -231,7 +725,44 @@ fn set_variable_from_json(
 
 /// Run all tasks against all inputs.
 /// Create an isolated environment for each distinct user.
+#[cfg(test)]
 pub(crate) fn run_all(handlers: &[HandlerSpec], events: &[Event]) -> Vec<ExecutionResult> {
+    let never_cancelled = Arc::new(AtomicBool::new(false));
+
+    // Can't be cancelled, so always Ok.
+    run_all_cancellable(handlers, events, &never_cancelled).unwrap_or_default()
+}
+
+/// Run all tasks against all inputs.
+/// Create an isolated environment for each distinct user.
+/// If the cancel flag is set, the running isolate is terminated and the remainder of the batch is abandoned.
+pub(crate) fn run_all_cancellable(
+    handlers: &[HandlerSpec],
+    events: &[Event],
+    cancel: &Arc<AtomicBool>,
+) -> Result<Vec<ExecutionResult>, Cancelled> {
+    run_all_pooled(
+        handlers,
+        events,
+        cancel,
+        isolate_pooling(),
+        handler_time_budget(),
+        timeout_grace(),
+    )
+}
+
+/// Run all tasks against all inputs, as [run_all_cancellable].
+/// If `pooling` is set, each handler's isolate is kept for the next call on this thread.
+/// If `budget` is given, each handler is skipped for the rest of the Events once its function has run for that long.
+/// The watchdog allows `grace` past each timeout before terminating a handler.
+fn run_all_pooled(
+    handlers: &[HandlerSpec],
+    events: &[Event],
+    cancel: &Arc<AtomicBool>,
+    pooling: bool,
+    budget: Option<Duration>,
+    grace: Duration,
+) -> Result<Vec<ExecutionResult>, Cancelled> {
     log::info!(
         "Run {} tasks against {} inputs",
         handlers.len(),
@@ -251,20 +782,32 @@ pub(crate) fn run_all(handlers: &[HandlerSpec], events: &[Event]) -> Vec<Executi
     // State machine driven from channel:
     // When a handler is sent, it will wait for another message with a timeout. If none comes, it will terminate that handler.
     // When None is sent, it will wait for a new handler to watch.
+    // It wakes at least every tick to check the cancel flag, and terminates the current isolate if set.
+    let watchdog_cancel = cancel.clone();
     let watchdog_thread = thread::spawn(move || {
         let mut done = false;
         let mut current_isolate: Option<IsolateHandle> = None;
         let mut current_handler_id = -1;
         // Initial value is arbitrary.
-        let mut current_duration = EXECUTION_TIMEOUT;
+        let mut current_duration = Duration::from_millis(DEFAULT_EXECUTION_TIMEOUT_MS as u64);
+        let mut deadline = Instant::now() + current_duration;
         while !done {
-            match watchdog_receive_handler.recv_timeout(current_duration) {
+            let wait = if current_isolate.is_some() {
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .min(WATCHDOG_TICK)
+            } else {
+                WATCHDOG_TICK
+            };
+
+            match watchdog_receive_handler.recv_timeout(wait) {
                 // If one was sent, store it to set the timeout. If None was sent, store that to reset the timeout.
                 Ok(maybe_isolate) => {
                     if let Some((isolate, handler_id, duration)) = maybe_isolate {
                         current_isolate = Some(isolate);
                         current_handler_id = handler_id;
                         current_duration = duration;
+                        deadline = Instant::now() + duration + grace;
                     } else {
                         current_isolate = None;
                         current_handler_id = -1;
@@ -272,6 +815,16 @@ pub(crate) fn run_all(handlers: &[HandlerSpec], events: &[Event]) -> Vec<Executi
                 }
                 Err(error) => match error {
                     RecvTimeoutError::Disconnected => done = true,
+                    RecvTimeoutError::Timeout if watchdog_cancel.load(Ordering::SeqCst) => {
+                        if let Some(isolate) = current_isolate.take() {
+                            log::info!("Cancel handler id {}", current_handler_id);
+                            isolate.terminate_execution();
+                            current_handler_id = -1;
+                        }
+                    }
+                    RecvTimeoutError::Timeout if Instant::now() < deadline => {
+                        // Woken to check for cancellation, not yet timed out.
+                    }
                     RecvTimeoutError::Timeout => {
                         if let Some(isolate) = current_isolate {
                             log::info!(
@@ -298,207 +851,481 @@ pub(crate) fn run_all(handlers: &[HandlerSpec], events: &[Event]) -> Vec<Executi
     // Build the full JSON for each, including hydrating identifiers etc.
     let hydrated_events: Vec<(&Event, String)> = events
         .iter()
-        .filter_map(|event| event.to_json_value().map(|json| (event, json)))
+        .filter_map(|event| {
+            event
+                .to_handler_json_value(include_raw_event())
+                .map(|json| (event, json))
+        })
         .collect();
 
     // Isolated environment for each task, re-used for all input data.
     for handler_spec in handlers.iter() {
-        log::debug!("Running task id {}", handler_spec.handler_id);
-
-        let isolate = &mut v8::Isolate::new(Default::default());
-
-        // Handle that can be sent to watchdog thread.
-        let watchdog_handle = isolate.thread_safe_handle();
-
-        let handle_scope = &mut v8::HandleScope::new(isolate);
-
-        // Each task associated with the user.
-        let task_context = v8::Context::new(handle_scope, Default::default());
-        let task_scope = &mut v8::ContextScope::new(handle_scope, task_context);
-        let task_proxy = task_context.global(task_scope);
+        if cancel.load(Ordering::SeqCst) {
+            break;
+        }
 
-        // Set the global 'environment' variable.
-        set_variable_from_json(task_scope, task_proxy, "environment", &environment_json);
+        log::debug!("Running task id {}", handler_spec.handler_id);
 
-        // Start the timer for the watchdog.
-        // Load can take a few milliseconds.
-        watchdog_send_handler
-            .send(Some((
-                watchdog_handle.clone(),
-                handler_spec.handler_id,
-                LOAD_TIMEOUT,
-            )))
-            .unwrap();
-
-        // Load the script from the task spec and execute it.
-        // The script should define a function called 'f', which we'll retrieve from the scope.
-        // This means we don't need to retain a direct handle to the script itself once it's executed.
-        // On failure, log exception message to results.
-        let ok: bool = load_script(handler_spec, &mut results, task_scope);
-
-        watchdog_send_handler.send(None).unwrap();
-
-        // Now retrieve the function from the context.
-        if ok {
-            if let Some((function_as_f, function_as_v)) =
-                get_f_function(handler_spec, &mut results, task_scope, task_proxy)
-            {
-                // Execute f for each input.
-                // Function execution should be much quicker than loading.
-                for (event, json) in hydrated_events.iter() {
-                    let input_handle = marshal_task_input(task_scope, json);
-
-                    // Run in a TryCatch so we can retrieve error messages.
-                    let mut try_catch_scope = v8::TryCatch::new(task_scope);
-
-                    // Start the watchdog timer for this isolate.
-                    // We will terminate the whole isolate, not this function execution, but that's proportionate for a misbehaving function.
-                    watchdog_send_handler
-                        .send(Some((
-                            watchdog_handle.clone(),
-                            handler_spec.handler_id,
-                            EXECUTION_TIMEOUT,
-                        )))
-                        .unwrap();
-
-                    let run =
-                        function_as_f.call(&mut try_catch_scope, function_as_v, &[input_handle]);
-
-                    // Reset watchdog if it terminated normally.
-                    watchdog_send_handler.send(None).unwrap();
-
-                    match run {
-                        None => {
-                            // Run failed. Try to report the exception.
-                            if let Some(ex) = try_catch_scope.exception() {
-                                let message = ex.to_rust_string_lossy(&mut try_catch_scope);
-                                report_error(
-                                    handler_spec.handler_id,
-                                    event.event_id,
-                                    &mut results,
-                                    format!("Failed to run the function. Exception: {}", message),
-                                );
-                            } else {
-                                report_error(
-                                    handler_spec.handler_id,
-                                    event.event_id,
-                                    &mut results,
-                                    String::from(
-                                        "Failed to run the function, no exception available.",
-                                    ),
-                                );
-                            }
-                        }
-                        Some(result) => {
-                            // Run succeeded. Expect an array of results in a
-                            // JSON object, which will be translated into
-                            // individual Result objects.
-                            report_result_output(
-                                handler_spec,
-                                event.event_id,
-                                &mut results,
-                                result,
-                                &mut try_catch_scope,
-                            );
-                        }
-                    }
-                }
-            }
-        }
+        // Reuse the isolate from a previous batch, if there is one, so the code doesn't have to be loaded again.
+        let mut handler_isolate = pooling
+            .then(|| take_pooled_isolate(handler_spec))
+            .flatten()
+            .unwrap_or_else(|| HandlerIsolate::new(handler_spec));
+
+        // Safety: isolates are only entered while they're in use. See [HandlerIsolate].
+        unsafe { handler_isolate.isolate.enter() };
+        let loaded = run_handler(
+            &mut handler_isolate,
+            handler_spec,
+            &hydrated_events,
+            &environment_json,
+            &watchdog_send_handler,
+            cancel,
+            budget,
+            &mut results,
+        );
+        unsafe { handler_isolate.isolate.exit() };
 
         // Poll  for any terminated handlers and report.
-        report_terminated(&watchdog_receive_terminated, &mut results);
+        let terminated = report_terminated(&watchdog_receive_terminated, &mut results);
+
+        // Terminated isolates aren't reused, as their state is unknown.
+        if pooling
+            && loaded
+            && !terminated
+            && !handler_isolate.heap_state.exceeded()
+            && !cancel.load(Ordering::SeqCst)
+        {
+            pool_isolate(handler_isolate);
+        }
     }
 
     drop(watchdog_send_handler);
 
+    // Record which metadata assertion each result came from, for provenance.
+    let assertion_ids: HashMap<i64, i64> = events
+        .iter()
+        .map(|event| (event.event_id, event.assertion_id))
+        .collect();
+    for result in results.iter_mut() {
+        result.assertion_id = assertion_ids.get(&result.event_id).copied().unwrap_or(-1);
+    }
+
     // Watchdog thread must exit or it'll keep ticking away, which would cause a memory leak.
     // If it doesn't terminate almost immediately that's a bug, and it's better to hang or panic.
     log::debug!("Wait for watchdog...");
     watchdog_thread.join().unwrap();
     log::debug!("Watchdog stopped.");
 
-    results
-}
-
-/// Poll from 'terminated handler' channel and report an error message.
-fn report_terminated(terminated_chan: &mpsc::Receiver<i64>, results: &mut Vec<ExecutionResult>) {
-    // Read until we got all messages, not until it closed.
-    for handler_id in terminated_chan.try_iter() {
-        report_error(
-            handler_id,
-            -1,
-            results,
-            String::from("Handler function took too long to run and was terminated."),
-        );
+    if cancel.load(Ordering::SeqCst) {
+        log::info!("Execution cancelled.");
+        return Err(Cancelled);
     }
+
+    Ok(results)
 }
 
-#[cfg(test)]
-mod tests {
-    //! Tests for the V8 runtime. These are all run serially to avoid concurrency issues, as V8 uses a global object.
+/// Run a handler over the Events in its isolate, which must be entered.
+/// The code is loaded first, unless the isolate came from the pool with it already loaded.
+/// Once a per-Event function has run for the `budget`, the remaining Events get a time budget error instead.
+/// Return false if the code couldn't be loaded.
+#[allow(clippy::too_many_arguments)]
+fn run_handler(
+    handler_isolate: &mut HandlerIsolate,
+    handler_spec: &HandlerSpec,
+    hydrated_events: &[(&Event, String)],
+    environment_json: &str,
+    watchdog_send_handler: &mpsc::Sender<Option<(IsolateHandle, i64, Duration)>>,
+    cancel: &AtomicBool,
+    budget: Option<Duration>,
+    results: &mut Vec<ExecutionResult>,
+) -> bool {
+    // Per-handler timeout. Guard against nonsensical stored values.
+    let execution_timeout = Duration::from_millis(handler_spec.timeout_ms.max(1) as u64);
 
-    use scholarly_identifiers::identifiers::Identifier;
-    use serial_test::serial;
+    let heap_state = &handler_isolate.heap_state;
 
-    use super::*;
+    // Handle that can be sent to watchdog thread.
+    let watchdog_handle = handler_isolate.isolate.thread_safe_handle();
 
-    fn init_tests() {
-        init();
-    }
+    let handle_scope = &mut v8::HandleScope::new(&mut handler_isolate.isolate);
 
-    /**
-     * Happy paths.
-     */
+    let pooled = handler_isolate
+        .loaded
+        .as_ref()
+        .map(|(context, function, kind)| {
+            (
+                Local::new(handle_scope, context),
+                Local::new(handle_scope, function),
+                *kind,
+            )
+        });
 
-    /// When multiple results are returned from a function each should have a result.
-    #[test]
-    #[serial]
-    fn multiple_results() {
-        init_tests();
+    let (task_context, function_as_f, function_kind) = match pooled {
+        Some(pooled) => pooled,
+        None => {
+            // Each task associated with the user.
+            let task_context = v8::Context::new(handle_scope, Default::default());
+            let task_scope = &mut v8::ContextScope::new(handle_scope, task_context);
+            let task_proxy = task_context.global(task_scope);
+
+            // Set the global 'environment' variable, unless it came from the snapshot.
+            if snapshot().is_none() {
+                set_variable_from_json(task_scope, task_proxy, "environment", environment_json);
+            }
 
-        let handlers: Vec<HandlerSpec> = vec![HandlerSpec {
-            handler_id: 1234,
-            code: String::from("function f(args) { return [{\"result\": \"one\"}, {\"result\": \"two\"}, {\"result\": \"three\"}]; }"),
-            status: 1
-        }];
+            // Start the timer for the watchdog.
+            // Load can take a few milliseconds.
+            watchdog_send_handler
+                .send(Some((
+                    watchdog_handle.clone(),
+                    handler_spec.handler_id,
+                    LOAD_TIMEOUT,
+                )))
+                .unwrap();
 
-        let events: Vec<Event> = vec![Event {
-            event_id: 4321,
-            analyzer: crate::db::source::EventAnalyzerId::Test,
-            source: crate::db::source::MetadataSourceId::Test,
-            subject_id: None,
-            object_id: None,
-            json: String::from("{}"),
-            assertion_id: -1,
-        }];
+            // Load the script from the task spec and execute it.
+            // The script should define a function called 'f', which we'll retrieve from the scope.
+            // This means we don't need to retain a direct handle to the script itself once it's executed.
+            // On failure, log exception message to results.
+            let ok: bool = load_script(handler_spec, results, task_scope);
+
+            watchdog_send_handler.send(None).unwrap();
+
+            if heap_state.exceeded() {
+                report_error(
+                    handler_spec.handler_id,
+                    -1,
+                    results,
+                    ErrorKind::MemoryLimit,
+                    String::from(MEMORY_LIMIT_MESSAGE),
+                );
+            }
 
-        let results = run_all(&handlers, &events);
+            if !ok {
+                return false;
+            }
 
-        assert_eq!(
-            results,
-            vec![
-                ExecutionResult {
-                    handler_id: 1234,
-                    event_id: 4321,
-                    result: Some(String::from("{\"result\":\"one\"}")),
-                    error: None,
-                    result_id: -1,
-                    created: None
-                },
-                ExecutionResult {
-                    handler_id: 1234,
-                    event_id: 4321,
-                    result: Some(String::from("{\"result\":\"two\"}")),
-                    error: None,
-                    result_id: -1,
-                    created: None
-                },
+            // Now retrieve the function from the context.
+            let Some((function_as_f, _, function_kind)) =
+                get_f_function(handler_spec, results, task_scope, task_proxy)
+            else {
+                return false;
+            };
+
+            handler_isolate.loaded = Some((
+                v8::Global::new(task_scope, task_context),
+                v8::Global::new(task_scope, function_as_f),
+                function_kind,
+            ));
+
+            (task_context, function_as_f, function_kind)
+        }
+    };
+
+    let task_scope = &mut v8::ContextScope::new(handle_scope, task_context);
+    let function_as_v: Local<v8::Value> = function_as_f.into();
+
+    match function_kind {
+        FunctionKind::Batch => {
+            // Execute f_batch once for all inputs, if there are any.
+            // Results aren't attributable to a single Event.
+            if !hydrated_events.is_empty() {
+                let batch_json = format!(
+                    "[{}]",
+                    hydrated_events
+                        .iter()
+                        .map(|(_, json)| json.as_str())
+                        .collect::<Vec<&str>>()
+                        .join(",")
+                );
+                let input_handle = marshal_task_input(task_scope, &batch_json);
+
+                let mut try_catch_scope = v8::TryCatch::new(task_scope);
+
+                // The timeout scales with the number of Events in the batch.
+                let batch_timeout = execution_timeout.saturating_mul(hydrated_events.len() as u32);
+
+                watchdog_send_handler
+                    .send(Some((
+                        watchdog_handle.clone(),
+                        handler_spec.handler_id,
+                        batch_timeout,
+                    )))
+                    .unwrap();
+
+                let run = function_as_f.call(&mut try_catch_scope, function_as_v, &[input_handle]);
+                let run = settle_promise(&mut try_catch_scope, run);
+
+                watchdog_send_handler.send(None).unwrap();
+
+                if !cancel.load(Ordering::SeqCst) {
+                    report_run(
+                        handler_spec,
+                        -1,
+                        run,
+                        heap_state,
+                        results,
+                        &mut try_catch_scope,
+                    );
+                }
+            }
+        }
+        FunctionKind::PerEvent => {
+            // Execute f for each input.
+            // Function execution should be much quicker than loading.
+            let mut spent = Duration::ZERO;
+            for (index, (event, json)) in hydrated_events.iter().enumerate() {
+                if let Some(budget) = budget.filter(|budget| spent >= *budget) {
+                    let skipped = &hydrated_events[index..];
+                    log::info!(
+                        "Handler id {} used its time budget of {:?}, skipping {} Events",
+                        handler_spec.handler_id,
+                        budget,
+                        skipped.len()
+                    );
+                    for (event, _) in skipped.iter() {
+                        report_error(
+                            handler_spec.handler_id,
+                            event.event_id,
+                            results,
+                            ErrorKind::TimeBudget,
+                            format!(
+                                "Handler used its time budget of {}ms for the batch, so wasn't run for this Event.",
+                                budget.as_millis()
+                            ),
+                        );
+                    }
+                    break;
+                }
+
+                let input_handle = marshal_task_input(task_scope, json);
+
+                // Run in a TryCatch so we can retrieve error messages.
+                let mut try_catch_scope = v8::TryCatch::new(task_scope);
+
+                // Start the watchdog timer for this isolate.
+                // We will terminate the whole isolate, not this function execution, but that's proportionate for a misbehaving function.
+                watchdog_send_handler
+                    .send(Some((
+                        watchdog_handle.clone(),
+                        handler_spec.handler_id,
+                        execution_timeout,
+                    )))
+                    .unwrap();
+
+                let started = Instant::now();
+                let run = function_as_f.call(&mut try_catch_scope, function_as_v, &[input_handle]);
+                let run = settle_promise(&mut try_catch_scope, run);
+                spent += started.elapsed();
+
+                // Reset watchdog if it terminated normally.
+                watchdog_send_handler.send(None).unwrap();
+
+                if cancel.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                // Isolate was terminated if memory was exceeded, so there's no point running further events.
+                if !report_run(
+                    handler_spec,
+                    event.event_id,
+                    run,
+                    heap_state,
+                    results,
+                    &mut try_catch_scope,
+                ) {
+                    break;
+                }
+            }
+        }
+    }
+
+    true
+}
+
+/// Check that handler code loads within the time limit and defines a function `f` or `f_batch`.
+/// Uses a throwaway isolate. Return the error message if not.
+pub(crate) fn validate_handler(code: &str) -> Result<(), String> {
+    let handler_spec = HandlerSpec {
+        handler_id: -1,
+        code: String::from(code),
+        status: 0,
+        timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+        hash: None,
+        owner_id: 0,
+    };
+
+    let mut results: Vec<ExecutionResult> = vec![];
+
+    let heap_state = HeapLimitState::new();
+    let isolate = &mut create_isolate(&heap_state);
+
+    // Terminate the isolate if loading doesn't finish in time.
+    // Returns true if it had to terminate.
+    let watchdog_handle = isolate.thread_safe_handle();
+    let (send_done, receive_done) = mpsc::channel::<()>();
+    let watchdog_thread = thread::spawn(move || {
+        if let Err(RecvTimeoutError::Timeout) = receive_done.recv_timeout(LOAD_TIMEOUT) {
+            watchdog_handle.terminate_execution();
+            true
+        } else {
+            false
+        }
+    });
+
+    let ok = {
+        let handle_scope = &mut v8::HandleScope::new(isolate);
+        let task_context = v8::Context::new(handle_scope, Default::default());
+        let task_scope = &mut v8::ContextScope::new(handle_scope, task_context);
+        let task_proxy = task_context.global(task_scope);
+
+        // Same environment as execution, in case the script refers to it when loading.
+        if snapshot().is_none() {
+            set_variable_from_json(
+                task_scope,
+                task_proxy,
+                "environment",
+                &Global::build().json(),
+            );
+        }
+
+        load_script(&handler_spec, &mut results, task_scope)
+            && get_f_function(&handler_spec, &mut results, task_scope, task_proxy).is_some()
+    };
+
+    drop(send_done);
+    let terminated = watchdog_thread.join().unwrap();
+
+    if terminated {
+        Err(String::from(
+            "Handler function took too long to load and was terminated.",
+        ))
+    } else if heap_state.exceeded() {
+        Err(String::from(MEMORY_LIMIT_MESSAGE))
+    } else if ok {
+        Ok(())
+    } else {
+        Err(results
+            .into_iter()
+            .find_map(|x| x.error)
+            .unwrap_or(String::from("Failed to load the function.")))
+    }
+}
+
+/// Poll from 'terminated handler' channel and report an error message.
+/// Return true if any were terminated.
+fn report_terminated(
+    terminated_chan: &mpsc::Receiver<i64>,
+    results: &mut Vec<ExecutionResult>,
+) -> bool {
+    let mut terminated = false;
+
+    // Read until we got all messages, not until it closed.
+    for handler_id in terminated_chan.try_iter() {
+        terminated = true;
+        report_error(
+            handler_id,
+            -1,
+            results,
+            ErrorKind::Timeout,
+            String::from("Handler function took too long to run and was terminated."),
+        );
+    }
+
+    terminated
+}
+
+#[cfg(test)]
+mod tests {
+    //! Tests for the V8 runtime. These are all run serially to avoid concurrency issues, as V8 uses a global object.
+
+    use scholarly_identifiers::identifiers::Identifier;
+    use serial_test::serial;
+
+    use super::*;
+
+    fn init_tests() {
+        // Custom environment is built into the snapshot, so must be set first.
+        crate::execution::model::init_custom_environment(
+            crate::execution::model::parse_custom_environment(r#"{"corpus": "test-corpus"}"#)
+                .unwrap(),
+        );
+        init();
+    }
+
+    /// Initialization is only done once, so it's safe to repeat, and builds the startup snapshot.
+    #[test]
+    #[serial]
+    fn init_repeated() {
+        init_tests();
+        init();
+        init();
+        assert!(snapshot().is_some());
+    }
+
+    /*
+     * Happy paths.
+     */
+
+    /// When multiple results are returned from a function each should have a result.
+    #[test]
+    #[serial]
+    fn multiple_results() {
+        init_tests();
+
+        let handlers: Vec<HandlerSpec> = vec![HandlerSpec {
+            handler_id: 1234,
+            code: String::from("function f(args) { return [{\"result\": \"one\"}, {\"result\": \"two\"}, {\"result\": \"three\"}]; }"),
+            status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
+        }];
+
+        let events: Vec<Event> = vec![Event {
+            event_id: 4321,
+            analyzer: crate::db::source::EventAnalyzerId::Test,
+            source: crate::db::source::MetadataSourceId::Test,
+            subject_id: None,
+            object_id: None,
+            json: String::from("{}"),
+            assertion_id: -1,
+            occurred_at: None,
+        }];
+
+        let results = run_all(&handlers, &events);
+
+        assert_eq!(
+            results,
+            vec![
+                ExecutionResult {
+                    handler_id: 1234,
+                    event_id: 4321,
+                    result: Some(String::from("{\"result\":\"one\"}")),
+                    error: None,
+                    error_kind: None,
+                    assertion_id: -1,
+                    result_id: -1,
+                    created: None
+                },
+                ExecutionResult {
+                    handler_id: 1234,
+                    event_id: 4321,
+                    result: Some(String::from("{\"result\":\"two\"}")),
+                    error: None,
+                    error_kind: None,
+                    assertion_id: -1,
+                    result_id: -1,
+                    created: None
+                },
                 ExecutionResult {
                     handler_id: 1234,
                     event_id: 4321,
                     result: Some(String::from("{\"result\":\"three\"}")),
                     error: None,
+                    error_kind: None,
+                    assertion_id: -1,
                     result_id: -1,
                     created: None
                 }
@@ -516,6 +1343,9 @@ mod tests {
             handler_id: 1234,
             code: String::from("function f(args) { return []; }"),
             status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
         }];
 
         let events: Vec<Event> = vec![Event {
@@ -526,6 +1356,7 @@ mod tests {
             object_id: None,
             json: String::from("{}"),
             assertion_id: -1,
+            occurred_at: None,
         }];
 
         let results = run_all(&handlers, &events);
@@ -544,6 +1375,9 @@ mod tests {
             handler_id: 1234,
             code: String::from("function f(args) { return [args]; }"),
             status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
         }];
 
         // Event using an Identifier.
@@ -556,6 +1390,7 @@ mod tests {
             object_id: Some(Identifier::parse("https://doi.org/10.5555/242424x")),
             json: String::from("{\"hello\": \"world\"}"),
             assertion_id: -1,
+            occurred_at: None,
         }];
 
         let results = run_all(&handlers, &events);
@@ -631,16 +1466,25 @@ mod tests {
                 handler_id: 1,
                 code: String::from("function f(args) { return [args.x + '-one']; }"),
                 status: 1,
+                timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+                hash: None,
+                owner_id: 0,
             },
             HandlerSpec {
                 handler_id: 2,
                 code: String::from("function f(args) { return [args.x + '-two']; }"),
                 status: 1,
+                timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+                hash: None,
+                owner_id: 0,
             },
             HandlerSpec {
                 handler_id: 3,
                 code: String::from("function f(args) { return [args.x + '-three']; }"),
                 status: 1,
+                timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+                hash: None,
+                owner_id: 0,
             },
         ];
 
@@ -654,6 +1498,7 @@ mod tests {
                 object_id: None,
                 json: String::from("{\"x\": \"one\"}"),
                 assertion_id: -1,
+                occurred_at: None,
             },
             Event {
                 event_id: 2,
@@ -663,6 +1508,7 @@ mod tests {
                 object_id: None,
                 json: String::from("{\"x\": \"two\"}"),
                 assertion_id: -1,
+                occurred_at: None,
             },
             Event {
                 event_id: 3,
@@ -672,6 +1518,7 @@ mod tests {
                 object_id: None,
                 json: String::from("{\"x\": \"three\"}"),
                 assertion_id: -1,
+                occurred_at: None,
             },
         ];
 
@@ -685,6 +1532,8 @@ mod tests {
                     event_id: 1,
                     result: Some(String::from("\"one-one\"")),
                     error: None,
+                    error_kind: None,
+                    assertion_id: -1,
                     result_id: -1,
                     created: None
                 },
@@ -693,6 +1542,8 @@ mod tests {
                     event_id: 2,
                     result: Some(String::from("\"two-one\"")),
                     error: None,
+                    error_kind: None,
+                    assertion_id: -1,
                     result_id: -1,
                     created: None
                 },
@@ -701,6 +1552,8 @@ mod tests {
                     event_id: 3,
                     result: Some(String::from("\"three-one\"")),
                     error: None,
+                    error_kind: None,
+                    assertion_id: -1,
                     result_id: -1,
                     created: None
                 },
@@ -709,6 +1562,8 @@ mod tests {
                     event_id: 1,
                     result: Some(String::from("\"one-two\"")),
                     error: None,
+                    error_kind: None,
+                    assertion_id: -1,
                     result_id: -1,
                     created: None
                 },
@@ -717,6 +1572,8 @@ mod tests {
                     event_id: 2,
                     result: Some(String::from("\"two-two\"")),
                     error: None,
+                    error_kind: None,
+                    assertion_id: -1,
                     result_id: -1,
                     created: None
                 },
@@ -725,6 +1582,8 @@ mod tests {
                     event_id: 3,
                     result: Some(String::from("\"three-two\"")),
                     error: None,
+                    error_kind: None,
+                    assertion_id: -1,
                     result_id: -1,
                     created: None
                 },
@@ -733,6 +1592,8 @@ mod tests {
                     event_id: 1,
                     result: Some(String::from("\"one-three\"")),
                     error: None,
+                    error_kind: None,
+                    assertion_id: -1,
                     result_id: -1,
                     created: None
                 },
@@ -741,6 +1602,8 @@ mod tests {
                     event_id: 2,
                     result: Some(String::from("\"two-three\"")),
                     error: None,
+                    error_kind: None,
+                    assertion_id: -1,
                     result_id: -1,
                     created: None
                 },
@@ -749,6 +1612,8 @@ mod tests {
                     event_id: 3,
                     result: Some(String::from("\"three-three\"")),
                     error: None,
+                    error_kind: None,
+                    assertion_id: -1,
                     result_id: -1,
                     created: None
                 }
@@ -775,6 +1640,9 @@ mod tests {
             handler_id: 1234,
             code: String::from("function x() {}; function f(args) { return x; }"),
             status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
         }];
 
         let events: Vec<Event> = vec![Event {
@@ -785,6 +1653,7 @@ mod tests {
             object_id: None,
             json: String::from("{}"),
             assertion_id: -1,
+            occurred_at: None,
         }];
 
         let results = run_all(&handlers, &events);
@@ -808,6 +1677,9 @@ mod tests {
             handler_id: 1234,
             code: String::from("{}; function f(args) { }"),
             status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
         }];
 
         let events: Vec<Event> = vec![Event {
@@ -818,6 +1690,7 @@ mod tests {
             object_id: None,
             json: String::from("{}"),
             assertion_id: -1,
+            occurred_at: None,
         }];
 
         let results = run_all(&handlers, &events);
@@ -846,6 +1719,9 @@ mod tests {
                 "function x(i) { return x(i+1); } function f(args) { return x(1); }",
             ),
             status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
         }];
 
         let events: Vec<Event> = vec![Event {
@@ -856,6 +1732,7 @@ mod tests {
             object_id: None,
             json: String::from("{}"),
             assertion_id: -1,
+            occurred_at: None,
         }];
 
         let results = run_all(&handlers, &events);
@@ -877,6 +1754,9 @@ mod tests {
                 "function x(i) { return x(i+1); }; x(1); function f(args) { return [1] }",
             ),
             status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
         }];
 
         let events: Vec<Event> = vec![Event {
@@ -887,6 +1767,7 @@ mod tests {
             object_id: None,
             json: String::from("{}"),
             assertion_id: -1,
+            occurred_at: None,
         }];
 
         let results = run_all(&handlers, &events);
@@ -911,6 +1792,9 @@ mod tests {
                 }",
             ),
             status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
         }];
 
         // Send 2 events. Neither should be executed.
@@ -923,6 +1807,7 @@ mod tests {
                 object_id: None,
                 json: String::from("{}"),
                 assertion_id: -1,
+                occurred_at: None,
             },
             Event {
                 event_id: 1234,
@@ -932,6 +1817,7 @@ mod tests {
                 object_id: None,
                 json: String::from("{}"),
                 assertion_id: -1,
+                occurred_at: None,
             },
         ];
 
@@ -973,6 +1859,9 @@ mod tests {
                 }",
             ),
             status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
         }];
 
         // Send 2 events. Neither should be executed.
@@ -985,6 +1874,7 @@ mod tests {
                 object_id: None,
                 json: String::from("{}"),
                 assertion_id: -1,
+                occurred_at: None,
             },
             Event {
                 event_id: 2222,
@@ -994,6 +1884,7 @@ mod tests {
                 object_id: None,
                 json: String::from("{}"),
                 assertion_id: -1,
+                occurred_at: None,
             },
             Event {
                 event_id: 3333,
@@ -1003,6 +1894,7 @@ mod tests {
                 object_id: None,
                 json: String::from("{}"),
                 assertion_id: -1,
+                occurred_at: None,
             },
         ];
 
@@ -1049,6 +1941,9 @@ mod tests {
                 }",
             ),
             status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
         }];
 
         let events: Vec<Event> = vec![
@@ -1060,6 +1955,7 @@ mod tests {
                 object_id: None,
                 json: String::from("{}"),
                 assertion_id: -1,
+                occurred_at: None,
             },
             Event {
                 event_id: 2222,
@@ -1069,6 +1965,7 @@ mod tests {
                 object_id: None,
                 json: String::from("{}"),
                 assertion_id: -1,
+                occurred_at: None,
             },
             Event {
                 event_id: 3333,
@@ -1078,6 +1975,7 @@ mod tests {
                 object_id: None,
                 json: String::from("{}"),
                 assertion_id: -1,
+                occurred_at: None,
             },
         ];
 
@@ -1088,22 +1986,74 @@ mod tests {
         assert_contains(-1, 1234, "too long", &results);
     }
 
-    // Language features.
-
-    /// The Deno variable shouldn't be accessible.
+    /// A handler that defines `f_batch` is called once with all Events, and can aggregate across them.
     #[test]
     #[serial]
-    fn prohibited_deno() {
+    fn batch_handler_sum() {
         init_tests();
 
         let handlers: Vec<HandlerSpec> = vec![HandlerSpec {
             handler_id: 1234,
             code: String::from(
-                "Deno.serve((_req) => {
-                  return new Response('Hello, World!');
-                });",
+                "function f_batch(events) {
+                    let total = 0;
+                    for (const event of events) { total += event.count; }
+                    return [{ calls: 1, events: events.length, total: total }];
+                }
+                function f(args) { return [\"per-event\"]; }",
+            ),
+            status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
+        }];
+
+        let events: Vec<Event> = [(1111, 1), (2222, 2), (3333, 3)]
+            .iter()
+            .map(|(event_id, count)| Event {
+                event_id: *event_id,
+                analyzer: crate::db::source::EventAnalyzerId::Test,
+                source: crate::db::source::MetadataSourceId::Test,
+                subject_id: None,
+                object_id: None,
+                json: format!("{{\"count\": {}}}", count),
+                assertion_id: -1,
+                occurred_at: None,
+            })
+            .collect();
+
+        let results = run_all(&handlers, &events);
+
+        assert_eq!(
+            results,
+            vec![ExecutionResult {
+                result_id: -1,
+                event_id: -1,
+                handler_id: 1234,
+                result: Some(String::from("{\"calls\":1,\"events\":3,\"total\":6}")),
+                error: None,
+                error_kind: None,
+                assertion_id: -1,
+                created: None,
+            }]
+        );
+    }
+
+    /// A result over the size limit is replaced with an error, but other results from the same call are kept.
+    #[test]
+    #[serial]
+    fn result_size_limit() {
+        init_tests();
+
+        let handlers: Vec<HandlerSpec> = vec![HandlerSpec {
+            handler_id: 1,
+            code: String::from(
+                "function f(args) { return ['x'.repeat(300 * 1024), {'small': true}]; }",
             ),
             status: 1,
+            timeout_ms: 1000,
+            hash: None,
+            owner_id: 0,
         }];
 
         let events: Vec<Event> = vec![Event {
@@ -1114,24 +2064,40 @@ mod tests {
             object_id: None,
             json: String::from("{}"),
             assertion_id: -1,
+            occurred_at: None,
         }];
 
         let results = run_all(&handlers, &events);
 
-        assert_contains(-1, 1234, "Deno is not defined", &results);
+        assert_eq!(results.len(), 2, "Expected two results, got {:?}", results);
+
+        assert_eq!(results[0].result, None);
+        assert_eq!(results[0].error_kind, Some(ErrorKind::ResultTooLarge));
+        assert!(results[0]
+            .error
+            .as_ref()
+            .unwrap()
+            .starts_with("Result exceeded size limit"));
+
+        assert_eq!(results[1].result, Some(String::from(r#"{"small":true}"#)));
+        assert_eq!(results[1].error, None);
     }
 
-    /// The JSON functions should be available.
-    /// Not much use, but who knows.
+    /// Results over the limit from one call are dropped, and a single error reports the truncation.
     #[test]
     #[serial]
-    fn json_deno() {
+    fn result_count_limit() {
         init_tests();
 
         let handlers: Vec<HandlerSpec> = vec![HandlerSpec {
-            handler_id: 1234,
-            code: String::from("function f() {return [JSON.stringify([1,2,3])] }"),
+            handler_id: 1,
+            code: String::from(
+                "function f(args) { return Array.from({length: 1500}, (_, i) => i); }",
+            ),
             status: 1,
+            timeout_ms: 1000,
+            hash: None,
+            owner_id: 0,
         }];
 
         let events: Vec<Event> = vec![Event {
@@ -1142,20 +2108,888 @@ mod tests {
             object_id: None,
             json: String::from("{}"),
             assertion_id: -1,
+            occurred_at: None,
         }];
 
         let results = run_all(&handlers, &events);
 
+        assert_eq!(results.len(), DEFAULT_MAX_RESULTS_PER_EVENT + 1);
+
+        assert_eq!(results[0].result, Some(String::from("0")));
         assert_eq!(
-            results,
-            vec![ExecutionResult {
-                handler_id: 1234,
-                event_id: 1111,
-                result_id: -1,
-                result: Some(String::from("\"[1,2,3]\"")),
-                error: None,
-                created: None
-            }]
+            results[DEFAULT_MAX_RESULTS_PER_EVENT - 1].result,
+            Some(String::from("999"))
+        );
+
+        let last = results.last().unwrap();
+        assert_eq!(last.result, None);
+        assert_eq!(last.error_kind, Some(ErrorKind::TooManyResults));
+        assert_eq!(
+            last.error,
+            Some(String::from(
+                "Function returned 1500 results, limit is 1000. Only the first 1000 were kept."
+            ))
+        );
+    }
+
+    /// Each kind of failure is reported with its error kind, as well as the message.
+    #[test]
+    #[serial]
+    fn error_kinds() {
+        init_tests();
+
+        let cases = [
+            (1, "function f(args) { return [1]", ErrorKind::LoadCompile),
+            (
+                2,
+                "throw 'oops'; function f(args) { return [1]; }",
+                ErrorKind::LoadRuntime,
+            ),
+            (3, "function g(args) { return [1]; }", ErrorKind::NoFunction),
+            (4, "function f(args) { return 1; }", ErrorKind::BadReturn),
+            (
+                5,
+                "function f(args) { throw 'oops'; }",
+                ErrorKind::ExecRuntime,
+            ),
+            (
+                6,
+                "function f(args) { while(true) {}; }",
+                ErrorKind::Timeout,
+            ),
+        ];
+
+        let handlers: Vec<HandlerSpec> = cases
+            .iter()
+            .map(|(handler_id, code, _)| HandlerSpec {
+                handler_id: *handler_id,
+                code: String::from(*code),
+                status: 1,
+                timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+                hash: None,
+                owner_id: 0,
+            })
+            .collect();
+
+        let events: Vec<Event> = vec![Event {
+            event_id: 1111,
+            analyzer: crate::db::source::EventAnalyzerId::Test,
+            source: crate::db::source::MetadataSourceId::Test,
+            subject_id: None,
+            object_id: None,
+            json: String::from("{}"),
+            assertion_id: -1,
+            occurred_at: None,
+        }];
+
+        let results = run_all(&handlers, &events);
+
+        for (handler_id, _, kind) in cases.iter() {
+            assert!(
+                results
+                    .iter()
+                    .any(|r| r.handler_id == *handler_id && r.error_kind == Some(*kind)),
+                "Expected {:?} for handler {}, got {:?}",
+                kind,
+                handler_id,
+                results
+            );
+        }
+    }
+
+    /// Results carry the assertion id of the Event that triggered them.
+    #[test]
+    #[serial]
+    fn result_assertion_id() {
+        init_tests();
+
+        let handlers: Vec<HandlerSpec> = vec![HandlerSpec {
+            handler_id: 1234,
+            code: String::from("function f(args) { return [1]; }"),
+            status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
+        }];
+
+        let events: Vec<Event> = [(1111, 5555), (2222, -1)]
+            .iter()
+            .map(|(event_id, assertion_id)| Event {
+                event_id: *event_id,
+                analyzer: crate::db::source::EventAnalyzerId::Test,
+                source: crate::db::source::MetadataSourceId::Test,
+                subject_id: None,
+                object_id: None,
+                json: String::from("{}"),
+                assertion_id: *assertion_id,
+                occurred_at: None,
+            })
+            .collect();
+
+        let results = run_all(&handlers, &events);
+
+        let assertion_ids: Vec<(i64, i64)> = results
+            .iter()
+            .map(|x| (x.event_id, x.assertion_id))
+            .collect();
+        assert_eq!(assertion_ids, vec![(1111, 5555), (2222, -1)]);
+    }
+
+    /// A handler that allocates without limit is terminated with a memory error, rather than crashing the process.
+    #[test]
+    #[serial]
+    fn heap_limit_run() {
+        init_tests();
+
+        let handlers: Vec<HandlerSpec> = vec![HandlerSpec {
+            handler_id: 1234,
+            code: String::from(
+                "function f(args) {
+                    let a = [];
+                    while(true) { a.push(new Array(1000000).fill(1)); }
+                    return [a.length];
+                }",
+            ),
+            status: 1,
+            // Generous timeout so that memory is the limit that's hit.
+            timeout_ms: 10000,
+            hash: None,
+            owner_id: 0,
+        }];
+
+        let events: Vec<Event> = vec![Event {
+            event_id: 1111,
+            analyzer: crate::db::source::EventAnalyzerId::Test,
+            source: crate::db::source::MetadataSourceId::Test,
+            subject_id: None,
+            object_id: None,
+            json: String::from("{}"),
+            assertion_id: -1,
+            occurred_at: None,
+        }];
+
+        let results = run_all(&handlers, &events);
+
+        assert_contains(1111, 1234, "memory limit", &results);
+    }
+
+    /// An async function's results are used once its Promise settles, and a rejection is reported.
+    #[test]
+    #[serial]
+    fn async_handler() {
+        init_tests();
+
+        let handlers: Vec<HandlerSpec> = vec![
+            HandlerSpec {
+                handler_id: 1234,
+                code: String::from(
+                    "async function f(args) { const x = await Promise.resolve('one'); return [x]; }",
+                ),
+                status: 1,
+                timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+                hash: None,
+                owner_id: 0,
+            },
+            HandlerSpec {
+                handler_id: 5678,
+                code: String::from("async function f(args) { throw new Error('Rejected'); }"),
+                status: 1,
+                timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+                hash: None,
+                owner_id: 0,
+            },
+        ];
+
+        let events: Vec<Event> = vec![Event {
+            event_id: 4321,
+            analyzer: crate::db::source::EventAnalyzerId::Test,
+            source: crate::db::source::MetadataSourceId::Test,
+            subject_id: None,
+            object_id: None,
+            json: String::from("{}"),
+            assertion_id: -1,
+            occurred_at: None,
+        }];
+
+        let results = run_all(&handlers, &events);
+
+        assert_eq!(
+            results[0].result,
+            Some(String::from("\"one\"")),
+            "Expected the resolved value."
+        );
+        assert_contains(4321, 5678, "Rejected", &results);
+    }
+
+    /// A handler that queues Promise callbacks forever is terminated by the watchdog, rather than hanging.
+    #[test]
+    #[serial]
+    fn endless_microtasks_run() {
+        init_tests();
+
+        let handlers: Vec<HandlerSpec> = vec![HandlerSpec {
+            handler_id: 1234,
+            code: String::from(
+                "function f(args) {
+                    let n = 0;
+                    function grow() { n += 1; return Promise.resolve(n).then(grow); }
+                    return grow();
+                }",
+            ),
+            status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
+        }];
+
+        let events: Vec<Event> = vec![Event {
+            event_id: 4321,
+            analyzer: crate::db::source::EventAnalyzerId::Test,
+            source: crate::db::source::MetadataSourceId::Test,
+            subject_id: None,
+            object_id: None,
+            json: String::from("{}"),
+            assertion_id: -1,
+            occurred_at: None,
+        }];
+
+        let results = run_all(&handlers, &events);
+
+        assert!(
+            results.iter().all(|x| x.result.is_none()),
+            "Expected no results, got {:?}",
+            results
+        );
+        assert_contains(-1, 1234, "too long", &results);
+    }
+
+    /// Setting the cancel flag terminates a running handler and abandons the batch.
+    #[test]
+    #[serial]
+    fn cancel_run() {
+        init_tests();
+
+        // Would run for the whole timeout if not cancelled.
+        let handlers: Vec<HandlerSpec> = vec![HandlerSpec {
+            handler_id: 1234,
+            code: String::from("function f(args) { while(true) {}; return [args]; }"),
+            status: 1,
+            timeout_ms: 60000,
+            hash: None,
+            owner_id: 0,
+        }];
+
+        let events: Vec<Event> = vec![
+            Event {
+                event_id: 1111,
+                analyzer: crate::db::source::EventAnalyzerId::Test,
+                source: crate::db::source::MetadataSourceId::Test,
+                subject_id: None,
+                object_id: None,
+                json: String::from("{}"),
+                assertion_id: -1,
+                occurred_at: None,
+            },
+            Event {
+                event_id: 2222,
+                analyzer: crate::db::source::EventAnalyzerId::Test,
+                source: crate::db::source::MetadataSourceId::Test,
+                subject_id: None,
+                object_id: None,
+                json: String::from("{}"),
+                assertion_id: -1,
+                occurred_at: None,
+            },
+        ];
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let cancel_trigger = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            cancel_trigger.store(true, Ordering::SeqCst);
+        });
+
+        let start = Instant::now();
+        let result = run_all_cancellable(&handlers, &events, &cancel);
+
+        assert_eq!(result, Err(Cancelled), "Expected batch to be cancelled.");
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "Expected cancellation to terminate execution promptly, took {:?}",
+            start.elapsed()
+        );
+    }
+
+    /// The global 'environment' variable is available, whether from the snapshot or set per isolate.
+    #[test]
+    #[serial]
+    fn environment_available() {
+        init_tests();
+
+        let handlers: Vec<HandlerSpec> = vec![HandlerSpec {
+            handler_id: 1234,
+            code: String::from("function f(args) { return [environment.environment]; }"),
+            status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
+        }];
+
+        let events: Vec<Event> = vec![Event {
+            event_id: 1111,
+            analyzer: crate::db::source::EventAnalyzerId::Test,
+            source: crate::db::source::MetadataSourceId::Test,
+            subject_id: None,
+            object_id: None,
+            json: String::from("{}"),
+            assertion_id: -1,
+            occurred_at: None,
+        }];
+
+        let results = run_all(&handlers, &events);
+
+        assert_eq!(
+            results.first().unwrap().result,
+            Some(String::from("\"Pardalotus Metabeak\""))
+        );
+    }
+
+    /// Custom values from the deployment are available to the function.
+    #[test]
+    #[serial]
+    fn custom_environment_available() {
+        init_tests();
+
+        let handlers: Vec<HandlerSpec> = vec![HandlerSpec {
+            handler_id: 1234,
+            code: String::from("function f(args) { return [environment.custom.corpus]; }"),
+            status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
+        }];
+
+        let events: Vec<Event> = vec![Event {
+            event_id: 1111,
+            analyzer: crate::db::source::EventAnalyzerId::Test,
+            source: crate::db::source::MetadataSourceId::Test,
+            subject_id: None,
+            object_id: None,
+            json: String::from("{}"),
+            assertion_id: -1,
+            occurred_at: None,
+        }];
+
+        let results = run_all(&handlers, &events);
+
+        assert_eq!(
+            results.first().unwrap().result,
+            Some(String::from("\"test-corpus\""))
+        );
+    }
+
+    /// With pooling, a handler's isolate is kept between batches, along with its global state.
+    /// Changed code gets a new isolate.
+    #[test]
+    #[serial]
+    fn isolate_pool_reuse() {
+        init_tests();
+
+        let counter = |code: &str| HandlerSpec {
+            handler_id: 1234,
+            code: String::from(code),
+            status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
+        };
+        let handlers = vec![counter(
+            "let c = 0; function f(args) { c += 1; return [c]; }",
+        )];
+        let changed = vec![counter(
+            "let c = 10; function f(args) { c += 1; return [c]; }",
+        )];
+
+        let events: Vec<Event> = vec![Event {
+            event_id: 4321,
+            analyzer: crate::db::source::EventAnalyzerId::Test,
+            source: crate::db::source::MetadataSourceId::Test,
+            subject_id: None,
+            object_id: None,
+            json: String::from("{}"),
+            assertion_id: -1,
+            occurred_at: None,
+        }];
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let run = |handlers: &[HandlerSpec], pooling: bool| {
+            run_all_pooled(handlers, &events, &cancel, pooling, None, Duration::ZERO).unwrap()[0]
+                .result
+                .clone()
+        };
+
+        assert_eq!(run(&handlers, true), Some(String::from("1")));
+        assert_eq!(run(&handlers, true), Some(String::from("2")), "Reused.");
+        assert_eq!(run(&changed, true), Some(String::from("11")), "Reloaded.");
+
+        retain_pooled_isolates(&[]);
+        assert_eq!(run(&changed, true), Some(String::from("11")), "Dropped.");
+
+        retain_pooled_isolates(&[]);
+        assert_eq!(run(&handlers, false), Some(String::from("1")));
+        assert_eq!(
+            run(&handlers, false),
+            Some(String::from("1")),
+            "Not pooled."
+        );
+    }
+
+    /// Dropping pooled isolates applies to other threads' pools too, the next time they're used.
+    #[test]
+    #[serial]
+    fn isolate_pool_retain_other_thread() {
+        init_tests();
+
+        let handlers = vec![HandlerSpec {
+            handler_id: 1235,
+            code: String::from("let c = 0; function f(args) { c += 1; return [c]; }"),
+            status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
+        }];
+
+        let events: Vec<Event> = vec![Event {
+            event_id: 4322,
+            analyzer: crate::db::source::EventAnalyzerId::Test,
+            source: crate::db::source::MetadataSourceId::Test,
+            subject_id: None,
+            object_id: None,
+            json: String::from("{}"),
+            assertion_id: -1,
+            occurred_at: None,
+        }];
+
+        let (pooled_send, pooled_recv) = mpsc::channel();
+        let (retained_send, retained_recv) = mpsc::channel::<()>();
+
+        let worker = thread::spawn(move || {
+            let cancel = Arc::new(AtomicBool::new(false));
+            let run = || {
+                run_all_pooled(&handlers, &events, &cancel, true, None, Duration::ZERO).unwrap()[0]
+                    .result
+                    .clone()
+            };
+
+            let first = run();
+            let reused = run();
+            pooled_send.send(()).unwrap();
+
+            retained_recv.recv().unwrap();
+            let after_retain = run();
+
+            with_pool(|pool| pool.clear());
+            (first, reused, after_retain)
+        });
+
+        pooled_recv.recv().unwrap();
+        retain_pooled_isolates(&[]);
+        retained_send.send(()).unwrap();
+
+        let (first, reused, after_retain) = worker.join().unwrap();
+        assert_eq!(first, Some(String::from("1")));
+        assert_eq!(reused, Some(String::from("2")), "Reused on the worker.");
+        assert_eq!(
+            after_retain,
+            Some(String::from("1")),
+            "Dropped from the worker's pool by another thread."
+        );
+    }
+
+    /// A handler that's slow, but within its timeout, is skipped for the rest of the batch once it's used its budget.
+    /// Other handlers still run for every Event.
+    #[test]
+    #[serial]
+    fn time_budget_exceeded() {
+        init_tests();
+
+        let handlers = vec![
+            HandlerSpec {
+                handler_id: 1,
+                code: String::from(
+                    "function f(args) { const end = Date.now() + 20; while (Date.now() < end) {} return [1]; }",
+                ),
+                status: 1,
+                timeout_ms: 50,
+                hash: None,
+                owner_id: 0,
+            },
+            HandlerSpec {
+                handler_id: 2,
+                code: String::from("function f(args) { return [2]; }"),
+                status: 1,
+                timeout_ms: 50,
+                hash: None,
+                owner_id: 0,
+            },
+        ];
+
+        let events: Vec<Event> = (0..20)
+            .map(|event_id| Event {
+                event_id,
+                analyzer: crate::db::source::EventAnalyzerId::Test,
+                source: crate::db::source::MetadataSourceId::Test,
+                subject_id: None,
+                object_id: None,
+                json: String::from("{}"),
+                assertion_id: -1,
+                occurred_at: None,
+            })
+            .collect();
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let results = run_all_pooled(
+            &handlers,
+            &events,
+            &cancel,
+            false,
+            Some(Duration::from_millis(100)),
+            Duration::ZERO,
+        )
+        .unwrap();
+
+        let slow: Vec<&ExecutionResult> = results.iter().filter(|x| x.handler_id == 1).collect();
+        let succeeded = slow.iter().filter(|x| x.result.is_some()).count();
+        let skipped = slow
+            .iter()
+            .filter(|x| x.error_kind == Some(ErrorKind::TimeBudget))
+            .count();
+
+        assert_eq!(slow.len(), 20, "One result for each Event.");
+        // How many fit in the budget depends on the machine, but some run and some are skipped.
+        assert!(
+            (1..20).contains(&succeeded),
+            "Expected to run until the budget was used, got {}",
+            succeeded
+        );
+        assert_eq!(succeeded + skipped, 20);
+        assert!(
+            slow.iter()
+                .filter(|x| x.result.is_none())
+                .all(|x| x.error_kind == Some(ErrorKind::TimeBudget)),
+            "Every one not run was skipped for the time budget."
+        );
+
+        assert_eq!(
+            results
+                .iter()
+                .filter(|x| x.handler_id == 2 && x.result.is_some())
+                .count(),
+            20
+        );
+    }
+
+    /// A handler that takes slightly longer than its timeout, e.g. because it was preempted, isn't terminated within the grace period.
+    #[test]
+    #[serial]
+    fn timeout_grace_allows_borderline() {
+        init_tests();
+
+        let handlers = vec![HandlerSpec {
+            handler_id: 1,
+            code: String::from(
+                "function f(args) { const end = Date.now() + 15; while (Date.now() < end) {} return [1]; }",
+            ),
+            status: 1,
+            timeout_ms: 10,
+            hash: None,
+            owner_id: 0,
+        }];
+
+        let events: Vec<Event> = vec![Event {
+            event_id: 4321,
+            analyzer: crate::db::source::EventAnalyzerId::Test,
+            source: crate::db::source::MetadataSourceId::Test,
+            subject_id: None,
+            object_id: None,
+            json: String::from("{}"),
+            assertion_id: -1,
+            occurred_at: None,
+        }];
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let run = |grace: Duration| {
+            run_all_pooled(&handlers, &events, &cancel, false, None, grace).unwrap()
+        };
+
+        let strict = run(Duration::ZERO);
+        assert!(
+            strict
+                .iter()
+                .any(|x| x.error_kind == Some(ErrorKind::Timeout)),
+            "Terminated without grace."
+        );
+        assert!(strict.iter().all(|x| x.result.is_none()));
+
+        let lenient = run(Duration::from_millis(50));
+        assert_eq!(lenient.len(), 1);
+        assert_eq!(lenient[0].result, Some(String::from("1")));
+        assert_eq!(lenient[0].error_kind, None);
+    }
+
+    /// Compare a steady stream of small batches with and without isolate pooling. See DR-0020.
+    /// Run with `cargo test --release bench_isolate_pool -- --ignored --nocapture --test-threads=1`
+    #[test]
+    #[serial]
+    #[ignore]
+    fn bench_isolate_pool() {
+        init_tests();
+
+        let handlers: Vec<HandlerSpec> = (0..10)
+            .map(|handler_id| HandlerSpec {
+                handler_id,
+                code: format!(
+                    "const lookup = Array.from({{length: 1000}}, (_, i) => i * {});
+                    function f(args) {{ return [lookup[1]]; }}",
+                    handler_id
+                ),
+                status: 1,
+                timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+                hash: None,
+                owner_id: 0,
+            })
+            .collect();
+
+        let events: Vec<Event> = vec![Event {
+            event_id: 4321,
+            analyzer: crate::db::source::EventAnalyzerId::Test,
+            source: crate::db::source::MetadataSourceId::Test,
+            subject_id: None,
+            object_id: None,
+            json: String::from("{}"),
+            assertion_id: -1,
+            occurred_at: None,
+        }];
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut durations = vec![];
+        for pooling in [false, true] {
+            let start = Instant::now();
+            for _ in 0..100 {
+                run_all_pooled(&handlers, &events, &cancel, pooling, None, Duration::ZERO).unwrap();
+            }
+            durations.push(start.elapsed());
+        }
+        retain_pooled_isolates(&[]);
+
+        println!(
+            "100 batches of 1 Event through 10 handlers. Without pooling: {:?}. With pooling: {:?}",
+            durations[0], durations[1]
+        );
+    }
+
+    /// Compare isolate setup time with and without the snapshot. See DR-0019.
+    /// Run with `cargo test --release bench_isolate_creation -- --ignored --nocapture --test-threads=1`
+    #[test]
+    #[serial]
+    #[ignore]
+    fn bench_isolate_creation() {
+        init_tests();
+
+        let environment_json = Global::build().json();
+
+        let start = Instant::now();
+        for _ in 0..100 {
+            let isolate = &mut v8::Isolate::new(Default::default());
+            let scope = &mut v8::HandleScope::new(isolate);
+            let context = v8::Context::new(scope, Default::default());
+            let scope = &mut v8::ContextScope::new(scope, context);
+            let global = context.global(scope);
+            set_variable_from_json(scope, global, "environment", &environment_json);
+        }
+        let without_snapshot = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..100 {
+            let heap_state = HeapLimitState::new();
+            let isolate = &mut create_isolate(&heap_state);
+            let scope = &mut v8::HandleScope::new(isolate);
+            let context = v8::Context::new(scope, Default::default());
+            let _scope = &mut v8::ContextScope::new(scope, context);
+        }
+        let with_snapshot = start.elapsed();
+
+        println!(
+            "100 isolates. Without snapshot: {:?}. With snapshot: {:?}",
+            without_snapshot, with_snapshot
+        );
+    }
+
+    // Language features.
+
+    /// The Deno variable shouldn't be accessible.
+    #[test]
+    #[serial]
+    fn prohibited_deno() {
+        init_tests();
+
+        let handlers: Vec<HandlerSpec> = vec![HandlerSpec {
+            handler_id: 1234,
+            code: String::from(
+                "Deno.serve((_req) => {
+                  return new Response('Hello, World!');
+                });",
+            ),
+            status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
+        }];
+
+        let events: Vec<Event> = vec![Event {
+            event_id: 1111,
+            analyzer: crate::db::source::EventAnalyzerId::Test,
+            source: crate::db::source::MetadataSourceId::Test,
+            subject_id: None,
+            object_id: None,
+            json: String::from("{}"),
+            assertion_id: -1,
+            occurred_at: None,
+        }];
+
+        let results = run_all(&handlers, &events);
+
+        assert_contains(-1, 1234, "Deno is not defined", &results);
+    }
+
+    /// The JSON functions should be available.
+    /// Not much use, but who knows.
+    #[test]
+    #[serial]
+    fn json_deno() {
+        init_tests();
+
+        let handlers: Vec<HandlerSpec> = vec![HandlerSpec {
+            handler_id: 1234,
+            code: String::from("function f() {return [JSON.stringify([1,2,3])] }"),
+            status: 1,
+            timeout_ms: DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
+        }];
+
+        let events: Vec<Event> = vec![Event {
+            event_id: 1111,
+            analyzer: crate::db::source::EventAnalyzerId::Test,
+            source: crate::db::source::MetadataSourceId::Test,
+            subject_id: None,
+            object_id: None,
+            json: String::from("{}"),
+            assertion_id: -1,
+            occurred_at: None,
+        }];
+
+        let results = run_all(&handlers, &events);
+
+        assert_eq!(
+            results,
+            vec![ExecutionResult {
+                handler_id: 1234,
+                event_id: 1111,
+                result_id: -1,
+                result: Some(String::from("\"[1,2,3]\"")),
+                error: None,
+                error_kind: None,
+                assertion_id: -1,
+                created: None
+            }]
+        );
+    }
+
+    // Validation.
+
+    /// A handler that loads and defines `f` is valid.
+    #[test]
+    #[serial]
+    fn validate_ok() {
+        init_tests();
+
+        assert_eq!(
+            validate_handler("function f(args) { return [args]; }"),
+            Ok(())
+        );
+    }
+
+    /// Syntax errors are reported with the exception message.
+    #[test]
+    #[serial]
+    fn validate_syntax_error() {
+        init_tests();
+
+        let result = validate_handler("function f(args) { return [args; }");
+
+        assert!(
+            result.clone().unwrap_err().contains("SyntaxError"),
+            "Expected syntax error, got {:?}",
+            result
+        );
+    }
+
+    /// Code defining `f_batch` instead of `f` is valid.
+    #[test]
+    #[serial]
+    fn validate_batch_ok() {
+        init_tests();
+
+        let result = validate_handler("function f_batch(events) { return [events.length]; }");
+        assert_eq!(result, Ok(()));
+    }
+
+    /// Code without a function `f` is invalid.
+    #[test]
+    #[serial]
+    fn validate_missing_f() {
+        init_tests();
+
+        let result = validate_handler("function g(args) { return [args]; }");
+        assert!(result.is_err(), "Expected error, got {:?}", result);
+
+        let result = validate_handler("let f = 1;");
+        assert!(
+            result
+                .clone()
+                .unwrap_err()
+                .contains("'f' was not a function"),
+            "Expected error, got {:?}",
+            result
+        );
+    }
+
+    /// Code that assigns something other than a function to `f` is rejected at load time, saying what it was.
+    #[test]
+    #[serial]
+    fn validate_f_not_function() {
+        init_tests();
+
+        let result = validate_handler("var f = 5;");
+        assert_eq!(
+            result,
+            Err(String::from("'f' was not a function, it was number. Check you don't have a conflicting variable named `f`."))
+        );
+    }
+
+    /// Code that doesn't finish loading is terminated and invalid.
+    #[test]
+    #[serial]
+    fn validate_slow_load() {
+        init_tests();
+
+        let result = validate_handler("while(true) {}; function f(args) { return [1]; }");
+
+        assert!(
+            result.clone().unwrap_err().contains("too long"),
+            "Expected timeout error, got {:?}",
+            result
         );
     }
 
@@ -1,12 +1,17 @@
 //! Local File System functions.
 
-use std::fs;
+use std::{fs, path::Path};
+
+use serde::Serialize;
 
 use crate::{db::handler::HandlerState, execution::model::HandlerSpec};
 
-/// Load tasks from JS files in directory.
+/// Load tasks from JS files in directory, each with the given timeout.
 /// Return list of filenames and task specs.
-pub(crate) fn load_tasks_from_dir(load_dir: std::path::PathBuf) -> Vec<(String, HandlerSpec)> {
+pub(crate) fn load_tasks_from_dir(
+    load_dir: std::path::PathBuf,
+    timeout_ms: i32,
+) -> Vec<(String, HandlerSpec)> {
     let mut result = vec![];
 
     match fs::read_dir(load_dir) {
@@ -17,7 +22,10 @@ pub(crate) fn load_tasks_from_dir(load_dir: std::path::PathBuf) -> Vec<(String,
             for file in listing {
                 match file {
                     Ok(entry) => {
-                        if entry.path().is_file() {
+                        // Only JS files, so other files such as a manifest from `--dump-handlers` are ignored.
+                        if entry.path().is_file()
+                            && entry.path().extension().is_some_and(|x| x == "js")
+                        {
                             match fs::read_to_string(entry.path()) {
                                 Err(e) => log::error!("Can't read file: {}", e),
                                 Ok(content) => {
@@ -27,6 +35,9 @@ pub(crate) fn load_tasks_from_dir(load_dir: std::path::PathBuf) -> Vec<(String,
                                             handler_id: 0,
                                             code: content,
                                             status: HandlerState::Enabled as i32,
+                                            timeout_ms,
+                                            hash: None,
+                                            owner_id: 0,
                                         },
                                     ));
                                 }
@@ -44,6 +55,47 @@ pub(crate) fn load_tasks_from_dir(load_dir: std::path::PathBuf) -> Vec<(String,
     result
 }
 
+/// Entry in the manifest written alongside dumped handlers.
+#[derive(Serialize)]
+struct ManifestEntry {
+    id: i64,
+    file: String,
+    hash: Option<String>,
+    status: i32,
+    timeout_ms: i32,
+}
+
+/// Write each handler's code to `<handler_id>.js` in the directory, creating it if necessary.
+/// Also write `manifest.json` mapping handler ids to files, hashes, status and timeouts.
+/// The directory can be loaded again with `--load-handlers`.
+pub(crate) fn dump_handlers_to_dir(
+    dump_dir: &Path,
+    handlers: &[HandlerSpec],
+) -> Result<(), std::io::Error> {
+    fs::create_dir_all(dump_dir)?;
+
+    let mut manifest = vec![];
+    for handler in handlers {
+        let file = format!("{}.js", handler.handler_id);
+        fs::write(dump_dir.join(&file), &handler.code)?;
+
+        manifest.push(ManifestEntry {
+            id: handler.handler_id,
+            file,
+            hash: handler.hash.clone(),
+            status: handler.status,
+            timeout_ms: handler.timeout_ms,
+        });
+    }
+
+    fs::write(
+        dump_dir.join("manifest.json"),
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+
+    Ok(())
+}
+
 /// Load files in directory.
 /// Return list of filenames and contents.
 pub(crate) fn load_files_from_dir(
@@ -62,3 +114,78 @@ pub(crate) fn load_files_from_dir(
 
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// Dumped handlers load again with the same code, and the manifest is ignored.
+    #[test]
+    fn dump_handlers_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("metabeak-dump-{}", std::process::id()));
+
+        let handlers = vec![
+            HandlerSpec {
+                handler_id: 3,
+                code: String::from("function f(args) { return [1]; }"),
+                status: HandlerState::Enabled as i32,
+                timeout_ms: 10,
+                hash: Some(String::from("abc")),
+                owner_id: 0,
+            },
+            HandlerSpec {
+                handler_id: 7,
+                code: String::from("function f(args) { return [2]; }"),
+                status: HandlerState::Enabled as i32,
+                timeout_ms: 20,
+                hash: Some(String::from("def")),
+                owner_id: 0,
+            },
+        ];
+
+        dump_handlers_to_dir(&dir, &handlers).unwrap();
+
+        let manifest: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("manifest.json")).unwrap()).unwrap();
+        assert_eq!(
+            manifest,
+            serde_json::json!([
+                {"id": 3, "file": "3.js", "hash": "abc", "status": 1, "timeout_ms": 10},
+                {"id": 7, "file": "7.js", "hash": "def", "status": 1, "timeout_ms": 20},
+            ])
+        );
+
+        let mut loaded: Vec<String> = load_tasks_from_dir(dir.clone(), 10)
+            .into_iter()
+            .map(|(_, task)| task.code)
+            .collect();
+        loaded.sort();
+        assert_eq!(
+            loaded,
+            vec![
+                String::from("function f(args) { return [1]; }"),
+                String::from("function f(args) { return [2]; }"),
+            ]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Handlers loaded from disk all get the supplied timeout.
+    #[test]
+    fn load_tasks_default_timeout() {
+        let tasks = load_tasks_from_dir(PathBuf::from("samples/handlers"), 250);
+
+        assert!(!tasks.is_empty(), "Expected sample handlers to load.");
+
+        for (filename, task) in tasks.iter() {
+            assert_eq!(
+                task.timeout_ms, 250,
+                "Expected configured timeout for {}",
+                filename
+            );
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Logger setup.
+
+use std::{io::Write, str::FromStr};
+
+use serde_json::json;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// Format for log output.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum LogFormat {
+    /// Human-readable, the default.
+    Text,
+
+    /// One JSON object per line, for log aggregators.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "Unknown log format '{}'. Use 'text' or 'json'.",
+                value
+            )),
+        }
+    }
+}
+
+/// Start the logger in the given format.
+/// Logs at Info level.
+pub(crate) fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    builder.filter_level(log::LevelFilter::Info);
+
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            writeln!(
+                buf,
+                "{}",
+                json_line(
+                    record.level(),
+                    record.target(),
+                    OffsetDateTime::now_utc(),
+                    &record.args().to_string()
+                )
+            )
+        });
+    }
+
+    builder.init();
+}
+
+/// Single line JSON object for a log record.
+fn json_line(level: log::Level, target: &str, timestamp: OffsetDateTime, message: &str) -> String {
+    json!({
+        "timestamp": timestamp.format(&Rfc3339).unwrap_or_default(),
+        "level": level.as_str(),
+        "target": target,
+        "message": message,
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_format() {
+        assert_eq!(LogFormat::from_str("text"), Ok(LogFormat::Text));
+        assert_eq!(LogFormat::from_str("json"), Ok(LogFormat::Json));
+        assert!(LogFormat::from_str("xml").is_err());
+    }
+
+    /// Multi-line messages with quotes are escaped so each record stays on one line.
+    #[test]
+    fn json_line_fields() {
+        let timestamp = OffsetDateTime::parse("2024-11-20T10:15:30Z", &Rfc3339).unwrap();
+
+        let line = json_line(
+            log::Level::Warn,
+            "pardalotus_metabeak::service",
+            timestamp,
+            "Pumped \"5\" events\nin 10ms",
+        );
+
+        assert!(!line.contains('\n'));
+
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "timestamp": "2024-11-20T10:15:30Z",
+                "level": "WARN",
+                "target": "pardalotus_metabeak::service",
+                "message": "Pumped \"5\" events\nin 10ms",
+            })
+        );
+    }
+}
@@ -1,5 +1,7 @@
 use metadata_assertion::crossref::{self};
+use metadata_assertion::datacite;
 use std::path::PathBuf;
+use std::time::Instant;
 use std::{env, process::exit};
 use structopt::StructOpt;
 use tokio::task::JoinSet;
@@ -8,6 +10,7 @@ mod db;
 mod event_extraction;
 mod execution;
 mod local;
+mod logging;
 mod metadata_assertion;
 mod service;
 mod util;
@@ -23,6 +26,19 @@ struct Options {
     )]
     load_handlers: Option<PathBuf>,
 
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help("Write each enabled handler function to <handler_id>.js in the directory at path, with a manifest.json of ids, hashes and status. Can be loaded again with --load-handlers.")
+    )]
+    dump_handlers: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help("Timeout in milliseconds for each handler function execution. Applied to handlers loaded from disk, and to those uploaded via the API without a timeout.")
+    )]
+    default_handler_timeout_ms: Option<i32>,
+
     /// Load tasks from directory at path
     #[structopt(
         long,
@@ -31,12 +47,32 @@ struct Options {
     )]
     load_events: Option<PathBuf>,
 
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help("On startup, load Crossref works as metadata assertions from directory at path. Each file should contain newline-delimited JSON works, e.g. from a Crossref data dump.")
+    )]
+    load_assertions: Option<PathBuf>,
+
     #[structopt(
         long,
         help("Execute handlers over all Events in the queue. Exit when queue is empty.")
     )]
     execute: bool,
 
+    #[structopt(
+        long,
+        help("With --execute, don't exit when the queue is empty. Wait for new Events and keep executing.")
+    )]
+    daemon: bool,
+
+    #[structopt(
+        long,
+        conflicts_with("daemon"),
+        help("With --extract, --fetch-metadata or --execute, process one batch and stop, rather than emptying the queue. With several workers, each processes one batch.")
+    )]
+    once: bool,
+
     #[structopt(
         long,
         help("Fetch all Crossref metadata assertions since the last run.")
@@ -49,11 +85,160 @@ struct Options {
     )]
     fetch_crossref_secondary: Option<String>,
 
+    #[structopt(
+        long,
+        parse(try_from_str = util::parse_date),
+        help("Fetch Crossref metadata assertions deposited on or after the given date, e.g. '2021-01-01', as secondary metadata assertions. Combines with --secondary-until-date, --crossref-types and --fetch-crossref-secondary.")
+    )]
+    secondary_from_date: Option<time::OffsetDateTime>,
+
+    #[structopt(
+        long,
+        parse(try_from_str = util::parse_date),
+        help("Fetch Crossref metadata assertions deposited on or before the given date, e.g. '2021-01-02', as secondary metadata assertions. Combines with --secondary-from-date, --crossref-types and --fetch-crossref-secondary.")
+    )]
+    secondary_until_date: Option<time::OffsetDateTime>,
+
+    #[structopt(
+        long,
+        help("Order of a secondary harvest, as a Crossref sort field with an optional order, e.g. 'deposited' or 'deposited:asc'. Descending if no order is given. Default is the API's order.")
+    )]
+    secondary_sort: Option<crossref::works_api_client::FilterSort>,
+
+    #[structopt(
+        long,
+        help("With a secondary harvest, count the matching works and log a sample of DOIs, without storing anything.")
+    )]
+    dry_run: bool,
+
+    #[structopt(
+        long,
+        parse(try_from_str = util::parse_date),
+        help("Fetch all Crossref metadata assertions indexed since the given date, e.g. '2020-01-01', as primary metadata assertions. Does not read or update the checkpoint used by --fetch-crossref.")
+    )]
+    fetch_crossref_from: Option<time::OffsetDateTime>,
+
+    #[structopt(
+        long,
+        help("With --fetch-crossref, --fetch-crossref-from or a secondary harvest, only fetch works of these Crossref types, comma-separated, e.g. 'book,book-chapter'. Default is all types.")
+    )]
+    crossref_types: Option<String>,
+
+    #[structopt(
+        long,
+        help("Stop any Crossref harvest after this many pages of results. Default is unlimited.")
+    )]
+    crossref_max_pages: Option<usize>,
+
+    #[structopt(
+        long,
+        conflicts_with("resume-harvest"),
+        help("Pause harvesting. Any running harvest, in this or another process, stops before its next page without moving the checkpoint. Harvests stay paused until --resume-harvest.")
+    )]
+    pause_harvest: bool,
+
+    #[structopt(
+        long,
+        help("Resume harvesting after --pause-harvest. Applied before any harvest in this run.")
+    )]
+    resume_harvest: bool,
+
+    #[structopt(
+        long,
+        help("Remove the named harvest checkpoint, e.g. 'crossref-not-before', so the next harvest starts from now.")
+    )]
+    reset_checkpoint: Option<String>,
+
+    #[structopt(
+        long,
+        parse(try_from_str = util::parse_checkpoint_setting),
+        help("Set the named harvest checkpoint to a date, e.g. 'crossref-not-before=2024-01-01', so the next harvest starts from there. May move it earlier, to re-harvest a period.")
+    )]
+    set_checkpoint: Option<(String, time::OffsetDateTime)>,
+
+    #[structopt(
+        long,
+        help("Fetch all DataCite metadata assertions since the last run.")
+    )]
+    fetch_datacite: bool,
+
     #[structopt(long, help("Process the entire Metadata Assertion queue to produce Events. Exit when queue is empty."))]
     extract: bool,
 
+    #[structopt(
+        long,
+        help("Number of concurrent tasks for --extract. Each uses its own database connection, so keep it below DB_MAX_CONNECTIONS. Default 5.")
+    )]
+    extract_workers: Option<usize>,
+
+    #[structopt(
+        long,
+        help("With --extract, only extract Events from these analyzers, comma-separated, e.g. 'reference,lifecycle'. Default is all analyzers.")
+    )]
+    extract_analyzers: Option<String>,
+
+    #[structopt(
+        long,
+        help("Fetch metadata for the entities queued by --extract when METABEAK_DEFER_METADATA_FETCH is set. Exit when the queue is empty.")
+    )]
+    fetch_metadata: bool,
+
+    #[structopt(
+        long,
+        help("Number of concurrent tasks for --fetch-metadata. Each uses its own database connection. Default 5.")
+    )]
+    fetch_metadata_workers: Option<usize>,
+
+    #[structopt(
+        long,
+        help("Extract Events from the Metadata Assertion with this id and print them as JSON, without inserting them. Respects --extract-analyzers. For debugging extractors.")
+    )]
+    extract_assertion: Option<i64>,
+
+    #[structopt(
+        long,
+        help("Recalculate the success, error and timeout counters for all handlers from stored results.")
+    )]
+    recount: bool,
+
+    #[structopt(
+        long,
+        parse(try_from_str = util::parse_date),
+        help("Delete handler results created before the given date, e.g. '2024-01-01'. Only reports how many would be deleted unless --yes is also given.")
+    )]
+    purge_results_before: Option<time::OffsetDateTime>,
+
+    #[structopt(long, help("Confirm deletion with --purge-results-before."))]
+    yes: bool,
+
+    #[structopt(
+        long,
+        help("Number of identifiers to cache when resolving entities. Zero disables the cache. Default 100000.")
+    )]
+    identifier_cache_size: Option<usize>,
+
+    #[structopt(
+        long,
+        help("Check that all enabled handlers still load and define a function. Exit with an error if any fail.")
+    )]
+    validate_handlers: bool,
+
     #[structopt(long, help("Start the API server and block."))]
     api: bool,
+
+    #[structopt(
+        long,
+        default_value = "text",
+        help("Log format, 'text' or 'json'. JSON logs have one object per line with timestamp, level, target and message.")
+    )]
+    log_format: logging::LogFormat,
+}
+
+impl Options {
+    /// Do any of the selected options load or run handler functions?
+    fn uses_v8(&self) -> bool {
+        self.validate_handlers || self.load_handlers.is_some() || self.execute || self.api
+    }
 }
 
 /// Run the main function.
@@ -61,31 +246,177 @@ struct Options {
 /// This means if you select the right options, the output of one stage will be available for the next.
 #[tokio::main]
 async fn main() {
-    env_logger::Builder::from_default_env()
-        .filter_level(log::LevelFilter::Info)
-        .init();
-
     let opt = Options::from_args();
 
-    let uri = env::var("DB_URI");
-    if let Err(_) = uri {
-        log::error!("DB_URI not supplied");
+    logging::init(opt.log_format);
+
+    let default_handler_timeout_ms = opt
+        .default_handler_timeout_ms
+        .unwrap_or(execution::run::DEFAULT_EXECUTION_TIMEOUT_MS);
+    if default_handler_timeout_ms < 1 {
+        log::error!("--default-handler-timeout-ms must be at least 1");
         exit(1);
     }
 
+    let extract_workers = opt
+        .extract_workers
+        .unwrap_or(event_extraction::service::DEFAULT_WORKERS);
+    if extract_workers < 1 {
+        log::error!("--extract-workers must be at least 1");
+        exit(1);
+    }
+
+    let fetch_metadata_workers = opt
+        .fetch_metadata_workers
+        .unwrap_or(metadata_assertion::retrieve::DEFAULT_FETCH_WORKERS);
+    if fetch_metadata_workers < 1 {
+        log::error!("--fetch-metadata-workers must be at least 1");
+        exit(1);
+    }
+
+    let extract_analyzers = match opt
+        .extract_analyzers
+        .as_deref()
+        .map(db::source::EventAnalyzerId::parse_list)
+        .transpose()
+    {
+        Ok(analyzers) => analyzers,
+        Err(e) => {
+            log::error!("Invalid --extract-analyzers: {}", e);
+            exit(1);
+        }
+    };
+
+    let crossref_types: Vec<String> = opt
+        .crossref_types
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(String::from)
+        .collect();
+
+    let secondary_filter = crossref::works_api_client::FilterBuilder::new()
+        .raw(opt.fetch_crossref_secondary.as_deref())
+        .since(opt.secondary_from_date)
+        .until(opt.secondary_until_date)
+        .types(&crossref_types);
+
+    let secondary_filter = if secondary_filter.is_empty() {
+        None
+    } else {
+        match secondary_filter.build() {
+            Ok(filter) => Some(filter),
+            Err(e) => {
+                log::error!("Invalid secondary harvest filter: {}", e);
+                exit(1);
+            }
+        }
+    };
+
+    if opt.dry_run && secondary_filter.is_none() {
+        log::error!("--dry-run only applies to a secondary harvest, and none was requested.");
+        exit(1);
+    }
+
+    let checkpoint_ids = opt
+        .reset_checkpoint
+        .iter()
+        .chain(opt.set_checkpoint.iter().map(|(id, _)| id));
+    for id in checkpoint_ids {
+        if !service::is_checkpoint_id(id) {
+            log::error!(
+                "Unknown checkpoint '{}'. Expected one of: {}, or {}:<types> for a --crossref-types harvest.",
+                id,
+                service::CHECKPOINT_IDS.join(", "),
+                crossref::metadata_agent::CROSSREF_NB
+            );
+            exit(1);
+        }
+    }
+
+    let uri = match db::pool::db_uri(env::var("DB_URI").ok(), env::var("DB_URI_FILE").ok()) {
+        Ok(uri) => uri,
+        Err(e) => {
+            log::error!("{}", e);
+            exit(1);
+        }
+    };
+
+    db::entity::init_identifier_cache(
+        opt.identifier_cache_size
+            .unwrap_or(db::entity::DEFAULT_IDENTIFIER_CACHE_SIZE),
+    );
+
     // Boot the database.
-    let db_pool = db::pool::get_pool(uri.unwrap()).await.unwrap();
+    let db_pool = db::pool::get_pool(uri).await.unwrap();
+
+    // Custom values for the handler environment. Must be set before booting v8.
+    if let Ok(custom_json) = env::var("HANDLER_ENV_JSON") {
+        match execution::model::parse_custom_environment(&custom_json) {
+            Ok(custom) => execution::model::init_custom_environment(custom),
+            Err(e) => {
+                log::error!("Invalid HANDLER_ENV_JSON: {}", e);
+                db::pool::close_pool(&db_pool).await;
+                exit(1);
+            }
+        }
+    }
 
     // Boot the v8 environment, as it's used in both validation and execution of functions.
-    execution::run::init();
+    // Harvesting and extraction don't need it, so skip it and save the memory.
+    if opt.uses_v8() {
+        let start = Instant::now();
+        execution::run::init();
+        log::info!("Initialized V8 in {:?}", start.elapsed());
+    } else {
+        log::debug!("Not initializing V8, as no handlers will be run.");
+    }
 
     // Run Optional features.
+    if opt.validate_handlers {
+        match service::validate_handlers(&db_pool).await {
+            Ok(failures) if failures.is_empty() => {
+                log::info!("All handlers are valid.");
+            }
+            Ok(failures) => {
+                for (handler_id, message) in failures.iter() {
+                    log::error!("Handler {} is invalid: {}", handler_id, message);
+                }
+                log::error!("{} handlers are invalid.", failures.len());
+                db::pool::close_pool(&db_pool).await;
+                exit(1);
+            }
+            Err(e) => {
+                log::error!("Error validating handlers: {:?}", e);
+                db::pool::close_pool(&db_pool).await;
+                exit(1);
+            }
+        }
+    }
+
     if let Some(path) = opt.load_handlers {
         log::info!(
             "Reading functions from {}",
             path.clone().into_os_string().into_string().unwrap()
         );
-        service::load_handler_functions_from_disk(&db_pool, path).await;
+        service::load_handler_functions_from_disk(&db_pool, path, default_handler_timeout_ms).await;
+    }
+
+    if let Some(path) = opt.dump_handlers {
+        log::info!(
+            "Writing functions to {}",
+            path.clone().into_os_string().into_string().unwrap()
+        );
+        match service::dump_handler_functions_to_disk(&db_pool, &path).await {
+            Ok(count) => {
+                log::info!("Wrote {} functions", count);
+            }
+            Err(e) => {
+                log::error!("Didn't write functions: {:?}", e);
+            }
+        }
     }
 
     if let Some(path) = opt.load_events {
@@ -103,9 +434,53 @@ async fn main() {
         }
     }
 
+    if let Some(path) = opt.load_assertions {
+        log::info!(
+            "Reading metadata assertions from {}",
+            path.clone().into_os_string().into_string().unwrap()
+        );
+        match service::load_assertions_from_disk(&db_pool, path).await {
+            Ok(count) => {
+                log::info!("Loaded {} metadata assertions", count);
+            }
+            Err(e) => {
+                log::error!("Didn't load metadata assertions: {}", e);
+            }
+        }
+    }
+
+    if opt.pause_harvest || opt.resume_harvest {
+        if let Err(e) = service::set_harvest_running(&db_pool, opt.resume_harvest).await {
+            log::error!("Error setting harvest control flag: {:?}", e);
+        }
+    }
+
+    // Stop rather than harvest from the old checkpoint.
+    if let Some(ref id) = opt.reset_checkpoint {
+        if let Err(e) = service::reset_checkpoint(&db_pool, id).await {
+            log::error!("Error resetting checkpoint {}: {:?}", id, e);
+            db::pool::close_pool(&db_pool).await;
+            exit(1);
+        }
+    }
+
+    if let Some((ref id, date)) = opt.set_checkpoint {
+        if let Err(e) = service::set_checkpoint(&db_pool, id, date).await {
+            log::error!("Error setting checkpoint {}: {:?}", id, e);
+            db::pool::close_pool(&db_pool).await;
+            exit(1);
+        }
+    }
+
     if opt.fetch_crossref {
         log::info!("Poll Crossref for new metadata...");
-        match crossref::metadata_agent::poll_newly_indexed_data(&db_pool).await {
+        match crossref::metadata_agent::poll_newly_indexed_data(
+            &db_pool,
+            &crossref_types,
+            opt.crossref_max_pages,
+        )
+        .await
+        {
             Ok(_) => {
                 log::info!("Finished polling Crossref for metadata.");
             }
@@ -115,13 +490,20 @@ async fn main() {
         }
     }
 
-    if let Some(filter) = opt.fetch_crossref_secondary {
+    if let Some(filter) = secondary_filter {
         log::info!(
             "Poll Crossref for secondary metadata assertions with filter {}...",
             filter
         );
 
-        match crossref::metadata_agent::fetch_secondary_metadata_with_filter(&db_pool, filter).await
+        match crossref::metadata_agent::fetch_secondary_metadata_with_filter(
+            &db_pool,
+            filter,
+            opt.secondary_sort,
+            opt.crossref_max_pages,
+            opt.dry_run,
+        )
+        .await
         {
             Ok(_) => {
                 log::info!("Finished polling Crossref for secondary metadata.");
@@ -132,15 +514,48 @@ async fn main() {
         }
     }
 
+    if let Some(after) = opt.fetch_crossref_from {
+        log::info!("Poll Crossref for metadata indexed since {}...", after);
+        match crossref::metadata_agent::fetch_indexed_from(
+            &db_pool,
+            after,
+            &crossref_types,
+            opt.crossref_max_pages,
+        )
+        .await
+        {
+            Ok(_) => {
+                log::info!("Finished polling Crossref for metadata.");
+            }
+            Err(e) => {
+                log::error!("Error polling Crossref for metadata: {:?}", e);
+            }
+        }
+    }
+
+    if opt.fetch_datacite {
+        log::info!("Poll DataCite for new metadata...");
+        match datacite::metadata_agent::poll_newly_indexed_data(&db_pool).await {
+            Ok(_) => {
+                log::info!("Finished polling DataCite for metadata.");
+            }
+            Err(e) => {
+                log::error!("Error polling DataCite for metadata: {:?}", e);
+            }
+        }
+    }
+
     if opt.extract {
         let mut set = JoinSet::new();
 
-        for i in 0..5 {
+        for i in 0..extract_workers {
             log::info!("Start extract task {}", i);
             let db_pool = db_pool.clone();
+            let analyzers = extract_analyzers.clone();
+            let once = opt.once;
             set.spawn(async move {
                 log::info!("Processing metadata to extract events...");
-                match event_extraction::service::drain(&db_pool).await {
+                match event_extraction::service::drain(&db_pool, analyzers.as_deref(), once).await {
                     Ok(_) => {
                         log::info!("Finished extracting events.");
                     }
@@ -156,17 +571,120 @@ async fn main() {
         log::info!("All extract tasks complete.");
     }
 
+    if opt.fetch_metadata {
+        let mut set = JoinSet::new();
+
+        for i in 0..fetch_metadata_workers {
+            log::info!("Start fetch metadata task {}", i);
+            let db_pool = db_pool.clone();
+            let once = opt.once;
+            set.spawn(async move {
+                match metadata_assertion::retrieve::drain_fetch_queue(&db_pool, once).await {
+                    Ok(_) => {
+                        log::info!("Finished fetching queued metadata.");
+                    }
+                    Err(e) => {
+                        log::error!("Error fetching queued metadata: {:?}", e);
+                    }
+                };
+            });
+        }
+
+        set.join_all().await;
+        log::info!("All fetch metadata tasks complete.");
+    }
+
+    if let Some(assertion_id) = opt.extract_assertion {
+        match event_extraction::service::extract_one(
+            &db_pool,
+            assertion_id,
+            extract_analyzers.as_deref(),
+        )
+        .await
+        {
+            Ok(Some(events)) => {
+                let values: Vec<serde_json::Value> = events
+                    .iter()
+                    .filter_map(|x| x.to_json_value())
+                    .filter_map(|x| serde_json::from_str(&x).ok())
+                    .collect();
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&values).unwrap_or_default()
+                );
+                log::info!(
+                    "Extracted {} events from assertion id {}",
+                    values.len(),
+                    assertion_id
+                );
+            }
+            Ok(None) => {
+                log::error!("No metadata assertion with id {}", assertion_id);
+            }
+            Err(e) => {
+                log::error!("Error extracting assertion id {}: {:?}", assertion_id, e);
+            }
+        }
+    }
+
     // Run executor.
     if opt.execute {
         log::info!("Starting executor...");
-        service::drain(&db_pool).await;
+        if opt.daemon {
+            if let Err(e) = service::drain_forever(&db_pool).await {
+                log::error!("Executor stopped: {:?}", e);
+            }
+        } else {
+            service::drain(&db_pool, opt.once).await;
+        }
         log::info!("Finish executor.");
     }
 
+    if let Some(before) = opt.purge_results_before {
+        if opt.yes {
+            log::info!("Purging results created before {}...", before);
+            match db::handler::purge_results(&db_pool, before).await {
+                Ok(count) => {
+                    log::info!("Purged {} results.", count);
+                }
+                Err(e) => {
+                    log::error!("Error purging results: {:?}", e);
+                }
+            }
+        } else {
+            match db::handler::count_results_before(&db_pool, before).await {
+                Ok(count) => {
+                    log::warn!(
+                        "Would purge {} results created before {}. Add --yes to delete them.",
+                        count,
+                        before
+                    );
+                }
+                Err(e) => {
+                    log::error!("Error counting results to purge: {:?}", e);
+                }
+            }
+        }
+    }
+
+    if opt.recount {
+        log::info!("Recounting handler results...");
+        match db::handler::recount_results(&db_pool).await {
+            Ok(count) => {
+                log::info!("Recounted results for {} handlers.", count);
+            }
+            Err(e) => {
+                log::error!("Error recounting results: {:?}", e);
+            }
+        }
+    }
+
     // Run API server.
     if opt.api {
         log::info!("Starting API server...");
-        api::run(&db_pool).await;
+        // Optional. If not supplied, mutating endpoints are unauthenticated.
+        let api_key = env::var("METABEAK_API_KEY").ok().filter(|x| !x.is_empty());
+        api::run(&db_pool, default_handler_timeout_ms, api_key).await;
     }
 
     // Gracefully closing the pool avoids extraneous errors in the PostgreSQL log.
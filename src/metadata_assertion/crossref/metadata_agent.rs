@@ -1,109 +1,267 @@
 //! Agent for retrieving metadata assertions from the Crossref API.
 
 use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::OnceLock;
 
-use scholarly_identifiers::identifiers::Identifier;
 use sqlx::{Pool, Postgres};
 
 use time::{Duration, OffsetDateTime};
 
-use crate::db::agents::get_checkpoint;
-use crate::db::agents::set_checkpoint;
+use crate::db::agents::get_checkpoint_position;
+use crate::db::agents::set_checkpoint_forward;
+use crate::db::agents::{clear_cursor_checkpoint, get_cursor_checkpoint, set_cursor_checkpoint};
 use crate::db::metadata::MetadataAssertionReason;
-use crate::metadata_assertion::crossref::works_api_client::harvest_with_filter_to_chan;
-use crate::metadata_assertion::crossref::{
-    metadata::get_index_date, works_api_client::harvest_precise_index_date,
+use crate::db::source::MetadataSourceId;
+use crate::metadata_assertion::crossref::works_api_client::harvest_precise_index_date;
+use crate::metadata_assertion::crossref::works_api_client::{
+    harvest_position, harvest_with_filter_to_chan, index_date_filter, FilterSort, HarvestEnd,
+    HarvestPosition,
 };
-use crate::metadata_assertion::service::assert_metadata;
+use crate::metadata_assertion::service::{assert_metadata, get_identifier_and_json};
+use crate::util::env_or_default;
 
 /// Date value for checkpointing the harvest.
-const CROSSREF_NB: &str = "crossref-not-before";
+pub(crate) const CROSSREF_NB: &str = "crossref-not-before";
+
+/// Cursor for resuming a harvest that stopped early.
+const CROSSREF_CURSOR: &str = "crossref-cursor";
+
+/// Checkpoint for a harvest restricted to the types, e.g. `crossref-not-before:book,book-chapter`,
+/// so that it doesn't move the checkpoint for works of other types.
+/// The same types in any order share a checkpoint. An unrestricted harvest uses `base`.
+fn checkpoint_id(base: &str, types: &[String]) -> String {
+    if types.is_empty() {
+        return String::from(base);
+    }
+
+    let mut types = types.to_vec();
+    types.sort();
+    types.dedup();
+    format!("{}:{}", base, types.join(","))
+}
+
+/// Default number of metadata assertions committed together while harvesting.
+const DEFAULT_HARVEST_BATCH_SIZE: usize = 1000;
+
+static HARVEST_BATCH_SIZE: OnceLock<usize> = OnceLock::new();
+
+/// Number of metadata assertions committed together while harvesting.
+/// Configured from the METABEAK_HARVEST_BATCH_SIZE environment variable, read once.
+fn harvest_batch_size() -> usize {
+    *HARVEST_BATCH_SIZE
+        .get_or_init(|| env_or_default("METABEAK_HARVEST_BATCH_SIZE", DEFAULT_HARVEST_BATCH_SIZE))
+}
+
+/// Number of DOIs logged as a sample by a dry run.
+const DRY_RUN_SAMPLE_SIZE: usize = 10;
 
 /// Retrieve all new Crossref data since the last run.
 /// The date used for checkpointing is the latest indexed date reported by the Crossref API, not the local datetime.
-pub(crate) async fn poll_newly_indexed_data(pool: &Pool<Postgres>) -> anyhow::Result<()> {
-    let mut tx = pool.begin().await?;
-    // Start from most recent run, now.
-    // Add 1 hour margin for jitter. This results in duplicate fetches but they are de-duplicated in the database.
-    let saturating_sub = get_checkpoint(CROSSREF_NB, &mut tx)
-        .await?
-        .unwrap_or(OffsetDateTime::now_utc())
-        .saturating_sub(Duration::HOUR);
-    let after = saturating_sub;
+/// The DOI of the latest work is stored with it, so the next run continues precisely after that work.
+/// If `types` isn't empty, only works of those types are retrieved, with their own checkpoints,
+/// see [`checkpoint_id`]. If there's no checkpoint for the types yet, start from the unrestricted one.
+///
+/// Assertions are committed in batches as they arrive. Works arrive newest first, so the checkpoint
+/// only moves once the harvest is complete. If it's interrupted the next run fetches the same works
+/// again, and the committed ones are de-duplicated. The same applies if the harvest stops at `max_pages`.
+///
+/// When it stops at `max_pages`, is paused or fails, the cursor for the next page is stored in the `crossref-cursor`
+/// checkpoint, so the next run resumes from there rather than the first page, if the cursor hasn't expired.
+pub(crate) async fn poll_newly_indexed_data(
+    pool: &Pool<Postgres>,
+    types: &[String],
+    max_pages: Option<usize>,
+) -> anyhow::Result<()> {
+    let checkpoint = checkpoint_id(CROSSREF_NB, types);
+    let cursor_checkpoint = checkpoint_id(CROSSREF_CURSOR, types);
 
-    // Get only assertions indexed after the date.
-    let new_after = harvest_recently_indexed(&after, pool).await?;
+    let mut tx = pool.begin().await?;
+    let stored = match get_checkpoint_position(&checkpoint, &mut tx).await? {
+        None if !types.is_empty() => get_checkpoint_position(CROSSREF_NB, &mut tx).await?,
+        stored => stored,
+    };
+    let after = match stored {
+        // Continue after the last work harvested.
+        Some((date, Some(doi))) => (date, doi),
+
+        // Start from most recent run, or now, e.g. if the checkpoint was set by hand.
+        // Add 1 hour margin for jitter. This results in duplicate fetches but they are de-duplicated in the database.
+        checkpoint => (
+            checkpoint
+                .map(|(date, _)| date)
+                .unwrap_or(OffsetDateTime::now_utc())
+                .saturating_sub(Duration::HOUR),
+            String::new(),
+        ),
+    };
+    tx.commit().await?;
 
-    set_checkpoint(CROSSREF_NB, new_after, &mut tx).await?;
+    // Get only assertions indexed after the position.
+    let (new_date, new_doi) =
+        harvest_recently_indexed(&after, types, max_pages, Some(&cursor_checkpoint), pool).await?;
 
+    let mut tx = pool.begin().await?;
+    let tiebreak = Some(new_doi.as_str()).filter(|x| !x.is_empty());
+    set_checkpoint_forward(&checkpoint, new_date, tiebreak, &mut tx).await?;
     tx.commit().await?;
 
     Ok(())
 }
 
-/// Retrieve all Crossref data matching given Crossref REST API filter.
+/// Retrieve all Crossref data indexed since the given date as primary metadata assertions.
+/// Unlike [`poll_newly_indexed_data`], the checkpoint is neither read nor updated.
+pub(crate) async fn fetch_indexed_from(
+    pool: &Pool<Postgres>,
+    after: OffsetDateTime,
+    types: &[String],
+    max_pages: Option<usize>,
+) -> anyhow::Result<()> {
+    let (latest, _) =
+        harvest_recently_indexed(&(after, String::new()), types, max_pages, None, pool).await?;
+
+    log::info!("Harvested from {} up to {}", after, latest);
+
+    Ok(())
+}
+
+/// Retrieve all Crossref data matching given Crossref REST API filter, in the given order if any.
+/// With `dry_run`, the works are counted and a sample of DOIs logged, but nothing is stored.
 pub(crate) async fn fetch_secondary_metadata_with_filter(
     pool: &Pool<Postgres>,
     filter: String,
+    sort: Option<FilterSort>,
+    max_pages: Option<usize>,
+    dry_run: bool,
 ) -> anyhow::Result<()> {
     let tx = pool.begin().await?;
 
-    harvest_secondary_with_filter(filter, pool).await?;
+    harvest_secondary_with_filter(filter, sort, max_pages, dry_run, pool).await?;
 
     tx.commit().await?;
 
     Ok(())
 }
 
-pub(crate) fn get_identifier_and_json(
-    json_value: serde_json::Value,
-) -> Option<(Identifier, String)> {
-    if let Some(doi) = &json_value["DOI"].as_str() {
-        // Normalise and identify the type of the identifier.
-        // For Crossref records, this will be the DOI type ID.
-        let identifier = scholarly_identifiers::identifiers::Identifier::parse(doi);
-
-        if let Ok(json_value) = serde_json::to_string(&json_value) {
-            Some((identifier, json_value))
-        } else {
-            None
+/// Harvest data after the given position, returning the position of the most recent work.
+/// If none were retrieved, the `after` position is returned, so it can be attempted again next time.
+/// If `types` isn't empty, only works of those types are retrieved.
+///
+/// Assertions are committed in batches of [harvest_batch_size]. The channel from the API client
+/// holds at most one batch, so fetching waits when the database is the bottleneck.
+///
+/// If `max_pages` is given, stop after that many pages. As works arrive newest first, older ones may be missed,
+/// so the `after` position is returned.
+///
+/// If `cursor_checkpoint` is given, resume from the cursor stored there if it's for the same query,
+/// and store the cursor to resume from if the harvest stops early.
+pub(crate) async fn harvest_recently_indexed(
+    after: &HarvestPosition,
+    types: &[String],
+    max_pages: Option<usize>,
+    cursor_checkpoint: Option<&str>,
+    pool: &Pool<Postgres>,
+) -> anyhow::Result<HarvestPosition> {
+    let batch_size = harvest_batch_size();
+
+    // A cursor is only valid for the query it came from.
+    let query = index_date_filter(&after.0, types);
+    let resume_cursor = match cursor_checkpoint {
+        Some(id) => {
+            let mut tx = pool.begin().await?;
+            let stored = get_cursor_checkpoint(id, &mut tx).await?;
+            tx.commit().await?;
+
+            match stored {
+                Some((cursor, stored_query)) if stored_query == query => {
+                    log::info!("Resume harvest from stored cursor for {}", query);
+                    Some(cursor)
+                }
+                Some((_, stored_query)) => {
+                    log::info!(
+                        "Stored cursor is for {}, not {}, so not resuming from it.",
+                        stored_query,
+                        query
+                    );
+                    None
+                }
+                None => None,
+            }
+        }
+        None => None,
+    };
+
+    let (send_metadata_docs, receive_metadata_docs) = tokio::sync::mpsc::channel(batch_size);
+    let after_position = after.clone();
+    let types = types.to_vec();
+    let control_pool = pool.clone();
+    let c = tokio::task::spawn(async move {
+        harvest_precise_index_date(
+            send_metadata_docs,
+            after_position,
+            types,
+            max_pages,
+            resume_cursor,
+            control_pool,
+        )
+        .await
+    });
+
+    log::info!("Start harvest after {} {}", after.0, after.1);
+    let (count, latest) =
+        store_harvested(receive_metadata_docs, after.clone(), batch_size, pool).await?;
+    log::info!(
+        "Stop harvest, retrieved {}, latest {} {}",
+        count,
+        latest.0,
+        latest.1
+    );
+
+    let end = c.await??;
+
+    // Only stored once everything fetched so far is committed, so resuming doesn't skip anything.
+    if let Some(id) = cursor_checkpoint {
+        let mut tx = pool.begin().await?;
+        match &end {
+            HarvestEnd::Incomplete(Some(cursor)) => {
+                set_cursor_checkpoint(id, cursor, &query, &mut tx).await?
+            }
+            _ => clear_cursor_checkpoint(id, &mut tx).await?,
         }
-    } else {
-        None
+        tx.commit().await?;
     }
-}
 
-/// Harvest data until the given date, returning the index date of the most recent.
-/// If none were retrieved, the `after` date is returned, so it can be attepmted again next time.
-pub(crate) async fn harvest_recently_indexed<'a>(
-    after: &OffsetDateTime,
-    pool: &Pool<Postgres>,
-) -> anyhow::Result<OffsetDateTime> {
-    let (send_metadata_docs, receive_metadata_docs): (
-        Sender<serde_json::Value>,
-        Receiver<serde_json::Value>,
-    ) = mpsc::channel();
-    let after_a = *after;
-    let c =
-        tokio::task::spawn(
-            async move { harvest_precise_index_date(send_metadata_docs, after_a).await },
+    if end != HarvestEnd::Complete {
+        log::warn!(
+            "Harvest stopped early, so works indexed before {} may be missing.",
+            latest.0
         );
+    }
+
+    Ok(harvested_up_to(&end, after, latest))
+}
 
-    let mut latest_date = *after;
+/// Store works from the channel as primary metadata assertions, committing every `batch_size`.
+/// Return how many were stored, and the position of the most recent work, or `after` if none were later.
+async fn store_harvested(
+    mut receive_metadata_docs: tokio::sync::mpsc::Receiver<serde_json::Value>,
+    after: HarvestPosition,
+    batch_size: usize,
+    pool: &Pool<Postgres>,
+) -> anyhow::Result<(usize, HarvestPosition)> {
+    // Tracked across batches.
+    let mut latest = after;
 
-    log::info!("Start harvest after {}", after);
     let mut count = 0;
     let mut tx = pool.begin().await?;
 
-    for item in receive_metadata_docs {
-        if let Some(indexed) = get_index_date(&item) {
-            latest_date = indexed.max(latest_date);
+    while let Some(item) = receive_metadata_docs.recv().await {
+        if let Some(position) = harvest_position(&item) {
+            latest = position.max(latest);
 
-            if let Some((identifier, json)) = get_identifier_and_json(item) {
+            if let Some((identifier, json)) =
+                get_identifier_and_json(item, MetadataSourceId::Crossref)
+            {
                 count += 1;
-                if (count % 1000) == 0 {
-                    log::info!("Harvested {} items.", count);
-                }
 
                 assert_metadata(
                     &identifier,
@@ -114,21 +272,40 @@ pub(crate) async fn harvest_recently_indexed<'a>(
                     &mut tx,
                 )
                 .await?;
+
+                if (count % batch_size) == 0 {
+                    tx.commit().await?;
+                    log::info!("Harvested {} items.", count);
+                    tx = pool.begin().await?;
+                }
             }
         }
     }
     tx.commit().await?;
 
-    log::info!("Stop harvest, retrieved {}, latest {}", count, latest_date);
+    Ok((count, latest))
+}
 
-    c.await?.unwrap();
-    Ok(latest_date)
+/// Position the checkpoint can move up to after a harvest.
+/// Works arrive newest first, so if it stopped early, older ones may be missing and it stays at `after`.
+fn harvested_up_to(
+    end: &HarvestEnd,
+    after: &HarvestPosition,
+    latest: HarvestPosition,
+) -> HarvestPosition {
+    match end {
+        HarvestEnd::Complete => latest,
+        HarvestEnd::Incomplete(_) => after.clone(),
+    }
 }
 
 /// Harvest data until the given date, returning the index date of the most recent.
-/// If none were retrieved, the `after` date is returned, so it can be attepmted again next time.
-pub(crate) async fn harvest_secondary_with_filter<'a>(
+/// If none were retrieved, the `after` date is returned, so it can be attempted again next time.
+pub(crate) async fn harvest_secondary_with_filter(
     filter: String,
+    sort: Option<FilterSort>,
+    max_pages: Option<usize>,
+    dry_run: bool,
     pool: &Pool<Postgres>,
 ) -> anyhow::Result<()> {
     log::info!("Start harvest for filter {}", filter);
@@ -137,15 +314,30 @@ pub(crate) async fn harvest_secondary_with_filter<'a>(
         Sender<serde_json::Value>,
         Receiver<serde_json::Value>,
     ) = mpsc::channel();
-    let c =
-        tokio::task::spawn(
-            async move { harvest_with_filter_to_chan(send_metadata_docs, filter).await },
+    let control_pool = pool.clone();
+    let filter_description = filter.clone();
+    let c = tokio::task::spawn(async move {
+        harvest_with_filter_to_chan(send_metadata_docs, filter, sort, max_pages, control_pool).await
+    });
+
+    if dry_run {
+        let (count, sample) = count_and_sample(receive_metadata_docs, DRY_RUN_SAMPLE_SIZE);
+        log::info!(
+            "Dry run for filter {} matched {} works, none stored. Sample: {}",
+            filter_description,
+            count,
+            sample.join(", ")
         );
 
+        c.await?.unwrap();
+        return Ok(());
+    }
+
     let mut count = 0;
     let mut tx = pool.begin().await?;
     for item in receive_metadata_docs {
-        if let Some((identifier, json)) = get_identifier_and_json(item) {
+        if let Some((identifier, json)) = get_identifier_and_json(item, MetadataSourceId::Crossref)
+        {
             count += 1;
             if (count % 1000) == 0 {
                 log::info!("Harvested {} items.", count);
@@ -171,3 +363,174 @@ pub(crate) async fn harvest_secondary_with_filter<'a>(
 
     Ok(())
 }
+
+/// Count the works that have a DOI, and take the first few DOIs as a sample.
+fn count_and_sample(
+    items: impl IntoIterator<Item = serde_json::Value>,
+    sample_size: usize,
+) -> (usize, Vec<String>) {
+    let mut count = 0;
+    let mut sample = Vec::with_capacity(sample_size);
+
+    for item in items {
+        if let Some((identifier, _)) = get_identifier_and_json(item, MetadataSourceId::Crossref) {
+            count += 1;
+            if sample.len() < sample_size {
+                sample.push(identifier.to_id_string_pair().0);
+            }
+        }
+    }
+
+    (count, sample)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use serde_json::json;
+
+    use crate::db::pool::get_pool;
+    use crate::util::parse_date;
+
+    use super::*;
+
+    /// Every work with a DOI is counted, but only the first few are sampled.
+    #[test]
+    fn dry_run_count_and_sample() {
+        let items = vec![
+            json!({"DOI": "10.5555/1"}),
+            json!({"title": ["No DOI"]}),
+            json!({"DOI": "10.5555/2"}),
+            json!({"DOI": "10.5555/3"}),
+        ];
+
+        let (count, sample) = count_and_sample(items, 2);
+
+        assert_eq!(count, 3);
+        assert_eq!(
+            sample,
+            vec![String::from("10.5555/1"), String::from("10.5555/2")]
+        );
+    }
+
+    /// Each set of types has its own checkpoint, whatever the order.
+    #[test]
+    fn checkpoint_per_types() {
+        assert_eq!(checkpoint_id(CROSSREF_NB, &[]), "crossref-not-before");
+        assert_eq!(
+            checkpoint_id(
+                CROSSREF_NB,
+                &[
+                    String::from("book-chapter"),
+                    String::from("book"),
+                    String::from("book-chapter")
+                ]
+            ),
+            "crossref-not-before:book,book-chapter"
+        );
+        assert_eq!(
+            checkpoint_id(CROSSREF_CURSOR, &[String::from("book")]),
+            "crossref-cursor:book"
+        );
+    }
+
+    /// The checkpoint only moves up to the latest work if the harvest was complete.
+    #[test]
+    fn checkpoint_only_after_complete() {
+        let after = (
+            parse_date("2024-11-20T00:00:00Z").unwrap(),
+            String::from("10.5555/1"),
+        );
+        let latest = (
+            parse_date("2024-11-21T10:00:00Z").unwrap(),
+            String::from("10.5555/2"),
+        );
+
+        assert_eq!(
+            harvested_up_to(&HarvestEnd::Complete, &after, latest.clone()),
+            latest
+        );
+        assert_eq!(
+            harvested_up_to(&HarvestEnd::Incomplete(None), &after, latest.clone()),
+            after
+        );
+        assert_eq!(
+            harvested_up_to(
+                &HarvestEnd::Incomplete(Some(String::from("next"))),
+                &after,
+                latest
+            ),
+            after
+        );
+    }
+
+    /// Number of assertions stored for DOIs starting with the prefix.
+    async fn stored_with_prefix(prefix: &str, pool: &Pool<Postgres>) -> i64 {
+        sqlx::query_scalar(
+            "SELECT COUNT(*) FROM metadata_assertion
+            JOIN entity ON entity.entity_id = metadata_assertion.subject_entity_id
+            WHERE entity.identifier LIKE $1;",
+        )
+        .bind(format!("{}%", prefix))
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Each full batch is committed while the harvest is still running,
+    /// and the latest indexed date is tracked across batches.
+    /// Run with `cargo test harvest_batch_commits -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn harvest_batch_commits() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        // Unique per run, as batches are committed.
+        let prefix = format!(
+            "10.5555/batch-{}-",
+            OffsetDateTime::now_utc().unix_timestamp_nanos()
+        );
+        let work = |n: usize, indexed: &str| json!({"DOI": format!("{}{}", prefix, n), "indexed": {"date-time": indexed}});
+
+        let after = (parse_date("2024-11-20T00:00:00Z").unwrap(), String::new());
+        let (send, receive) = tokio::sync::mpsc::channel(2);
+        let store_pool = pool.clone();
+        let consumer =
+            tokio::spawn(async move { store_harvested(receive, after, 2, &store_pool).await });
+
+        send.send(work(1, "2024-11-21T10:00:00Z")).await.unwrap();
+        send.send(work(2, "2024-11-20T10:00:00Z")).await.unwrap();
+
+        // The first batch is visible before the harvest ends.
+        let mut committed = 0;
+        for _ in 0..100 {
+            committed = stored_with_prefix(&prefix, &pool).await;
+            if committed == 2 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert_eq!(committed, 2, "First batch committed.");
+
+        send.send(work(3, "2024-11-20T12:00:00Z")).await.unwrap();
+        drop(send);
+
+        let (count, latest) = consumer.await.unwrap().unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(
+            latest,
+            (
+                parse_date("2024-11-21T10:00:00Z").unwrap(),
+                format!("{}1", prefix)
+            ),
+            "Latest work's position, for the checkpoint."
+        );
+        assert_eq!(
+            stored_with_prefix(&prefix, &pool).await,
+            3,
+            "Partial batch committed at the end."
+        );
+    }
+}
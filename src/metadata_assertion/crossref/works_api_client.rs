@@ -2,30 +2,37 @@
 use anyhow::Result;
 use backon::Retryable;
 use serde::Deserialize;
+use sqlx::{Pool, Postgres};
+use std::future::Future;
+use std::str::FromStr;
 use std::sync::mpsc::Sender;
 use std::time::Duration as SD;
 use time::format_description;
-use time::{Duration, OffsetDateTime};
+use time::{Date, OffsetDateTime};
+use tokio::sync::mpsc;
 use tokio::time::sleep;
 
 use backon::ExponentialBuilder;
 
+use crate::db::agents::{get_control_flag, HARVEST_RUNNING};
 use crate::metadata_assertion::crossref::metadata::get_index_date;
+use crate::util::http_client;
 
 const BASE: &str = "https://api.crossref.org/v1/works";
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, PartialEq)]
 struct CrossrefResponse {
     message: CrossrefResponseMessage,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, PartialEq)]
 struct CrossrefResponseMessage {
     #[serde(alias = "total-results")]
     total_results: usize,
 
+    /// Sometimes missing or null at the end of the results.
     #[serde(alias = "next-cursor")]
-    next_cursor: String,
+    next_cursor: Option<String>,
 
     // Leave the work model as an opaque structure, we're not concerned with the detailed internal schema.
     items: Vec<serde_json::Value>,
@@ -34,7 +41,7 @@ struct CrossrefResponseMessage {
 async fn request_url(url: &str) -> Result<CrossrefResponse> {
     log::debug!("Try {}", url);
 
-    let response = reqwest::get(url).await?;
+    let response = http_client().get(url).send().await?;
 
     if response.status() != 200 {
         log::info!(
@@ -59,18 +66,219 @@ async fn request_url(url: &str) -> Result<CrossrefResponse> {
     Ok(deserialised)
 }
 
+/// Cursor for the next page, or None at the end of the results.
+/// A missing or empty cursor means there are no more pages.
+fn next_cursor(message: &CrossrefResponseMessage) -> Option<String> {
+    message.next_cursor.clone().filter(|x| !x.is_empty())
+}
+
+/// Filter for works indexed from the date, optionally restricted to the given work types.
+/// Multiple types are combined with OR by the API.
+fn indexed_filter(from_date: &str, types: &[String]) -> String {
+    let mut filter = format!("from-index-date:{}", from_date);
+    for work_type in types {
+        filter.push_str(",type:");
+        filter.push_str(work_type);
+    }
+    filter
+}
+
+/// URL for a page of works indexed from the date, newest first.
+/// The query is URL-encoded, as types and cursors may contain reserved characters.
+fn indexed_url(rows: u32, cursor: &str, from_date: &str, types: &[String]) -> Result<String> {
+    let url = reqwest::Url::parse_with_params(
+        BASE,
+        &[
+            ("filter", indexed_filter(from_date, types).as_str()),
+            ("sort", "indexed"),
+            ("order", "desc"),
+            ("rows", rows.to_string().as_str()),
+            ("cursor", cursor),
+        ],
+    )?;
+
+    Ok(String::from(url))
+}
+
+/// Day of the date, as used by the API's date filters.
+fn filter_date(date: &OffsetDateTime) -> String {
+    let ymd_format = format_description::parse("[year]-[month]-[day]").unwrap();
+    date.format(&ymd_format).unwrap()
+}
+
+/// Filter used to harvest works indexed after the date, optionally of the given types.
+/// A cursor is only valid for the query it came from, so this identifies the query a stored cursor belongs to.
+pub(crate) fn index_date_filter(after: &OffsetDateTime, types: &[String]) -> String {
+    indexed_filter(&filter_date(after), types)
+}
+
+/// Fields the API can sort works by.
+const SORT_FIELDS: [&str; 7] = [
+    "created",
+    "deposited",
+    "indexed",
+    "updated",
+    "published",
+    "is-referenced-by-count",
+    "references-count",
+];
+
+/// Sort order for a filtered harvest.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) struct FilterSort {
+    field: &'static str,
+    descending: bool,
+}
+
+impl FilterSort {
+    /// Query string parameters for the sort.
+    fn query(&self) -> String {
+        format!(
+            "&sort={}&order={}",
+            self.field,
+            if self.descending { "desc" } else { "asc" }
+        )
+    }
+}
+
+/// Parse `<field>` or `<field>:<asc|desc>`, e.g. `deposited:asc`.
+/// Descending if the order isn't given, as in the API.
+impl FromStr for FilterSort {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (field, order) = value.split_once(':').unwrap_or((value, "desc"));
+
+        let Some(field) = SORT_FIELDS.iter().copied().find(|x| *x == field) else {
+            return Err(format!(
+                "Unknown sort field '{}'. Use one of: {}",
+                field,
+                SORT_FIELDS.join(", ")
+            ));
+        };
+
+        let descending = match order {
+            "desc" => true,
+            "asc" => false,
+            _ => {
+                return Err(format!(
+                    "Unknown sort order '{}'. Use 'asc' or 'desc'.",
+                    order
+                ))
+            }
+        };
+
+        Ok(FilterSort { field, descending })
+    }
+}
+
+/// Builds a filter for a secondary harvest from structured options, optionally added to a raw filter.
+/// Dates are deposit dates, inclusive.
+#[derive(Debug, Default)]
+pub(crate) struct FilterBuilder {
+    raw: Option<String>,
+    from_date: Option<Date>,
+    until_date: Option<Date>,
+    types: Vec<String>,
+}
+
+impl FilterBuilder {
+    pub(crate) fn new() -> FilterBuilder {
+        FilterBuilder::default()
+    }
+
+    /// Raw Crossref filter, e.g. 'has-orcid:true'.
+    pub(crate) fn raw(mut self, filter: Option<&str>) -> FilterBuilder {
+        self.raw = filter.map(String::from);
+        self
+    }
+
+    pub(crate) fn since(mut self, date: Option<OffsetDateTime>) -> FilterBuilder {
+        self.from_date = date.map(|x| x.date());
+        self
+    }
+
+    pub(crate) fn until(mut self, date: Option<OffsetDateTime>) -> FilterBuilder {
+        self.until_date = date.map(|x| x.date());
+        self
+    }
+
+    pub(crate) fn types(mut self, types: &[String]) -> FilterBuilder {
+        self.types = types.to_vec();
+        self
+    }
+
+    /// Is there anything to harvest? Types alone would match too much.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.raw.is_none() && self.from_date.is_none() && self.until_date.is_none()
+    }
+
+    /// Compose the filter string, checking that each part is well-formed.
+    /// The API ignores some malformed filters, so would return more than was asked for.
+    pub(crate) fn build(&self) -> Result<String, String> {
+        if self.is_empty() {
+            return Err(String::from(
+                "A filter needs a raw filter, a from date or an until date.",
+            ));
+        }
+
+        if let (Some(from_date), Some(until_date)) = (self.from_date, self.until_date) {
+            if until_date < from_date {
+                return Err(format!(
+                    "Until date {} is before from date {}.",
+                    until_date, from_date
+                ));
+            }
+        }
+
+        let mut parts: Vec<String> = vec![];
+
+        if let Some(ref raw) = self.raw {
+            for part in raw.split(',').map(|x| x.trim()) {
+                match part.split_once(':') {
+                    Some((name, value)) if !name.is_empty() && !value.is_empty() => {
+                        parts.push(String::from(part))
+                    }
+                    _ => {
+                        return Err(format!(
+                            "Malformed filter '{}'. Expected <name>:<value>.",
+                            part
+                        ))
+                    }
+                }
+            }
+        }
+
+        if let Some(from_date) = self.from_date {
+            parts.push(format!("from-deposit-date:{}", from_date));
+        }
+
+        if let Some(until_date) = self.until_date {
+            parts.push(format!("until-deposit-date:{}", until_date));
+        }
+
+        for work_type in self.types.iter() {
+            if work_type.is_empty() || work_type.contains([',', ':']) {
+                return Err(format!("Invalid work type '{}'.", work_type));
+            }
+            parts.push(format!("type:{}", work_type));
+        }
+
+        Ok(parts.join(","))
+    }
+}
+
 /// Fetch historical data until the given [`not_before`] date.
 /// Request sorted results, so we can stop paging when we hit the date.
-/// Due to lack of secondary sort beyond date, it's sensible to add extra padding.
+/// There's no secondary sort beyond date, so ties are broken by the caller, see [`HarvestPosition`].
+/// If `types` isn't empty, only fetch works of those types, e.g. "journal-article".
 pub(crate) async fn fetch_from_indexed(
     rows: u32,
     cursor: &str,
     from_date: &str,
-) -> Result<(Vec<serde_json::Value>, String)> {
-    let url = format!(
-        "{}?filter=from-index-date:{}&sort=indexed&order=desc&rows={}&cursor={}",
-        BASE, from_date, rows, cursor
-    );
+    types: &[String],
+) -> Result<(Vec<serde_json::Value>, Option<String>)> {
+    let url = indexed_url(rows, cursor, from_date, types)?;
 
     let request = || request_url(&url);
     let response = request.retry(ExponentialBuilder::default()).await?;
@@ -83,16 +291,25 @@ pub(crate) async fn fetch_from_indexed(
         );
     }
 
-    Ok((response.message.items, response.message.next_cursor))
+    let cursor = next_cursor(&response.message);
+    Ok((response.message.items, cursor))
 }
 
-/// Fetch documents matching Crossref filter.
+/// Fetch documents matching Crossref filter, in the given order if any.
 pub(crate) async fn fetch_with_filter(
     rows: u32,
     cursor: &str,
     filter: &str,
-) -> Result<(Vec<serde_json::Value>, String)> {
-    let url = format!("{}?filter={}&rows={}&cursor={}", BASE, filter, rows, cursor);
+    sort: Option<FilterSort>,
+) -> Result<(Vec<serde_json::Value>, Option<String>)> {
+    let url = format!(
+        "{}?filter={}{}&rows={}&cursor={}",
+        BASE,
+        filter,
+        sort.map(|x| x.query()).unwrap_or_default(),
+        rows,
+        cursor
+    );
 
     let request = || request_url(&url);
     let response = request.retry(ExponentialBuilder::default()).await?;
@@ -106,67 +323,173 @@ pub(crate) async fn fetch_with_filter(
         );
     }
 
-    Ok((response.message.items, response.message.next_cursor))
+    let cursor = next_cursor(&response.message);
+    Ok((response.message.items, cursor))
+}
+
+/// Position in a harvest: the indexed date-time and the lower-case DOI.
+/// The API only sorts by a single field, so works indexed at the same moment
+/// come back in no particular order. The DOI breaks ties deterministically.
+pub(crate) type HarvestPosition = (OffsetDateTime, String);
+
+/// Harvest position of an item, if it has an indexed date and DOI.
+pub(crate) fn harvest_position(item: &serde_json::Value) -> Option<HarvestPosition> {
+    Some((get_index_date(item)?, item["DOI"].as_str()?.to_lowercase()))
+}
+
+/// Select the items in a page of results that come after the position.
+/// Also return whether later pages may contain more.
+///
+/// Pages are sorted by indexed date, newest first, but are in no particular
+/// order within a date. So a page that only reaches the position's date may be
+/// followed by more works from that date. Only stop once a page goes past it.
+fn select_after(
+    items: Vec<serde_json::Value>,
+    after: &HarvestPosition,
+) -> (Vec<serde_json::Value>, bool) {
+    let more = !items.is_empty()
+        && !items
+            .iter()
+            .any(|item| get_index_date(item).is_some_and(|indexed| indexed < after.0));
+
+    let wanted = items
+        .into_iter()
+        .filter(|item| harvest_position(item).is_some_and(|position| position.gt(after)))
+        .collect();
+
+    (wanted, more)
+}
+
+/// Has an operator paused harvesting? If the flag can't be read, carry on harvesting.
+async fn harvest_paused(pool: &Pool<Postgres>) -> bool {
+    match get_control_flag(HARVEST_RUNNING, pool).await {
+        Ok(running) => running == Some(false),
+        Err(e) => {
+            log::error!("Can't read harvest control flag: {:?}", e);
+            false
+        }
+    }
+}
+
+/// How a harvest of works indexed since a position ended.
+#[derive(Debug, PartialEq)]
+pub(crate) enum HarvestEnd {
+    /// All works since the position were harvested.
+    Complete,
+
+    /// Stopped early, e.g. at the page limit or paused, so some works may be missing.
+    /// With the cursor for the next page, if the harvest can be resumed from it.
+    Incomplete(Option<String>),
 }
 
-/// Harvest metadata indexed with Crossref since date-time to channel.
-/// Stop at the precise date-time, plus some padding.
+/// Cursor worth storing to resume a harvest from. The first page's cursor isn't, as that's starting again.
+fn resumable_cursor(cursor: &str) -> Option<String> {
+    (!cursor.is_empty() && cursor != "*").then(|| String::from(cursor))
+}
+
+/// Has the harvest fetched as many pages as it's allowed? Never, if there's no maximum.
+fn page_limit_reached(pages: usize, max_pages: Option<usize>) -> bool {
+    max_pages.is_some_and(|max_pages| pages >= max_pages)
+}
+
+/// Harvest metadata indexed with Crossref after the position to channel.
+/// Stop at the precise position, using the DOI to break ties between works
+/// with the same indexed date.
 ///
 /// This is designed for doing continual live queries to the API. It doesn't
 /// consume the entire result set, only those works that were indexed since the
 /// given date-time.
+///
+/// If `types` isn't empty, only harvest works of those types.
+/// If `max_pages` is given, stop after that many pages.
+/// Before each page, check whether harvesting was paused, and if so stop.
+///
+/// If `resume_cursor` is given, continue an earlier harvest of the same query from it, rather than the first page.
+/// Cursors expire, so if it's rejected, start again from the first page.
+///
+/// Return whether all works since the position were harvested, or it stopped early because of the page limit,
+/// a pause or an error, with the cursor to resume from.
 pub(crate) async fn harvest_precise_index_date(
-    chan: Sender<serde_json::Value>,
-    after: OffsetDateTime,
-) -> Result<()> {
+    chan: mpsc::Sender<serde_json::Value>,
+    after: HarvestPosition,
+    types: Vec<String>,
+    max_pages: Option<usize>,
+    resume_cursor: Option<String>,
+    pool: Pool<Postgres>,
+) -> Result<HarvestEnd> {
     log::debug!("Harvest to channel");
 
     let rows = 1000;
-    let mut cursor = String::from("*");
-    let mut again = true;
-
-    let ymd_format = format_description::parse("[year]-[month]-[day]").unwrap();
 
     // The API only deals in time intervals of one day, so we can't request the
-    // specific cut-off time. Instead we need to truncate it to the day
-    // boundary. Choose the start of the day before the requested cut-off. This
-    // means we're not asking the API to sort the entire data set. It should be
-    // sufficient to use the start of the current day, but adding an extra day
-    // avoids a potential boundary condition. We won't retrieve that much data,
-    // as we finish pagination when we hit the not_before date.
-    let from_index_date = after
-        .saturating_sub(Duration::DAY)
-        .format(&ymd_format)
-        .unwrap();
+    // specific cut-off time. Instead we need to truncate it to the start of
+    // the day. This means we're not asking the API to sort the entire data
+    // set. We won't retrieve that much data, as we finish pagination when we
+    // pass the position.
+    let from_index_date = filter_date(&after.0);
+
+    let end = harvest_pages(
+        chan,
+        after,
+        max_pages,
+        resume_cursor,
+        || harvest_paused(&pool),
+        |cursor: String| {
+            let from_index_date = &from_index_date;
+            let types = &types;
+            async move { fetch_from_indexed(rows, &cursor, from_index_date, types).await }
+        },
+    )
+    .await;
+
+    Ok(end)
+}
+
+/// Page through works sorted newest first, sending those after the position to the channel.
+/// `fetch` gets the page at a cursor, returning its works and the next cursor.
+/// See [`harvest_precise_index_date`].
+async fn harvest_pages<P, PF, F, FF>(
+    chan: mpsc::Sender<serde_json::Value>,
+    after: HarvestPosition,
+    max_pages: Option<usize>,
+    resume_cursor: Option<String>,
+    paused: P,
+    mut fetch: F,
+) -> HarvestEnd
+where
+    P: Fn() -> PF,
+    PF: Future<Output = bool>,
+    F: FnMut(String) -> FF,
+    FF: Future<Output = Result<(Vec<serde_json::Value>, Option<String>)>>,
+{
+    let mut resuming = resume_cursor.is_some();
+    let mut cursor = resume_cursor.unwrap_or_else(|| String::from("*"));
+    let mut again = true;
+    let mut pages = 0;
 
+    let mut end = HarvestEnd::Complete;
     while again {
-        let result = fetch_from_indexed(rows, &cursor, &from_index_date).await;
+        if paused().await {
+            log::warn!("Harvest paused after {} pages.", pages);
+            end = HarvestEnd::Incomplete(resumable_cursor(&cursor));
+            break;
+        }
+
+        let result = fetch(cursor.clone()).await;
 
         match result {
             Ok((items, new_cursor)) => {
+                resuming = false;
                 let num_items = items.len();
 
-                // Stop when there are zero results, means we reached the end of the result set.
-                if num_items == 0 {
-                    again = false;
-                }
+                let (wanted_items, more) = select_after(items, &after);
+                again = more && new_cursor.is_some();
 
-                // Find those items indexed after the not_before date.
-                let wanted_items: Vec<serde_json::Value> = items
-                    .into_iter()
-                    .filter(|item| {
-                        if let Some(item_indexed) = get_index_date(item) {
-                            item_indexed.gt(&after)
-                        } else {
-                            false
-                        }
-                    })
-                    .collect();
-
-                // Stop when there are no results after the not_before date. Results are ordered by the index date, so it's safe to stop here.
-                // Only stop at the precise date when flag is set. Otherwise rely on the API to send whatever fits the query.
-                if wanted_items.is_empty() {
+                pages += 1;
+                if again && page_limit_reached(pages, max_pages) {
+                    log::warn!("Stopping harvest at limit of {} pages.", pages);
                     again = false;
+                    end = HarvestEnd::Incomplete(new_cursor.as_deref().and_then(resumable_cursor));
                 }
 
                 log::debug!(
@@ -175,41 +498,72 @@ pub(crate) async fn harvest_precise_index_date(
                     wanted_items.len(),
                 );
 
+                // Waits while the channel is full, so fetching waits for the database.
                 for item in wanted_items {
-                    chan.send(item).unwrap();
+                    if chan.send(item).await.is_err() {
+                        log::error!("Harvest receiver stopped.");
+                        return HarvestEnd::Incomplete(None);
+                    }
                 }
-                cursor = new_cursor;
+                cursor = new_cursor.unwrap_or_default();
+            }
+            Err(e) if resuming => {
+                log::warn!(
+                    "Can't resume harvest from stored cursor, it may have expired. Starting again from {}. {:?}",
+                    filter_date(&after.0),
+                    e
+                );
+                resuming = false;
+                cursor = String::from("*");
             }
             Err(e) => {
-                log::error!("Error! {:?}", e);
+                log::error!("Harvest failed after {} pages. {:?}", pages, e);
                 again = false;
+                end = HarvestEnd::Incomplete(resumable_cursor(&cursor));
             }
         }
     }
 
-    Ok(())
+    end
 }
 
 /// Harvest metadata matching filter to channel.
+/// If `max_pages` is given, stop after that many pages.
+/// Before each page, check whether harvesting was paused, and if so stop.
 pub(crate) async fn harvest_with_filter_to_chan(
     chan: Sender<serde_json::Value>,
     filter: String,
+    sort: Option<FilterSort>,
+    max_pages: Option<usize>,
+    pool: Pool<Postgres>,
 ) -> Result<()> {
     log::debug!("Harvest to channel");
 
     let rows = 1000;
     let mut cursor = String::from("*");
     let mut again = true;
+    let mut pages = 0;
 
     while again {
-        let result = fetch_with_filter(rows, &cursor, &filter).await;
+        if harvest_paused(&pool).await {
+            log::warn!("Harvest paused after {} pages.", pages);
+            break;
+        }
+
+        let result = fetch_with_filter(rows, &cursor, &filter, sort).await;
 
         match result {
             Ok((items, new_cursor)) => {
                 let num_items = items.len();
 
-                // Stop when there are zero results, means we reached the end of the result set.
-                if num_items == 0 {
+                // Stop when there are zero results, or no cursor, means we reached the end of the result set.
+                if num_items == 0 || new_cursor.is_none() {
+                    again = false;
+                }
+
+                pages += 1;
+                if again && page_limit_reached(pages, max_pages) {
+                    log::warn!("Stopping harvest at limit of {} pages.", pages);
                     again = false;
                 }
 
@@ -218,7 +572,7 @@ pub(crate) async fn harvest_with_filter_to_chan(
                 for item in items {
                     chan.send(item).unwrap();
                 }
-                cursor = new_cursor;
+                cursor = new_cursor.unwrap_or_default();
             }
             Err(e) => {
                 log::error!("Error! {:?}", e);
@@ -229,3 +583,396 @@ pub(crate) async fn harvest_with_filter_to_chan(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use axum::{http::header::CONTENT_ENCODING, routing::get, Router};
+    use flate2::{write::GzEncoder, Compression};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const PAGE: &str = include_str!("../../../testing/unit/crossref/works-page.json");
+
+    const IDENTICAL_PAGE: &str =
+        include_str!("../../../testing/unit/crossref/identical-indexed.json");
+
+    /// Serve the same page plain and gzip-compressed, return the base URL.
+    async fn serve_page() -> String {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(PAGE.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let app = Router::new().route("/plain", get(|| async { PAGE })).route(
+            "/gzip",
+            get(move || {
+                let body = compressed.clone();
+                async move { ([(CONTENT_ENCODING, "gzip")], body) }
+            }),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        format!("http://{}", address)
+    }
+
+    #[test]
+    fn page_limit() {
+        assert!(
+            !page_limit_reached(1_000_000, None),
+            "Unlimited by default."
+        );
+        assert!(!page_limit_reached(2, Some(3)));
+        assert!(page_limit_reached(3, Some(3)));
+    }
+
+    /// Type filters compose with the index date filter.
+    #[test]
+    fn indexed_filter_types() {
+        assert_eq!(
+            indexed_filter("2024-11-20", &[]),
+            "from-index-date:2024-11-20"
+        );
+        assert_eq!(
+            indexed_filter(
+                "2024-11-20",
+                &[String::from("book"), String::from("journal-article")]
+            ),
+            "from-index-date:2024-11-20,type:book,type:journal-article"
+        );
+    }
+
+    /// Types and cursors are URL-encoded, so they can't add to or break the query.
+    #[test]
+    fn indexed_url_encoded() {
+        assert_eq!(
+            indexed_url(1000, "*", "2024-11-20", &[String::from("book")]).unwrap(),
+            "https://api.crossref.org/v1/works?filter=from-index-date%3A2024-11-20%2Ctype%3Abook&sort=indexed&order=desc&rows=1000&cursor=*"
+        );
+
+        let url = indexed_url(10, "AoJ+/x=", "2024-11-20", &[String::from("book&rows=1")]).unwrap();
+        assert!(url.contains("type%3Abook%26rows%3D1&"), "{}", url);
+        assert!(url.ends_with("&cursor=AoJ%2B%2Fx%3D"), "{}", url);
+    }
+
+    /// The filter for a stored cursor is the same whatever the time of day.
+    #[test]
+    fn index_date_filter_by_day() {
+        let morning = crate::util::parse_date("2024-11-20T08:00:00Z").unwrap();
+        let evening = crate::util::parse_date("2024-11-20T20:00:00Z").unwrap();
+
+        assert_eq!(
+            index_date_filter(&morning, &[String::from("book")]),
+            "from-index-date:2024-11-20,type:book"
+        );
+        assert_eq!(
+            index_date_filter(&morning, &[]),
+            index_date_filter(&evening, &[])
+        );
+    }
+
+    /// Only a cursor past the first page is worth resuming from.
+    #[test]
+    fn resumable_cursors() {
+        assert_eq!(resumable_cursor("*"), None);
+        assert_eq!(resumable_cursor(""), None);
+        assert_eq!(
+            resumable_cursor("DnF1ZXJ5VGhlbkZldGNo"),
+            Some(String::from("DnF1ZXJ5VGhlbkZldGNo"))
+        );
+    }
+
+    fn date(value: &str) -> Option<OffsetDateTime> {
+        Some(crate::util::parse_date(value).unwrap())
+    }
+
+    /// Structured options compose with a raw filter.
+    #[test]
+    fn filter_builder_compose() {
+        assert_eq!(
+            FilterBuilder::new()
+                .since(date("2021-01-01"))
+                .until(date("2021-01-02"))
+                .build(),
+            Ok(String::from(
+                "from-deposit-date:2021-01-01,until-deposit-date:2021-01-02"
+            ))
+        );
+
+        assert_eq!(
+            FilterBuilder::new()
+                .raw(Some("has-orcid:true"))
+                .since(date("2021-01-01T10:00:00Z"))
+                .types(&[String::from("book"), String::from("book-chapter")])
+                .build(),
+            Ok(String::from(
+                "has-orcid:true,from-deposit-date:2021-01-01,type:book,type:book-chapter"
+            ))
+        );
+
+        assert_eq!(
+            FilterBuilder::new()
+                .raw(Some(
+                    "from-deposit-date:2021-01-01, until-deposit-date:2021-01-02"
+                ))
+                .build(),
+            Ok(String::from(
+                "from-deposit-date:2021-01-01,until-deposit-date:2021-01-02"
+            )),
+            "Raw filter passed through."
+        );
+    }
+
+    #[test]
+    fn filter_builder_invalid() {
+        assert!(FilterBuilder::new().build().is_err(), "Empty.");
+        assert!(
+            FilterBuilder::new()
+                .types(&[String::from("book")])
+                .build()
+                .is_err(),
+            "Types alone."
+        );
+        assert!(
+            FilterBuilder::new()
+                .since(date("2021-01-02"))
+                .until(date("2021-01-01"))
+                .build()
+                .is_err(),
+            "Until before from."
+        );
+        assert!(FilterBuilder::new()
+            .raw(Some("from-deposit-date"))
+            .build()
+            .is_err());
+        assert!(FilterBuilder::new()
+            .raw(Some("has-orcid:true,"))
+            .build()
+            .is_err());
+        assert!(FilterBuilder::new()
+            .since(date("2021-01-01"))
+            .types(&[String::from("book:chapter")])
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn filter_sort() {
+        assert_eq!(
+            FilterSort::from_str("deposited").map(|x| x.query()),
+            Ok(String::from("&sort=deposited&order=desc"))
+        );
+        assert_eq!(
+            FilterSort::from_str("deposited:asc").map(|x| x.query()),
+            Ok(String::from("&sort=deposited&order=asc"))
+        );
+        assert!(FilterSort::from_str("title").is_err());
+        assert!(FilterSort::from_str("deposited:up").is_err());
+    }
+
+    fn position(date_time: &str, doi: &str) -> HarvestPosition {
+        (
+            OffsetDateTime::parse(
+                date_time,
+                &time::format_description::well_known::Iso8601::DEFAULT,
+            )
+            .unwrap(),
+            String::from(doi),
+        )
+    }
+
+    fn dois(items: &[serde_json::Value]) -> Vec<&str> {
+        items.iter().filter_map(|x| x["DOI"].as_str()).collect()
+    }
+
+    /// Works sharing the position's indexed date are selected by DOI, and the page past the date ends the harvest.
+    #[test]
+    fn select_after_identical_timestamps() {
+        let page: Vec<serde_json::Value> = serde_json::from_str(IDENTICAL_PAGE).unwrap();
+
+        let (wanted, more) =
+            select_after(page.clone(), &position("2024-11-20T10:00:00Z", "10.5555/3"));
+        assert_eq!(
+            dois(&wanted),
+            vec!["10.5555/NEWER", "10.5555/5", "10.5555/4"]
+        );
+        assert!(!more, "Page reaches works before the position.");
+
+        // An empty DOI takes everything at the position's date.
+        let (wanted, _) = select_after(page, &position("2024-11-20T10:00:00Z", ""));
+        assert_eq!(
+            dois(&wanted),
+            vec![
+                "10.5555/NEWER",
+                "10.5555/5",
+                "10.5555/1",
+                "10.5555/4",
+                "10.5555/2",
+                "10.5555/3"
+            ]
+        );
+    }
+
+    /// A page that only reaches the position's date may be followed by more works from that date.
+    #[test]
+    fn select_after_continues_within_timestamp() {
+        let page: Vec<serde_json::Value> = serde_json::from_str(IDENTICAL_PAGE).unwrap();
+        let same_date: Vec<serde_json::Value> = page
+            .into_iter()
+            .filter(|x| x["indexed"]["date-time"] == "2024-11-20T10:00:00Z")
+            .collect();
+
+        let (wanted, more) =
+            select_after(same_date, &position("2024-11-20T10:00:00Z", "10.5555/3"));
+        assert_eq!(dois(&wanted), vec!["10.5555/5", "10.5555/4"]);
+        assert!(more);
+
+        let (wanted, more) = select_after(vec![], &position("2024-11-20T10:00:00Z", ""));
+        assert!(wanted.is_empty());
+        assert!(!more, "Empty page is the end of the results.");
+    }
+
+    fn work(doi: &str, indexed: &str) -> serde_json::Value {
+        serde_json::json!({"DOI": doi, "indexed": {"date-time": indexed}})
+    }
+
+    /// Three pages of works, newest first. The last goes past 2024-11-20.
+    fn stub_page(cursor: &str) -> Result<(Vec<serde_json::Value>, Option<String>)> {
+        match cursor {
+            "*" => Ok((
+                vec![work("10.5555/1", "2024-11-22T10:00:00Z")],
+                Some(String::from("c2")),
+            )),
+            "c2" => Ok((
+                vec![work("10.5555/2", "2024-11-21T10:00:00Z")],
+                Some(String::from("c3")),
+            )),
+            "c3" => Ok((vec![work("10.5555/3", "2024-11-19T10:00:00Z")], None)),
+            _ => Err(anyhow::anyhow!("Unknown cursor {}", cursor)),
+        }
+    }
+
+    /// Harvest the stub pages after 2024-11-20, failing at the `fail_at` cursor if given.
+    /// Return how it ended, the cursors requested, and the DOIs harvested.
+    async fn harvest_stub(
+        resume_cursor: Option<&str>,
+        fail_at: Option<&str>,
+    ) -> (HarvestEnd, Vec<String>, Vec<String>) {
+        let (send, mut receive) = mpsc::channel(10);
+        let mut requested = vec![];
+
+        let end = harvest_pages(
+            send,
+            position("2024-11-20T00:00:00Z", ""),
+            None,
+            resume_cursor.map(String::from),
+            || std::future::ready(false),
+            |cursor: String| {
+                requested.push(cursor.clone());
+                std::future::ready(if fail_at == Some(cursor.as_str()) {
+                    Err(anyhow::anyhow!("Failed at {}", cursor))
+                } else {
+                    stub_page(&cursor)
+                })
+            },
+        )
+        .await;
+
+        let mut harvested = vec![];
+        while let Some(item) = receive.recv().await {
+            harvested.push(String::from(item["DOI"].as_str().unwrap()));
+        }
+
+        (end, requested, harvested)
+    }
+
+    #[tokio::test]
+    async fn harvest_from_first_page() {
+        let (end, requested, harvested) = harvest_stub(None, None).await;
+
+        assert_eq!(end, HarvestEnd::Complete);
+        assert_eq!(requested, vec!["*", "c2", "c3"]);
+        assert_eq!(harvested, vec!["10.5555/1", "10.5555/2"]);
+    }
+
+    /// A stored cursor continues from its page.
+    #[tokio::test]
+    async fn harvest_resume_from_cursor() {
+        let (end, requested, harvested) = harvest_stub(Some("c2"), None).await;
+
+        assert_eq!(end, HarvestEnd::Complete);
+        assert_eq!(requested, vec!["c2", "c3"]);
+        assert_eq!(harvested, vec!["10.5555/2"]);
+    }
+
+    /// An expired stored cursor starts again from the first page.
+    #[tokio::test]
+    async fn harvest_resume_expired_cursor() {
+        let (end, requested, harvested) = harvest_stub(Some("expired"), None).await;
+
+        assert_eq!(end, HarvestEnd::Complete);
+        assert_eq!(requested, vec!["expired", "*", "c2", "c3"]);
+        assert_eq!(harvested, vec!["10.5555/1", "10.5555/2"]);
+    }
+
+    /// An error stops the harvest as incomplete, with the cursor of the failed page to resume from.
+    #[tokio::test]
+    async fn harvest_error_incomplete() {
+        let (end, requested, harvested) = harvest_stub(None, Some("c2")).await;
+
+        assert_eq!(end, HarvestEnd::Incomplete(Some(String::from("c2"))));
+        assert_eq!(requested, vec!["*", "c2"]);
+        assert_eq!(harvested, vec!["10.5555/1"]);
+
+        let (end, _, harvested) = harvest_stub(None, Some("*")).await;
+        assert_eq!(
+            end,
+            HarvestEnd::Incomplete(None),
+            "Nothing to resume from on the first page."
+        );
+        assert!(harvested.is_empty());
+
+        let (end, requested, _) = harvest_stub(Some("expired"), Some("*")).await;
+        assert_eq!(
+            end,
+            HarvestEnd::Incomplete(None),
+            "Starting again after an expired cursor can fail too."
+        );
+        assert_eq!(requested, vec!["expired", "*"]);
+    }
+
+    /// The last page may have a missing, null or empty cursor. It's parsed, and ends the results.
+    #[test]
+    fn missing_next_cursor() {
+        let page: CrossrefResponse = serde_json::from_str(PAGE).unwrap();
+        assert!(next_cursor(&page.message).is_some());
+
+        for message in [
+            r#"{"total-results": 1, "items": [{"DOI": "10.5555/1"}]}"#,
+            r#"{"total-results": 1, "next-cursor": null, "items": [{"DOI": "10.5555/1"}]}"#,
+            r#"{"total-results": 1, "next-cursor": "", "items": [{"DOI": "10.5555/1"}]}"#,
+        ] {
+            let response: CrossrefResponse =
+                serde_json::from_str(&format!(r#"{{"message": {}}}"#, message)).unwrap();
+
+            assert_eq!(response.message.items.len(), 1, "Items are still returned.");
+            assert_eq!(next_cursor(&response.message), None);
+        }
+    }
+
+    /// Compressed responses should be decoded transparently and parse to the same value.
+    #[tokio::test]
+    async fn compressed_response_identical() {
+        let base = serve_page().await;
+
+        let plain = request_url(&format!("{}/plain", base)).await.unwrap();
+        let compressed = request_url(&format!("{}/gzip", base)).await.unwrap();
+
+        assert_eq!(plain.message.items.len(), 2);
+        assert_eq!(plain, compressed);
+    }
+}
@@ -0,0 +1,39 @@
+//! Functions for working with DataCite metadata.
+
+use time::{format_description::well_known::Iso8601, OffsetDateTime};
+
+/// Get the updated date for the DOI record, if present and valid.
+pub(crate) fn get_updated_date(item: &serde_json::Value) -> Option<OffsetDateTime> {
+    item["attributes"]["updated"]
+        .as_str()
+        .and_then(|value| OffsetDateTime::parse(value, &Iso8601::DEFAULT).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn read_item(path: &str) -> serde_json::Value {
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn updated() {
+        let item = read_item("testing/unit/datacite/doi.json");
+
+        assert_eq!(
+            get_updated_date(&item),
+            OffsetDateTime::parse("2024-03-01T10:31:53Z", &Iso8601::DEFAULT).ok(),
+        );
+    }
+
+    /// Records without an updated date return None rather than fail.
+    #[test]
+    fn missing_fields() {
+        let item = serde_json::json!({"type": "dois"});
+
+        assert_eq!(get_updated_date(&item), None);
+    }
+}
@@ -0,0 +1,98 @@
+//! Agent for retrieving metadata assertions from the DataCite API.
+
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use sqlx::{Pool, Postgres};
+
+use time::{Duration, OffsetDateTime};
+
+use crate::db::agents::get_checkpoint;
+use crate::db::agents::set_checkpoint;
+use crate::db::metadata::MetadataAssertionReason;
+use crate::db::source::MetadataSourceId;
+use crate::metadata_assertion::datacite::metadata::get_updated_date;
+use crate::metadata_assertion::datacite::works_api_client::harvest_precise_updated_date;
+use crate::metadata_assertion::service::{assert_metadata, get_identifier_and_json};
+
+/// Date value for checkpointing the harvest.
+pub(crate) const DATACITE_NB: &str = "datacite-not-before";
+
+/// Retrieve all new DataCite data since the last run.
+/// The date used for checkpointing is the latest updated date reported by the DataCite API, not the local datetime.
+/// If the harvest fails, the checkpoint isn't moved, so the next run tries again from the same date.
+pub(crate) async fn poll_newly_indexed_data(pool: &Pool<Postgres>) -> anyhow::Result<()> {
+    let mut tx = pool.begin().await?;
+    // Start from most recent run, now.
+    // Add 1 hour margin for jitter. This results in duplicate fetches but they are de-duplicated in the database.
+    let after = get_checkpoint(DATACITE_NB, &mut tx)
+        .await?
+        .unwrap_or(OffsetDateTime::now_utc())
+        .saturating_sub(Duration::HOUR);
+
+    // Get only assertions updated after the date.
+    let new_after = harvest_recently_updated(&after, pool).await?;
+
+    set_checkpoint(DATACITE_NB, new_after, &mut tx).await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Harvest data updated after the given date, returning the updated date of the most recent.
+/// If none were retrieved, the `after` date is returned, so it can be attempted again next time.
+/// Return an error if a page couldn't be fetched. The assertions received until then are kept.
+pub(crate) async fn harvest_recently_updated(
+    after: &OffsetDateTime,
+    pool: &Pool<Postgres>,
+) -> anyhow::Result<OffsetDateTime> {
+    let (send_metadata_docs, receive_metadata_docs): (
+        Sender<serde_json::Value>,
+        Receiver<serde_json::Value>,
+    ) = mpsc::channel();
+    let after_a = *after;
+    let c = tokio::task::spawn(async move {
+        harvest_precise_updated_date(send_metadata_docs, after_a).await
+    });
+
+    let mut latest_date = *after;
+
+    log::info!("Start DataCite harvest after {}", after);
+    let mut count = 0;
+    let mut tx = pool.begin().await?;
+
+    for item in receive_metadata_docs {
+        if let Some(updated) = get_updated_date(&item) {
+            latest_date = updated.max(latest_date);
+
+            if let Some((identifier, json)) =
+                get_identifier_and_json(item, MetadataSourceId::Datacite)
+            {
+                count += 1;
+                if (count % 1000) == 0 {
+                    log::info!("Harvested {} items.", count);
+                }
+
+                assert_metadata(
+                    &identifier,
+                    &json,
+                    MetadataSourceId::Datacite,
+                    MetadataAssertionReason::Primary,
+                    pool,
+                    &mut tx,
+                )
+                .await?;
+            }
+        }
+    }
+    tx.commit().await?;
+
+    log::info!(
+        "Stop DataCite harvest, retrieved {}, latest {}",
+        count,
+        latest_date
+    );
+
+    c.await??;
+    Ok(latest_date)
+}
@@ -0,0 +1,3 @@
+pub(crate) mod metadata;
+pub(crate) mod metadata_agent;
+pub(crate) mod works_api_client;
@@ -0,0 +1,164 @@
+//! Client for DataCite REST API
+use anyhow::Result;
+use backon::Retryable;
+use reqwest::Url;
+use serde::Deserialize;
+use std::sync::mpsc::Sender;
+use std::time::Duration as SD;
+use time::format_description;
+use time::{Duration, OffsetDateTime};
+use tokio::time::sleep;
+
+use backon::ExponentialBuilder;
+
+use crate::metadata_assertion::datacite::metadata::get_updated_date;
+use crate::util::http_client;
+
+const BASE: &str = "https://api.datacite.org/dois";
+
+#[derive(Deserialize, Debug)]
+struct DataciteResponse {
+    // Leave the DOI model as an opaque structure, we're not concerned with the detailed internal schema.
+    data: Vec<serde_json::Value>,
+
+    meta: DataciteResponseMeta,
+
+    links: DataciteResponseLinks,
+}
+
+#[derive(Deserialize, Debug)]
+struct DataciteResponseMeta {
+    total: usize,
+}
+
+#[derive(Deserialize, Debug)]
+struct DataciteResponseLinks {
+    // Absent on the last page.
+    next: Option<String>,
+}
+
+async fn request_url(url: &str) -> Result<DataciteResponse> {
+    log::debug!("Try {}", url);
+
+    let response = http_client().get(url).send().await?;
+
+    if response.status() != 200 {
+        log::info!(
+            "Got {} from {}: {:?}",
+            response.status(),
+            url,
+            response.headers()
+        );
+    }
+
+    // Special case for slow down.
+    if response.status() == 429 {
+        log::error!("Slowing down!");
+        sleep(SD::from_secs(10)).await;
+    }
+
+    let text = response.text().await?;
+
+    // Parse the response to ensure we got back valid JSON.
+    let deserialised = serde_json::from_str::<DataciteResponse>(&text)?;
+
+    Ok(deserialised)
+}
+
+/// URL for the first page of DOIs updated since the given date, sorted by updated date.
+/// Subsequent pages are found from the 'next' link.
+fn first_page_url(rows: u32, from_date: &str) -> Result<String> {
+    let url = Url::parse_with_params(
+        BASE,
+        &[
+            ("query", format!("updated:[{} TO *]", from_date)),
+            ("sort", String::from("updated")),
+            ("page[size]", rows.to_string()),
+            ("page[cursor]", String::from("1")),
+        ],
+    )?;
+
+    Ok(url.to_string())
+}
+
+/// Fetch a page of results from the URL.
+/// Return the items and the URL of the next page, if there is one.
+async fn fetch_page(url: &str, first: bool) -> Result<(Vec<serde_json::Value>, Option<String>)> {
+    let request = || request_url(url);
+    let response = request.retry(ExponentialBuilder::default()).await?;
+
+    // On first page log how many results might be present.
+    if first {
+        log::info!(
+            "Fetching results since updated date, total possible {} ",
+            response.meta.total
+        );
+    }
+
+    Ok((response.data, response.links.next))
+}
+
+/// Harvest metadata updated in DataCite since date-time to channel.
+/// Skip those updated before the precise date-time.
+///
+/// This is designed for doing continual live queries to the API. It doesn't
+/// consume the entire result set, only those DOIs that were updated since the
+/// given date-time.
+///
+/// If a page can't be fetched, stop and return the error, as later pages would be missed.
+pub(crate) async fn harvest_precise_updated_date(
+    chan: Sender<serde_json::Value>,
+    after: OffsetDateTime,
+) -> Result<()> {
+    log::debug!("Harvest to channel");
+
+    let rows = 1000;
+
+    let ymd_format = format_description::parse("[year]-[month]-[day]").unwrap();
+
+    // Query in whole days to avoid escaping a date-time in the query, and
+    // filter to the precise date-time below. Choose the start of the day
+    // before the requested cut-off to avoid a potential boundary condition.
+    let from_updated_date = after
+        .saturating_sub(Duration::DAY)
+        .format(&ymd_format)
+        .unwrap();
+
+    let mut next_url = Some(first_page_url(rows, &from_updated_date)?);
+    let mut first = true;
+
+    while let Some(url) = next_url.take() {
+        let (items, new_next_url) = fetch_page(&url, first).await?;
+        let num_items = items.len();
+
+        // Stop when there are zero results, means we reached the end of the result set.
+        if num_items > 0 {
+            next_url = new_next_url;
+        }
+
+        // Find those items updated after the not_before date.
+        let wanted_items: Vec<serde_json::Value> = items
+            .into_iter()
+            .filter(|item| {
+                if let Some(item_updated) = get_updated_date(item) {
+                    item_updated.gt(&after)
+                } else {
+                    false
+                }
+            })
+            .collect();
+
+        log::debug!(
+            "Page of {}, of which {} wanted",
+            num_items,
+            wanted_items.len(),
+        );
+
+        for item in wanted_items {
+            chan.send(item).unwrap();
+        }
+        first = false;
+    }
+
+    Ok(())
+}
@@ -1,3 +1,4 @@
 pub(crate) mod crossref;
+pub(crate) mod datacite;
 pub(crate) mod retrieve;
 pub(crate) mod service;
@@ -1,16 +1,50 @@
-use anyhow::Result;
-use backon::ConstantBuilder;
+use anyhow::{anyhow, Result};
+use backon::ExponentialBuilder;
 use backon::Retryable;
 use scholarly_identifiers::identifiers::Identifier;
 use serde_json::Value;
 use sqlx::Postgres;
 use sqlx::Transaction;
+use std::future::Future;
+use std::sync::OnceLock;
 use std::time::Duration;
-use tokio::time::sleep;
 
 use crate::db::metadata::MetadataAssertionReason;
 use crate::db::source::MetadataSourceId;
 use crate::metadata_assertion::service::assert_metadata;
+use crate::util::{env_or_default_allow_zero, http_client};
+
+/// Default number of times to retry a content negotiation request.
+const DEFAULT_MAX_RETRIES: usize = 3;
+
+static MAX_RETRIES: OnceLock<usize> = OnceLock::new();
+
+/// Backoff for content negotiation requests.
+/// Jitter stops concurrent fetches from retrying in lockstep.
+/// Number of retries is configured from the METABEAK_CN_MAX_RETRIES environment variable, read once.
+/// Zero means no retries.
+fn backoff() -> ExponentialBuilder {
+    let max_retries = *MAX_RETRIES
+        .get_or_init(|| env_or_default_allow_zero("METABEAK_CN_MAX_RETRIES", DEFAULT_MAX_RETRIES));
+
+    ExponentialBuilder::default()
+        .with_min_delay(Duration::from_millis(500))
+        .with_max_delay(Duration::from_secs(30))
+        .with_max_times(max_retries)
+        .with_jitter()
+}
+
+/// Call the fetch function until it succeeds or the backoff is exhausted.
+async fn fetch_with_backoff<F, Fut>(fetch: F, backoff: ExponentialBuilder) -> Result<Value>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Value>>,
+{
+    fetch
+        .retry(backoff)
+        .notify(|err, delay| log::info!("Retry in {:?} after error: {:?}", delay, err))
+        .await
+}
 
 /// Attempt to fetch and store a metadata assertion for a DOI.
 pub(crate) async fn try_collect_metadata_assertion<'a>(
@@ -25,15 +59,7 @@ pub(crate) async fn try_collect_metadata_assertion<'a>(
     {
         log::debug!("Try collect metadata for: {:?}", identifier);
         if let Some(url) = identifier.to_uri() {
-            let request = || request_url(&url);
-            match request
-                .retry(
-                    ConstantBuilder::default()
-                        .with_max_times(2)
-                        .with_delay(Duration::from_millis(500)),
-                )
-                .await
-            {
+            match fetch_with_backoff(|| request_url(&url), backoff()).await {
                 Ok(json) => {
                     assert_metadata(
                         identifier,
@@ -69,8 +95,7 @@ pub(crate) async fn try_collect_metadata_assertion<'a>(
 async fn request_url(url: &str) -> Result<Value> {
     log::debug!("Try {}", url);
 
-    let client = reqwest::Client::new();
-    let response = client
+    let response = http_client()
         .get(url)
         .header("Accept", "application/vnd.citationstyles.csl+json")
         .send()
@@ -80,10 +105,9 @@ async fn request_url(url: &str) -> Result<Value> {
         log::info!("Got {} from {:?}", response.status(), response.headers());
     }
 
-    // Special case for slow down.
+    // Fail so that the request is retried after the backoff.
     if response.status() == 429 {
-        log::error!("Slowing down!");
-        sleep(Duration::from_secs(10)).await;
+        return Err(anyhow!("Rate limited by resolver"));
     }
 
     let text = response.text().await?;
@@ -93,3 +117,63 @@ async fn request_url(url: &str) -> Result<Value> {
 
     Ok(json)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn test_backoff() -> ExponentialBuilder {
+        ExponentialBuilder::default()
+            .with_min_delay(Duration::from_millis(1))
+            .with_max_times(3)
+            .with_jitter()
+    }
+
+    /// Transient failures are retried until the request succeeds.
+    #[tokio::test]
+    async fn retry_until_success() {
+        let attempts = &AtomicUsize::new(0);
+
+        let result = fetch_with_backoff(
+            || async move {
+                if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                    Err(anyhow!("Rate limited by resolver"))
+                } else {
+                    Ok(serde_json::json!({"DOI": "10.5555/12345678"}))
+                }
+            },
+            test_backoff(),
+        )
+        .await;
+
+        assert_eq!(
+            result.unwrap(),
+            serde_json::json!({"DOI": "10.5555/12345678"})
+        );
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    /// Give up once the retries are exhausted.
+    #[tokio::test]
+    async fn retry_gives_up() {
+        let attempts = &AtomicUsize::new(0);
+
+        let result = fetch_with_backoff(
+            || async move {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(anyhow!("Rate limited by resolver"))
+            },
+            test_backoff(),
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(
+            attempts.load(Ordering::SeqCst),
+            4,
+            "First try plus 3 retries."
+        );
+    }
+}
@@ -1,11 +1,28 @@
+use std::{env, sync::OnceLock};
+
 use scholarly_identifiers::identifiers::Identifier;
 use sqlx::{Pool, Postgres, Transaction};
 
-use crate::db;
+use crate::{db, util::parse_flag};
 
 pub(crate) mod doi;
 pub(crate) mod ror;
 
+/// Number of queued entities fetched in each transaction by a `--fetch-metadata` worker.
+const FETCH_BATCH_SIZE: i32 = 10;
+
+/// Default number of `--fetch-metadata` workers.
+pub(crate) const DEFAULT_FETCH_WORKERS: usize = 5;
+
+static DEFER_FETCH: OnceLock<bool> = OnceLock::new();
+
+/// Should extraction queue metadata fetches for `--fetch-metadata` workers, rather than fetching inline?
+/// Configured from the METABEAK_DEFER_METADATA_FETCH environment variable, read once. Off by default.
+pub(crate) fn defer_fetch() -> bool {
+    *DEFER_FETCH
+        .get_or_init(|| parse_flag(env::var("METABEAK_DEFER_METADATA_FETCH").ok().as_deref()))
+}
+
 /// Attempt to ensure an entity has a metadata assertion.
 pub(crate) async fn ensure_metadata_assertion<'a>(
     identifier: &Identifier,
@@ -24,3 +41,73 @@ pub(crate) async fn ensure_metadata_assertion<'a>(
         log::debug!("Already got metadata for {:?}, {}", identifier, entity_id);
     }
 }
+
+/// Ensure an entity has a metadata assertion, either now or, if fetches are deferred, by queueing it
+/// for a `--fetch-metadata` worker. Only identifiers that metadata can be fetched for are queued.
+pub(crate) async fn request_metadata_assertion<'a>(
+    identifier: &Identifier,
+    entity_id: i64,
+    pool: &Pool<Postgres>,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<(), sqlx::Error> {
+    if !defer_fetch() {
+        ensure_metadata_assertion(identifier, entity_id, pool, tx).await;
+        return Ok(());
+    }
+
+    if matches!(identifier, Identifier::Doi { .. })
+        && !db::metadata::has_metadata_assertion(entity_id, pool).await
+    {
+        db::metadata::enqueue_fetch(entity_id, tx).await?;
+    }
+
+    Ok(())
+}
+
+/// Fetch metadata for a batch of queued entities, in one transaction.
+/// Return the number taken from the queue.
+pub(crate) async fn pump_fetch_queue(pool: &Pool<Postgres>) -> Result<usize, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let entries = db::metadata::poll_fetch_queue(FETCH_BATCH_SIZE, &mut tx).await?;
+
+    for entry in entries.iter() {
+        match entry.identifier() {
+            Some(identifier) => {
+                ensure_metadata_assertion(&identifier, entry.entity_id, pool, &mut tx).await
+            }
+            None => log::error!(
+                "Can't read identifier for queued entity {}",
+                entry.entity_id
+            ),
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(entries.len())
+}
+
+/// Fetch metadata for queued entities until the queue is empty.
+/// If `once`, only fetch one batch.
+pub(crate) async fn drain_fetch_queue(
+    pool: &Pool<Postgres>,
+    once: bool,
+) -> Result<(), sqlx::Error> {
+    let mut total = 0;
+
+    loop {
+        let count = pump_fetch_queue(pool).await?;
+        total += count;
+
+        log::debug!("Fetched metadata for {} queued entities", count);
+
+        if once || count < FETCH_BATCH_SIZE as usize {
+            break;
+        }
+    }
+
+    log::info!("Fetched metadata for {} queued entities in total", total);
+
+    Ok(())
+}
@@ -1,7 +1,8 @@
+#[allow(dead_code)]
 pub(crate) async fn retrieve_metadata_assertion(
-    identifier: &scholarly_identifiers::identifiers::Identifier,
-    entity_id: i64,
-    pool: &sqlx::Pool<sqlx::Postgres>,
+    _identifier: &scholarly_identifiers::identifiers::Identifier,
+    _entity_id: i64,
+    _pool: &sqlx::Pool<sqlx::Postgres>,
 ) {
     todo!()
 }
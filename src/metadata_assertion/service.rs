@@ -8,6 +8,37 @@ use crate::{
     util::hash_data,
 };
 
+/// JSON pointers to the fields that can hold a record's primary identifier, in order of preference.
+fn identifier_fields(source: MetadataSourceId) -> &'static [&'static str] {
+    match source {
+        MetadataSourceId::Crossref => &["/DOI"],
+        MetadataSourceId::Datacite => &["/attributes/doi", "/id"],
+
+        // CSL JSON from content negotiation, or other sources, which may use any of these.
+        _ => &["/DOI", "/doi", "/id"],
+    }
+}
+
+/// Get the primary identifier of a metadata record from the source, along with the serialized JSON.
+/// The identifier is taken from the first of the source's identifier fields that's present.
+/// None if none are present.
+pub(crate) fn get_identifier_and_json(
+    json_value: serde_json::Value,
+    source: MetadataSourceId,
+) -> Option<(Identifier, String)> {
+    let value = identifier_fields(source)
+        .iter()
+        .find_map(|field| json_value.pointer(field).and_then(|x| x.as_str()))?;
+
+    // Normalise and identify the type of the identifier.
+    // For Crossref records, this will be the DOI type ID.
+    let identifier = Identifier::parse(value);
+
+    serde_json::to_string(&json_value)
+        .ok()
+        .map(|json| (identifier, json))
+}
+
 /// Assert metadata about a subject from a given source in a transaction.
 /// If there's a duplidate assertion  based on the source and content, ignore it.
 pub(crate) async fn assert_metadata<'a>(
@@ -29,3 +60,69 @@ pub(crate) async fn assert_metadata<'a>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn read_json(path: &str) -> serde_json::Value {
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    fn identifier(path: &str, source: MetadataSourceId) -> Option<String> {
+        get_identifier_and_json(read_json(path), source).map(|(x, _)| x.to_stable_string())
+    }
+
+    /// Each source's identifier is found where it keeps it.
+    #[test]
+    fn identifier_per_source() {
+        assert_eq!(
+            identifier(
+                "testing/unit/crossref-article.json",
+                MetadataSourceId::Crossref
+            ),
+            Some(String::from("10.33262/exploradordigital.v8i4.3221"))
+        );
+        assert_eq!(
+            identifier("testing/unit/datacite/doi.json", MetadataSourceId::Datacite),
+            Some(String::from("10.5438/0012"))
+        );
+        assert_eq!(
+            identifier(
+                "testing/unit/csl/work.json",
+                MetadataSourceId::ContentNegotiation
+            ),
+            Some(String::from("10.5555/csl-work"))
+        );
+    }
+
+    /// The JSON is kept, and None is only returned when no field matches.
+    #[test]
+    fn identifier_missing() {
+        let (_, json) = get_identifier_and_json(
+            serde_json::json!({"DOI": "10.5555/12345678", "title": ["A Title"]}),
+            MetadataSourceId::Crossref,
+        )
+        .unwrap();
+        assert!(json.contains("A Title"));
+
+        // Crossref records only have their identifier in "DOI".
+        assert!(get_identifier_and_json(
+            read_json("testing/unit/csl/work.json"),
+            MetadataSourceId::Crossref
+        )
+        .is_none());
+        assert!(get_identifier_and_json(
+            serde_json::json!({"title": "No identifier"}),
+            MetadataSourceId::ContentNegotiation
+        )
+        .is_none());
+        assert!(get_identifier_and_json(
+            serde_json::json!({"type": "dois"}),
+            MetadataSourceId::Datacite
+        )
+        .is_none());
+    }
+}
@@ -1,21 +1,125 @@
 //! Service layer
 //! For running and coordinating functions.
 
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    env,
+    fmt::{self, Display, Formatter},
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+
+use futures::{FutureExt, Stream};
+use scholarly_identifiers::identifiers::Identifier;
 use serde_json::Value;
-use sqlx::{Error, Pool, Postgres};
+use sqlx::{postgres::PgListener, Error, Pool, Postgres, Transaction};
+use time::OffsetDateTime;
+use tokio::task::JoinHandle;
 
 use crate::{
-    db::{self, event::EventQueueState},
+    db::{
+        self,
+        event::{EventQueueState, QueuedEvent},
+        metadata::MetadataAssertionReason,
+        source::MetadataSourceId,
+    },
     execution::{
         self,
-        model::{Event, ExecutionResult, HandlerSpec},
+        model::{ErrorKind, Event, ExecutionResult, HandlerSpec},
     },
     local,
-    util::hash_data,
+    metadata_assertion::{
+        crossref, datacite,
+        service::{assert_metadata, get_identifier_and_json},
+    },
+    util::{env_or_default_allow_zero, hash_data, parse_flag},
 };
 
 const EXECUTE_BATCH_SIZE: i32 = 100;
 
+/// Error from a service function, so that callers can tell a missing item or bad input from a failure.
+#[derive(Debug)]
+pub(crate) enum ServiceError {
+    /// The requested item doesn't exist.
+    NotFound,
+
+    /// The request was invalid, with the reason.
+    Validation(String),
+
+    /// The database failed.
+    Database(Error),
+}
+
+impl From<Error> for ServiceError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::RowNotFound => ServiceError::NotFound,
+            err => ServiceError::Database(err),
+        }
+    }
+}
+
+impl Display for ServiceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceError::NotFound => write!(f, "Not found"),
+            ServiceError::Validation(message) => write!(f, "Invalid request: {}", message),
+            ServiceError::Database(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+/// Channel notified by the database when Events are added to the queue.
+const EVENT_QUEUE_CHANNEL: &str = "event_queue";
+
+/// Channel notified to cancel handler execution in progress.
+const CANCEL_EXECUTION_CHANNEL: &str = "cancel_execution";
+
+/// Most Events that can be re-run in one request.
+pub(crate) const MAX_RERUN_LIMIT: i64 = 10000;
+
+/// Default number of times an Event is retried when every handler errors, before it's dead-lettered.
+const DEFAULT_DEAD_LETTER_RETRIES: i32 = 2;
+
+static DEAD_LETTER_RETRIES: OnceLock<i32> = OnceLock::new();
+
+/// Number of times an Event is retried, from `METABEAK_DEAD_LETTER_RETRIES`.
+/// Zero dead-letters an Event the first time every handler errors.
+fn dead_letter_retries() -> i32 {
+    *DEAD_LETTER_RETRIES.get_or_init(|| {
+        env_or_default_allow_zero("METABEAK_DEAD_LETTER_RETRIES", DEFAULT_DEAD_LETTER_RETRIES)
+    })
+}
+
+static EXECUTE_EXCLUSIVE: OnceLock<bool> = OnceLock::new();
+
+/// Should only one process execute a batch at a time? From `METABEAK_EXECUTE_EXCLUSIVE`, default false.
+fn execute_exclusive() -> bool {
+    *EXECUTE_EXCLUSIVE
+        .get_or_init(|| parse_flag(env::var("METABEAK_EXECUTE_EXCLUSIVE").ok().as_deref()))
+}
+
+/// Default number of distinct errors in a summary.
+pub(crate) const DEFAULT_ERROR_SUMMARY_LIMIT: i64 = 20;
+
+/// Most distinct errors in a summary.
+const MAX_ERROR_SUMMARY_LIMIT: i64 = 100;
+
+/// Longest time to wait for a notification before polling the queue anyway.
+const MAX_IDLE_WAIT: Duration = Duration::from_secs(60);
+
+/// Page size when tailing results.
+const TAIL_PAGE_SIZE: i32 = 100;
+
+/// How long to wait before checking for new results when tailing.
+const TAIL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 /// List all handlers.
 /// For now, assumes that there are enough to fit in memory, and an API response.
 pub(crate) async fn list_handlers(pool: &Pool<Postgres>) -> Result<Vec<HandlerSpec>, sqlx::Error> {
@@ -23,13 +127,23 @@ pub(crate) async fn list_handlers(pool: &Pool<Postgres>) -> Result<Vec<HandlerSp
     db::handler::get_all_enabled_handlers(&mut tx).await
 }
 
-/// Load functions from specified directory.
+/// List enabled handlers belonging to the owner.
+pub(crate) async fn list_handlers_for_owner(
+    pool: &Pool<Postgres>,
+    owner_id: i32,
+) -> Result<Vec<HandlerSpec>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    db::handler::get_handlers_for_owner(owner_id, &mut tx).await
+}
+
+/// Load functions from specified directory, each with the given timeout.
 /// These are configured at boot, not directly by a user, so the result is logged.
 pub(crate) async fn load_handler_functions_from_disk(
     pool: &Pool<Postgres>,
     path: std::path::PathBuf,
+    timeout_ms: i32,
 ) {
-    let tasks = local::load_tasks_from_dir(path);
+    let tasks = local::load_tasks_from_dir(path, timeout_ms);
     for (filename, task) in tasks {
         match load_handler(pool, &task).await {
             TaskLoadResult::New { task_id } => {
@@ -38,6 +152,9 @@ pub(crate) async fn load_handler_functions_from_disk(
             TaskLoadResult::Exists { task_id } => {
                 log::info!("Task already exists at {} with id {}", &filename, task_id)
             }
+            TaskLoadResult::Invalid { message } => {
+                log::error!("Task at {} isn't valid: {}", &filename, message)
+            }
             TaskLoadResult::FailedSave() => {
                 log::error!("Failed to load task from {}", &filename)
             }
@@ -45,20 +162,37 @@ pub(crate) async fn load_handler_functions_from_disk(
     }
 }
 
+/// Write all enabled handler functions to the directory, with a manifest.
+/// Return the number written.
+pub(crate) async fn dump_handler_functions_to_disk(
+    pool: &Pool<Postgres>,
+    path: &std::path::Path,
+) -> anyhow::Result<usize> {
+    let handlers = list_handlers(pool).await?;
+    local::dump_handlers_to_dir(path, &handlers)?;
+    Ok(handlers.len())
+}
+
 pub(crate) enum TaskLoadResult {
     New { task_id: i64 },
     Exists { task_id: i64 },
+    Invalid { message: String },
     FailedSave(),
 }
 
 /// Load a function. On creation return New ID, or report that it already exists.
+/// The code is validated first, and not saved if invalid.
 pub(crate) async fn load_handler(pool: &Pool<Postgres>, task: &HandlerSpec) -> TaskLoadResult {
+    if let Err(message) = execution::run::validate_handler(&task.code) {
+        return TaskLoadResult::Invalid { message };
+    }
+
     let hash = hash_data(&task.code);
 
     log::info!("Load function {}", hash);
 
     let insert_result =
-        db::handler::insert_handler(task, &hash, 0, db::handler::HandlerState::Enabled, pool);
+        db::handler::insert_handler(task, &hash, db::handler::HandlerState::Enabled, pool);
 
     match insert_result.await {
         Ok((handler_id, true)) => TaskLoadResult::New {
@@ -70,10 +204,172 @@ pub(crate) async fn load_handler(pool: &Pool<Postgres>, task: &HandlerSpec) -> T
         Err(e) => {
             log::error!("Failed to save handler {}: {:?}", hash, e);
             TaskLoadResult::FailedSave()
-        },
+        }
+    }
+}
+
+pub(crate) enum CodeUpdateResult {
+    Updated,
+    NotFound,
+    Invalid { message: String },
+    Conflict { task_id: i64 },
+    FailedSave(),
+}
+
+/// Replace the code of an existing function, keeping its ID and results.
+/// The code is validated first. It's a conflict if another function already has the same code.
+pub(crate) async fn update_handler_code(
+    pool: &Pool<Postgres>,
+    handler_id: i64,
+    code: &str,
+) -> CodeUpdateResult {
+    if let Err(message) = execution::run::validate_handler(code) {
+        return CodeUpdateResult::Invalid { message };
+    }
+
+    let hash = hash_data(code);
+
+    log::info!("Update function {} to {}", handler_id, hash);
+
+    let result = async {
+        let mut tx = pool.begin().await?;
+        let updated = db::handler::update_code(handler_id, code, &hash, &mut tx).await?;
+        tx.commit().await?;
+        Ok::<bool, Error>(updated)
+    };
+
+    match result.await {
+        Ok(true) => CodeUpdateResult::Updated,
+        Ok(false) => CodeUpdateResult::NotFound,
+
+        // The hash is unique, so another function already has this code.
+        // Checked by the database rather than beforehand, so that concurrent updates can't both pass.
+        Err(Error::Database(e)) if e.is_unique_violation() => {
+            match db::handler::get_by_hash(pool, &hash).await {
+                Ok(Some(existing)) => CodeUpdateResult::Conflict {
+                    task_id: existing.handler_id,
+                },
+                Ok(None) => CodeUpdateResult::FailedSave(),
+                Err(e) => {
+                    log::error!("Failed to find handler with hash {}: {:?}", hash, e);
+                    CodeUpdateResult::FailedSave()
+                }
+            }
+        }
+        Err(e) => {
+            log::error!("Failed to update handler {}: {:?}", handler_id, e);
+            CodeUpdateResult::FailedSave()
+        }
     }
 }
 
+/// Outcome of loading a single Event.
+#[derive(Debug, PartialEq)]
+pub(crate) enum EventLoadResult {
+    /// Inserted as a new Event.
+    New,
+
+    /// Identical to an existing Event, so not inserted.
+    Duplicate,
+
+    /// Not inserted, with the reasons why.
+    Invalid(Vec<String>),
+}
+
+/// Validate and parse a single Event, given in the public JSON representation.
+fn parse_event(item: &Value) -> Result<Event, Vec<String>> {
+    Event::validate_json(item)?;
+
+    // Parse to break apart array and re-serialize.
+    // Not the most efficient, but this is a cold code path.
+    let json = serde_json::to_string(item)
+        .map_err(|e| vec![format!("Can't serialize event input: {}", e)])?;
+
+    Event::from_json_value(&json).ok_or_else(|| vec![String::from("Can't read event input.")])
+}
+
+/// Validate and insert Events, given in the public JSON representation. Return the outcome for each, in order.
+/// The distinct subject and object identifiers are resolved first, several at once, then the Events are inserted in the transaction.
+async fn load_event_batch<'a>(
+    items: &[Value],
+    pool: &Pool<Postgres>,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<Vec<EventLoadResult>, sqlx::Error> {
+    load_event_batch_with(items, |key| db::entity::resolve_key(key, pool), tx).await
+}
+
+/// Validate and insert Events as [`load_event_batch`], resolving each distinct identifier with the lookup function.
+async fn load_event_batch_with<'a, F, Fut>(
+    items: &[Value],
+    lookup: F,
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<Vec<EventLoadResult>, sqlx::Error>
+where
+    F: Fn(db::entity::IdentifierKey) -> Fut,
+    Fut: Future<Output = Result<i64, sqlx::Error>>,
+{
+    let events: Vec<Result<Event, Vec<String>>> = items.iter().map(parse_event).collect();
+
+    // Subject and Object are optional.
+    let entity_ids = db::entity::resolve_identifiers(
+        events
+            .iter()
+            .flatten()
+            .flat_map(|event| event.subject_id.iter().chain(event.object_id.iter())),
+        db::entity::RESOLVE_CONCURRENCY,
+        lookup,
+    )
+    .await?;
+
+    let entity_id = |identifier: &Option<Identifier>| {
+        identifier
+            .as_ref()
+            .and_then(|id| entity_ids.get(&db::entity::identifier_key(id)).copied())
+    };
+
+    let mut results = Vec::with_capacity(items.len());
+    for event in events {
+        let event = match event {
+            Ok(event) => event,
+            Err(errors) => {
+                results.push(EventLoadResult::Invalid(errors));
+                continue;
+            }
+        };
+
+        let inserted = db::event::insert_event(
+            &event,
+            entity_id(&event.subject_id),
+            entity_id(&event.object_id),
+            EventQueueState::New,
+            tx,
+        )
+        .await?;
+
+        results.push(match inserted {
+            Some(_) => EventLoadResult::New,
+            None => EventLoadResult::Duplicate,
+        });
+    }
+
+    Ok(results)
+}
+
+/// Load a list of Events in one transaction. Return the outcome for each, in order.
+/// Invalid Events are skipped, but a database error aborts the whole load.
+pub(crate) async fn load_events(
+    pool: &Pool<Postgres>,
+    items: &[Value],
+) -> Result<Vec<EventLoadResult>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let results = load_event_batch(items, pool, &mut tx).await?;
+
+    tx.commit().await?;
+
+    Ok(results)
+}
+
 pub(crate) async fn load_events_from_disk(
     pool: &Pool<Postgres>,
     path: std::path::PathBuf,
@@ -85,44 +381,20 @@ pub(crate) async fn load_events_from_disk(
     for (filename, data) in files {
         match serde_json::from_str::<Vec<Value>>(&data) {
             Ok(items) => {
-                for item in items {
-                    // Parse to break apart array and re-serialize.
-                    // Not the most efficient, but this is a cold code path.
-                    match serde_json::to_string(&item) {
-                        Ok(json) => {
-                            if let Some(event) = Event::from_json_value(&json) {
-                                // Subject and Object are optional.
-                                let subject_entity_id = if let Some(ref id) = event.subject_id {
-                                    Some(db::entity::resolve_identifier(id, pool).await?)
-                                } else {
-                                    None
-                                };
-
-                                let object_entity_id = if let Some(ref id) = event.object_id {
-                                    Some(db::entity::resolve_identifier(id, pool).await?)
-                                } else {
-                                    None
-                                };
-
-                                // Normalize
-                                db::event::insert_event(
-                                    &event,
-                                    subject_entity_id,
-                                    object_entity_id,
-                                    EventQueueState::New,
-                                    &mut tx,
-                                )
-                                .await?;
-                            } else {
-                                log::error!(
-                                    "Didn't insert event from file: {}. Input: {}",
-                                    filename,
-                                    &json
-                                );
-                            }
+                let results = load_event_batch(&items, pool, &mut tx).await?;
+                for (index, result) in results.into_iter().enumerate() {
+                    match result {
+                        EventLoadResult::New => {}
+                        EventLoadResult::Duplicate => {
+                            log::debug!("Ignored duplicate event from file: {}", filename);
                         }
-                        Err(e) => {
-                            log::error!("Can't serialize event input: {:?}", e);
+                        EventLoadResult::Invalid(errors) => {
+                            log::error!(
+                                "Invalid event in file: {} at index {}: {}",
+                                filename,
+                                index,
+                                errors.join(" ")
+                            );
                         }
                     }
                 }
@@ -138,6 +410,64 @@ pub(crate) async fn load_events_from_disk(
     Ok(())
 }
 
+/// Crossref works from newline-delimited JSON, as subject identifiers with their JSON.
+/// Blank lines are ignored. Return the works, and the number of lines skipped because they weren't JSON or had no DOI.
+fn crossref_works_from_ndjson(data: &str) -> (Vec<(Identifier, String)>, usize) {
+    let mut works = vec![];
+    let mut skipped = 0;
+
+    for line in data.lines().filter(|x| !x.trim().is_empty()) {
+        match serde_json::from_str::<Value>(line)
+            .ok()
+            .and_then(|x| get_identifier_and_json(x, MetadataSourceId::Crossref))
+        {
+            Some(work) => works.push(work),
+            None => skipped += 1,
+        }
+    }
+
+    (works, skipped)
+}
+
+/// Load Crossref works from files of newline-delimited JSON in the directory at path, as Primary metadata assertions.
+/// Each file is loaded in its own transaction. Works without a DOI are skipped.
+/// Return the total number of assertions loaded. Duplicates of existing assertions are counted, but not inserted.
+pub(crate) async fn load_assertions_from_disk(
+    pool: &Pool<Postgres>,
+    path: std::path::PathBuf,
+) -> Result<usize, sqlx::Error> {
+    let files = local::load_files_from_dir(path)?;
+
+    let mut total = 0;
+    for (filename, data) in files {
+        let (works, skipped) = crossref_works_from_ndjson(&data);
+
+        let mut tx = pool.begin().await?;
+        for (identifier, json) in works.iter() {
+            assert_metadata(
+                identifier,
+                json,
+                MetadataSourceId::Crossref,
+                MetadataAssertionReason::Primary,
+                pool,
+                &mut tx,
+            )
+            .await?;
+        }
+        tx.commit().await?;
+
+        log::info!(
+            "Loaded {} assertions from file: {}. Skipped {} without a DOI or not JSON.",
+            works.len(),
+            filename,
+            skipped
+        );
+        total += works.len();
+    }
+
+    Ok(total)
+}
+
 #[derive(Debug)]
 pub(crate) struct PumpResult {
     events_processed: u32,
@@ -147,26 +477,47 @@ pub(crate) struct PumpResult {
     total_duration: u128,
     results: usize,
     handlers: usize,
+
+    /// Number of Events moved to the dead letter table.
+    dead_letters: usize,
+
+    /// Execution was cancelled, and the batch rolled back.
+    cancelled: bool,
 }
 
-pub(crate) async fn drain(pool: &Pool<Postgres>) {
+/// Execute batches of Events from the queue until it's empty.
+/// If `once` is set, stop after one batch.
+pub(crate) async fn drain(pool: &Pool<Postgres>, once: bool) {
     let mut count = EXECUTE_BATCH_SIZE;
 
+    let cancel = Arc::new(AtomicBool::new(false));
+    let cancel_listener = listen_for_cancel(pool, cancel.clone());
+
     // Keep going until we get a less-than-full page.
     while count >= EXECUTE_BATCH_SIZE {
-        match try_pump(pool, EXECUTE_BATCH_SIZE).await {
+        match try_pump(pool, EXECUTE_BATCH_SIZE, &cancel).await {
+            Ok(result) if result.cancelled => {
+                log::warn!("Execution cancelled. Batch rolled back, Events remain on the queue.");
+                // Terminate loop.
+                count = 0;
+            }
             Ok(result) => {
                 log::info!(
-            "Pumped {} events through {} handlers in {}ms. Got {} results. Poll: {}, execute: {}, save: {}",
+            "Pumped {} events through {} handlers in {}ms. Got {} results, {} dead letters. Poll: {}, execute: {}, save: {}",
             result.events_processed,
             result.handlers,
             result.total_duration,
             result.results,
+            result.dead_letters,
             result.poll_duration,
             result.execute_duration,
             result.save_duration
         );
-                count = result.events_processed as i32;
+                count = if once {
+                    0
+                } else {
+                    result.events_processed as i32
+                };
             }
             Err(e) => {
                 log::error!("Failed to poll queue. Error: {:?}", e);
@@ -175,86 +526,1306 @@ pub(crate) async fn drain(pool: &Pool<Postgres>) {
             }
         }
     }
+
+    cancel_listener.abort();
+}
+
+/// Set the cancel flag when a cancellation notification is received.
+/// If it's not possible to listen, execution carries on without the ability to cancel.
+fn listen_for_cancel(pool: &Pool<Postgres>, cancel: Arc<AtomicBool>) -> JoinHandle<()> {
+    let pool = pool.clone();
+    tokio::spawn(async move {
+        let mut listener = match PgListener::connect_with(&pool).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::error!("Can't listen for cancellation: {:?}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = listener.listen(CANCEL_EXECUTION_CHANNEL).await {
+            log::error!("Can't listen for cancellation: {:?}", e);
+            return;
+        }
+
+        loop {
+            match listener.recv().await {
+                Ok(_) => {
+                    log::warn!("Received request to cancel execution.");
+                    cancel.store(true, Ordering::SeqCst);
+                }
+                Err(e) => {
+                    log::error!("Error listening for cancellation: {:?}", e);
+                    return;
+                }
+            }
+        }
+    })
+}
+
+/// Ask all running executors to cancel their current batch.
+pub(crate) async fn cancel_execution(pool: &Pool<Postgres>) -> Result<(), Error> {
+    db::pool::notify(CANCEL_EXECUTION_CHANNEL, pool).await
+}
+
+/// Drain the queue, then wait for notification of new Events and repeat. Never returns unless there's an error listening.
+pub(crate) async fn drain_forever(pool: &Pool<Postgres>) -> Result<(), Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(EVENT_QUEUE_CHANNEL).await?;
+
+    loop {
+        drain(pool, false).await;
+
+        match wait_for_wakeup(listener.recv(), MAX_IDLE_WAIT).await {
+            Some(Ok(_)) => log::debug!("Woken by new Events."),
+            Some(Err(e)) => {
+                log::error!("Error waiting for notification: {:?}", e);
+                tokio::time::sleep(MAX_IDLE_WAIT).await;
+            }
+            None => log::debug!("No notification, poll anyway."),
+        }
+
+        // Notifications that have already arrived are covered by the next drain.
+        while let Some(Ok(Some(_))) = listener.try_recv().now_or_never() {}
+    }
+}
+
+/// Wait for the notification future, up to the maximum wait.
+/// Return its output, or None if it timed out.
+async fn wait_for_wakeup<F: Future>(notified: F, max_wait: Duration) -> Option<F::Output> {
+    tokio::time::timeout(max_wait, notified).await.ok()
 }
 
 /// Poll for a batch of inputs, run handler functions.
 /// Does not necessarily consume all messages on the queue.
-pub(crate) async fn try_pump(pool: &Pool<Postgres>, batch_size: i32) -> Result<PumpResult, Error> {
+/// If cancelled during execution, the transaction is rolled back so the Events remain on the queue.
+pub(crate) async fn try_pump(
+    pool: &Pool<Postgres>,
+    batch_size: i32,
+    cancel: &Arc<AtomicBool>,
+) -> Result<PumpResult, Error> {
     let start_poll = std::time::Instant::now();
 
     let mut tx = pool.begin().await?;
 
+    // Held until the batch is committed or rolled back, so another process waits to poll.
+    if execute_exclusive() {
+        db::agents::lock_execution(&mut tx).await?;
+    }
+
     let events = db::event::poll(batch_size, &mut tx).await?;
-    log::debug!("Polled {} from Event queue", events.len());
+    let events_processed = events.len() as u32;
+    log::debug!("Polled {} from Event queue", events_processed);
 
     // Get all handlers. Do so from inside the transaction so there's a
     // consistent view of the handlers table. If it becomes necessary to chunk
     // into batches of handlers in future, this will be important.
     let handlers: Vec<HandlerSpec> = db::handler::get_all_enabled_handlers(&mut tx).await?;
 
+    // Queue state is needed after execution, to retry or dead-letter failing Events.
+    let queued: Vec<(i64, Option<i64>, i32)> = events
+        .iter()
+        .map(|x| (x.event.event_id, x.handler_id, x.attempts))
+        .collect();
+
     let start_execution = std::time::Instant::now();
-    let results = execution::run::run_all(&handlers, &events);
+    let results = match execute_polled(&handlers, events, cancel) {
+        Ok(results) => results,
+        Err(execution::run::Cancelled) => {
+            tx.rollback().await?;
+            let finish = std::time::Instant::now();
+
+            return Ok(PumpResult {
+                events_processed: 0,
+                handlers: handlers.len(),
+                results: 0,
+                dead_letters: 0,
+                poll_duration: start_execution.duration_since(start_poll).as_millis(),
+                execute_duration: finish.duration_since(start_execution).as_millis(),
+                save_duration: 0,
+                total_duration: finish.duration_since(start_poll).as_millis(),
+                cancelled: true,
+            });
+        }
+    };
 
     let start_save = std::time::Instant::now();
     db::handler::save_results(&results, &mut tx).await?;
 
     log::debug!("Saved {} execution results", results.len());
 
+    let dead_letters = retry_failed(&queued, &results, &mut tx).await?;
+
     tx.commit().await?;
     let finish = std::time::Instant::now();
 
     Ok(PumpResult {
-        events_processed: events.len() as u32,
+        events_processed,
         handlers: handlers.len(),
         results: results.len(),
+        dead_letters,
         poll_duration: start_execution.duration_since(start_poll).as_millis(),
         execute_duration: start_save.duration_since(start_execution).as_millis(),
         save_duration: finish.duration_since(start_save).as_millis(),
         total_duration: finish.duration_since(start_poll).as_millis(),
+        cancelled: false,
     })
 }
 
-/// Get Handler Spec by ID, or None.
+/// Was the handler not run for the Event because the batch ran out of time, rather than failing on it?
+fn skipped(result: &ExecutionResult) -> bool {
+    result.error_kind == Some(ErrorKind::TimeBudget)
+}
+
+/// IDs of Events for which every handler errored.
+/// Results that aren't for a single Event, e.g. from batch handlers, aren't counted,
+/// nor are handlers skipped by the time budget.
+fn all_failed(results: &[ExecutionResult]) -> Vec<i64> {
+    let mut failed: BTreeMap<i64, bool> = BTreeMap::new();
+    for result in results.iter().filter(|x| x.event_id != -1 && !skipped(x)) {
+        *failed.entry(result.event_id).or_insert(true) &= result.error.is_some();
+    }
+
+    failed
+        .into_iter()
+        .filter_map(|(event_id, failed)| failed.then_some(event_id))
+        .collect()
+}
+
+/// IDs of Events for which every handler was skipped by the time budget, so none has run yet.
+fn all_skipped(results: &[ExecutionResult]) -> Vec<i64> {
+    let mut all: BTreeMap<i64, bool> = BTreeMap::new();
+    for result in results.iter().filter(|x| x.event_id != -1) {
+        *all.entry(result.event_id).or_insert(true) &= skipped(result);
+    }
+
+    all.into_iter()
+        .filter_map(|(event_id, all)| all.then_some(event_id))
+        .collect()
+}
+
+/// Put Events for which every handler errored back on the queue.
+/// Once they've been retried the configured number of times, move them to the dead letter table instead.
+/// Events skipped by the time budget are put back without counting an attempt, and never dead-lettered.
+/// Return the number dead-lettered.
+async fn retry_failed<'a>(
+    queued: &[(i64, Option<i64>, i32)],
+    results: &[ExecutionResult],
+    tx: &mut Transaction<'a, Postgres>,
+) -> Result<usize, Error> {
+    let retries = dead_letter_retries();
+
+    let failed = all_failed(results);
+    let queued: HashMap<i64, (Option<i64>, i32)> = queued
+        .iter()
+        .map(|(event_id, handler_id, attempts)| (*event_id, (*handler_id, *attempts)))
+        .collect();
+
+    for event_id in all_skipped(results) {
+        let Some((handler_id, attempts)) = queued.get(&event_id) else {
+            continue;
+        };
+
+        db::event::requeue_failed(event_id, *handler_id, *attempts, tx).await?;
+    }
+
+    let mut dead_letters = 0;
+    for event_id in failed {
+        let Some((handler_id, attempts)) = queued.get(&event_id) else {
+            continue;
+        };
+
+        let attempts = attempts + 1;
+        if attempts > retries {
+            log::warn!(
+                "Every handler errored for Event {} after {} attempts. Moving to dead letters.",
+                event_id,
+                attempts
+            );
+            db::event::record_dead_letter(event_id, attempts, tx).await?;
+            dead_letters += 1;
+        } else {
+            db::event::requeue_failed(event_id, *handler_id, attempts, tx).await?;
+        }
+    }
+
+    Ok(dead_letters)
+}
+
+/// Run handlers over polled Events.
+/// Events restricted to a single handler are only run against that handler, and only if it's enabled.
+fn execute_polled(
+    handlers: &[HandlerSpec],
+    events: Vec<QueuedEvent>,
+    cancel: &Arc<AtomicBool>,
+) -> Result<Vec<ExecutionResult>, execution::run::Cancelled> {
+    // Pooled isolates for handlers that have since been disabled or changed aren't needed.
+    execution::run::retain_pooled_isolates(handlers);
+
+    let mut unrestricted: Vec<Event> = vec![];
+    let mut restricted: BTreeMap<i64, Vec<Event>> = BTreeMap::new();
+    for QueuedEvent {
+        event, handler_id, ..
+    } in events
+    {
+        match handler_id {
+            Some(handler_id) => restricted.entry(handler_id).or_default().push(event),
+            None => unrestricted.push(event),
+        }
+    }
+
+    let mut results = execution::run::run_all_cancellable(handlers, &unrestricted, cancel)?;
+
+    for (handler_id, events) in restricted {
+        match handlers.iter().find(|x| x.handler_id == handler_id) {
+            Some(handler) => results.append(&mut execution::run::run_all_cancellable(
+                std::slice::from_ref(handler),
+                &events,
+                cancel,
+            )?),
+            None => log::warn!(
+                "Skip {} Events for handler {} as it's not enabled.",
+                events.len(),
+                handler_id
+            ),
+        }
+    }
+
+    Ok(results)
+}
+
+/// Put up to `limit` existing Events after the given Event ID back on the queue, so they're executed again.
+/// If `handler_id` is given, only that handler is run for them.
+/// The limit must be positive, and is capped at [MAX_RERUN_LIMIT].
+/// Return the number re-enqueued, and the ID of the last one, if any.
+pub(crate) async fn rerun_events(
+    pool: &Pool<Postgres>,
+    after_event_id: i64,
+    limit: i64,
+    handler_id: Option<i64>,
+) -> Result<(i64, Option<i64>), ServiceError> {
+    if limit < 1 {
+        return Err(ServiceError::Validation(String::from(
+            "limit must be at least 1.",
+        )));
+    }
+
+    let (count, last_event_id) =
+        db::event::reenqueue_events(limit.min(MAX_RERUN_LIMIT), after_event_id, handler_id, pool)
+            .await?;
+
+    log::info!(
+        "Re-enqueued {} Events after {} for handler {:?}",
+        count,
+        after_event_id,
+        handler_id
+    );
+
+    // The queue trigger only fires for new Events, so wake executors explicitly.
+    if count > 0 {
+        db::pool::notify(EVENT_QUEUE_CHANNEL, pool).await?;
+    }
+
+    Ok((count, last_event_id))
+}
+
+/// Check that all enabled handlers still load and define a function.
+/// Return the handler ids that failed, with their error messages. Doesn't modify handlers.
+pub(crate) async fn validate_handlers(pool: &Pool<Postgres>) -> Result<Vec<(i64, String)>, Error> {
+    let mut tx = pool.begin().await?;
+    let handlers = db::handler::get_all_enabled_handlers(&mut tx).await?;
+    tx.rollback().await?;
+
+    log::info!("Validating {} handlers", handlers.len());
+
+    Ok(handlers
+        .iter()
+        .filter_map(|handler| {
+            execution::run::validate_handler(&handler.code)
+                .err()
+                .map(|message| (handler.handler_id, message))
+        })
+        .collect())
+}
+
+/// Get Handler Spec by ID.
 pub(crate) async fn get_handler_by_id(
     pool: &Pool<Postgres>,
     handler_id: i64,
-) -> Option<HandlerSpec> {
-    match db::handler::get_by_id(pool, handler_id).await {
-        Ok(handler_id) => Some(handler_id),
-        Err(e) => {
-            log::error!("Didn't find handler id {}, error: {:?}", handler_id, e);
-            None
+) -> Result<HandlerSpec, ServiceError> {
+    Ok(db::handler::get_by_id(pool, handler_id).await?)
+}
+
+/// What's known about an identifier.
+pub(crate) struct IdentifierInfo {
+    pub(crate) entity_id: i64,
+
+    /// Most recent metadata assertion, if any.
+    pub(crate) assertion: Option<db::metadata::MetadataAssertion>,
+
+    /// Number of Events with the identifier as subject.
+    pub(crate) subject_events: i64,
+
+    /// Number of Events with the identifier as object.
+    pub(crate) object_events: i64,
+}
+
+/// Look up an identifier's entity, latest metadata assertion and Event counts.
+/// If a source is given, the latest version from that source is returned.
+/// NotFound if the identifier has never been seen. Doesn't create the entity.
+pub(crate) async fn get_identifier_info(
+    pool: &Pool<Postgres>,
+    identifier: &Identifier,
+    source: Option<MetadataSourceId>,
+) -> Result<IdentifierInfo, ServiceError> {
+    let Some(entity_id) = db::entity::find_identifier(identifier, pool).await? else {
+        return Err(ServiceError::NotFound);
+    };
+
+    let assertion = match source {
+        Some(source) => db::metadata::latest_for_subject(entity_id, source, pool).await?,
+        None => db::metadata::latest_assertion(entity_id, pool).await?,
+    };
+    let (subject_events, object_events) = db::event::count_for_entity(entity_id, pool).await?;
+
+    Ok(IdentifierInfo {
+        entity_id,
+        assertion,
+        subject_events,
+        object_events,
+    })
+}
+
+/// Page of Events where the identifier is the subject or object, with IDs after the cursor.
+/// Return the Events and the cursor for the next page, or NotFound if the identifier has never been seen.
+pub(crate) async fn get_events_for_identifier(
+    pool: &Pool<Postgres>,
+    identifier: &Identifier,
+    role: db::event::EntityRole,
+    cursor: i64,
+    page_size: i32,
+) -> Result<(Vec<Event>, i64), ServiceError> {
+    let Some(entity_id) = db::entity::find_identifier(identifier, pool).await? else {
+        return Err(ServiceError::NotFound);
+    };
+
+    let events = match role {
+        db::event::EntityRole::Subject => {
+            db::event::events_for_subject(entity_id, cursor, page_size, pool).await?
         }
+        db::event::EntityRole::Object => {
+            db::event::events_for_object(entity_id, cursor, page_size, pool).await?
+        }
+    };
+
+    let next_cursor = events.last().map(|x| x.event_id).unwrap_or(-1);
+    Ok((events, next_cursor))
+}
+
+/// Find the handler whose code has the given SHA-1 hash.
+pub(crate) async fn get_handler_by_hash(
+    pool: &Pool<Postgres>,
+    hash: &str,
+) -> Result<HandlerSpec, ServiceError> {
+    db::handler::get_by_hash(pool, hash)
+        .await?
+        .ok_or(ServiceError::NotFound)
+}
+
+/// Get a single result by id.
+pub(crate) async fn get_result_by_id(
+    pool: &Pool<Postgres>,
+    result_id: i64,
+) -> Result<ExecutionResult, ServiceError> {
+    db::handler::get_result_by_id(pool, result_id)
+        .await?
+        .ok_or(ServiceError::NotFound)
+}
+
+/// Pause or resume harvesting. A running harvest stops before its next page.
+/// While paused, harvests stop before fetching anything.
+pub(crate) async fn set_harvest_running(pool: &Pool<Postgres>, running: bool) -> Result<(), Error> {
+    db::agents::set_control_flag(db::agents::HARVEST_RUNNING, running, pool).await?;
+
+    if running {
+        log::info!("Harvesting resumed.");
+    } else {
+        log::info!("Harvesting paused.");
+    }
+
+    Ok(())
+}
+
+/// Named date checkpoints that can be reset or set by an operator.
+pub(crate) const CHECKPOINT_IDS: [&str; 2] = [
+    crossref::metadata_agent::CROSSREF_NB,
+    datacite::metadata_agent::DATACITE_NB,
+];
+
+/// Can an operator reset or set the checkpoint? Either one of [`CHECKPOINT_IDS`],
+/// or the Crossref checkpoint for a harvest restricted to types, e.g. `crossref-not-before:book`.
+pub(crate) fn is_checkpoint_id(id: &str) -> bool {
+    CHECKPOINT_IDS.contains(&id)
+        || id
+            .strip_prefix(crossref::metadata_agent::CROSSREF_NB)
+            .and_then(|x| x.strip_prefix(':'))
+            .is_some_and(|types| !types.is_empty())
+}
+
+/// Remove a named checkpoint, logging its previous value.
+pub(crate) async fn reset_checkpoint(pool: &Pool<Postgres>, id: &str) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let previous = db::agents::clear_checkpoint(id, &mut tx).await?;
+    tx.commit().await?;
+
+    match previous {
+        Some(previous) => log::info!("Reset checkpoint {}, was {}.", id, previous),
+        None => log::info!("Checkpoint {} wasn't set.", id),
+    }
+
+    Ok(())
+}
+
+/// Set a named checkpoint to the given date, even if it's earlier, logging its previous and new values.
+pub(crate) async fn set_checkpoint(
+    pool: &Pool<Postgres>,
+    id: &str,
+    date: OffsetDateTime,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let previous = db::agents::get_checkpoint(id, &mut tx).await?;
+    db::agents::set_checkpoint(id, date, &mut tx).await?;
+    tx.commit().await?;
+
+    match previous {
+        Some(previous) => log::info!("Set checkpoint {} to {}, was {}.", id, date, previous),
+        None => log::info!("Set checkpoint {} to {}, wasn't set.", id, date),
+    }
+
+    Ok(())
+}
+
+/// Most common errors for a handler, up to the limit.
+pub(crate) async fn get_error_summary(
+    pool: &Pool<Postgres>,
+    handler_id: i64,
+    limit: i64,
+) -> Result<Vec<db::handler::ErrorSummary>, ServiceError> {
+    Ok(
+        db::handler::error_summary(pool, handler_id, limit.clamp(1, MAX_ERROR_SUMMARY_LIMIT))
+            .await?,
+    )
+}
+
+/// Number of Events produced by each analyzer, by name.
+pub(crate) async fn get_event_counts(
+    pool: &Pool<Postgres>,
+) -> Result<BTreeMap<String, i64>, ServiceError> {
+    Ok(db::event::counts_by_analyzer(pool).await?)
+}
+
+/// Totals of a handler's results.
+pub(crate) async fn get_result_stats(
+    pool: &Pool<Postgres>,
+    handler_id: i64,
+) -> Result<db::handler::ResultStats, ServiceError> {
+    Ok(db::handler::result_stats(pool, handler_id).await?)
+}
+
+/// Cursor to pass to the database for a page of results.
+/// A negative cursor is the start, which is the newest result when descending.
+fn results_start_cursor(cursor: i64, descending: bool) -> i64 {
+    if descending && cursor < 0 {
+        i64::MAX
+    } else {
+        cursor
     }
 }
 
+/// Cursor for the page of results after this one.
+/// When descending, an empty page gives 0, as there's nothing older. A negative cursor would start again from the newest.
+fn next_results_cursor(results: &[ExecutionResult], descending: bool) -> i64 {
+    match results.last() {
+        Some(result) => result.result_id,
+        None if descending => 0,
+        None => -1,
+    }
+}
+
+/// Which results to return.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum ResultFilter {
+    All,
+    Success,
+    Errors,
+}
+
 /// Get a page of results, plus a cursor for the next page.
-/// If filter_successful is true, only return successful results.
+/// If descending, return the newest first.
 pub(crate) async fn get_results(
     pool: &Pool<Postgres>,
     handler_id: i64,
     cursor: i64,
     page_size: i32,
-    filter_successful: bool,
-) -> (Vec<ExecutionResult>, i64) {
-    let results: Result<Vec<ExecutionResult>, sqlx::Error> = if filter_successful {
-        db::handler::get_success_results(pool, handler_id, cursor, page_size).await
-    } else {
-        db::handler::get_all_results(pool, handler_id, cursor, page_size).await
+    filter: ResultFilter,
+    descending: bool,
+) -> Result<(Vec<ExecutionResult>, i64), ServiceError> {
+    let cursor = results_start_cursor(cursor, descending);
+
+    let results = match filter {
+        ResultFilter::All => {
+            db::handler::get_all_results(pool, handler_id, cursor, page_size, descending).await
+        }
+        ResultFilter::Success => {
+            db::handler::get_success_results(pool, handler_id, cursor, page_size, descending).await
+        }
+        ResultFilter::Errors => {
+            db::handler::get_error_results(pool, handler_id, cursor, page_size, descending).await
+        }
+    }?;
+
+    let next_cursor = next_results_cursor(&results, descending);
+    Ok((results, next_cursor))
+}
+
+/// Get a page of dead letters, plus a cursor for the next page.
+pub(crate) async fn get_dead_letters(
+    pool: &Pool<Postgres>,
+    cursor: i64,
+    page_size: i32,
+) -> Result<(Vec<db::event::DeadLetter>, i64), ServiceError> {
+    let dead_letters = db::event::get_dead_letters(pool, cursor, page_size).await?;
+    let next_cursor = dead_letters.last().map(|x| x.dead_letter_id).unwrap_or(-1);
+    Ok((dead_letters, next_cursor))
+}
+
+/// Stream successful results for a handler as they're produced, starting after the cursor.
+/// If no cursor is given, start from the most recent result.
+/// Polling stops when the stream is dropped, e.g. when the client disconnects.
+/// Takes an owned pool so the stream can outlive the caller.
+pub(crate) async fn tail_results(
+    pool: Pool<Postgres>,
+    handler_id: i64,
+    cursor: Option<i64>,
+) -> impl Stream<Item = ExecutionResult> {
+    let cursor = match cursor {
+        Some(cursor) => cursor,
+        None => match db::handler::get_latest_result_id(&pool, handler_id).await {
+            Ok(latest) => latest.unwrap_or(-1),
+            Err(err) => {
+                log::error!(
+                    "Error retrieving latest result for handler id: {}, error: {:?}",
+                    handler_id,
+                    err
+                );
+                -1
+            }
+        },
     };
 
-    match results {
-        Ok(results) => {
-            let next_cursor = results.last().map(|x| x.result_id).unwrap_or(-1);
-            (results, next_cursor)
+    tail(cursor, TAIL_POLL_INTERVAL, move |cursor| {
+        let pool = pool.clone();
+        async move {
+            results_or_empty(
+                get_results(
+                    &pool,
+                    handler_id,
+                    cursor,
+                    TAIL_PAGE_SIZE,
+                    ResultFilter::Success,
+                    false,
+                )
+                .await,
+                handler_id,
+                cursor,
+            )
         }
-        Err(err) => {
-            log::error!(
-                "Error retrieving results for handler id: {}, error: {:?}",
+    })
+}
+
+/// Stream all successful results for a handler after the cursor, fetching page by page.
+/// Ends after the last page, or after yielding an error if a page can't be fetched, so that a
+/// caller can tell an incomplete stream from a complete one.
+/// Takes an owned pool so the stream can outlive the caller.
+pub(crate) fn all_results(
+    pool: Pool<Postgres>,
+    handler_id: i64,
+    cursor: i64,
+) -> impl Stream<Item = Result<ExecutionResult, ServiceError>> {
+    paginate(cursor, move |cursor| {
+        let pool = pool.clone();
+        async move {
+            get_results(
+                &pool,
                 handler_id,
-                err
+                cursor,
+                TAIL_PAGE_SIZE,
+                ResultFilter::Success,
+                false,
+            )
+            .await
+        }
+    })
+}
+
+/// A page of results for a tail, which keeps polling rather than returning an error.
+/// An error is logged, and treated as an empty page at the same cursor.
+fn results_or_empty(
+    page: Result<(Vec<ExecutionResult>, i64), ServiceError>,
+    handler_id: i64,
+    cursor: i64,
+) -> (Vec<ExecutionResult>, i64) {
+    page.unwrap_or_else(|err| {
+        log::error!(
+            "Error retrieving results for handler id: {}, error: {}",
+            handler_id,
+            err
+        );
+        (vec![], cursor)
+    })
+}
+
+/// Fetch pages of results after the cursor, yielding each result.
+/// Ends at the first empty page, or after yielding the first error.
+fn paginate<F, Fut, E>(cursor: i64, fetch: F) -> impl Stream<Item = Result<ExecutionResult, E>>
+where
+    F: Fn(i64) -> Fut,
+    Fut: Future<Output = Result<(Vec<ExecutionResult>, i64), E>>,
+{
+    futures::stream::unfold(
+        Some((cursor, VecDeque::new(), fetch)),
+        move |state| async move {
+            let (mut cursor, mut buffer, fetch) = state?;
+            if buffer.is_empty() {
+                match fetch(cursor).await {
+                    Ok((results, next_cursor)) => {
+                        cursor = next_cursor;
+                        buffer.extend(results);
+                    }
+                    Err(err) => return Some((Err(err), None)),
+                }
+            }
+
+            let result = buffer.pop_front()?;
+            Some((Ok(result), Some((cursor, buffer, fetch))))
+        },
+    )
+}
+
+/// Repeatedly fetch pages of results after the cursor, yielding each result.
+/// When a page is empty, wait for the interval before trying again.
+fn tail<F, Fut>(cursor: i64, interval: Duration, fetch: F) -> impl Stream<Item = ExecutionResult>
+where
+    F: Fn(i64) -> Fut,
+    Fut: Future<Output = (Vec<ExecutionResult>, i64)>,
+{
+    futures::stream::unfold(
+        (cursor, VecDeque::new(), fetch),
+        move |(mut cursor, mut buffer, fetch)| async move {
+            loop {
+                if let Some(result) = buffer.pop_front() {
+                    return Some((result, (cursor, buffer, fetch)));
+                }
+
+                let (results, next_cursor) = fetch(cursor).await;
+                if results.is_empty() {
+                    tokio::time::sleep(interval).await;
+                } else {
+                    cursor = next_cursor;
+                    buffer.extend(results);
+                }
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::sync::{Arc, Mutex};
+
+    use futures::StreamExt;
+
+    use crate::db::pool::get_pool;
+    use crate::db::source::EventAnalyzerId;
+
+    use super::*;
+
+    /// Unrestricted Events go to every handler, restricted ones only to their handler, and only if it's enabled.
+    #[test]
+    #[serial_test::serial]
+    fn execute_polled_restricted() {
+        execution::run::init();
+
+        let handler = |handler_id| HandlerSpec {
+            handler_id,
+            code: String::from("function f(args) { return [1]; }"),
+            status: 1,
+            timeout_ms: execution::run::DEFAULT_EXECUTION_TIMEOUT_MS,
+            hash: None,
+            owner_id: 0,
+        };
+        let queued = |event_id, handler_id| QueuedEvent {
+            event: Event {
+                event_id,
+                analyzer: EventAnalyzerId::Test,
+                source: MetadataSourceId::Test,
+                subject_id: None,
+                object_id: None,
+                json: String::from("{}"),
+                assertion_id: -1,
+                occurred_at: None,
+            },
+            handler_id,
+            attempts: 0,
+        };
+
+        let results = execute_polled(
+            &[handler(1), handler(2)],
+            vec![queued(10, None), queued(11, Some(2)), queued(12, Some(3))],
+            &Arc::new(AtomicBool::new(false)),
+        )
+        .unwrap();
+
+        let ran: BTreeSet<(i64, i64)> =
+            results.iter().map(|x| (x.handler_id, x.event_id)).collect();
+        assert_eq!(ran, BTreeSet::from([(1, 10), (2, 10), (2, 11)]));
+        assert_eq!(results.len(), 3);
+    }
+
+    #[test]
+    fn checkpoint_ids() {
+        assert!(is_checkpoint_id("crossref-not-before"));
+        assert!(is_checkpoint_id("datacite-not-before"));
+        assert!(is_checkpoint_id("crossref-not-before:book,book-chapter"));
+
+        assert!(!is_checkpoint_id("crossref-not-before:"));
+        assert!(!is_checkpoint_id("crossref-not-beforebook"));
+        assert!(!is_checkpoint_id("datacite-not-before:book"));
+        assert!(!is_checkpoint_id("crossref-cursor"));
+    }
+
+    fn result(result_id: i64) -> ExecutionResult {
+        ExecutionResult {
+            result_id,
+            handler_id: 1,
+            event_id: 1,
+            result: Some(String::from("{}")),
+            error: None,
+            error_kind: None,
+            assertion_id: -1,
+            created: None,
+        }
+    }
+
+    fn event_result(handler_id: i64, event_id: i64, error: Option<&str>) -> ExecutionResult {
+        ExecutionResult {
+            handler_id,
+            event_id,
+            result: error.is_none().then(|| String::from("{}")),
+            error: error.map(String::from),
+            ..result(-1)
+        }
+    }
+
+    /// Only Events where every handler errored are counted as failed.
+    #[test]
+    fn all_failed_events() {
+        let results = vec![
+            // Every handler errored.
+            event_result(1, 10, Some("Bad")),
+            event_result(2, 10, Some("Worse")),
+            // One handler succeeded.
+            event_result(1, 11, Some("Bad")),
+            event_result(2, 11, None),
+            // No errors.
+            event_result(1, 12, None),
+            // Batch results aren't for a single Event.
+            event_result(3, -1, Some("Bad")),
+            // Only handler errored, e.g. when restricted.
+            event_result(2, 13, Some("Bad")),
+        ];
+
+        assert_eq!(all_failed(&results), vec![10, 13]);
+        assert!(all_failed(&[]).is_empty());
+    }
+
+    fn skipped_result(handler_id: i64, event_id: i64) -> ExecutionResult {
+        ExecutionResult {
+            error_kind: Some(ErrorKind::TimeBudget),
+            ..event_result(handler_id, event_id, Some("Skipped"))
+        }
+    }
+
+    /// Handlers skipped by the time budget don't count towards an Event failing.
+    /// Events where every handler was skipped are reported separately.
+    #[test]
+    fn skipped_events_not_failed() {
+        let results = vec![
+            // Every handler skipped.
+            skipped_result(1, 10),
+            skipped_result(2, 10),
+            // One handler errored, the other was skipped.
+            event_result(1, 11, Some("Bad")),
+            skipped_result(2, 11),
+            // One handler succeeded, the other was skipped.
+            event_result(1, 12, None),
+            skipped_result(2, 12),
+        ];
+
+        assert_eq!(all_failed(&results), vec![11]);
+        assert_eq!(all_skipped(&results), vec![10]);
+        assert!(all_skipped(&[]).is_empty());
+    }
+
+    /// Ascending pages continue after the last result, starting from -1.
+    #[test]
+    fn results_cursor_ascending() {
+        assert_eq!(results_start_cursor(-1, false), -1);
+        assert_eq!(results_start_cursor(20, false), 20);
+
+        assert_eq!(next_results_cursor(&[result(21), result(25)], false), 25);
+        assert_eq!(next_results_cursor(&[], false), -1);
+    }
+
+    /// Descending pages start from the newest and continue before the last result, ending at 0.
+    #[test]
+    fn results_cursor_descending() {
+        assert_eq!(results_start_cursor(-1, true), i64::MAX);
+        assert_eq!(results_start_cursor(20, true), 20);
+
+        assert_eq!(next_results_cursor(&[result(25), result(21)], true), 21);
+        assert_eq!(next_results_cursor(&[], true), 0);
+    }
+
+    /// Works with a DOI are read, others are skipped, and blank lines are ignored.
+    #[test]
+    fn ndjson_works() {
+        let data = std::fs::read_to_string("testing/unit/crossref/works.ndjson").unwrap();
+
+        let (works, skipped) = crossref_works_from_ndjson(&data);
+
+        assert_eq!(
+            works
+                .iter()
+                .map(|(x, _)| x.to_stable_string())
+                .collect::<Vec<_>>(),
+            vec![
+                Identifier::parse("10.5555/ndjson-1").to_stable_string(),
+                Identifier::parse("10.5555/ndjson-2").to_stable_string()
+            ]
+        );
+        assert!(works[1].1.contains("Second Work"));
+        assert_eq!(skipped, 2);
+    }
+
+    /// A notification wakes the waiter promptly, rather than after the maximum wait.
+    #[tokio::test]
+    async fn wakeup_on_notification() {
+        let (send, receive) = tokio::sync::oneshot::channel::<()>();
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            send.send(()).unwrap();
+        });
+
+        let start = std::time::Instant::now();
+        let result = wait_for_wakeup(receive, Duration::from_secs(10)).await;
+
+        assert!(result.is_some(), "Expected to be woken by notification.");
+        assert!(
+            start.elapsed() < Duration::from_secs(1),
+            "Expected to wake quickly, took {:?}",
+            start.elapsed()
+        );
+    }
+
+    /// With no notification, the waiter gives up after the maximum wait.
+    #[tokio::test]
+    async fn wakeup_timeout() {
+        let result =
+            wait_for_wakeup(futures::future::pending::<()>(), Duration::from_millis(10)).await;
+
+        assert!(result.is_none(), "Expected timeout without notification.");
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Inserting an Event, which puts it on the queue, notifies a listener well before the maximum wait.
+    /// Committed, as notifications are only sent on commit, then deleted.
+    /// Run with `cargo test queue_insert_notifies -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn queue_insert_notifies() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        let mut listener = PgListener::connect_with(&pool).await.unwrap();
+        listener.listen(EVENT_QUEUE_CHANNEL).await.unwrap();
+
+        let event = Event {
+            event_id: -1,
+            analyzer: EventAnalyzerId::Lifecycle,
+            source: MetadataSourceId::Crossref,
+            subject_id: None,
+            object_id: None,
+            assertion_id: -1,
+            json: format!(
+                "{{\"notify-test\":\"{}\"}}",
+                OffsetDateTime::now_utc().unix_timestamp_nanos()
+            ),
+            occurred_at: None,
+        };
+
+        let mut tx = pool.begin().await.unwrap();
+        let event_id = db::event::insert_event(&event, None, None, EventQueueState::New, &mut tx)
+            .await
+            .unwrap()
+            .unwrap() as i64;
+        tx.commit().await.unwrap();
+
+        let start = std::time::Instant::now();
+        let woken = wait_for_wakeup(listener.recv(), MAX_IDLE_WAIT).await;
+        let elapsed = start.elapsed();
+
+        sqlx::query("DELETE FROM event_queue WHERE event_id = $1")
+            .bind(event_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM event WHERE event_id = $1")
+            .bind(event_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(
+            matches!(woken, Some(Ok(_))),
+            "Expected to be woken by notification."
+        );
+        assert!(
+            elapsed < Duration::from_secs(5),
+            "Expected to wake quickly, took {:?}",
+            elapsed
+        );
+    }
+
+    /// A result inserted after tailing starts is delivered on the stream.
+    #[tokio::test]
+    async fn tail_delivers_new_result() {
+        let stored: Arc<Mutex<Vec<ExecutionResult>>> = Arc::new(Mutex::new(vec![result(1)]));
+
+        let fetch_stored = stored.clone();
+        let stream = tail(1, Duration::from_millis(10), move |cursor| {
+            let page: Vec<ExecutionResult> = fetch_stored
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|x| x.result_id > cursor)
+                .map(|x| result(x.result_id))
+                .collect();
+            let next_cursor = page.last().map(|x| x.result_id).unwrap_or(-1);
+            async move { (page, next_cursor) }
+        });
+        let mut stream = Box::pin(stream);
+
+        // Nothing after the cursor yet.
+        let early = tokio::time::timeout(Duration::from_millis(50), stream.next()).await;
+        assert!(early.is_err(), "Expected no results before insert.");
+
+        stored.lock().unwrap().push(result(2));
+
+        let delivered = tokio::time::timeout(Duration::from_secs(1), stream.next())
+            .await
+            .expect("Expected result to be delivered.");
+        assert_eq!(delivered, Some(result(2)));
+    }
+
+    /// All pages are fetched in order, and the stream ends after the last one.
+    #[tokio::test]
+    async fn paginate_all_pages() {
+        let stored: Vec<i64> = (1..=5).collect();
+
+        let stream = paginate(-1, move |cursor| {
+            let page: Vec<ExecutionResult> = stored
+                .iter()
+                .filter(|x| **x > cursor)
+                .take(2)
+                .map(|x| result(*x))
+                .collect();
+            let next_cursor = page.last().map(|x| x.result_id).unwrap_or(-1);
+            async move { Ok::<_, ServiceError>((page, next_cursor)) }
+        });
+
+        let ids: Vec<i64> = stream.map(|x| x.unwrap().result_id).collect().await;
+        assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+    }
+
+    /// A page that can't be fetched is yielded as an error, and the stream ends there.
+    #[tokio::test]
+    async fn paginate_ends_on_error() {
+        let stream = paginate(-1, move |cursor| async move {
+            if cursor < 0 {
+                Ok((vec![result(1), result(2)], 2))
+            } else {
+                Err(ServiceError::Validation(String::from("Broken")))
+            }
+        });
+
+        let items: Vec<Result<ExecutionResult, ServiceError>> = stream.collect().await;
+        assert_eq!(items.len(), 3);
+        assert!(items[..2].iter().all(|x| x.is_ok()));
+        assert!(matches!(items[2], Err(ServiceError::Validation(_))));
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// An Event skipped by the time budget on its last allowed attempt is put back on the queue
+    /// with the same number of attempts, where one that errored is dead-lettered.
+    /// Rolled back, so nothing is left behind.
+    /// Run with `cargo test budget_skipped_requeued -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn budget_skipped_requeued() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+        let run = OffsetDateTime::now_utc().unix_timestamp_nanos();
+        let retries = dead_letter_retries();
+
+        let mut tx = pool.begin().await.unwrap();
+        let mut event_ids = vec![];
+        for n in 0..2 {
+            let event = Event {
+                event_id: -1,
+                analyzer: EventAnalyzerId::Lifecycle,
+                source: MetadataSourceId::Crossref,
+                subject_id: None,
+                object_id: None,
+                assertion_id: -1,
+                json: format!("{{\"budget-test\":\"{}-{}\"}}", run, n),
+                occurred_at: None,
+            };
+            let event_id =
+                db::event::insert_event(&event, None, None, EventQueueState::New, &mut tx)
+                    .await
+                    .unwrap()
+                    .unwrap();
+            event_ids.push(event_id as i64);
+        }
+        let (skipped_id, errored_id) = (event_ids[0], event_ids[1]);
+
+        // As if polled.
+        sqlx::query("DELETE FROM event_queue WHERE event_id = ANY($1)")
+            .bind(&event_ids)
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+
+        let queued = vec![(skipped_id, None, retries), (errored_id, None, retries)];
+        let results = vec![
+            skipped_result(1, skipped_id),
+            event_result(1, errored_id, Some("Bad")),
+        ];
+
+        let dead_letters = retry_failed(&queued, &results, &mut tx).await.unwrap();
+        assert_eq!(dead_letters, 1);
+
+        let requeued: Vec<(i64, i32)> = sqlx::query_as(
+            "SELECT event_id, attempts FROM event_queue WHERE event_id = ANY($1) ORDER BY event_id",
+        )
+        .bind(&event_ids)
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+        assert_eq!(requeued, vec![(skipped_id, retries)]);
+
+        let dead: Vec<i64> =
+            sqlx::query_scalar("SELECT event_id FROM dead_letter_event WHERE event_id = ANY($1)")
+                .bind(&event_ids)
+                .fetch_all(&mut *tx)
+                .await
+                .unwrap();
+        assert_eq!(dead, vec![errored_id]);
+
+        tx.rollback().await.unwrap();
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Loading 1000 Events whose subjects and objects are drawn from 30 identifiers looks each identifier up once,
+    /// and every inserted Event gets the entity_ids of its own identifiers.
+    /// Events are rolled back, the entities created for them are deleted.
+    /// Run with `cargo test load_events_resolves_each_identifier_once -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn load_events_resolves_each_identifier_once() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+        let marker = format!(
+            "load-dedup-{}",
+            OffsetDateTime::now_utc().unix_timestamp_nanos()
+        );
+
+        // Subjects from 10 DOIs and objects from 20 others, making 200 distinct Events.
+        let items: Vec<Value> = (0..1000)
+            .map(|n| {
+                serde_json::json!({
+                    "source": "test",
+                    "analyzer": "test",
+                    "subject_id": format!("https://doi.org/10.5555/{}-{}", marker, n % 10),
+                    "object_id": format!("https://doi.org/10.5555/{}-{}", marker, 10 + (n / 10) % 20),
+                })
+            })
+            .collect();
+
+        let lookups: Mutex<HashMap<db::entity::IdentifierKey, usize>> = Mutex::new(HashMap::new());
+
+        let mut tx = pool.begin().await.unwrap();
+        let results = load_event_batch_with(
+            &items,
+            |key| {
+                *lookups.lock().unwrap().entry(key.clone()).or_default() += 1;
+                db::entity::resolve_key(key, &pool)
+            },
+            &mut tx,
+        )
+        .await
+        .unwrap();
+
+        let stored: Vec<(String, String)> = sqlx::query_as(
+            "SELECT subject.identifier, object.identifier
+             FROM event
+             JOIN entity AS subject ON subject.entity_id = event.subject_entity_id
+             JOIN entity AS object ON object.entity_id = event.object_entity_id
+             WHERE subject.identifier LIKE $1",
+        )
+        .bind(format!("%{}%", marker))
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+
+        tx.rollback().await.unwrap();
+        sqlx::query("DELETE FROM entity WHERE identifier LIKE $1")
+            .bind(format!("%{}%", marker))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let lookups = lookups.into_inner().unwrap();
+        assert_eq!(lookups.len(), 30, "Each distinct identifier looked up.");
+        assert!(
+            lookups.values().all(|x| *x == 1),
+            "Each identifier looked up once."
+        );
+
+        assert_eq!(
+            results
+                .iter()
+                .filter(|x| **x == EventLoadResult::New)
+                .count(),
+            200
+        );
+        assert_eq!(
+            results
+                .iter()
+                .filter(|x| **x == EventLoadResult::Duplicate)
+                .count(),
+            800
+        );
+
+        // Each stored Event's entities are the identifiers it was loaded with.
+        let identifier = |item: &Value, field: &str| {
+            db::entity::identifier_key(&Identifier::parse(item[field].as_str().unwrap())).1
+        };
+        let expected: BTreeSet<(String, String)> = items
+            .iter()
+            .map(|x| (identifier(x, "subject_id"), identifier(x, "object_id")))
+            .collect();
+        assert_eq!(stored.len(), 200);
+        assert_eq!(stored.into_iter().collect::<BTreeSet<_>>(), expected);
+    }
+
+    /// Poll and commit batches as [`try_pump`] does, without running handlers, until the queue is empty.
+    /// Return the IDs of the Events polled.
+    async fn drain_ids(pool: Pool<Postgres>, exclusive: bool) -> Vec<i64> {
+        let mut polled = vec![];
+        loop {
+            let mut tx = pool.begin().await.unwrap();
+            if exclusive {
+                db::agents::lock_execution(&mut tx).await.unwrap();
+            }
+
+            let events = db::event::poll(3, &mut tx).await.unwrap();
+            if events.is_empty() {
+                return polled;
+            }
+            polled.extend(events.iter().map(|x| x.event.event_id));
+
+            // Hold the batch a while, so the drains overlap.
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            tx.commit().await.unwrap();
+        }
+    }
+
+    /// Needs a database with the schema, from DB_URI.
+    /// Two drains polling the same queue at once, with and without the execution lock,
+    /// each process every Event exactly once between them.
+    /// Drains the whole queue, so use a test database.
+    /// Run with `cargo test concurrent_drains_once -- --ignored`
+    #[tokio::test]
+    #[ignore]
+    async fn concurrent_drains_once() {
+        let pool = get_pool(env::var("DB_URI").unwrap()).await.unwrap();
+
+        for exclusive in [false, true] {
+            let run = OffsetDateTime::now_utc().unix_timestamp_nanos();
+
+            let mut inserted: BTreeSet<i64> = BTreeSet::new();
+            let mut tx = pool.begin().await.unwrap();
+            for n in 0..20 {
+                let event = Event {
+                    event_id: -1,
+                    analyzer: EventAnalyzerId::Lifecycle,
+                    source: MetadataSourceId::Crossref,
+                    subject_id: None,
+                    object_id: None,
+                    assertion_id: -1,
+                    json: format!("{{\"drain-test\":\"{}-{}\"}}", run, n),
+                    occurred_at: None,
+                };
+                let event_id =
+                    db::event::insert_event(&event, None, None, EventQueueState::New, &mut tx)
+                        .await
+                        .unwrap()
+                        .unwrap();
+                inserted.insert(event_id as i64);
+            }
+            tx.commit().await.unwrap();
+
+            let (first, second) = tokio::join!(
+                drain_ids(pool.clone(), exclusive),
+                drain_ids(pool.clone(), exclusive)
+            );
+
+            sqlx::query("DELETE FROM event WHERE event_id = ANY($1)")
+                .bind(inserted.iter().copied().collect::<Vec<i64>>())
+                .execute(&pool)
+                .await
+                .unwrap();
+
+            let ours: Vec<i64> = first
+                .iter()
+                .chain(second.iter())
+                .copied()
+                .filter(|x| inserted.contains(x))
+                .collect();
+
+            assert_eq!(
+                ours.len(),
+                inserted.len(),
+                "No Event processed twice, exclusive: {}",
+                exclusive
+            );
+            assert_eq!(
+                ours.into_iter().collect::<BTreeSet<i64>>(),
+                inserted,
+                "Every Event processed, exclusive: {}",
+                exclusive
             );
-            (vec![], -1)
         }
     }
 }
@@ -1,8 +1,30 @@
 use sha1::{Digest, Sha1};
+use std::{env, fmt::Display, str::FromStr, sync::OnceLock};
+use time::{
+    format_description::{self, well_known::Iso8601},
+    Date, OffsetDateTime,
+};
 
 // This is provided by Cargo at build time, so complied as a static string.
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// Git commit, if `METABEAK_GIT_COMMIT` was set when building.
+pub const GIT_COMMIT: Option<&str> = option_env!("METABEAK_GIT_COMMIT");
+
+static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+/// Shared HTTP client for external APIs.
+/// Advertises gzip and brotli in `Accept-Encoding` and decodes compressed responses transparently.
+pub(crate) fn http_client() -> &'static reqwest::Client {
+    HTTP_CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .gzip(true)
+            .brotli(true)
+            .build()
+            .expect("Can't build HTTP client")
+    })
+}
+
 /// Hash for uniqueness in the database.
 pub(crate) fn hash_data(data: &str) -> String {
     let mut hasher = Sha1::new();
@@ -14,3 +36,199 @@ pub(crate) fn hash_data(data: &str) -> String {
         .collect::<Vec<_>>()
         .join("")
 }
+
+/// Read a positive number from the environment variable, or use the default if it's not set or invalid.
+pub(crate) fn env_or_default<T: FromStr + PartialOrd + Default + Copy + Display>(
+    name: &str,
+    default: T,
+) -> T {
+    parse_setting(name, env::var(name).ok(), default, false)
+}
+
+/// Read a non-negative number from the environment variable, or use the default if it's not set or invalid.
+/// For settings where zero means none, e.g. a number of retries.
+pub(crate) fn env_or_default_allow_zero<T: FromStr + PartialOrd + Default + Copy + Display>(
+    name: &str,
+    default: T,
+) -> T {
+    parse_setting(name, env::var(name).ok(), default, true)
+}
+
+/// Parse a numeric setting, which must be positive, or also zero if allowed.
+/// Use the default if it's not set, and log an error if it's invalid.
+fn parse_setting<T: FromStr + PartialOrd + Default + Copy + Display>(
+    name: &str,
+    value: Option<String>,
+    default: T,
+    allow_zero: bool,
+) -> T {
+    match value.map(|x| x.parse::<T>()) {
+        Some(Ok(value)) if value > T::default() || (allow_zero && value == T::default()) => value,
+        Some(_) => {
+            log::error!("Invalid {}, using default {}", name, default);
+            default
+        }
+        None => default,
+    }
+}
+
+/// Interpret an environment variable as an on/off flag. Unset is off.
+pub(crate) fn parse_flag(value: Option<&str>) -> bool {
+    matches!(
+        value.map(|x| x.trim().to_lowercase()).as_deref(),
+        Some("1" | "true" | "yes" | "on")
+    )
+}
+
+/// Parse a date supplied by the user, either as a date (taken as midnight UTC) or a full ISO 8601 date-time.
+pub(crate) fn parse_date(value: &str) -> Result<OffsetDateTime, String> {
+    if let Ok(date_time) = OffsetDateTime::parse(value, &Iso8601::DEFAULT) {
+        return Ok(date_time);
+    }
+
+    let ymd_format = format_description::parse("[year]-[month]-[day]").unwrap();
+    match Date::parse(value, &ymd_format) {
+        Ok(date) => Ok(date.midnight().assume_utc()),
+        Err(_) => Err(format!(
+            "Invalid date '{}'. Expected YYYY-MM-DD or an ISO 8601 date-time such as 2020-01-01T00:00:00Z.",
+            value
+        )),
+    }
+}
+
+/// Parse a checkpoint setting supplied by the user, in the form `<id>=<date>`.
+pub(crate) fn parse_checkpoint_setting(value: &str) -> Result<(String, OffsetDateTime), String> {
+    match value.split_once('=') {
+        Some((id, date)) if !id.trim().is_empty() => {
+            Ok((String::from(id.trim()), parse_date(date.trim())?))
+        }
+        _ => Err(format!(
+            "Invalid checkpoint setting '{}'. Expected <id>=<date>, e.g. crossref-not-before=2024-01-01.",
+            value
+        )),
+    }
+}
+
+/// Canonical ISBN-13 form of an ISBN, without hyphens or spaces.
+/// ISBN-10s with a valid check digit are converted to ISBN-13. ISBN-13s are returned as they are.
+/// None if it doesn't look like an ISBN, so the caller can leave it unchanged.
+pub(crate) fn normalize_isbn(value: &str) -> Option<String> {
+    let chars: Vec<char> = value
+        .chars()
+        .filter(|x| *x != '-' && !x.is_whitespace())
+        .map(|x| x.to_ascii_uppercase())
+        .collect();
+
+    if chars.len() == 13 && chars.iter().all(|x| x.is_ascii_digit()) {
+        return Some(chars.into_iter().collect());
+    }
+
+    if chars.len() != 10 || !chars[..9].iter().all(|x| x.is_ascii_digit()) {
+        return None;
+    }
+
+    // ISBN-10 check digit is weighted 10 down to 1, modulo 11, with 'X' for 10.
+    let digits: Vec<u32> = chars[..9].iter().filter_map(|x| x.to_digit(10)).collect();
+    let check = match chars[9] {
+        'X' => 10,
+        x => x.to_digit(10)?,
+    };
+    let sum: u32 = digits
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (10 - i as u32) * x)
+        .sum::<u32>()
+        + check;
+    if !sum.is_multiple_of(11) {
+        return None;
+    }
+
+    // ISBN-13 is the 978 prefix, the first 9 digits, and a check digit weighted alternately 1 and 3, modulo 10.
+    let mut isbn13: Vec<u32> = vec![9, 7, 8];
+    isbn13.extend(digits);
+    let weighted: u32 = isbn13
+        .iter()
+        .enumerate()
+        .map(|(i, x)| if i % 2 == 0 { *x } else { x * 3 })
+        .sum();
+    isbn13.push((10 - weighted % 10) % 10);
+
+    Some(isbn13.iter().map(|x| x.to_string()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Zero is only accepted where it's allowed. Negative and unparseable values use the default.
+    #[test]
+    fn parse_settings() {
+        let setting = |value: &str, allow_zero| {
+            parse_setting("TEST_SETTING", Some(String::from(value)), 2, allow_zero)
+        };
+
+        assert_eq!(setting("5", false), 5);
+        assert_eq!(setting("0", false), 2);
+        assert_eq!(setting("0", true), 0);
+        assert_eq!(setting("-1", true), 2);
+        assert_eq!(setting("many", true), 2);
+        assert_eq!(parse_setting("TEST_SETTING", None, 2, true), 2);
+    }
+
+    #[test]
+    fn parse_date_formats() {
+        let expected = OffsetDateTime::from_unix_timestamp(1577836800).unwrap();
+
+        assert_eq!(parse_date("2020-01-01"), Ok(expected));
+        assert_eq!(parse_date("2020-01-01T00:00:00Z"), Ok(expected));
+    }
+
+    #[test]
+    fn parse_date_invalid() {
+        assert!(parse_date("2020-13-01").is_err());
+        assert!(parse_date("yesterday").is_err());
+        assert!(parse_date("").is_err());
+    }
+
+    #[test]
+    fn parse_checkpoint_settings() {
+        let expected = OffsetDateTime::from_unix_timestamp(1577836800).unwrap();
+
+        assert_eq!(
+            parse_checkpoint_setting("crossref-not-before=2020-01-01"),
+            Ok((String::from("crossref-not-before"), expected))
+        );
+        assert_eq!(
+            parse_checkpoint_setting("crossref-not-before=2020-01-01T00:00:00Z"),
+            Ok((String::from("crossref-not-before"), expected))
+        );
+
+        assert!(parse_checkpoint_setting("crossref-not-before").is_err());
+        assert!(parse_checkpoint_setting("=2020-01-01").is_err());
+        assert!(parse_checkpoint_setting("crossref-not-before=yesterday").is_err());
+    }
+
+    /// ISBN-10 and ISBN-13 forms of the same number are the same.
+    #[test]
+    fn normalize_isbn_forms() {
+        assert_eq!(
+            normalize_isbn("0521643651"),
+            Some(String::from("9780521643658"))
+        );
+        assert_eq!(
+            normalize_isbn("0-521-64365-1"),
+            Some(String::from("9780521643658"))
+        );
+        assert_eq!(
+            normalize_isbn("978-0-521-64365-8"),
+            Some(String::from("9780521643658"))
+        );
+        assert_eq!(
+            normalize_isbn("080442957x"),
+            Some(String::from("9780804429573"))
+        );
+
+        assert_eq!(normalize_isbn("0521643652"), None, "Bad check digit.");
+        assert_eq!(normalize_isbn("10.5555/12345678"), None);
+    }
+}